@@ -0,0 +1,273 @@
+// Dojo Learning Environment
+// Copyright (C) 2023-2024 Carlos Perez-Lopez
+//
+// This project is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>
+//
+// You can contact the author via carlospzlz@gmail.com
+
+use log::error;
+use std::cmp::Ordering;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use super::q_learning;
+
+#[derive(Clone, Copy, PartialEq)]
+enum SortBy {
+    Name,
+    Characters,
+    States,
+    TrainingTime,
+    Modified,
+}
+
+struct AgentEntry {
+    path: PathBuf,
+    name: String,
+    characters: String,
+    number_of_states: usize,
+    training_time: Duration,
+    modified: SystemTime,
+}
+
+// Lists the agent directories (and agent files) under a root folder, with
+// the metadata from their agent.json
+pub struct AgentPicker {
+    root: String,
+    entries: Vec<AgentEntry>,
+    filter: String,
+    sort_by: SortBy,
+    ascending: bool,
+    selected: Option<PathBuf>,
+}
+
+impl AgentPicker {
+    pub fn new(root: &str) -> Self {
+        let mut agent_picker = Self {
+            root: root.to_string(),
+            entries: Vec::new(),
+            filter: String::new(),
+            sort_by: SortBy::Name,
+            ascending: true,
+            selected: None,
+        };
+        agent_picker.refresh();
+        agent_picker
+    }
+
+    pub fn refresh(&mut self) {
+        self.entries.clear();
+        let dir = match fs::read_dir(&self.root) {
+            Ok(dir) => dir,
+            Err(e) => {
+                error!("Error listing agents in {}: {}", self.root, e);
+                return;
+            }
+        };
+        for entry in dir.flatten() {
+            let path = entry.path();
+            // Anything without an agent.json is not an agent, unless it is
+            // an agent file
+            let Some(metadata) = q_learning::load_agent_metadata(&path) else {
+                continue;
+            };
+            let metadata_path = match path.is_file() {
+                true => path.clone(),
+                false => path.join("agent.json"),
+            };
+            let modified = fs::metadata(metadata_path)
+                .and_then(|metadata| metadata.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            let characters = match metadata.characters {
+                Some([agent, opponent]) => format!("{} vs {}", agent, opponent),
+                None => "?".to_string(),
+            };
+            self.entries.push(AgentEntry {
+                name: entry.file_name().to_string_lossy().to_string(),
+                path,
+                characters,
+                number_of_states: metadata.number_of_states,
+                training_time: metadata.training_time,
+                modified,
+            });
+        }
+        self.sort();
+    }
+
+    fn sort(&mut self) {
+        let sort_by = self.sort_by;
+        self.entries.sort_by(|a, b| match sort_by {
+            SortBy::Name => natural_cmp(&a.name, &b.name),
+            SortBy::Characters => natural_cmp(&a.characters, &b.characters),
+            SortBy::States => a.number_of_states.cmp(&b.number_of_states),
+            SortBy::TrainingTime => a.training_time.cmp(&b.training_time),
+            SortBy::Modified => a.modified.cmp(&b.modified),
+        });
+        if !self.ascending {
+            self.entries.reverse();
+        }
+    }
+
+    // Returns the chosen agent path once "Load" is clicked
+    pub fn show(&mut self, ctx: &egui::Context, open: &mut bool) -> Option<PathBuf> {
+        let mut chosen = None;
+        egui::Window::new("Load Agent").open(open).show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Folder:");
+                if ui.text_edit_singleline(&mut self.root).lost_focus() {
+                    self.refresh();
+                }
+                if ui.button("Refresh").clicked() {
+                    self.refresh();
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Filter:");
+                ui.text_edit_singleline(&mut self.filter);
+            });
+            egui::ScrollArea::vertical()
+                .max_height(300.0)
+                .show(ui, |ui| {
+                    egui::Grid::new("agent_picker")
+                        .striped(true)
+                        .show(ui, |ui| {
+                            self.header(ui, "Name", SortBy::Name);
+                            self.header(ui, "Characters", SortBy::Characters);
+                            self.header(ui, "States", SortBy::States);
+                            self.header(ui, "Training Time", SortBy::TrainingTime);
+                            self.header(ui, "Modified", SortBy::Modified);
+                            ui.end_row();
+
+                            let filter = self.filter.to_lowercase();
+                            for entry in self.entries.iter() {
+                                let text =
+                                    format!("{} {}", entry.name, entry.characters).to_lowercase();
+                                if !text.contains(&filter) {
+                                    continue;
+                                }
+                                let is_selected = self.selected.as_ref() == Some(&entry.path);
+                                if ui.selectable_label(is_selected, &entry.name).clicked() {
+                                    self.selected = Some(entry.path.clone());
+                                }
+                                ui.label(&entry.characters);
+                                ui.label(format!("{}", entry.number_of_states));
+                                ui.label(format_duration(entry.training_time));
+                                ui.label(format_age(entry.modified));
+                                ui.end_row();
+                            }
+                        });
+                });
+            ui.horizontal(|ui| {
+                let can_load = self.selected.is_some();
+                if ui
+                    .add_enabled(can_load, egui::Button::new("Load"))
+                    .clicked()
+                {
+                    chosen = self.selected.clone();
+                }
+            });
+        });
+        if chosen.is_some() {
+            *open = false;
+        }
+        chosen
+    }
+
+    fn header(&mut self, ui: &mut egui::Ui, name: &str, sort_by: SortBy) {
+        let arrow = match (self.sort_by == sort_by, self.ascending) {
+            (true, true) => " ⏶",
+            (true, false) => " ⏷",
+            (false, _) => "",
+        };
+        if ui.button(format!("{}{}", name, arrow)).clicked() {
+            if self.sort_by == sort_by {
+                self.ascending = !self.ascending;
+            } else {
+                self.sort_by = sort_by;
+                self.ascending = true;
+            }
+            self.sort();
+        }
+    }
+}
+
+// So that agent_2 comes before agent_10
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        match (a.chars().next(), b.chars().next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (number_a, rest_a) = split_number(a);
+                let (number_b, rest_b) = split_number(b);
+                // Compare by length first, numbers can be longer than u64
+                let trimmed_a = number_a.trim_start_matches('0');
+                let trimmed_b = number_b.trim_start_matches('0');
+                let ordering = trimmed_a
+                    .len()
+                    .cmp(&trimmed_b.len())
+                    .then_with(|| trimmed_a.cmp(trimmed_b));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                (a, b) = (rest_a, rest_b);
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.to_ascii_lowercase().cmp(&y.to_ascii_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                (a, b) = (&a[x.len_utf8()..], &b[y.len_utf8()..]);
+            }
+        }
+    }
+}
+
+fn split_number(s: &str) -> (&str, &str) {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    s.split_at(end)
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let total_seconds = duration.as_secs();
+    let hours = total_seconds / 3600;
+    let minutes = (total_seconds % 3600) / 60;
+    let seconds = total_seconds % 60;
+    format!("{:02}:{:02}:{:02}", hours, minutes, seconds)
+}
+
+fn format_age(modified: SystemTime) -> String {
+    let age = SystemTime::now()
+        .duration_since(modified)
+        .unwrap_or(Duration::ZERO)
+        .as_secs();
+    match age {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{} min ago", age / 60),
+        3600..=86399 => format!("{} h ago", age / 3600),
+        _ => format!("{} days ago", age / 86400),
+    }
+}
+
+pub fn default_root(last_opened: &Option<PathBuf>) -> String {
+    // Where the last agent was, otherwise the working directory
+    last_opened
+        .as_deref()
+        .and_then(Path::parent)
+        .map(|parent| parent.to_string_lossy().to_string())
+        .unwrap_or_else(|| ".".to_string())
+}
@@ -0,0 +1,160 @@
+// Dojo Learning Environment
+// Copyright (C) 2023-2024 Carlos Perez-Lopez
+//
+// This project is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>
+//
+// You can contact the author via carlospzlz@gmail.com
+
+use std::collections::VecDeque;
+
+// SPU output, interleaved stereo
+const SAMPLE_RATE: i32 = 44100;
+// Beyond this the emulation runs ahead of real time, the oldest are dropped
+const MAX_LEVEL: usize = SAMPLE_RATE as usize / 5;
+
+// Samples waiting to be played, resampled to the device rate as they are
+// played. SpeedController::resample_ratio drifts the rate a little around
+// it, so the level stays close to its target instead of running dry or
+// piling up.
+struct RingBuffer {
+    frames: VecDeque<[i16; 2]>,
+    // Input frames per output frame, without the drift
+    step: f64,
+    // Output/input ratio on top of it, from the speed controller
+    ratio: f64,
+    // Between the first two frames, they are interpolated
+    position: f64,
+    muted: bool,
+}
+
+impl RingBuffer {
+    fn new(device_rate: i32) -> Self {
+        Self {
+            frames: VecDeque::with_capacity(MAX_LEVEL),
+            step: SAMPLE_RATE as f64 / device_rate as f64,
+            ratio: 1.0,
+            position: 0.0,
+            muted: false,
+        }
+    }
+
+    fn push(&mut self, samples: &[i16]) {
+        for frame in samples.chunks_exact(2) {
+            if self.frames.len() == MAX_LEVEL {
+                self.frames.pop_front();
+            }
+            self.frames.push_back([frame[0], frame[1]]);
+        }
+    }
+
+    fn fill(&mut self, out: &mut [i16]) {
+        let step = self.step / self.ratio;
+        for frame in out.chunks_exact_mut(2) {
+            while self.position >= 1.0 && self.frames.len() > 1 {
+                self.frames.pop_front();
+                self.position -= 1.0;
+            }
+            // Silence until more comes, the emulation is behind
+            let (Some(a), Some(b)) = (self.frames.front(), self.frames.get(1)) else {
+                frame.fill(0);
+                continue;
+            };
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let (a, b) = (a[channel] as f64, b[channel] as f64);
+                *sample = match self.muted {
+                    true => 0,
+                    false => (a + (b - a) * self.position) as i16,
+                };
+            }
+            self.position += step;
+        }
+    }
+}
+
+#[cfg(feature = "audio")]
+impl sdl2::audio::AudioCallback for RingBuffer {
+    type Channel = i16;
+
+    fn callback(&mut self, out: &mut [i16]) {
+        self.fill(out);
+    }
+}
+
+// Plays the SPU output on the default device, through SDL2. Muted, the
+// samples are still consumed at the same pace, so audio sync keeps working.
+pub struct AudioOutput {
+    #[cfg(feature = "audio")]
+    device: sdl2::audio::AudioDevice<RingBuffer>,
+}
+
+impl AudioOutput {
+    #[cfg(feature = "audio")]
+    pub fn new() -> Result<Self, String> {
+        // SDL would turn Ctrl+C into an event nobody polls here
+        sdl2::hint::set("SDL_NO_SIGNAL_HANDLERS", "1");
+        let audio = sdl2::init()?.audio()?;
+        let desired = sdl2::audio::AudioSpecDesired {
+            freq: Some(SAMPLE_RATE),
+            channels: Some(2),
+            samples: Some(1024),
+        };
+        let device = audio.open_playback(None, &desired, |spec| RingBuffer::new(spec.freq))?;
+        if device.spec().channels != 2 {
+            return Err("The audio device isn't stereo".to_string());
+        }
+        device.resume();
+        Ok(Self { device })
+    }
+
+    #[cfg(not(feature = "audio"))]
+    pub fn new() -> Result<Self, String> {
+        Err("Built without the audio feature".to_string())
+    }
+
+    // Interleaved stereo at 44.1kHz, as System::get_audio_samples gives them
+    pub fn push(&mut self, samples: &[i16]) {
+        #[cfg(feature = "audio")]
+        self.device.lock().push(samples);
+        #[cfg(not(feature = "audio"))]
+        let _ = samples;
+    }
+
+    // Samples (not frames) still to be played, what SpeedController takes
+    pub fn queued_samples(&mut self) -> usize {
+        #[cfg(feature = "audio")]
+        let queued = self.device.lock().frames.len() * 2;
+        #[cfg(not(feature = "audio"))]
+        let queued = 0;
+        queued
+    }
+
+    // What SpeedController::resample_ratio gives for queued_samples
+    pub fn set_resample_ratio(&mut self, ratio: f64) {
+        #[cfg(feature = "audio")]
+        {
+            self.device.lock().ratio = ratio;
+        }
+        #[cfg(not(feature = "audio"))]
+        let _ = ratio;
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        #[cfg(feature = "audio")]
+        {
+            self.device.lock().muted = muted;
+        }
+        #[cfg(not(feature = "audio"))]
+        let _ = muted;
+    }
+}
@@ -0,0 +1,102 @@
+// Dojo Learning Environment
+// Copyright (C) 2023-2024 Carlos Perez-Lopez
+//
+// This project is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>
+//
+// You can contact the author via carlospzlz@gmail.com
+
+use super::cpu_log::parse_hex;
+use super::psx::System;
+
+// PC breakpoints. The emulation pauses when one is hit, Continue runs the
+// instruction it stopped at and carries on. Kept here too, so they survive
+// loading a state or a movie.
+pub struct Breakpoints {
+    addresses: Vec<u32>,
+    // Hex, as typed
+    address: String,
+    error: Option<String>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Self {
+            addresses: Vec::new(),
+            address: "80000000".to_string(),
+            error: None,
+        }
+    }
+
+    // For a system replacing the one they were added to
+    pub fn apply(&self, system: &mut System) {
+        for &address in &self.addresses {
+            system.add_breakpoint(address);
+        }
+    }
+
+    pub fn show(
+        &mut self,
+        ctx: &egui::Context,
+        open: &mut bool,
+        system: &mut System,
+        is_running: &mut bool,
+    ) {
+        egui::Window::new("Breakpoints").open(open).show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.add(egui::TextEdit::singleline(&mut self.address).desired_width(70.0));
+                if ui.button("Add").clicked() {
+                    match parse_hex(&self.address) {
+                        Ok(address) if !self.addresses.contains(&address) => {
+                            system.add_breakpoint(address);
+                            self.addresses.push(address);
+                            self.error = None;
+                        }
+                        Ok(_) => self.error = None,
+                        Err(e) => self.error = Some(e),
+                    }
+                }
+            });
+            if let Some(error) = &self.error {
+                ui.label(error);
+            }
+            let mut removed = None;
+            for &address in &self.addresses {
+                ui.horizontal(|ui| {
+                    ui.monospace(format!("{:08x}", address));
+                    if ui.button("Remove").clicked() {
+                        removed = Some(address);
+                    }
+                });
+            }
+            if let Some(address) = removed {
+                system.remove_breakpoint(address);
+                self.addresses.retain(|&other| other != address);
+            }
+            ui.separator();
+            match system.get_breakpoint_hit() {
+                Some(pc) => {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("Stopped at {:08x}", pc));
+                        if ui.button("Continue").clicked() {
+                            *is_running = true;
+                        }
+                    });
+                }
+                None => {
+                    ui.label("No breakpoint hit");
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,109 @@
+// Dojo Learning Environment
+// Copyright (C) 2023-2024 Carlos Perez-Lopez
+//
+// This project is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>
+//
+// You can contact the author via carlospzlz@gmail.com
+
+use super::psx::{InstructionLogConfig, System};
+
+// Sampled instruction logging, written to the log at debug level (run with
+// RUST_LOG=debug). Every Nth instruction, optionally only within a PC range,
+// until the limit of lines is reached.
+pub struct CpuLog {
+    every: u64,
+    limit: u64,
+    in_range: bool,
+    // Hex, as typed
+    start: String,
+    end: String,
+    error: Option<String>,
+}
+
+impl CpuLog {
+    pub fn new() -> Self {
+        Self {
+            every: 1000,
+            limit: 10000,
+            in_range: false,
+            start: "80000000".to_string(),
+            end: "801fffff".to_string(),
+            error: None,
+        }
+    }
+
+    fn config(&self) -> Result<InstructionLogConfig, String> {
+        let pc_range = match self.in_range {
+            true => Some((parse_hex(&self.start)?, parse_hex(&self.end)?)),
+            false => None,
+        };
+        Ok(InstructionLogConfig {
+            every: self.every,
+            pc_range,
+            limit: self.limit,
+        })
+    }
+
+    pub fn show(&mut self, ctx: &egui::Context, open: &mut bool, system: &mut System) {
+        egui::Window::new("CPU Log").open(open).show(ctx, |ui| {
+            egui::Grid::new("cpu_log").show(ui, |ui| {
+                ui.label("Every:");
+                ui.add(egui::DragValue::new(&mut self.every).clamp_range(1..=1_000_000));
+                ui.end_row();
+                ui.checkbox(&mut self.in_range, "PC Range:");
+                ui.horizontal(|ui| {
+                    ui.add_enabled(
+                        self.in_range,
+                        egui::TextEdit::singleline(&mut self.start).desired_width(70.0),
+                    );
+                    ui.label("-");
+                    ui.add_enabled(
+                        self.in_range,
+                        egui::TextEdit::singleline(&mut self.end).desired_width(70.0),
+                    );
+                });
+                ui.end_row();
+                ui.label("Limit:");
+                ui.add(egui::DragValue::new(&mut self.limit).clamp_range(1..=10_000_000));
+                ui.end_row();
+            });
+            ui.horizontal(|ui| {
+                if ui.button("Start").clicked() {
+                    match self.config() {
+                        Ok(config) => {
+                            system.set_instruction_log(Some(config));
+                            self.error = None;
+                        }
+                        Err(e) => self.error = Some(e),
+                    }
+                }
+                if ui.button("Stop").clicked() {
+                    system.set_instruction_log(None);
+                }
+                match system.get_instructions_logged() {
+                    Some(logged) => ui.label(format!("{} lines logged", logged)),
+                    None => ui.label("Not logging"),
+                };
+            });
+            if let Some(error) = &self.error {
+                ui.label(error);
+            }
+        });
+    }
+}
+
+pub fn parse_hex(text: &str) -> Result<u32, String> {
+    let digits = text.trim().trim_start_matches("0x");
+    u32::from_str_radix(digits, 16).map_err(|_| format!("Invalid address: {}", text))
+}
@@ -0,0 +1,201 @@
+// Dojo Learning Environment
+// Copyright (C) 2023-2024 Carlos Perez-Lopez
+//
+// This project is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>
+//
+// You can contact the author via carlospzlz@gmail.com
+
+use image::{Rgb, RgbImage};
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::time::Instant;
+
+// Agents
+#[allow(dead_code)]
+mod q_backend;
+#[allow(dead_code)]
+mod q_learning;
+// Computer vision, the agent states are abstractions from it
+#[allow(dead_code)]
+mod vision;
+
+use q_learning::{Agent, Progress, SerDesAgent};
+
+const DEFAULT_THUMBNAILS: usize = 64;
+// Pixels between abstractions in a contact sheet
+const THUMBNAIL_GAP: u32 = 2;
+
+// Saved agents, outside the GUI
+fn main() {
+    env_logger::init();
+    let args: Vec<String> = std::env::args().collect();
+    let result = match args.get(1).map(|command| command.as_str()) {
+        Some("convert") if args.len() >= 4 => convert(&args[2], &args[3]),
+        Some("inspect") if args.len() >= 3 => inspect(&args[2]),
+        Some("thumbnail") if args.len() >= 4 => {
+            let count = args
+                .get(4)
+                .and_then(|count| count.parse().ok())
+                .unwrap_or(DEFAULT_THUMBNAILS);
+            thumbnail(&args[2], &args[3], count)
+        }
+        _ => {
+            eprintln!("Usage: {} convert <agent> <new agent>", args[0]);
+            eprintln!("       {} inspect <agent>", args[0]);
+            eprintln!("       {} thumbnail <agent> <png> [count]", args[0]);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+// Agent directory into a single agent file, or back
+fn convert(path: &str, new_path: &str) -> Result<(), String> {
+    let start_time = Instant::now();
+    let agent = q_learning::load_agent(path)?;
+    let progress = Progress::default();
+    match q_learning::is_agent_file(Path::new(path)) {
+        true => q_learning::save_agent_with_progress(&agent, new_path, &progress)?,
+        false => q_learning::save_agent_file(&agent, new_path, &progress)?,
+    }
+    println!(
+        "{} states converted in {:.1} s, {:.1} KiB to {:.1} KiB",
+        agent.get_number_of_states(),
+        start_time.elapsed().as_secs_f64(),
+        size_on_disk(Path::new(path)) as f64 / 1024.0,
+        size_on_disk(Path::new(new_path)) as f64 / 1024.0
+    );
+    Ok(())
+}
+
+fn inspect(path: &str) -> Result<(), String> {
+    let format = match q_learning::is_agent_file(Path::new(path)) {
+        true => "file",
+        false => "directory",
+    };
+    let version = q_learning::load_agent_metadata(Path::new(path))
+        .ok_or(format!("{}: not an agent", path))?
+        .version;
+    let agent = q_learning::load_agent(path)?;
+    let metadata = SerDesAgent::new(&agent);
+    let characters = match &metadata.characters {
+        Some([agent, opponent]) => format!("{} vs {}", agent, opponent),
+        None => "?".to_string(),
+    };
+    let seconds = metadata.training_time.as_secs();
+    println!("Format: {}, version {}", format, version);
+    println!("Characters: {}", characters);
+    println!(
+        "States: {} ({} ever added)",
+        agent.get_number_of_states(),
+        metadata.number_of_states
+    );
+    println!("Iterations: {}", metadata.iteration_number);
+    println!(
+        "Training time: {:02}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds % 3600) / 60,
+        seconds % 60
+    );
+    println!(
+        "Learning rate: {}, discount factor: {}",
+        metadata.learning_rate, metadata.discount_factor
+    );
+    let (raw_frames, raw_bytes) = agent.get_raw_frames_size();
+    println!(
+        "Raw frames: {} ({:.1} KiB)",
+        raw_frames,
+        raw_bytes as f64 / 1024.0
+    );
+    match agent.get_backend_learned() {
+        Some(learned) => println!(
+            "Q backend: {}, {} tiles learned",
+            agent.get_backend_name(),
+            learned
+        ),
+        None => println!("Q backend: {}", agent.get_backend_name()),
+    }
+    println!("Transitions: {}", agent.get_transitions().len());
+    println!(
+        "Size on disk: {:.1} KiB",
+        size_on_disk(Path::new(path)) as f64 / 1024.0
+    );
+    if let Some(q_stats) = agent.get_q_stats() {
+        print!("{}", q_stats);
+    }
+    Ok(())
+}
+
+// Contact sheet of state abstractions, evenly spread over the states
+fn thumbnail(path: &str, png_path: &str, count: usize) -> Result<(), String> {
+    let agent: Agent = q_learning::load_agent(path)?;
+    let abstractions = agent.get_sample_abstractions(count);
+    if abstractions.is_empty() {
+        return Err(format!("{}: no states", path));
+    }
+    let columns = (abstractions.len() as f64).sqrt().ceil() as u32;
+    let rows = (abstractions.len() as u32).div_ceil(columns);
+    let width = abstractions
+        .iter()
+        .map(|frame| frame.width())
+        .max()
+        .unwrap();
+    let height = abstractions
+        .iter()
+        .map(|frame| frame.height())
+        .max()
+        .unwrap();
+    let mut sheet = RgbImage::from_pixel(
+        columns * (width + THUMBNAIL_GAP) - THUMBNAIL_GAP,
+        rows * (height + THUMBNAIL_GAP) - THUMBNAIL_GAP,
+        Rgb([32, 32, 32]),
+    );
+    for (i, frame) in abstractions.iter().enumerate() {
+        let x = (i as u32 % columns) * (width + THUMBNAIL_GAP);
+        let y = (i as u32 / columns) * (height + THUMBNAIL_GAP);
+        image::imageops::replace(&mut sheet, frame, x as i64, y as i64);
+    }
+    sheet
+        .save(png_path)
+        .map_err(|e| format!("{}: {}", png_path, e))?;
+    println!(
+        "{} of {} states written to {}",
+        abstractions.len(),
+        agent.get_number_of_states(),
+        png_path
+    );
+    Ok(())
+}
+
+// Bytes of a file, or of everything under a directory
+fn size_on_disk(path: &Path) -> u64 {
+    let Ok(metadata) = fs::metadata(path) else {
+        return 0;
+    };
+    if metadata.is_file() {
+        return metadata.len();
+    }
+    fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| size_on_disk(&entry.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
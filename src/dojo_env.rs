@@ -0,0 +1,156 @@
+// Dojo Learning Environment
+// Copyright (C) 2023-2024 Carlos Perez-Lopez
+//
+// This project is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>
+//
+// You can contact the author via carlospzlz@gmail.com
+
+use rand::Rng;
+use std::time::Instant;
+
+// Headless environment
+mod env;
+// RAM features for the Memory observation
+mod game_data;
+// Emu system
+#[allow(dead_code)]
+mod psx;
+// Reward functions, the GUI also picks them
+#[allow(dead_code)]
+mod reward;
+// Computer vision, the GUI also shows every stage
+#[allow(dead_code)]
+mod vision;
+
+use env::{DojoEnv, EnvConfig};
+
+// Steps replayed from a rewind at the end of every episode, with --rewind
+const REWIND_STEPS: usize = 60;
+
+// Runs episodes with random actions, to benchmark the environment or check
+// a state file without opening the GUI
+fn main() {
+    env_logger::init();
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 {
+        eprintln!(
+            "Usage: {} <state> [episodes] [--game <game>] [--frames-per-step <frames>] \
+             [--rewind <megabytes>]",
+            args[0]
+        );
+        return;
+    }
+    let episodes = args
+        .get(2)
+        .and_then(|episodes| episodes.parse().ok())
+        .unwrap_or(1);
+    let option = |name: &str| {
+        args.iter()
+            .position(|arg| arg == name)
+            .and_then(|i| args.get(i + 1))
+    };
+    let mut config = EnvConfig {
+        state_path: args[1].clone(),
+        game_path: option("--game").cloned(),
+        ..EnvConfig::default()
+    };
+    if let Some(frames) = option("--frames-per-step").and_then(|frames| frames.parse().ok()) {
+        config.frames_per_step = frames;
+    }
+    if let Some(megabytes) =
+        option("--rewind").and_then(|megabytes| megabytes.parse::<usize>().ok())
+    {
+        config.rewind_budget = megabytes << 20;
+    }
+    let check_rewind = config.rewind_budget > 0;
+
+    let mut dojo_env = match DojoEnv::new(config) {
+        Ok(dojo_env) => dojo_env,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let mut rng = rand::thread_rng();
+    let start_time = Instant::now();
+    let mut total_steps = 0;
+    for episode in 0..episodes {
+        if let Err(e) = dojo_env.reset() {
+            eprintln!("{}", e);
+            return;
+        }
+        let mut steps = 0;
+        let mut total_reward = 0.0;
+        let mut actions = Vec::new();
+        loop {
+            let action = rng.gen_range(0..dojo_env.action_space()) as u8;
+            let (_, reward, done, step_info) = dojo_env.step(action);
+            actions.push(action);
+            steps += 1;
+            total_reward += reward;
+            if done {
+                let result = match (
+                    step_info.info.agent_life > 0.0,
+                    step_info.info.opponent_life > 0.0,
+                ) {
+                    (true, false) => "won",
+                    (false, _) => "lost",
+                    (true, true) => "unfinished",
+                };
+                println!(
+                    "Episode {}: {}, {} steps, reward {:.3}",
+                    episode, result, steps, total_reward
+                );
+                if check_rewind {
+                    check_rewind_replay(&mut dojo_env, &actions);
+                }
+                break;
+            }
+        }
+        total_steps += steps;
+    }
+    let elapsed = start_time.elapsed().as_secs_f64();
+    let (width, height) = dojo_env.observation_space().unwrap_or_default();
+    println!(
+        "{} steps in {:.1} s ({:.1} steps/s), observations {}x{}",
+        total_steps,
+        elapsed,
+        total_steps as f64 / elapsed,
+        width,
+        height
+    );
+}
+
+// Goes back a few steps and takes them again, the episode must end the same
+fn check_rewind_replay(dojo_env: &mut DojoEnv, actions: &[u8]) {
+    let system = dojo_env.system();
+    let expected = (system.get_frame_count(), system.get_ram_hash());
+    let steps = actions.len().min(REWIND_STEPS);
+    if let Err(e) = dojo_env.rewind(steps) {
+        println!("Rewind failed: {}", e);
+        return;
+    }
+    for action in &actions[actions.len() - steps..] {
+        dojo_env.step(*action);
+    }
+    let system = dojo_env.system();
+    let replayed = (system.get_frame_count(), system.get_ram_hash());
+    match replayed == expected {
+        true => println!("Rewound {} steps, the replay ends the same", steps),
+        false => println!(
+            "Rewound {} steps, the replay differs (frame {}, RAM hash {:016x}, was frame {}, {:016x})",
+            steps, replayed.0, replayed.1, expected.0, expected.1
+        ),
+    }
+}
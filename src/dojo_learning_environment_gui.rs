@@ -16,15 +16,19 @@
 //
 // You can contact the author via carlospzlz@gmail.com
 
+use agent_picker::AgentPicker;
 use egui::plot::{Line, Plot, PlotPoints};
 use egui::{Align, Color32, ColorImage, Layout, Vec2};
 use egui_file::FileDialog;
-use image::{DynamicImage, Rgb, RgbImage};
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
-use std::time::{Duration, Instant};
+use image::{DynamicImage, GrayImage, Rgb, RgbImage};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
 
 // Utils to "see" the screen
 mod vision;
@@ -32,13 +36,109 @@ mod vision;
 mod psx;
 // AI agent
 mod q_learning;
+// Value functions other than a Q table per state
+mod q_backend;
 
+// Exported plots and reports
+mod report;
+
+// Agent selection with metadata
+mod agent_picker;
+
+// Recorded input sequences
+mod macros;
+
+// Reward functions picked at runtime
+mod reward;
+
+// Asks for files that can't be used
+mod file_prompt;
+
+// Frames from pressing buttons to the game responding
+mod input_latency;
+
+// Observations read from game memory
+mod game_data;
+
+// Session events over wall-clock time
+mod timeline;
+
+// SPU output, only with the audio feature
+#[cfg_attr(not(feature = "audio"), allow(dead_code))]
+mod audio;
+
+// Vision, reward and agent on synthetic frames
+#[cfg(test)]
+mod learning_loop_tests;
+
+use audio::AudioOutput;
+use file_prompt::{FilePrompt, PromptAction};
+use game_data::GameData;
+use input_latency::InputLatency;
+use macros::Macros;
+use psx::speed::{SpeedController, SpeedMode};
 use psx::System;
-use q_learning::Agent;
-use vision::LifeInfo;
+use q_backend::TileCoding;
+use q_learning::{
+    Agent, AgentWorker, Progress, SameState, Sampling, StateMatching, TrainingBudget,
+    TransferStrategy,
+};
+use reward::{RewardInfo, Rewards};
+use timeline::{Bookmark, EventKind, Timeline};
+use vision::{
+    AbstractionCache, AbstractionQuality, Exclusion, FrameAbstraction, LifeInfo, Observation,
+    VisionStages,
+};
 
 const STATES_DIR: &str = "states";
+// State files kept in memory, one per character pairing
+const STATE_POOL_SIZE: usize = 8;
+// One pixel probability model per character, kept across sessions
+const PIXEL_PROBABILITIES_DIR: &str = "pixel_probabilities";
+// Unsaved training is dumped here when the window closes
+const RECOVERY_DIR: &str = "recovery";
+const MACROS_DIR: &str = "macros";
+// Reward expression used when the reward is set to Script
+const REWARD_SCRIPT_PATH: &str = "reward.txt";
+// Where the game keeps what the Memory observation reads
+const GAME_DATA_PATH: &str = "game_data.json";
+// One StepInfo per line while logging steps
+const STEP_LOG_PATH: &str = "steps.jsonl";
+// One Demonstration per line, for every observation the human had control
+const DEMONSTRATIONS_PATH: &str = "demonstrations.jsonl";
+// Pad keys for the human override, same layout as psx-sdl. Bits as in the
+// agent actions.
+const OVERRIDE_KEYS: [(egui::Key, u8); 12] = [
+    (egui::Key::ArrowUp, 0),
+    (egui::Key::W, 0),
+    (egui::Key::ArrowDown, 1),
+    (egui::Key::S, 1),
+    (egui::Key::ArrowLeft, 2),
+    (egui::Key::A, 2),
+    (egui::Key::ArrowRight, 3),
+    (egui::Key::D, 3),
+    (egui::Key::I, 4),
+    (egui::Key::J, 5),
+    (egui::Key::L, 6),
+    (egui::Key::K, 7),
+];
 const REPLAY_DURATION: Duration = Duration::from_secs(2);
+// Fall back to the previous action if the agent takes longer than this
+const AGENT_DEADLINE: Duration = Duration::from_millis(8);
+// Deadline when keeping every observation, only a stuck agent gets there
+const AGENT_WAIT: Duration = Duration::from_secs(10);
+// Colours listed per character in the pixel probabilities window
+const TOP_COLORS: usize = 16;
+const TOP_COLORS_MIN_SAMPLES: u64 = 100;
+// Throughput rates are averaged over this much running time
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(5);
+// Emulated frames per second, for the speed ratio
+const PSX_FRAME_RATE: f64 = 60.0;
+// Reward for giving up a fight, as bad as losing the whole life bar
+const FORFEIT_PENALTY: f32 = 4.0;
+// Centroid distance (Manhattan, normalized) the characters count as close at
+// for the adaptive observation frequency
+const EXCHANGE_DISTANCE: f32 = 0.3;
 
 fn main() -> Result<(), eframe::Error> {
     env_logger::init(); // Log to stderr (if you run with `RUST_LOG=debug`)
@@ -53,7 +153,7 @@ fn main() -> Result<(), eframe::Error> {
     )
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 enum Character {
     Eddy,
     Jin,
@@ -66,7 +166,7 @@ enum Character {
     Nina,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum Vision {
     PSX,
     Life,
@@ -80,6 +180,109 @@ enum Vision {
     Segmented,
 }
 
+// GUI settings saved next to the agent in a recovery session
+#[derive(Serialize, Deserialize)]
+struct SessionConfig {
+    character1: Character,
+    character2: Character,
+    observation: Observation,
+    observation_frequency: u32,
+    learning_rate: f32,
+    discount_factor: f32,
+    red_thresholds: [u8; 2],
+    green_thresholds: [u8; 2],
+    blue_thresholds: [u8; 2],
+    dilate_k: u8,
+    max_mse: f64,
+    char1_probability_threshold: f64,
+    char2_probability_threshold: f64,
+    char1_dilate_k: u8,
+    char2_dilate_k: u8,
+    #[serde(default)]
+    exclusions: Vec<Exclusion>,
+    trace: u8,
+    radius: f32,
+    #[serde(default)]
+    mirrored: bool,
+    #[serde(default)]
+    swap_sides: bool,
+    #[serde(default)]
+    adaptive_frequency: bool,
+    #[serde(default = "default_neutral_frequency")]
+    neutral_frequency: u32,
+}
+
+fn default_neutral_frequency() -> u32 {
+    5
+}
+
+// Box being dragged over a view, start and current corners
+#[derive(Clone, Copy)]
+struct ExclusionDrag {
+    view: usize,
+    corners: [(u32, u32); 2],
+}
+
+// What gives way when vision and agent don't fit in the frame budget
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Pacing {
+    // Emulation keeps its speed, late observations are dropped
+    DropObservations,
+    // Observations follow game time, emulation slows down instead
+    KeepObservations,
+}
+
+// How the central panel is split between vision stages
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ViewLayout {
+    Single,
+    // PSX on top, vision below
+    Split,
+    SideBySide,
+    Grid,
+}
+
+impl ViewLayout {
+    fn number_of_views(&self) -> usize {
+        match self {
+            ViewLayout::Single => 1,
+            ViewLayout::Split | ViewLayout::SideBySide => 2,
+            ViewLayout::Grid => 4,
+        }
+    }
+}
+
+// Frames left to run while paused
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Step {
+    Frames(u32),
+    // Until the agent observes
+    Observation,
+}
+
+// Series shown in the States window
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PlotTab {
+    States,
+    MaxQ,
+}
+
+// Timeline bookmark the plots still have to jump to: states, max Q and win
+// rate, each one the next time it is drawn
+struct PlotJump {
+    bookmark: Bookmark,
+    pending: [bool; 3],
+}
+
+// Bookmark the plot (index in PlotJump::pending) has yet to jump to
+fn take_plot_jump(plot_jump: &mut Option<PlotJump>, plot: usize) -> Option<Bookmark> {
+    let jump = plot_jump.as_mut()?;
+    if !std::mem::take(&mut jump.pending[plot]) {
+        return None;
+    }
+    Some(jump.bookmark)
+}
+
 struct FrameTime {
     total_time: Duration,
     ui_time: Duration,
@@ -98,26 +301,307 @@ impl Default for FrameTime {
     }
 }
 
+// What happened on an observation, for logging and debugging outside the GUI
+// without running the vision again
+#[derive(Serialize)]
+struct StepInfo {
+    #[serde(flatten)]
+    info: RewardInfo,
+    // Emulated seconds, see System::get_emulated_time
+    time: f64,
+    // Training episodes finished before this one
+    round: usize,
+    evaluation: bool,
+    action: u8,
+    // The pad got it with left and right swapped, see swap_sides
+    sides_swapped: bool,
+    reward: f32,
+    // As of the last state the agent has processed, which lags behind if
+    // the agent missed its deadline
+    state_index: Option<usize>,
+    new_state: bool,
+}
+
+// State files of the last few combats, so starting one over (every episode)
+// or switching back to a pairing doesn't read it again. Entries are read
+// again when the file changes, e.g. saved again from psx-gui.
+struct StatePool {
+    // Least recently used first
+    entries: VecDeque<(String, SystemTime, Vec<u8>)>,
+    hits: u64,
+    misses: u64,
+}
+
+impl StatePool {
+    fn new() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn get(&mut self, filepath: &str) -> io::Result<&[u8]> {
+        let modified = fs::metadata(filepath)?.modified()?;
+        let index = self
+            .entries
+            .iter()
+            .position(|(path, _, _)| path == filepath);
+        let entry = match index.and_then(|index| self.entries.remove(index)) {
+            Some(entry) if entry.1 == modified => {
+                self.hits += 1;
+                entry
+            }
+            _ => {
+                self.misses += 1;
+                if self.entries.len() == STATE_POOL_SIZE {
+                    self.entries.pop_front();
+                }
+                (filepath.to_string(), modified, fs::read(filepath)?)
+            }
+        };
+        self.entries.push_back(entry);
+        Ok(&self.entries.back().unwrap().2)
+    }
+}
+
+// Agent save or load running on its own thread. The emulator stays paused
+// until it is done.
+struct AgentJob {
+    title: &'static str,
+    path: PathBuf,
+    progress: Arc<Progress>,
+    // The loaded agent, None when saving
+    thread: JoinHandle<Result<Option<Agent>, String>>,
+    // Training time of the agent being saved
+    training_time: Option<Duration>,
+    was_running: bool,
+}
+
+// An observation where the human had control
+#[derive(Serialize)]
+struct Demonstration {
+    #[serde(flatten)]
+    info: RewardInfo,
+    time: f64,
+    round: usize,
+    // Taken by the human
+    action: u8,
+    // What the agent would have done instead
+    agent_action: u8,
+    reward: f32,
+    // Whether the agent learned from it
+    learned: bool,
+}
+
+// Greedy matches against any opponent, apart from the training episodes
+// and the periodic evaluation
+struct Simulation {
+    matches: usize,
+    opponent: Character,
+    left: usize,
+    wins: usize,
+    // Return of the match being played
+    match_reward: f32,
+    total_reward: f32,
+    // Opponent being trained against, back in place once done
+    training_opponent: Character,
+    // Win rate and average return of the last run
+    result: Option<(f64, f32)>,
+}
+
+impl Simulation {
+    fn new() -> Self {
+        Self {
+            matches: 10,
+            opponent: Character::Lei,
+            left: 0,
+            wins: 0,
+            match_reward: 0.0,
+            total_reward: 0.0,
+            training_opponent: Character::Lei,
+            result: None,
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.left > 0
+    }
+
+    // True once the last match is over
+    fn end_match(&mut self, won: bool) -> bool {
+        self.wins += won as usize;
+        self.total_reward += self.match_reward;
+        self.match_reward = 0.0;
+        self.left -= 1;
+        if self.left > 0 {
+            return false;
+        }
+        let played = self.matches as f32;
+        self.result = Some((self.wins as f64 / played as f64, self.total_reward / played));
+        true
+    }
+}
+
+// Training throughput. Frame and observation rates come from the last
+// THROUGHPUT_WINDOW, episodes are too slow for that and use the whole session.
+struct Throughput {
+    // Time, frames and observations, sampled every update while running
+    samples: VecDeque<(Instant, u64, u64)>,
+    frames: u64,
+    observations: u64,
+    running_time: Duration,
+    episodes: usize,
+}
+
+impl Throughput {
+    fn new() -> Self {
+        Self {
+            samples: VecDeque::new(),
+            frames: 0,
+            observations: 0,
+            running_time: Duration::ZERO,
+            episodes: 0,
+        }
+    }
+
+    fn sample(&mut self, running_time: Duration) {
+        let now = Instant::now();
+        self.running_time += running_time;
+        self.samples
+            .push_back((now, self.frames, self.observations));
+        while let Some((time, _, _)) = self.samples.front() {
+            if now - *time <= THROUGHPUT_WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    // Rates while paused would count the pause
+    fn pause(&mut self) {
+        self.samples.clear();
+    }
+
+    // Frames and observations per second
+    fn rates(&self) -> Option<(f64, f64)> {
+        let (first_time, first_frames, first_observations) = self.samples.front()?;
+        let (last_time, last_frames, last_observations) = self.samples.back()?;
+        let elapsed = (*last_time - *first_time).as_secs_f64();
+        if elapsed <= 0.0 {
+            return None;
+        }
+        Some((
+            (last_frames - first_frames) as f64 / elapsed,
+            (last_observations - first_observations) as f64 / elapsed,
+        ))
+    }
+
+    fn episodes_per_hour(&self) -> Option<f64> {
+        if self.episodes == 0 {
+            return None;
+        }
+        Some(self.episodes as f64 * 3600.0 / self.running_time.as_secs_f64())
+    }
+}
+
 struct MyApp {
     system: Option<System>,
     frame: RgbImage,
+    // Luma of the same frame, straight from VRAM for the life bars
+    frame_gray: GrayImage,
+    display: vision::DisplayInfo,
     is_running: bool,
-    is_running_next_frame: bool,
+    step: Option<Step>,
+    // Frames run by the +N step
+    step_frames: u32,
     last_vision_stages: vision::VisionStages,
+    abstraction_cache: AbstractionCache,
     last_reward: f32,
     vision: Vision,
-    split_view: bool,
+    view_layout: ViewLayout,
+    // Visions shown next to the main one when comparing
+    compare_visions: [Vision; 3],
+    // One per view, updated in place instead of reallocated every frame
+    view_textures: Vec<egui::TextureHandle>,
     character1: Character,
     character2: Character,
     agent_life_info: LifeInfo,
     opponent_life_info: LifeInfo,
     replay: Option<std::time::Duration>,
-    agent: Agent,
+    speed_controller: SpeedController,
+    // None without a device, or built without the audio feature
+    audio: Option<AudioOutput>,
+    muted: bool,
+    pacing: Pacing,
+    // Observation periods missed while behind, see Pacing
+    missed_observations: u64,
+    // Observations the agent never saw, their abstraction scored under
+    // min_abstraction_quality (0 keeps them all)
+    discarded_observations: u64,
+    min_abstraction_quality: f32,
+    abstraction_quality: AbstractionQuality,
+    previous_centroids: Option<[(f32, f32); 2]>,
+    agent: AgentWorker,
+    training_budget: TrainingBudget,
+    episodes: Vec<bool>,
+    evaluation_interval: usize,
+    evaluation_matches: usize,
+    evaluation_left: usize,
+    evaluation_wins: usize,
+    evaluation_win_rates: Vec<[f64; 2]>,
+    simulation: Simulation,
     observation_frequency: u32,
+    // Observing at observation_frequency only during exchanges (life bars
+    // changing or characters close), at neutral_frequency otherwise
+    adaptive_frequency: bool,
+    neutral_frequency: u32,
+    // Life bars and centroid distance at the last observation
+    observed_lifes: (f32, f32),
+    observed_distance: f32,
+    observation: Observation,
+    // Read again whenever the Memory observation is picked
+    game_data: Result<GameData, String>,
+    // Playing from player 2's side, seen and controlled as player 1
+    mirrored: bool,
+    // Left and right are swapped while the agent is on the right, so
+    // actions are always toward and away from the opponent
+    swap_sides: bool,
+    sides_swapped: bool,
+    // Times the characters crossed sides, whether swapped or not
+    side_swaps: usize,
     time_from_last_observation: std::time::Duration,
     frame_time: FrameTime,
+    throughput: Throughput,
+    input_latency: InputLatency,
+    step_log: Option<BufWriter<File>>,
+    // Pad keys held, and whether the human keeps control without them
+    human_action: u8,
+    hold_override: bool,
+    learn_from_override: bool,
+    correction: f32,
+    demonstrations: usize,
+    demonstration_log: Option<BufWriter<File>>,
+    rewards: Rewards,
+    // Abandon the combat after this many emulated seconds without life bars,
+    // or in total. 0 disables them.
+    hud_lost_limit: u32,
+    max_combat_duration: u32,
+    // Emulated time the life bars went away at, and the combat started at
+    hud_lost_since: Option<Duration>,
+    combat_start: Duration,
     learning_rate: f32,
     discount_factor: f32,
+    same_state: SameState,
+    same_state_penalty: f32,
+    state_matching: StateMatching,
+    // Transitions kept to learn from again, 0 for none, and how many of them
+    // each replay learns from
+    replay_capacity: usize,
+    replay_batch_size: usize,
+    replay_sampling: Sampling,
+    // Same as the agent's, read on every observation without locking it
+    store_raw_frames: bool,
     red_thresholds: [u8; 2],
     green_thresholds: [u8; 2],
     blue_thresholds: [u8; 2],
@@ -129,62 +613,179 @@ struct MyApp {
     char2_probability_threshold: f64,
     char1_dilate_k: u8,
     char2_dilate_k: u8,
+    exclusions: Vec<Exclusion>,
+    editing_exclusions: bool,
+    exclusion_drag: Option<ExclusionDrag>,
     previous_trace_abstraction: RgbImage,
     trace: u8,
-    radius: u32,
+    radius: f32,
     show_states_plot: bool,
-    show_q_plot: bool,
+    plot_tab: PlotTab,
+    // Moving average window of the max Q plot, 1 shows the raw values only
+    q_smoothing: usize,
+    show_win_rate_plot: bool,
+    timeline: Timeline,
+    show_timeline: bool,
+    plot_jump: Option<PlotJump>,
+    show_pixel_probabilities: bool,
+    // Character (0 or 1) and whether it is saving, with its dialog
+    pixel_probability_dialog: Option<(usize, bool, FileDialog)>,
     opened_agent: Option<PathBuf>,
-    open_file_dialog: Option<FileDialog>,
+    agent_picker: Option<AgentPicker>,
+    show_agent_picker: bool,
+    // The picked agent is transferred into the current one instead of loaded
+    agent_transfer: Option<TransferStrategy>,
     saved_file: Option<PathBuf>,
     save_file_dialog: Option<FileDialog>,
+    exported_report: Option<PathBuf>,
+    export_report_dialog: Option<FileDialog>,
+    exported_graph: Option<PathBuf>,
+    export_graph_dialog: Option<FileDialog>,
+    reabstract_dialog: Option<FileDialog>,
+    agent_job: Option<AgentJob>,
+    state_pool: StatePool,
+    // Training time when the agent was last saved or loaded
+    saved_training_time: Duration,
+    show_recovery_prompt: bool,
+    macros: Macros,
+    show_macros: bool,
+    // Where the game is on this machine, for states that point elsewhere
+    game_path: Option<String>,
+    game_prompt: Option<FilePrompt>,
 }
 
 impl MyApp {
     fn new(_cc: &eframe::CreationContext<'_>) -> Self {
-        let radius = 20;
+        let radius = 0.05;
         let mut agent = Agent::new();
         agent.set_radius(radius);
+        let learning_rate = agent.get_learning_rate();
+        let discount_factor = agent.get_discount_factor();
         Self {
             system: None,
             frame: RgbImage::default(),
+            frame_gray: GrayImage::default(),
+            display: vision::DisplayInfo::default(),
             is_running: false,
-            is_running_next_frame: false,
+            step: None,
+            step_frames: 10,
             last_reward: 0.0,
             last_vision_stages: vision::VisionStages::default(),
+            abstraction_cache: AbstractionCache::new(),
             vision: Vision::Agent,
-            split_view: true,
+            view_layout: ViewLayout::Split,
+            compare_visions: [Vision::Mask, Vision::Segmented, Vision::Centroids],
+            view_textures: Vec::new(),
             character1: Character::Xiaoyu,
             character2: Character::Lei,
             agent_life_info: LifeInfo::default(),
             opponent_life_info: LifeInfo::default(),
             replay: None,
-            agent,
+            speed_controller: SpeedController::new(SpeedMode::Unlimited),
+            audio: match AudioOutput::new() {
+                Ok(audio) => Some(audio),
+                Err(e) => {
+                    println!("No audio output: {}", e);
+                    None
+                }
+            },
+            muted: false,
+            pacing: Pacing::DropObservations,
+            missed_observations: 0,
+            discarded_observations: 0,
+            min_abstraction_quality: 0.0,
+            abstraction_quality: AbstractionQuality::default(),
+            previous_centroids: None,
+            agent: AgentWorker::new(agent),
+            training_budget: TrainingBudget::new(),
+            episodes: Vec::new(),
+            evaluation_interval: 0,
+            evaluation_matches: 3,
+            evaluation_left: 0,
+            evaluation_wins: 0,
+            evaluation_win_rates: Vec::new(),
+            simulation: Simulation::new(),
             observation_frequency: 15,
+            adaptive_frequency: false,
+            neutral_frequency: default_neutral_frequency(),
+            observed_lifes: (1.0, 1.0),
+            observed_distance: 0.0,
+            observation: Observation::Trace,
+            game_data: GameData::load(GAME_DATA_PATH),
+            mirrored: false,
+            swap_sides: false,
+            sides_swapped: false,
+            side_swaps: 0,
             time_from_last_observation: Duration::from_secs(1),
             frame_time: FrameTime::default(),
-            learning_rate: 0.5,
-            discount_factor: 0.9,
+            throughput: Throughput::new(),
+            input_latency: InputLatency::new(),
+            step_log: None,
+            human_action: 0,
+            hold_override: false,
+            learn_from_override: true,
+            correction: 0.0,
+            demonstrations: 0,
+            demonstration_log: None,
+            rewards: Rewards::new(REWARD_SCRIPT_PATH),
+            hud_lost_limit: 10,
+            max_combat_duration: 180,
+            hud_lost_since: None,
+            combat_start: Duration::ZERO,
+            learning_rate,
+            discount_factor,
+            same_state: SameState::NoOp,
+            same_state_penalty: 0.0,
+            state_matching: StateMatching::Mse,
+            replay_capacity: 0,
+            replay_batch_size: 32,
+            replay_sampling: Sampling::Uniform,
+            store_raw_frames: false,
             red_thresholds: [0, 173],
             green_thresholds: [15, 165],
             blue_thresholds: [15, 156],
             dilate_k: 12,
             max_mse: 2000.0,
-            char1_pixel_probability: HashMap::new(),
-            char2_pixel_probability: HashMap::new(),
+            char1_pixel_probability: load_character_pixel_probability(&Character::Xiaoyu),
+            char2_pixel_probability: load_character_pixel_probability(&Character::Lei),
             char1_probability_threshold: 0.7,
             char2_probability_threshold: 0.7,
             char1_dilate_k: 2,
             char2_dilate_k: 2,
+            exclusions: Vec::new(),
+            editing_exclusions: false,
+            exclusion_drag: None,
             previous_trace_abstraction: RgbImage::default(),
             trace: 3,
             radius,
             show_states_plot: false,
-            show_q_plot: false,
+            plot_tab: PlotTab::States,
+            q_smoothing: 50,
+            show_win_rate_plot: false,
+            timeline: Timeline::new(),
+            show_timeline: false,
+            plot_jump: None,
+            show_pixel_probabilities: false,
+            pixel_probability_dialog: None,
             opened_agent: None,
-            open_file_dialog: None,
+            agent_picker: None,
+            show_agent_picker: false,
+            agent_transfer: None,
             saved_file: None,
             save_file_dialog: None,
+            exported_report: None,
+            export_report_dialog: None,
+            exported_graph: None,
+            export_graph_dialog: None,
+            reabstract_dialog: None,
+            agent_job: None,
+            state_pool: StatePool::new(),
+            saved_training_time: Duration::ZERO,
+            show_recovery_prompt: Path::new(RECOVERY_DIR).exists(),
+            macros: Macros::new(MACROS_DIR),
+            show_macros: false,
+            game_path: None,
+            game_prompt: None,
         }
     }
 }
@@ -194,34 +795,39 @@ impl eframe::App for MyApp {
         let start_time = Instant::now();
         self.menu_bar(ctx);
         self.show_states_plot(ctx);
-        self.show_q_plot(ctx);
+        self.show_win_rate_plot(ctx);
+        self.show_timeline(ctx);
+        self.show_pixel_probabilities(ctx);
+        self.macros.show(ctx, &mut self.show_macros);
         self.left_panel(ctx);
         self.right_panel(ctx);
         self.bottom_panel(ctx);
         self.central_panel(ctx);
         self.file_dialogs(ctx);
+        self.recovery_prompt(ctx);
+        self.game_prompt(ctx);
+        self.hotkeys(ctx);
+        self.agent_job(ctx);
         self.frame_time.ui_time = Instant::now() - start_time;
 
         // Processing
         if self.is_running {
             self.process_frame();
-        } else if self.is_running_next_frame {
-            self.is_running_next_frame = !self.process_frame();
+        } else if let Some(step) = self.step {
+            let observed = self.process_frame();
+            self.step = match step {
+                Step::Frames(frames) if frames > 1 => Some(Step::Frames(frames - 1)),
+                Step::Observation if !observed && self.observation_frequency > 0 => Some(step),
+                _ => None,
+            };
+            if !observed {
+                // Vision follows every step, not only the observed ones
+                let (_, vision_stages) = self.get_frame_abstraction(false);
+                self.last_vision_stages = vision_stages;
+            }
         } else {
             // Even if not running update vision
-            let (_, vision_stages) = vision::get_frame_abstraction(
-                &self.frame.clone(),
-                self.red_thresholds,
-                self.green_thresholds,
-                self.blue_thresholds,
-                self.dilate_k,
-                &mut self.char1_pixel_probability.clone(),
-                &mut self.char2_pixel_probability.clone(),
-                self.char1_probability_threshold,
-                self.char2_probability_threshold,
-                self.char1_dilate_k,
-                self.char2_dilate_k,
-            );
+            let (_, vision_stages) = self.get_frame_abstraction(false);
             self.last_vision_stages = vision_stages;
         }
 
@@ -231,9 +837,30 @@ impl eframe::App for MyApp {
         self.frame_time.total_time = Instant::now() - start_time;
 
         // Update traning time
-        if self.is_running || self.is_running_next_frame {
-            self.agent.add_training_time(self.frame_time.total_time);
+        if self.is_running || self.step.is_some() {
+            self.agent
+                .agent()
+                .add_training_time(self.frame_time.total_time);
+            self.throughput.sample(self.frame_time.total_time);
+            self.check_training_budget();
+        } else {
+            self.throughput.pause();
+            self.training_budget.stop();
+        }
+    }
+
+    fn on_close_event(&mut self) -> bool {
+        if let Some(demonstration_log) = self.demonstration_log.as_mut() {
+            if let Err(e) = demonstration_log.flush() {
+                eprintln!("Error writing {}: {}", DEMONSTRATIONS_PATH, e);
+            }
+        }
+        save_character_pixel_probability(&self.character1, &self.char1_pixel_probability);
+        save_character_pixel_probability(&self.character2, &self.char2_pixel_probability);
+        if self.agent.agent().get_training_time() > self.saved_training_time {
+            self.save_recovery_session();
         }
+        true
     }
 }
 
@@ -244,13 +871,28 @@ impl MyApp {
                 ui.menu_button("File", |ui| {
                     if ui.button("Load Agent").clicked() {
                         self.is_running = false;
-                        let dialog = FileDialog::select_folder(self.opened_agent.clone());
-                        let dialog = dialog.title("Load Agent");
-                        let mut dialog = dialog.default_size(Vec2 { x: 300.0, y: 200.0 });
-                        dialog.open();
-                        self.open_file_dialog = Some(dialog);
+                        let root = agent_picker::default_root(&self.opened_agent);
+                        self.agent_picker = Some(AgentPicker::new(&root));
+                        self.show_agent_picker = true;
+                        self.agent_transfer = None;
                         ui.close_menu();
                     }
+                    ui.menu_button("Transfer From Agent", |ui| {
+                        let strategies = [
+                            ("Matched States", TransferStrategy::MatchedStates),
+                            ("Policy Priors", TransferStrategy::PolicyPriors),
+                        ];
+                        for (name, strategy) in strategies {
+                            if ui.button(name).clicked() {
+                                self.is_running = false;
+                                let root = agent_picker::default_root(&self.opened_agent);
+                                self.agent_picker = Some(AgentPicker::new(&root));
+                                self.show_agent_picker = true;
+                                self.agent_transfer = Some(strategy);
+                                ui.close_menu();
+                            }
+                        }
+                    });
                     if ui.button("Save Agent").clicked() {
                         self.is_running = false;
                         let dialog = FileDialog::save_file(self.saved_file.clone());
@@ -260,16 +902,67 @@ impl MyApp {
                         self.save_file_dialog = Some(dialog);
                         ui.close_menu();
                     }
+                    if ui.button("Re-abstract Agent").clicked() {
+                        self.is_running = false;
+                        let dialog = FileDialog::select_folder(self.opened_agent.clone());
+                        let dialog = dialog.title("Re-abstract Agent");
+                        let mut dialog = dialog.default_size(Vec2 { x: 300.0, y: 200.0 });
+                        dialog.open();
+                        self.reabstract_dialog = Some(dialog);
+                        ui.close_menu();
+                    }
+                });
+
+                ui.menu_button("Export", |ui| {
+                    if ui.button("Export Report").clicked() {
+                        let dialog = FileDialog::save_file(self.exported_report.clone());
+                        let dialog = dialog.title("Export Report");
+                        let mut dialog = dialog.default_size(Vec2 { x: 300.0, y: 200.0 });
+                        dialog.open();
+                        self.export_report_dialog = Some(dialog);
+                        ui.close_menu();
+                    }
+                    if ui.button("Export Graph").clicked() {
+                        let dialog = FileDialog::save_file(self.exported_graph.clone());
+                        let dialog = dialog.title("Export Graph (.dot or .gexf)");
+                        let mut dialog = dialog.default_size(Vec2 { x: 300.0, y: 200.0 });
+                        dialog.open();
+                        self.export_graph_dialog = Some(dialog);
+                        ui.close_menu();
+                    }
+                    let mut log_steps = self.step_log.is_some();
+                    if ui.checkbox(&mut log_steps, "Log Steps").changed() {
+                        self.set_step_log(log_steps);
+                        ui.close_menu();
+                    }
                 });
 
                 // Additional menus can be added here, like Edit, View, etc.
                 ui.menu_button("Advanced", |ui| {
                     if ui.button("Open States Plot").clicked() {
                         self.show_states_plot = true;
+                        self.plot_tab = PlotTab::States;
                         ui.close_menu();
                     }
                     if ui.button("Open Q Plot").clicked() {
-                        self.show_q_plot = true;
+                        self.show_states_plot = true;
+                        self.plot_tab = PlotTab::MaxQ;
+                        ui.close_menu();
+                    }
+                    if ui.button("Open Win Rate Plot").clicked() {
+                        self.show_win_rate_plot = true;
+                        ui.close_menu();
+                    }
+                    if ui.button("Open Timeline").clicked() {
+                        self.show_timeline = true;
+                        ui.close_menu();
+                    }
+                    if ui.button("Open Pixel Probabilities").clicked() {
+                        self.show_pixel_probabilities = true;
+                        ui.close_menu();
+                    }
+                    if ui.button("Open Macros").clicked() {
+                        self.show_macros = true;
                         ui.close_menu();
                     }
                 });
@@ -281,55 +974,135 @@ impl MyApp {
         egui::CentralPanel::default().show(ctx, |ui| {
             // Fill all available space
             let asize = ui.available_size();
-            let new_width = asize[0].round() as u32;
-            let new_height = if self.split_view {
-                asize[1].round() / 2.0
-            } else {
-                asize[1].round()
-            } as u32;
-
-            // If split view, always show PSX view
-            let img = self.frame.clone();
-            if self.split_view {
-                let img = DynamicImage::ImageRgb8(img);
-                let img =
-                    img.resize_exact(new_width, new_height, image::imageops::FilterType::Lanczos3);
-                let img = img.to_rgb8();
-                let img =
-                    ColorImage::from_rgb([new_width as usize, new_height as usize], img.as_raw());
-                let texture = ctx.load_texture("psx_frame", img, Default::default());
-                ui.image(&texture, texture.size_vec2());
-            }
-
-            // Show vision chosen by user
-            let mut img = self.frame.clone();
-            match self.vision {
-                Vision::Life => img = vision::visualize_life_bars(img),
-                Vision::Agent => img = self.agent.get_last_state_abstraction(),
-                Vision::Crop => img = self.last_vision_stages.cropped_frame.clone(),
-                Vision::Contrast => img = self.last_vision_stages.contrast_frame.clone(),
-                Vision::Mask => img = self.last_vision_stages.mask.clone(),
-                Vision::Masked => img = self.last_vision_stages.masked_frame.clone(),
-                Vision::Centroids => img = self.last_vision_stages.centroids_hud.clone(),
-                Vision::Chars => img = self.last_vision_stages.chars_hud.clone(),
-                Vision::Segmented => img = self.last_vision_stages.segmented_frame.clone(),
-                Vision::PSX => (),
-            }
-
-            let img = DynamicImage::ImageRgb8(img);
-            let img =
-                img.resize_exact(new_width, new_height, image::imageops::FilterType::Lanczos3);
-            let img = img.to_rgb8();
-
-            // Load texture
-            let img = ColorImage::from_rgb([new_width as usize, new_height as usize], img.as_raw());
-            let texture = ctx.load_texture("psx_frame", img, Default::default());
-
-            // Show frame
-            ui.image(&texture, texture.size_vec2());
+            let spacing = ui.spacing().item_spacing;
+            let (columns, rows) = match self.view_layout {
+                ViewLayout::Single => (1, 1),
+                ViewLayout::Split => (1, 2),
+                ViewLayout::SideBySide => (2, 1),
+                ViewLayout::Grid => (2, 2),
+            };
+            let new_width = ((asize[0] - spacing[0] * (columns - 1) as f32) / columns as f32)
+                .round()
+                .max(1.0) as u32;
+            let new_height = ((asize[1] - spacing[1] * (rows - 1) as f32) / rows as f32)
+                .round()
+                .max(1.0) as u32;
+
+            // Split view always shows the PSX on top
+            let visions = match self.view_layout {
+                ViewLayout::Split => vec![Vision::PSX, self.vision],
+                _ => {
+                    let mut visions = vec![self.vision];
+                    visions.extend_from_slice(&self.compare_visions);
+                    visions.truncate(self.view_layout.number_of_views());
+                    visions
+                }
+            };
+            self.view_textures.truncate(visions.len());
+
+            for row in 0..rows {
+                ui.horizontal(|ui| {
+                    for column in 0..columns {
+                        let view = row * columns + column;
+                        let mut img = self.vision_image(visions[view]);
+                        let (width, height) = img.dimensions();
+                        // PSX and life bars are the only uncropped views
+                        let y_offset = match visions[view] {
+                            Vision::PSX | Vision::Life => vision::CROP_Y,
+                            _ => 0,
+                        };
+                        if self.editing_exclusions {
+                            vision::draw_exclusions(&mut img, &self.exclusions, y_offset);
+                            if let Some(ExclusionDrag {
+                                view: drag_view,
+                                corners,
+                            }) = self.exclusion_drag
+                            {
+                                if drag_view == view {
+                                    let exclusion = exclusion_from_corners(corners);
+                                    vision::draw_exclusions(&mut img, &[exclusion], y_offset);
+                                }
+                            }
+                        }
+                        let img = DynamicImage::ImageRgb8(img);
+                        let img = img.resize_exact(
+                            new_width,
+                            new_height,
+                            image::imageops::FilterType::Lanczos3,
+                        );
+                        let img = img.to_rgb8();
+
+                        // Load texture
+                        let img = ColorImage::from_rgb(
+                            [new_width as usize, new_height as usize],
+                            img.as_raw(),
+                        );
+                        if let Some(texture) = self.view_textures.get_mut(view) {
+                            texture.set(img, Default::default());
+                        } else {
+                            let name = format!("psx_frame_{}", view);
+                            let texture = ctx.load_texture(name, img, Default::default());
+                            self.view_textures.push(texture);
+                        }
+
+                        // Show frame
+                        let texture = &self.view_textures[view];
+                        let image = egui::Image::new(texture, texture.size_vec2());
+                        if !self.editing_exclusions {
+                            ui.add(image);
+                            continue;
+                        }
+                        let response = ui.add(image.sense(egui::Sense::drag()));
+                        let corner = response.interact_pointer_pos().map(|pos| {
+                            let pos = pos - response.rect.min;
+                            let x = pos.x / new_width as f32 * width as f32;
+                            let y = pos.y / new_height as f32 * height as f32;
+                            (
+                                x.clamp(0.0, width as f32) as u32,
+                                (y.clamp(0.0, height as f32) as u32).saturating_sub(y_offset),
+                            )
+                        });
+                        if let Some(corner) = corner {
+                            if response.drag_started() {
+                                self.exclusion_drag = Some(ExclusionDrag {
+                                    view,
+                                    corners: [corner, corner],
+                                });
+                            } else if let Some(drag) = &mut self.exclusion_drag {
+                                if drag.view == view {
+                                    drag.corners[1] = corner;
+                                }
+                            }
+                        }
+                        if response.drag_released() {
+                            if let Some(drag) = self.exclusion_drag.take() {
+                                let exclusion = exclusion_from_corners(drag.corners);
+                                if exclusion[2] > 0 && exclusion[3] > 0 {
+                                    self.exclusions.push(exclusion);
+                                }
+                            }
+                        }
+                    }
+                });
+            }
         });
     }
 
+    fn vision_image(&self, vision: Vision) -> RgbImage {
+        match vision {
+            Vision::Life => vision::visualize_life_bars(self.frame.clone()),
+            Vision::Agent => self.agent.agent().get_last_state_abstraction(),
+            Vision::Crop => self.last_vision_stages.cropped_frame.clone(),
+            Vision::Contrast => self.last_vision_stages.contrast_frame.clone(),
+            Vision::Mask => self.last_vision_stages.mask.clone(),
+            Vision::Masked => self.last_vision_stages.masked_frame.clone(),
+            Vision::Centroids => self.last_vision_stages.centroids_hud.clone(),
+            Vision::Chars => self.last_vision_stages.chars_hud.clone(),
+            Vision::Segmented => self.last_vision_stages.segmented_frame.clone(),
+            Vision::PSX => self.frame.clone(),
+        }
+    }
+
     fn bottom_panel(&mut self, ctx: &egui::Context) {
         egui::TopBottomPanel::bottom("my_bottom_panel").show(ctx, |ui| {
             let asize = ui.available_size();
@@ -458,72 +1231,172 @@ impl MyApp {
                     }
                 }
             });
+            // One button per recorded macro
+            let names = self.macros.names();
+            if !names.is_empty() {
+                ui.horizontal_wrapped(|ui| {
+                    for (index, name) in names.iter().enumerate() {
+                        if ui.button(name).clicked() {
+                            self.macros.play(index);
+                        }
+                    }
+                });
+            }
         });
     }
 
     fn left_panel(&mut self, ctx: &egui::Context) {
+        let characters = (self.character1.clone(), self.character2.clone());
         egui::SidePanel::left("my_left_panel").show(ctx, |ui| {
             ui.horizontal(|_ui| {});
             egui::Grid::new("general_options").show(ui, |ui| {
                 ui.label("AI agent:");
-                egui::ComboBox::from_id_source("agent_character")
-                    .selected_text(format!("{:?}", self.character1))
+                character_combo_box(ui, "agent_character", &mut self.character1);
+                ui.end_row();
+                ui.label("Opponent:");
+                character_combo_box(ui, "opponent_character", &mut self.character2);
+                ui.end_row();
+                ui.label("Speed:");
+                let mut speed_mode = self.speed_controller.mode();
+                egui::ComboBox::from_id_source("speed")
+                    .selected_text(match speed_mode {
+                        SpeedMode::Percentage(percentage) => format!("{}%", percentage),
+                        _ => format!("{:?}", speed_mode),
+                    })
                     .show_ui(ui, |ui| {
-                        ui.selectable_value(&mut self.character1, Character::Eddy, "Eddy");
-                        ui.selectable_value(&mut self.character1, Character::Jin, "Jin");
-                        ui.selectable_value(&mut self.character1, Character::King, "King");
-                        ui.selectable_value(&mut self.character1, Character::Law, "Law");
-                        ui.selectable_value(&mut self.character1, Character::Lei, "Lei");
-                        ui.selectable_value(&mut self.character1, Character::Paul, "Paul");
-                        ui.selectable_value(&mut self.character1, Character::Xiaoyu, "Xiaoyu");
-                        ui.selectable_value(&mut self.character1, Character::Nina, "Nina");
-                        ui.selectable_value(
-                            &mut self.character1,
-                            Character::Yoshimitsu,
-                            "Yoshimitsu",
-                        );
+                        ui.selectable_value(&mut speed_mode, SpeedMode::Unlimited, "Unlimited");
+                        ui.selectable_value(&mut speed_mode, SpeedMode::Video, "Video");
+                        // Paced by the output, so only with one
+                        if self.audio.is_some() {
+                            ui.selectable_value(&mut speed_mode, SpeedMode::Audio, "Audio");
+                        }
+                        ui.selectable_value(&mut speed_mode, SpeedMode::Percentage(50), "50%");
+                        ui.selectable_value(&mut speed_mode, SpeedMode::Percentage(25), "25%");
                     });
+                if speed_mode != self.speed_controller.mode() {
+                    self.speed_controller.set_mode(speed_mode);
+                }
                 ui.end_row();
-                ui.label("Opponent:");
-                egui::ComboBox::from_id_source("opponent_character")
-                    .selected_text(format!("{:?}", self.character2))
+                ui.label("Audio:");
+                ui.add_enabled_ui(self.audio.is_some(), |ui| {
+                    let mute = ui
+                        .checkbox(&mut self.muted, "Mute")
+                        .on_disabled_hover_text("No audio output, see the README");
+                    if mute.changed() {
+                        self.set_muted(self.muted);
+                    }
+                });
+                ui.end_row();
+                ui.label("Pacing:");
+                egui::ComboBox::from_id_source("pacing")
+                    .selected_text(match self.pacing {
+                        Pacing::DropObservations => "Drop Obs",
+                        Pacing::KeepObservations => "Keep Obs",
+                    })
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.pacing, Pacing::DropObservations, "Drop Obs")
+                            .on_hover_text("Real time emulation, late observations are dropped");
+                        ui.selectable_value(&mut self.pacing, Pacing::KeepObservations, "Keep Obs")
+                            .on_hover_text("Every observation is made, emulation slows down");
+                    });
+                ui.end_row();
+                ui.label("Reward:");
+                ui.horizontal(|ui| self.rewards.combo_box(ui));
+                ui.end_row();
+                if let Some(error) = self.rewards.script_error() {
+                    ui.label("");
+                    ui.colored_label(Color32::RED, error);
+                    ui.end_row();
+                }
+                ui.label("Obs Freq (Hz):");
+                ui.horizontal(|ui| {
+                    ui.add(egui::DragValue::new(&mut self.observation_frequency).speed(0.1));
+                    ui.checkbox(&mut self.adaptive_frequency, "Adaptive")
+                        .on_hover_text(
+                            "Lower frequency in neutral, when life bars don't change and \
+                             the characters are apart",
+                        );
+                    if self.adaptive_frequency {
+                        let neutral = egui::DragValue::new(&mut self.neutral_frequency)
+                            .speed(0.1)
+                            .clamp_range(1..=self.observation_frequency.max(1));
+                        ui.add(neutral).on_hover_text("Frequency in neutral");
+                        let current = match self.is_exchange() {
+                            true => "exchange",
+                            false => "neutral",
+                        };
+                        ui.label(current);
+                    }
+                });
+                ui.end_row();
+                ui.label("Observation:");
+                let observation = self.observation;
+                egui::ComboBox::from_id_source("observation")
+                    .selected_text(format!("{:?}", self.observation))
                     .show_ui(ui, |ui| {
-                        ui.selectable_value(&mut self.character2, Character::Eddy, "Eddy");
-                        ui.selectable_value(&mut self.character2, Character::Jin, "Jin");
-                        ui.selectable_value(&mut self.character2, Character::King, "King");
-                        ui.selectable_value(&mut self.character2, Character::Law, "Law");
-                        ui.selectable_value(&mut self.character2, Character::Lei, "Lei");
-                        ui.selectable_value(&mut self.character2, Character::Paul, "Paul");
-                        ui.selectable_value(&mut self.character2, Character::Xiaoyu, "Xiaoyu");
-                        ui.selectable_value(&mut self.character2, Character::Nina, "Nina");
+                        ui.selectable_value(&mut self.observation, Observation::Raw, "Raw");
+                        ui.selectable_value(
+                            &mut self.observation,
+                            Observation::Abstraction,
+                            "Abstraction",
+                        );
+                        ui.selectable_value(&mut self.observation, Observation::Trace, "Trace");
+                        ui.selectable_value(
+                            &mut self.observation,
+                            Observation::AbstractionAndRaw,
+                            "Abstraction + Raw",
+                        );
                         ui.selectable_value(
-                            &mut self.character2,
-                            Character::Yoshimitsu,
-                            "Yoshimitsu",
+                            &mut self.observation,
+                            Observation::TraceAndRaw,
+                            "Trace + Raw",
                         );
+                        ui.selectable_value(&mut self.observation, Observation::Memory, "Memory");
                     });
                 ui.end_row();
-                ui.label("Obs Freq (Hz):");
-                ui.add(egui::DragValue::new(&mut self.observation_frequency).speed(0.1));
+                if self.observation == Observation::Memory && observation != Observation::Memory {
+                    self.game_data = GameData::load(GAME_DATA_PATH);
+                }
+                if let (Observation::Memory, Err(error)) = (self.observation, &self.game_data) {
+                    ui.label("");
+                    ui.colored_label(Color32::RED, error);
+                    ui.end_row();
+                }
+                ui.label("Mirrored:");
+                ui.checkbox(&mut self.mirrored, "Player 2")
+                    .on_hover_text("Frame and left/right flipped, player 2 plays as player 1");
+                ui.end_row();
+                ui.label("Side Swap:");
+                ui.checkbox(&mut self.swap_sides, "Mirror Left/Right")
+                    .on_hover_text("Left and right swapped while the agent is on the right side");
                 ui.end_row();
                 ui.label("Vision");
-                egui::ComboBox::from_id_source("vision")
-                    .selected_text(format!("{:?}", self.vision))
+                vision_combo_box(ui, "vision", &mut self.vision);
+                ui.end_row();
+                ui.label("Layout");
+                egui::ComboBox::from_id_source("view_layout")
+                    .selected_text(format!("{:?}", self.view_layout))
                     .show_ui(ui, |ui| {
-                        ui.selectable_value(&mut self.vision, Vision::PSX, "PSX");
-                        ui.selectable_value(&mut self.vision, Vision::Life, "Life");
-                        ui.selectable_value(&mut self.vision, Vision::Agent, "Agent");
-                        ui.selectable_value(&mut self.vision, Vision::Crop, "Crop");
-                        ui.selectable_value(&mut self.vision, Vision::Contrast, "Contrast");
-                        ui.selectable_value(&mut self.vision, Vision::Mask, "Mask");
-                        ui.selectable_value(&mut self.vision, Vision::Masked, "Masked");
-                        ui.selectable_value(&mut self.vision, Vision::Centroids, "Centroids HUD");
-                        ui.selectable_value(&mut self.vision, Vision::Chars, "Chars HUD");
-                        ui.selectable_value(&mut self.vision, Vision::Segmented, "Segmented");
+                        ui.selectable_value(&mut self.view_layout, ViewLayout::Single, "Single");
+                        ui.selectable_value(&mut self.view_layout, ViewLayout::Split, "Split");
+                        ui.selectable_value(
+                            &mut self.view_layout,
+                            ViewLayout::SideBySide,
+                            "Side by Side",
+                        );
+                        ui.selectable_value(&mut self.view_layout, ViewLayout::Grid, "Grid");
                     });
                 ui.end_row();
-                ui.label("Split View");
-                ui.checkbox(&mut self.split_view, "");
+                // The main vision is the first view, these fill the rest
+                let compared = match self.view_layout {
+                    ViewLayout::Single | ViewLayout::Split => 0,
+                    layout => layout.number_of_views() - 1,
+                };
+                for (i, vision) in self.compare_visions.iter_mut().take(compared).enumerate() {
+                    ui.label(format!("Compare {}", i + 1));
+                    vision_combo_box(ui, &format!("compare_vision_{}", i), vision);
+                    ui.end_row();
+                }
             });
             ui.horizontal(|_ui| {});
 
@@ -558,6 +1431,29 @@ impl MyApp {
                 ui.label("Dilate");
                 ui.add(egui::Slider::new(&mut self.dilate_k, 0..=20));
             });
+            ui.label("Exclusions");
+            ui.horizontal(|ui| {
+                ui.toggle_value(&mut self.editing_exclusions, "Edit");
+                if ui.button("Clear").clicked() {
+                    self.exclusions.clear();
+                }
+            });
+            if self.editing_exclusions {
+                ui.label("Drag boxes over a view to leave them out");
+            }
+            let mut removed = None;
+            egui::Grid::new("exclusions").show(ui, |ui| {
+                for (index, exclusion) in self.exclusions.iter().enumerate() {
+                    ui.label(format!("{:?}", exclusion));
+                    if ui.small_button("🗙").clicked() {
+                        removed = Some(index);
+                    }
+                    ui.end_row();
+                }
+            });
+            if let Some(index) = removed {
+                self.exclusions.remove(index);
+            }
             ui.label("Character 1");
             egui::Grid::new("char1").show(ui, |ui| {
                 ui.label("Thres.");
@@ -589,16 +1485,27 @@ impl MyApp {
             egui::Grid::new("state_comparison").show(ui, |ui| {
                 ui.label("Radius");
                 if ui
-                    .add(egui::Slider::new(&mut self.radius, 0..=255))
+                    .add(egui::Slider::new(&mut self.radius, 0.0..=1.0).max_decimals(3))
                     .changed()
                 {
-                    self.agent.set_radius(self.radius);
+                    self.agent.agent().set_radius(self.radius);
                 }
                 ui.end_row();
                 ui.label("MSE");
                 ui.add(egui::Slider::new(&mut self.max_mse, 0.0..=60000.0).max_decimals(3));
             });
         });
+
+        // Keep the model of the character left behind, pick up the new one
+        if self.character1 != characters.0 {
+            save_character_pixel_probability(&characters.0, &self.char1_pixel_probability);
+            self.char1_pixel_probability = load_character_pixel_probability(&self.character1);
+            self.abstraction_cache.clear();
+        }
+        if self.character2 != characters.1 {
+            let opponent = std::mem::replace(&mut self.character2, characters.1);
+            self.set_opponent(opponent);
+        }
     }
 
     fn load_current_combat(&mut self) -> bool {
@@ -606,56 +1513,402 @@ impl MyApp {
         let name2 = format!("{:?}", self.character2).to_lowercase();
         let filepath = format!("{}/{}_vs_{}.bin", STATES_DIR, name1, name2);
         println!("Loading {} ...", filepath);
-        match File::open(&filepath) {
-            Ok(mut file) => {
-                let mut bytes = Vec::new();
-                let _ = file.read_to_end(&mut bytes).unwrap();
-                // Careful, 'bios' and 'game' filepaths will be embedded
-                // in the psx state, files must be available.
-                self.system = Some(bincode::deserialize(&bytes).unwrap());
-                true
-            }
+        self.macros.stop();
+        self.hud_lost_since = None;
+        self.sides_swapped = false;
+        self.rewards.reset();
+        // An exchange until the first observation says otherwise
+        self.observed_lifes = (1.0, 1.0);
+        self.observed_distance = 0.0;
+        self.previous_centroids = None;
+        let system = match self.state_pool.get(&filepath) {
+            Ok(bytes) => bincode::deserialize::<System>(bytes),
             Err(error) => {
                 eprintln!("State not found: {} ({})", filepath, error);
-                false
+                return false;
             }
+        };
+        let mut system = match system {
+            Ok(system) => system,
+            Err(e) => {
+                eprintln!("Error reading emulator state: {}: {}", filepath, e);
+                return false;
+            }
+        };
+        // Careful, the 'game' filepath is embedded in the psx
+        // state, the file must be available.
+        if !self.check_game_file(&mut system) {
+            return false;
+        }
+        // Reverb would only cost time when nothing is played
+        system.set_spu_reverb(self.is_playing_audio());
+        // The state keeps the time it was saved at
+        self.combat_start = system.get_emulated_time();
+        self.system = Some(system);
+        self.mark_timeline(EventKind::StateLoad, format!("Loaded {}", filepath));
+        // Recorded in agent.json, handy when picking agents later.
+        // Simulations may face someone else, that isn't training.
+        if !self.simulation.is_running() {
+            self.agent.agent().set_characters(
+                format!("{:?}", self.character1),
+                format!("{:?}", self.character2),
+            );
         }
+        true
     }
 
-    fn right_panel(&mut self, ctx: &egui::Context) {
-        egui::SidePanel::right("my_right_panel").show(ctx, |ui| {
-            ui.horizontal(|_ui| {});
-            // Profiling
-            egui::Grid::new("profiling").show(ui, |ui| {
-                ui.label("FPS:");
-                if self.frame_time.total_time.as_millis() > 0 {
-                    ui.label(format!(
-                        "{:.2}",
-                        (1000 / self.frame_time.total_time.as_millis())
-                    ));
-                } else {
-                    ui.label("/0");
+    fn session_config(&self) -> SessionConfig {
+        SessionConfig {
+            character1: self.character1.clone(),
+            character2: self.character2.clone(),
+            observation: self.observation,
+            observation_frequency: self.observation_frequency,
+            adaptive_frequency: self.adaptive_frequency,
+            neutral_frequency: self.neutral_frequency,
+            learning_rate: self.learning_rate,
+            discount_factor: self.discount_factor,
+            red_thresholds: self.red_thresholds,
+            green_thresholds: self.green_thresholds,
+            blue_thresholds: self.blue_thresholds,
+            dilate_k: self.dilate_k,
+            max_mse: self.max_mse,
+            char1_probability_threshold: self.char1_probability_threshold,
+            char2_probability_threshold: self.char2_probability_threshold,
+            char1_dilate_k: self.char1_dilate_k,
+            char2_dilate_k: self.char2_dilate_k,
+            exclusions: self.exclusions.clone(),
+            trace: self.trace,
+            radius: self.radius,
+            mirrored: self.mirrored,
+            swap_sides: self.swap_sides,
+        }
+    }
+
+    fn apply_session_config(&mut self, config: SessionConfig) {
+        self.character1 = config.character1;
+        self.character2 = config.character2;
+        self.observation = config.observation;
+        self.observation_frequency = config.observation_frequency;
+        self.adaptive_frequency = config.adaptive_frequency;
+        self.neutral_frequency = config.neutral_frequency;
+        self.learning_rate = config.learning_rate;
+        self.discount_factor = config.discount_factor;
+        self.red_thresholds = config.red_thresholds;
+        self.green_thresholds = config.green_thresholds;
+        self.blue_thresholds = config.blue_thresholds;
+        self.dilate_k = config.dilate_k;
+        self.max_mse = config.max_mse;
+        self.char1_probability_threshold = config.char1_probability_threshold;
+        self.char2_probability_threshold = config.char2_probability_threshold;
+        self.char1_dilate_k = config.char1_dilate_k;
+        self.char2_dilate_k = config.char2_dilate_k;
+        self.exclusions = config.exclusions;
+        self.trace = config.trace;
+        self.radius = config.radius;
+        self.mirrored = config.mirrored;
+        self.swap_sides = config.swap_sides;
+        let mut agent = self.agent.agent();
+        agent.set_radius(self.radius);
+        agent.set_learning_rate(self.learning_rate);
+        agent.set_discount_factor(self.discount_factor);
+    }
+
+    // Agent, GUI settings and emulator state, replacing any previous session
+    fn save_recovery_session(&self) {
+        println!("Saving recovery session to {}...", RECOVERY_DIR);
+        let recovery_path = Path::new(RECOVERY_DIR);
+        if recovery_path.exists() {
+            if let Err(e) = fs::remove_dir_all(recovery_path) {
+                eprintln!("Error removing previous recovery session: {}", e);
+                return;
+            }
+        }
+        let agent_path = recovery_path.join("agent");
+        q_learning::save_agent(&self.agent.agent(), agent_path.to_str().unwrap());
+        match File::create(recovery_path.join("session.json")) {
+            Ok(file) => {
+                if let Err(e) = serde_json::to_writer_pretty(file, &self.session_config()) {
+                    eprintln!("Error writing session config: {}", e);
                 }
-                ui.end_row();
-                ui.label("Total Time (ms):");
-                ui.label(format!("{:.2}", self.frame_time.total_time.as_millis()));
-                ui.end_row();
-                ui.label("UI Time (ms):");
-                ui.label(format!("{:.2}", self.frame_time.ui_time.as_millis()));
-                ui.end_row();
-                ui.label("PSX Time (ms):");
-                ui.label(format!("{:.2}", self.frame_time.psx_time.as_millis()));
-                ui.end_row();
-                ui.label("Agent Time (ms):");
-                ui.label(format!("{:.2}", self.frame_time.agent_time.as_millis()));
-                ui.end_row();
-            });
-            ui.horizontal(|_ui| {});
-            ui.horizontal(|ui| {
-                ui.label("Life Stats");
-                let separator = egui::Separator::default();
-                ui.add(separator.horizontal());
-            });
+            }
+            Err(e) => eprintln!("Error creating session config: {}", e),
+        }
+        for (name, pixel_probability) in [
+            ("char1.bin", &self.char1_pixel_probability),
+            ("char2.bin", &self.char2_pixel_probability),
+        ] {
+            let path = recovery_path.join(name);
+            if let Err(e) = vision::save_pixel_probability(pixel_probability, &path) {
+                eprintln!("Error saving pixel probabilities: {}", e);
+            }
+        }
+        if let Some(system) = &self.system {
+            match bincode::serialize(system) {
+                Ok(bytes) => {
+                    if let Err(e) = fs::write(recovery_path.join("state.bin"), bytes) {
+                        eprintln!("Error writing emulator state: {}", e);
+                    }
+                }
+                Err(e) => eprintln!("Error serializing emulator state: {}", e),
+            }
+        }
+    }
+
+    fn restore_recovery_session(&mut self) {
+        println!("Restoring recovery session from {}...", RECOVERY_DIR);
+        let recovery_path = Path::new(RECOVERY_DIR);
+        let agent_path = recovery_path.join("agent");
+        match q_learning::load_agent(agent_path.to_str().unwrap()) {
+            Ok(agent) => {
+                self.saved_training_time = Duration::ZERO;
+                self.store_raw_frames = agent.get_store_raw_frames();
+                self.agent.set_agent(agent);
+                let mut agent = self.agent.agent();
+                agent.set_same_state(self.same_state, self.same_state_penalty);
+                agent.set_state_matching(self.state_matching);
+                agent.set_correction(self.correction);
+                agent.set_replay(
+                    self.replay_capacity,
+                    self.replay_batch_size,
+                    self.replay_sampling,
+                );
+                drop(agent);
+                self.opened_agent = None;
+            }
+            Err(e) => eprintln!("Error loading recovered agent: {}", e),
+        }
+        match File::open(recovery_path.join("session.json")) {
+            Ok(file) => match serde_json::from_reader(BufReader::new(file)) {
+                Ok(config) => self.apply_session_config(config),
+                Err(e) => eprintln!("Error reading session config: {}", e),
+            },
+            Err(e) => eprintln!("Error opening session config: {}", e),
+        }
+        // Characters may have changed with the config, the models go with them
+        match vision::load_pixel_probability(&recovery_path.join("char1.bin")) {
+            Ok(pixel_probability) => self.char1_pixel_probability = pixel_probability,
+            Err(e) => eprintln!("Error loading recovered pixel probabilities: {}", e),
+        }
+        match vision::load_pixel_probability(&recovery_path.join("char2.bin")) {
+            Ok(pixel_probability) => self.char2_pixel_probability = pixel_probability,
+            Err(e) => eprintln!("Error loading recovered pixel probabilities: {}", e),
+        }
+        self.abstraction_cache.clear();
+        // Optional, there is no state if the emulator never started
+        if let Ok(bytes) = fs::read(recovery_path.join("state.bin")) {
+            match bincode::deserialize::<System>(&bytes) {
+                Ok(mut system) => {
+                    if self.check_game_file(&mut system) {
+                        system.set_spu_reverb(self.is_playing_audio());
+                        self.system = Some(system);
+                    }
+                }
+                Err(e) => eprintln!("Error reading emulator state: {}", e),
+            }
+        }
+    }
+
+    // Points the state to the game picked on this machine, if any. Asks
+    // for it when it can't be used.
+    fn check_game_file(&mut self, system: &mut System) -> bool {
+        if let Some(game_path) = &self.game_path {
+            system.set_game_filepath(game_path);
+        }
+        match system.check_game_file() {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("{}", e);
+                let prompt = FilePrompt::new("Game Error", &e.path, &e.to_string(), "Cancel");
+                self.game_prompt = Some(prompt);
+                false
+            }
+        }
+    }
+
+    fn game_prompt(&mut self, ctx: &egui::Context) {
+        let Some(prompt) = &mut self.game_prompt else {
+            return;
+        };
+        match prompt.show(ctx) {
+            Some(PromptAction::Retry(path)) => {
+                self.game_path = Some(path);
+                self.game_prompt = None;
+                // Asks again if it still can't be used
+                self.load_current_combat();
+            }
+            Some(PromptAction::Cancel) => self.game_prompt = None,
+            None => (),
+        }
+    }
+
+    fn recovery_prompt(&mut self, ctx: &egui::Context) {
+        if !self.show_recovery_prompt {
+            return;
+        }
+        egui::Window::new("Recover Session")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label("The previous session was closed with unsaved training.");
+                ui.horizontal(|ui| {
+                    if ui.button("Restore").clicked() {
+                        self.restore_recovery_session();
+                        self.show_recovery_prompt = false;
+                    }
+                    if ui.button("Discard").clicked() {
+                        self.show_recovery_prompt = false;
+                    }
+                });
+            });
+        // Either way it is consumed, the next close saves a new one
+        if !self.show_recovery_prompt {
+            if let Err(e) = fs::remove_dir_all(RECOVERY_DIR) {
+                eprintln!("Error removing recovery session: {}", e);
+            }
+        }
+    }
+
+    fn right_panel(&mut self, ctx: &egui::Context) {
+        egui::SidePanel::right("my_right_panel").show(ctx, |ui| {
+            ui.horizontal(|_ui| {});
+            // Profiling
+            egui::Grid::new("profiling").show(ui, |ui| {
+                ui.label("FPS:");
+                if self.frame_time.total_time.as_millis() > 0 {
+                    ui.label(format!(
+                        "{:.2}",
+                        (1000 / self.frame_time.total_time.as_millis())
+                    ));
+                } else {
+                    ui.label("/0");
+                }
+                ui.end_row();
+                ui.label("Total Time (ms):");
+                ui.label(format!("{:.2}", self.frame_time.total_time.as_millis()));
+                ui.end_row();
+                ui.label("UI Time (ms):");
+                ui.label(format!("{:.2}", self.frame_time.ui_time.as_millis()));
+                ui.end_row();
+                ui.label("PSX Time (ms):");
+                ui.label(format!("{:.2}", self.frame_time.psx_time.as_millis()));
+                ui.end_row();
+                ui.label("Agent Time (ms):");
+                ui.label(format!("{:.2}", self.frame_time.agent_time.as_millis()));
+                ui.end_row();
+                ui.label("Vision Cache:");
+                let lookups = self.abstraction_cache.hits + self.abstraction_cache.misses;
+                ui.label(format!("{}/{} hits", self.abstraction_cache.hits, lookups));
+                ui.end_row();
+                ui.label("Display:");
+                let (x, y) = self.display.origin;
+                let (width, height) = self.display.size;
+                let depth = if self.display.is_24bit { 24 } else { 15 };
+                ui.label(format!(
+                    "{}x{} at ({}, {}), {}-bit",
+                    width, height, x, y, depth
+                ));
+                ui.end_row();
+                ui.label("State Pool:");
+                let lookups = self.state_pool.hits + self.state_pool.misses;
+                ui.label(format!("{}/{} hits", self.state_pool.hits, lookups));
+                ui.end_row();
+                ui.label("Obs/s:");
+                match self.throughput.rates() {
+                    Some((frames, observations)) => {
+                        ui.label(format!("{:.1}", observations));
+                        ui.end_row();
+                        ui.label("Speed:");
+                        ui.label(format!("{:.2}x", frames / PSX_FRAME_RATE));
+                    }
+                    None => {
+                        ui.label("-");
+                        ui.end_row();
+                        ui.label("Speed:");
+                        ui.label("-");
+                    }
+                }
+                ui.end_row();
+                ui.label("Dropped Obs:");
+                let busy_agent = self.agent.get_dropped_observations();
+                ui.label(format!("{}", self.missed_observations + busy_agent))
+                    .on_hover_text(format!(
+                        "{} late, {} while the agent was busy",
+                        self.missed_observations, busy_agent
+                    ));
+                ui.end_row();
+                if ui
+                    .checkbox(&mut self.input_latency.enabled, "Input Latency:")
+                    .on_hover_text("Frames from an action to the game responding, costs speed")
+                    .changed()
+                {
+                    self.input_latency.clear();
+                }
+                let (probes, missed) = self.input_latency.counts();
+                match self.input_latency.mean() {
+                    (memory, Some(display)) => {
+                        // The agent only acts once per observation period
+                        let period = match self.observation_frequency {
+                            0 => 0.0,
+                            frequency => PSX_FRAME_RATE / frequency as f64,
+                        };
+                        let memory =
+                            memory.map_or("-".to_string(), |memory| format!("{:.1}", memory));
+                        ui.label(format!("{:.1} frames", display))
+                            .on_hover_text(format!(
+                                "Read after {} frames, shown after {:.1} (max {}). {} of {} \
+                                 probes showed nothing. Observing every {:.1} frames, a \
+                                 reaction shows up to {:.1} frames after what caused it.",
+                                memory,
+                                display,
+                                self.input_latency.max_display().unwrap_or(0),
+                                missed,
+                                probes,
+                                period,
+                                period + display
+                            ));
+                    }
+                    _ => {
+                        ui.label("-");
+                    }
+                };
+                ui.end_row();
+                ui.label("Discarded Obs:");
+                let quality = &self.abstraction_quality;
+                ui.label(format!("{}", self.discarded_observations))
+                    .on_hover_text(format!(
+                        "Last abstraction: quality {:.2}, {:.1}% area, {} components, \
+                         centroids moved {:.3}",
+                        quality.score,
+                        quality.area * 100.0,
+                        quality.components,
+                        quality.centroid_jump
+                    ));
+                ui.end_row();
+                ui.label("Episodes/h:");
+                let episodes_per_hour = self.throughput.episodes_per_hour();
+                match episodes_per_hour {
+                    Some(episodes_per_hour) => ui.label(format!("{:.1}", episodes_per_hour)),
+                    None => ui.label("-"),
+                };
+                ui.end_row();
+                ui.label("ETA:");
+                let max_episodes = self.training_budget.max_episodes;
+                match episodes_per_hour {
+                    Some(episodes_per_hour) if max_episodes > 0 => {
+                        let left = self.training_budget.episodes_left(&self.episodes);
+                        let eta = Duration::from_secs_f64(left as f64 * 3600.0 / episodes_per_hour);
+                        ui.label(agent_picker::format_duration(eta))
+                    }
+                    _ => ui.label("-"),
+                };
+                ui.end_row();
+            });
+            ui.horizontal(|_ui| {});
+            ui.horizontal(|ui| {
+                ui.label("Life Stats");
+                let separator = egui::Separator::default();
+                ui.add(separator.horizontal());
+            });
             egui::Grid::new("life_stats").show(ui, |ui| {
                 ui.label("Life:");
                 ui.label(format!("{:.4}", self.agent_life_info.life));
@@ -675,25 +1928,44 @@ impl MyApp {
                 ui.add(separator.horizontal());
             });
             egui::Grid::new("ai_agent").show(ui, |ui| {
+                let agent = self.agent.agent();
                 ui.label("Training Time:");
-                let total_seconds = self.agent.get_training_time().as_secs();
+                let total_seconds = agent.get_training_time().as_secs();
                 let hours = total_seconds / 3600;
                 let minutes = (total_seconds % 3600) / 60;
                 let seconds = total_seconds % 60;
                 ui.label(format!("{:02}:{:02}:{:02}", hours, minutes, seconds));
                 ui.end_row();
                 ui.label("Iteration:");
-                let iteration_number = format!("{}", self.agent.get_iteration_number());
+                let iteration_number = format!("{}", agent.get_iteration_number());
                 ui.with_layout(Layout::right_to_left(Align::Min), |ui| {
                     ui.label(iteration_number);
                 });
                 ui.end_row();
                 ui.label("States:");
-                let number_of_states = format!("{}", self.agent.get_number_of_states());
+                let number_of_states = format!("{}", agent.get_number_of_states());
                 ui.with_layout(Layout::right_to_left(Align::Min), |ui| {
                     ui.label(number_of_states);
                 });
                 ui.end_row();
+                ui.label("Merged States:");
+                let merged_states = format!("{}", agent.get_merged_states());
+                ui.with_layout(Layout::right_to_left(Align::Min), |ui| {
+                    ui.label(merged_states);
+                });
+                ui.end_row();
+                ui.label("Same State:");
+                let same_state_count = format!("{}", agent.get_same_state_count());
+                ui.with_layout(Layout::right_to_left(Align::Min), |ui| {
+                    ui.label(same_state_count);
+                });
+                ui.end_row();
+                ui.label("Side Swaps:");
+                let side_swaps = format!("{}", self.side_swaps);
+                ui.with_layout(Layout::right_to_left(Align::Min), |ui| {
+                    ui.label(side_swaps);
+                });
+                ui.end_row();
             });
             ui.horizontal(|_ui| {});
 
@@ -707,14 +1979,293 @@ impl MyApp {
                 ui.label("Learning Rate:");
                 let learning_rate_widget = egui::DragValue::new(&mut self.learning_rate);
                 let learning_rate_widget = learning_rate_widget.speed(0.01).clamp_range(0..=1);
-                ui.add(learning_rate_widget);
+                if ui.add(learning_rate_widget).changed() {
+                    self.agent.agent().set_learning_rate(self.learning_rate);
+                }
                 ui.end_row();
                 ui.label("Discount Factor:");
                 let discount_factor_widget = egui::DragValue::new(&mut self.discount_factor);
                 let discount_factor_widget = discount_factor_widget.speed(0.01).clamp_range(0..=1);
-                ui.add(discount_factor_widget);
+                if ui.add(discount_factor_widget).changed() {
+                    self.agent.agent().set_discount_factor(self.discount_factor);
+                }
+                ui.end_row();
+                ui.label("Same State:");
+                let mut same_state = self.same_state;
+                egui::ComboBox::from_id_source("same_state")
+                    .selected_text(format!("{:?}", same_state))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut same_state, SameState::NoOp, "NoOp");
+                        ui.selectable_value(
+                            &mut same_state,
+                            SameState::RepeatPrevious,
+                            "RepeatPrevious",
+                        );
+                        ui.selectable_value(
+                            &mut same_state,
+                            SameState::SamplePolicy,
+                            "SamplePolicy",
+                        );
+                    });
+                let mut changed = same_state != self.same_state;
+                self.same_state = same_state;
+                ui.end_row();
+                if self.same_state == SameState::SamplePolicy {
+                    ui.label("Same State Penalty:");
+                    let penalty_widget = egui::DragValue::new(&mut self.same_state_penalty);
+                    changed |= ui.add(penalty_widget.speed(0.01)).changed();
+                    ui.end_row();
+                }
+                if changed {
+                    self.agent
+                        .agent()
+                        .set_same_state(self.same_state, self.same_state_penalty);
+                }
+                ui.label("State Matching:");
+                let mut state_matching = self.state_matching;
+                egui::ComboBox::from_id_source("state_matching")
+                    .selected_text(format!("{:?}", state_matching))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut state_matching, StateMatching::Mse, "Mse");
+                        ui.selectable_value(
+                            &mut state_matching,
+                            StateMatching::Occupancy,
+                            "Occupancy",
+                        )
+                        .on_hover_text("Exact match on a coarse grid, no MSE");
+                    });
+                if state_matching != self.state_matching {
+                    self.state_matching = state_matching;
+                    self.agent.agent().set_state_matching(state_matching);
+                }
+                ui.end_row();
+                ui.label("Q Backend:");
+                ui.horizontal(|ui| {
+                    let mut agent = self.agent.agent();
+                    let name = agent.get_backend_name();
+                    // What was learned doesn't carry over
+                    let untrained = agent.get_iteration_number() == 0;
+                    ui.add_enabled_ui(untrained, |ui| {
+                        egui::ComboBox::from_id_source("q_backend")
+                            .selected_text(name)
+                            .show_ui(ui, |ui| {
+                                if ui.selectable_label(name == "Table", "Table").clicked() {
+                                    agent.set_backend(None);
+                                }
+                                let tile_coding = ui
+                                    .selectable_label(name == TileCoding::NAME, TileCoding::NAME)
+                                    .on_hover_text(
+                                        "Linear over tile-coded centroids, no state search",
+                                    );
+                                if tile_coding.clicked() {
+                                    agent.set_backend(Some(Box::new(TileCoding::default())));
+                                }
+                            });
+                    });
+                    if let Some(learned) = agent.get_backend_learned() {
+                        ui.label(format!("{} tiles", learned));
+                    }
+                });
+                ui.end_row();
+                ui.label("Min Quality:");
+                let quality_widget = egui::DragValue::new(&mut self.min_abstraction_quality)
+                    .speed(0.01)
+                    .clamp_range(0..=1);
+                ui.add(quality_widget).on_hover_text(
+                    "Abstractions scored under it are discarded: too little or too much \
+                     detected, not two characters, or centroids jumping. 0 keeps them all.",
+                );
+                ui.end_row();
+                ui.label("Replay:");
+                ui.horizontal(|ui| {
+                    let capacity_widget = egui::DragValue::new(&mut self.replay_capacity)
+                        .speed(100)
+                        .clamp_range(0..=1_000_000);
+                    let mut changed = ui
+                        .add(capacity_widget)
+                        .on_hover_text("Transitions kept, 0 turns replay off")
+                        .changed();
+                    ui.label("Batch");
+                    let batch_widget =
+                        egui::DragValue::new(&mut self.replay_batch_size).clamp_range(1..=1024);
+                    changed |= ui.add(batch_widget).changed();
+                    let mut sampling = self.replay_sampling;
+                    egui::ComboBox::from_id_source("replay_sampling")
+                        .selected_text(format!("{:?}", sampling))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut sampling, Sampling::Uniform, "Uniform");
+                            ui.selectable_value(
+                                &mut sampling,
+                                Sampling::Prioritized,
+                                "Prioritized",
+                            )
+                            .on_hover_text("By TD error, surprising transitions come back more");
+                        });
+                    changed |= sampling != self.replay_sampling;
+                    self.replay_sampling = sampling;
+                    let mut agent = self.agent.agent();
+                    if changed {
+                        agent.set_replay(
+                            self.replay_capacity,
+                            self.replay_batch_size,
+                            self.replay_sampling,
+                        );
+                    }
+                    if self.replay_capacity > 0 {
+                        let (size, replayed) = agent.get_replay_size();
+                        ui.label(format!("{} kept, {} updates", size, replayed));
+                    }
+                });
+                ui.end_row();
+                ui.label("Raw Frames:");
+                ui.horizontal(|ui| {
+                    if ui.checkbox(&mut self.store_raw_frames, "Store").changed() {
+                        self.agent
+                            .agent()
+                            .set_store_raw_frames(self.store_raw_frames);
+                    }
+                    let (count, bytes) = self.agent.agent().get_raw_frames_size();
+                    ui.label(format!("{} ({:.1} MiB)", count, bytes as f64 / 1048576.0));
+                });
+                ui.end_row();
+                ui.label("Human Override:");
+                ui.horizontal(|ui| {
+                    ui.toggle_value(&mut self.hold_override, "Hold (H)");
+                    ui.checkbox(&mut self.learn_from_override, "Learn");
+                    if self.is_overriding() {
+                        ui.colored_label(Color32::LIGHT_RED, "Human");
+                    }
+                });
+                ui.end_row();
+                if self.learn_from_override {
+                    ui.label("Correction:");
+                    let correction_widget = egui::DragValue::new(&mut self.correction);
+                    let correction_widget = correction_widget.speed(0.01).clamp_range(0..=1);
+                    if ui.add(correction_widget).changed() {
+                        self.agent.agent().set_correction(self.correction);
+                    }
+                    ui.end_row();
+                }
+                ui.label("Demonstrations:");
+                ui.label(format!("{}", self.demonstrations));
+                ui.end_row();
+            });
+            ui.horizontal(|_ui| {});
+
+            // Training Budget
+            ui.horizontal(|ui| {
+                ui.label("Training Budget");
+                let separator = egui::Separator::default();
+                ui.add(separator.horizontal());
+            });
+            egui::Grid::new("training_budget").show(ui, |ui| {
+                ui.label("Max Time (min):");
+                ui.add(egui::DragValue::new(&mut self.training_budget.max_minutes));
+                ui.end_row();
+                ui.label("Max Episodes:");
+                ui.add(egui::DragValue::new(&mut self.training_budget.max_episodes));
+                ui.end_row();
+                ui.label("Max States:");
+                ui.add(egui::DragValue::new(&mut self.training_budget.max_states));
+                ui.end_row();
+                ui.label("Plateau Window:");
+                ui.add(egui::DragValue::new(
+                    &mut self.training_budget.plateau_window,
+                ));
+                ui.end_row();
+                ui.label("Win Rate:");
+                let win_rate = q_learning::win_rate(&self.episodes);
+                ui.label(format!("{:.2} ({})", win_rate, self.episodes.len()));
+            });
+            ui.horizontal(|_ui| {});
+
+            // Auto Restart
+            ui.horizontal(|ui| {
+                ui.label("Auto Restart");
+                let separator = egui::Separator::default();
+                ui.add(separator.horizontal());
+            });
+            egui::Grid::new("auto_restart").show(ui, |ui| {
+                ui.label("No Life Bars (s):");
+                ui.add(egui::DragValue::new(&mut self.hud_lost_limit));
+                ui.end_row();
+                ui.label("Max Combat (s):");
+                ui.add(egui::DragValue::new(&mut self.max_combat_duration));
+                ui.end_row();
+            });
+            ui.horizontal(|_ui| {});
+
+            // Evaluation
+            ui.horizontal(|ui| {
+                ui.label("Evaluation");
+                let separator = egui::Separator::default();
+                ui.add(separator.horizontal());
+            });
+            egui::Grid::new("evaluation").show(ui, |ui| {
+                ui.label("Every (episodes):");
+                ui.add(egui::DragValue::new(&mut self.evaluation_interval));
+                ui.end_row();
+                ui.label("Matches:");
+                ui.add(egui::DragValue::new(&mut self.evaluation_matches).clamp_range(1..=100));
+                ui.end_row();
+                ui.label("Last Win Rate:");
+                if self.evaluation_left > 0 {
+                    ui.label(format!("Evaluating ({} left)", self.evaluation_left));
+                } else if let Some([_, win_rate]) = self.evaluation_win_rates.last() {
+                    ui.label(format!("{:.2}", win_rate));
+                } else {
+                    ui.label("-");
+                }
             });
             ui.horizontal(|_ui| {});
+
+            // Simulation
+            ui.horizontal(|ui| {
+                ui.label("Simulation");
+                let separator = egui::Separator::default();
+                ui.add(separator.horizontal());
+            });
+            let running = self.simulation.is_running();
+            egui::Grid::new("simulation").show(ui, |ui| {
+                ui.label("Matches:");
+                ui.add_enabled(
+                    !running,
+                    egui::DragValue::new(&mut self.simulation.matches).clamp_range(1..=1000),
+                );
+                ui.end_row();
+                ui.label("Opponent:");
+                ui.add_enabled_ui(!running, |ui| {
+                    character_combo_box(ui, "simulation_opponent", &mut self.simulation.opponent);
+                });
+                ui.end_row();
+                ui.label("Win Rate:");
+                if running {
+                    ui.label(format!("Simulating ({} left)", self.simulation.left));
+                } else if let Some((win_rate, _)) = self.simulation.result {
+                    ui.label(format!("{:.2}", win_rate));
+                } else {
+                    ui.label("-");
+                }
+                ui.end_row();
+                ui.label("Avg Reward:");
+                match self.simulation.result {
+                    Some((_, reward)) if !running => ui.label(format!("{:.3}", reward)),
+                    _ => ui.label("-"),
+                };
+                ui.end_row();
+            });
+            ui.horizontal(|ui| {
+                if running {
+                    if ui.button("Stop Simulation").clicked() {
+                        self.end_simulation();
+                        self.reset_combat();
+                    }
+                } else if ui.button("Run Simulation").clicked() {
+                    self.start_simulation();
+                }
+            });
+            ui.label("No exploration nor learning, training stats are left alone");
+            ui.horizontal(|_ui| {});
             ui.horizontal(|ui| {
                 // Emulator Controls
                 if ui.button("Start").clicked() {
@@ -728,71 +2279,659 @@ impl MyApp {
                     self.is_running = false;
                 }
                 if ui.button("Next").clicked() {
-                    self.is_running_next_frame = true;
+                    self.step_observation();
+                }
+                if ui.button("+1").clicked() {
+                    self.step_frames(1);
+                }
+                if ui.button(format!("+{}", self.step_frames)).clicked() {
+                    self.step_frames(self.step_frames);
+                }
+                ui.add(egui::DragValue::new(&mut self.step_frames).clamp_range(1..=600));
+                ui.separator();
+                // Combat Controls
+                let has_system = self.system.is_some();
+                if ui
+                    .add_enabled(has_system, egui::Button::new("Reset Combat"))
+                    .clicked()
+                {
+                    self.reset_combat();
+                }
+                if ui
+                    .add_enabled(has_system, egui::Button::new("Skip Round"))
+                    .clicked()
+                {
+                    self.skip_round();
+                }
+                if ui
+                    .add_enabled(has_system, egui::Button::new("Forfeit"))
+                    .clicked()
+                {
+                    self.forfeit();
                 }
             });
         });
     }
 
+    // F steps one frame, Shift+F the +N frames and N until the next
+    // observation. H holds the human override, the pad keys take over.
+    // Ignored while typing in a text field.
+    fn hotkeys(&mut self, ctx: &egui::Context) {
+        if ctx.wants_keyboard_input() {
+            self.human_action = 0;
+            return;
+        }
+        let (frame, shift, next, hold) = ctx.input(|i| {
+            (
+                i.key_pressed(egui::Key::F),
+                i.modifiers.shift,
+                i.key_pressed(egui::Key::N),
+                i.key_pressed(egui::Key::H),
+            )
+        });
+        self.human_action = ctx.input(|i| {
+            OVERRIDE_KEYS
+                .iter()
+                .filter(|(key, _)| i.key_down(*key))
+                .fold(0, |action, (_, bit)| action | 1 << bit)
+        });
+        if hold {
+            self.hold_override = !self.hold_override;
+        }
+        if frame && shift {
+            self.step_frames(self.step_frames);
+        } else if frame {
+            self.step_frames(1);
+        } else if next {
+            self.step_observation();
+        }
+    }
+
+    // Appends every observation to STEP_LOG_PATH as JSON lines
+    fn set_step_log(&mut self, enabled: bool) {
+        if !enabled {
+            if let Some(mut step_log) = self.step_log.take() {
+                if let Err(e) = step_log.flush() {
+                    eprintln!("Error writing {}: {}", STEP_LOG_PATH, e);
+                }
+            }
+            return;
+        }
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(STEP_LOG_PATH);
+        match file {
+            Ok(file) => {
+                println!("Logging steps to {}", STEP_LOG_PATH);
+                self.step_log = Some(BufWriter::new(file));
+            }
+            Err(e) => eprintln!("Error opening {}: {}", STEP_LOG_PATH, e),
+        }
+    }
+
+    // Runs `frames` frames and pauses, loading the combat if needed
+    fn step_frames(&mut self, frames: u32) {
+        if self.system.is_none() && !self.load_current_combat() {
+            return;
+        }
+        self.is_running = false;
+        self.step = Some(Step::Frames(frames.max(1)));
+    }
+
+    // Runs until the agent observes and pauses
+    fn step_observation(&mut self) {
+        if self.system.is_none() && !self.load_current_combat() {
+            return;
+        }
+        self.is_running = false;
+        self.step = Some(Step::Observation);
+    }
+
     fn show_states_plot(&mut self, ctx: &egui::Context) {
         if self.show_states_plot {
             egui::Window::new("States")
                 .open(&mut self.show_states_plot) // Bind visibility to flag
                 .show(ctx, |ui| {
-                    ui.label("States per iteration");
+                    ui.horizontal(|ui| {
+                        ui.selectable_value(&mut self.plot_tab, PlotTab::States, "States");
+                        ui.selectable_value(&mut self.plot_tab, PlotTab::MaxQ, "Max Q");
+                    });
+                    ui.separator();
+                    match self.plot_tab {
+                        PlotTab::States => {
+                            ui.label("States per iteration");
 
-                    // Create plot from states per iteration
-                    let states_per_iteration = self.agent.get_states_per_iteration();
-                    let points = PlotPoints::from_iter(states_per_iteration);
-                    let line = Line::new(points);
-                    Plot::new("states_per_iteration")
-                        .view_aspect(2.0)
-                        .show(ui, |plot_ui| plot_ui.line(line));
+                            // Create plot from states per iteration
+                            let states_per_iteration =
+                                self.agent.agent().get_states_per_iteration();
+                            let jump = take_plot_jump(&mut self.plot_jump, 0).map(|bookmark| {
+                                timeline::plot_bounds(&states_per_iteration, bookmark.iterations)
+                            });
+                            let points = PlotPoints::from_iter(states_per_iteration);
+                            let line = Line::new(points);
+                            Plot::new("states_per_iteration").view_aspect(2.0).show(
+                                ui,
+                                |plot_ui| {
+                                    if let Some(bounds) = jump {
+                                        plot_ui.set_plot_bounds(bounds);
+                                    }
+                                    plot_ui.line(line);
+                                },
+                            );
+                        }
+                        PlotTab::MaxQ => {
+                            ui.horizontal(|ui| {
+                                ui.label("Max Q per iteration, smoothed over");
+                                let smoothing = egui::DragValue::new(&mut self.q_smoothing);
+                                ui.add(smoothing.clamp_range(1..=10000));
+                                ui.label("iterations");
+                            });
+
+                            let max_q_per_iteration = self.agent.agent().get_max_q_per_iteration();
+                            let jump = take_plot_jump(&mut self.plot_jump, 1).map(|bookmark| {
+                                timeline::plot_bounds(&max_q_per_iteration, bookmark.iterations)
+                            });
+                            let smoothed = moving_average(&max_q_per_iteration, self.q_smoothing);
+                            let line =
+                                Line::new(PlotPoints::from_iter(max_q_per_iteration)).name("Max Q");
+                            let smoothed_line =
+                                Line::new(PlotPoints::from_iter(smoothed)).name("Smoothed");
+                            let q_smoothing = self.q_smoothing;
+                            Plot::new("max_q_per_iteration")
+                                .view_aspect(2.0)
+                                .legend(egui::plot::Legend::default())
+                                .show(ui, |plot_ui| {
+                                    if let Some(bounds) = jump {
+                                        plot_ui.set_plot_bounds(bounds);
+                                    }
+                                    plot_ui.line(line);
+                                    if q_smoothing > 1 {
+                                        plot_ui.line(smoothed_line);
+                                    }
+                                });
+                        }
+                    }
                 });
         }
     }
 
-    fn show_q_plot(&mut self, ctx: &egui::Context) {
-        if self.show_q_plot {
-            egui::Window::new("Best Q")
-                .open(&mut self.show_q_plot) // Bind visibility to flag
+    fn show_win_rate_plot(&mut self, ctx: &egui::Context) {
+        if self.show_win_rate_plot {
+            egui::Window::new("Win Rate")
+                .open(&mut self.show_win_rate_plot) // Bind visibility to flag
                 .show(ctx, |ui| {
-                    ui.label("Max Q per iteration");
+                    ui.label("Training (last 10) and evaluation win rate per episode");
 
-                    // Create plot from states per iteration
-                    let max_q_per_iteration = self.agent.get_max_q_per_iteration();
-                    let points = PlotPoints::from_iter(max_q_per_iteration);
-                    let line = Line::new(points);
-                    Plot::new("max_q_per_iteration")
+                    // Training win rate over a sliding window
+                    let training_win_rates: Vec<[f64; 2]> = (1..=self.episodes.len())
+                        .map(|i| {
+                            let window = &self.episodes[i.saturating_sub(10)..i];
+                            [i as f64, q_learning::win_rate(window) as f64]
+                        })
+                        .collect();
+                    let jump = take_plot_jump(&mut self.plot_jump, 2).map(|bookmark| {
+                        timeline::plot_bounds(&training_win_rates, bookmark.episodes)
+                    });
+                    let training_line =
+                        Line::new(PlotPoints::from_iter(training_win_rates)).name("Training");
+                    let evaluation_points =
+                        PlotPoints::from_iter(self.evaluation_win_rates.iter().copied());
+                    let evaluation_line = Line::new(evaluation_points).name("Evaluation");
+                    Plot::new("win_rate_per_episode")
                         .view_aspect(2.0)
-                        .show(ui, |plot_ui| plot_ui.line(line));
+                        .legend(egui::plot::Legend::default())
+                        .show(ui, |plot_ui| {
+                            if let Some(bounds) = jump {
+                                plot_ui.set_plot_bounds(bounds);
+                            }
+                            plot_ui.line(training_line);
+                            plot_ui.line(evaluation_line);
+                        });
                 });
         }
     }
 
+    fn show_timeline(&mut self, ctx: &egui::Context) {
+        if !self.show_timeline {
+            return;
+        }
+        if let Some(bookmark) = self.timeline.show(ctx, &mut self.show_timeline) {
+            self.show_states_plot = true;
+            self.show_win_rate_plot = true;
+            self.plot_jump = Some(PlotJump {
+                bookmark,
+                pending: [true; 3],
+            });
+        }
+    }
+
+    // Marks the timeline, where the plots are now
+    fn mark_timeline(&mut self, kind: EventKind, label: String) {
+        let iteration = self.agent.agent().get_iteration_number();
+        self.timeline
+            .push(kind, label, iteration, self.episodes.len());
+    }
+
+    fn show_pixel_probabilities(&mut self, ctx: &egui::Context) {
+        let mut open = self.show_pixel_probabilities;
+        let mut reset = None;
+        let mut dialog = None;
+        egui::Window::new("Pixel Probabilities")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "Most likely colours of each character (seen at least {} times)",
+                    TOP_COLORS_MIN_SAMPLES
+                ));
+                ui.columns(2, |columns| {
+                    let models = [
+                        (&self.character1, &self.char1_pixel_probability),
+                        (&self.character2, &self.char2_pixel_probability),
+                    ];
+                    for (i, (character, pixel_probability)) in models.into_iter().enumerate() {
+                        let ui = &mut columns[i];
+                        ui.label(format!(
+                            "{:?} ({} colours)",
+                            character,
+                            pixel_probability.len()
+                        ));
+                        ui.horizontal(|ui| {
+                            if ui.button("Reset").clicked() {
+                                reset = Some(i);
+                            }
+                            if ui.button("Save").clicked() {
+                                dialog = Some((i, true));
+                            }
+                            if ui.button("Load").clicked() {
+                                dialog = Some((i, false));
+                            }
+                        });
+                        let top_colors = vision::top_pixel_probabilities(
+                            pixel_probability,
+                            TOP_COLORS,
+                            TOP_COLORS_MIN_SAMPLES,
+                        );
+                        egui::Grid::new(format!("pixel_probabilities_{}", i))
+                            .striped(true)
+                            .show(ui, |ui| {
+                                for (pixel, probability, samples) in top_colors {
+                                    let size = egui::vec2(16.0, 16.0);
+                                    let (rect, _) =
+                                        ui.allocate_exact_size(size, egui::Sense::hover());
+                                    let color = Color32::from_rgb(pixel[0], pixel[1], pixel[2]);
+                                    ui.painter().rect_filled(rect, 2.0, color);
+                                    ui.label(format!("{:.2}", probability));
+                                    ui.label(format!("{}", samples));
+                                    ui.end_row();
+                                }
+                            });
+                    }
+                });
+            });
+        self.show_pixel_probabilities = open;
+
+        match reset {
+            Some(0) => self.char1_pixel_probability.clear(),
+            Some(_) => self.char2_pixel_probability.clear(),
+            None => (),
+        }
+        if reset.is_some() {
+            self.abstraction_cache.clear();
+        }
+        if let Some((i, save)) = dialog {
+            let mut file_dialog = if save {
+                FileDialog::save_file(None).title("Save Pixel Probabilities")
+            } else {
+                FileDialog::open_file(None).title("Load Pixel Probabilities")
+            };
+            file_dialog.open();
+            self.pixel_probability_dialog = Some((i, save, file_dialog));
+        }
+    }
+
+    // Pauses the emulator and runs `job` on its own thread
+    fn start_agent_job(
+        &mut self,
+        title: &'static str,
+        path: PathBuf,
+        training_time: Option<Duration>,
+        job: impl FnOnce(&Progress) -> Result<Option<Agent>, String> + Send + 'static,
+    ) {
+        if self.agent_job.is_some() {
+            eprintln!("{} {}: wait for the current one", title, path.display());
+            return;
+        }
+        let progress = Arc::new(Progress::default());
+        let job_progress = progress.clone();
+        self.agent_job = Some(AgentJob {
+            title,
+            path,
+            progress,
+            thread: thread::spawn(move || job(&job_progress)),
+            training_time,
+            was_running: self.is_running,
+        });
+    }
+
+    // Progress of the running save or load, and what comes after it
+    fn agent_job(&mut self, ctx: &egui::Context) {
+        let Some(job) = &self.agent_job else {
+            return;
+        };
+        self.is_running = false;
+        self.step = None;
+        if !job.thread.is_finished() {
+            let (done, total) = job.progress.get();
+            egui::Window::new(job.title)
+                .collapsible(false)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    ui.label(job.path.display().to_string());
+                    let fraction = if total > 0 {
+                        done as f32 / total as f32
+                    } else {
+                        0.0
+                    };
+                    let text = format!("{} / {} states", done, total);
+                    ui.add(egui::ProgressBar::new(fraction).text(text));
+                    if job.progress.is_cancelled() {
+                        ui.label("Cancelling...");
+                    } else if ui.button("Cancel").clicked() {
+                        job.progress.cancel();
+                    }
+                });
+            ctx.request_repaint_after(Duration::from_millis(100));
+            return;
+        }
+        let job = self.agent_job.take().unwrap();
+        self.is_running = job.was_running;
+        let result = job
+            .thread
+            .join()
+            .unwrap_or_else(|_| Err("Agent thread panicked".to_string()));
+        match result {
+            Ok(Some(agent)) => self.agent_loaded(job.path, agent),
+            Ok(None) => {
+                if let Some(training_time) = job.training_time {
+                    self.saved_training_time = training_time;
+                }
+                let label = format!("Saved {}", job.path.display());
+                self.mark_timeline(EventKind::Checkpoint, label);
+            }
+            Err(e) => eprintln!("{} {}: {}", job.title, job.path.display(), e),
+        }
+    }
+
+    fn agent_loaded(&mut self, path: PathBuf, agent: Agent) {
+        if let Some(strategy) = self.agent_transfer.take() {
+            let summary = self
+                .agent
+                .agent()
+                .transfer_from(&agent, strategy, self.max_mse);
+            println!("Transferred from {}: {}", path.display(), summary);
+            return;
+        }
+        self.saved_training_time = agent.get_training_time();
+        // Trained with these, keep going the same way
+        self.learning_rate = agent.get_learning_rate();
+        self.discount_factor = agent.get_discount_factor();
+        self.store_raw_frames = agent.get_store_raw_frames();
+        self.agent.set_agent(agent);
+        let mut agent = self.agent.agent();
+        agent.set_same_state(self.same_state, self.same_state_penalty);
+        agent.set_state_matching(self.state_matching);
+        agent.set_correction(self.correction);
+        agent.set_replay(
+            self.replay_capacity,
+            self.replay_batch_size,
+            self.replay_sampling,
+        );
+        drop(agent);
+        self.opened_agent = Some(path);
+    }
+
     fn file_dialogs(&mut self, ctx: &egui::Context) {
         // Load Agent
-        if let Some(dialog) = &mut self.open_file_dialog {
-            if dialog.show(ctx).selected() {
-                if let Some(file) = dialog.path() {
-                    let path = file.to_str().unwrap();
-                    self.agent = q_learning::load_agent(path);
-                }
+        if let Some(agent_picker) = &mut self.agent_picker {
+            if let Some(path) = agent_picker.show(ctx, &mut self.show_agent_picker) {
+                let agent_path = path.to_string_lossy().to_string();
+                self.start_agent_job("Loading Agent", path, None, move |progress| {
+                    q_learning::load_agent_with_progress(&agent_path, progress).map(Some)
+                });
             }
         }
 
         // Save Agent
+        let mut save_path = None;
         if let Some(dialog) = &mut self.save_file_dialog {
+            if dialog.show(ctx).selected() {
+                save_path = dialog.path().map(|file| file.to_path_buf());
+            }
+        }
+        if let Some(path) = save_path {
+            // Saved from a copy, the agent is free meanwhile
+            let agent = self.agent.agent().clone();
+            let training_time = Some(agent.get_training_time());
+            let agent_path = path.to_string_lossy().to_string();
+            self.start_agent_job("Saving Agent", path, training_time, move |progress| {
+                q_learning::save_agent_with_progress(&agent, &agent_path, progress).map(|_| None)
+            });
+        }
+
+        // Save/Load Pixel Probabilities
+        let mut pixel_probability_path = None;
+        if let Some((i, save, dialog)) = &mut self.pixel_probability_dialog {
             if dialog.show(ctx).selected() {
                 if let Some(file) = dialog.path() {
-                    let path = file.to_str().unwrap();
-                    q_learning::save_agent(&self.agent, path);
+                    pixel_probability_path = Some((*i, *save, file.to_path_buf()));
+                }
+            }
+        }
+        if let Some((i, save, path)) = pixel_probability_path {
+            let pixel_probability = if i == 0 {
+                &mut self.char1_pixel_probability
+            } else {
+                &mut self.char2_pixel_probability
+            };
+            if save {
+                if let Err(e) = vision::save_pixel_probability(pixel_probability, &path) {
+                    eprintln!("Error saving pixel probabilities: {}", e);
                 }
+            } else {
+                match vision::load_pixel_probability(&path) {
+                    Ok(loaded) => {
+                        *pixel_probability = loaded;
+                        self.abstraction_cache.clear();
+                    }
+                    Err(e) => eprintln!("Error loading pixel probabilities: {}", e),
+                }
+            }
+        }
+
+        // Export Report
+        let mut export_path = None;
+        if let Some(dialog) = &mut self.export_report_dialog {
+            if dialog.show(ctx).selected() {
+                export_path = dialog.path().map(|file| file.to_path_buf());
+            }
+        }
+        if let Some(path) = export_path {
+            self.export_report(path.to_str().unwrap());
+            self.exported_report = Some(path);
+        }
+        // Export Graph
+        let mut export_path = None;
+        if let Some(dialog) = &mut self.export_graph_dialog {
+            if dialog.show(ctx).selected() {
+                export_path = dialog.path().map(|file| file.to_path_buf());
+            }
+        }
+        if let Some(path) = export_path {
+            let result =
+                q_learning::export_transition_graph(&self.agent.agent(), path.to_str().unwrap());
+            if let Err(e) = result {
+                eprintln!("Error exporting transition graph: {}", e);
             }
+            self.exported_graph = Some(path);
+        }
+        // Re-abstract Agent
+        let mut agent_path = None;
+        if let Some(dialog) = &mut self.reabstract_dialog {
+            if dialog.show(ctx).selected() {
+                agent_path = dialog.path().map(|file| file.to_path_buf());
+            }
+        }
+        if let Some(path) = agent_path {
+            self.reabstract_agent(&path);
         }
     }
 
+    // Migrates a saved agent to the current vision settings, next to it as
+    // <agent>_reabstracted. Only states saved with their raw frame change.
+    fn reabstract_agent(&self, path: &Path) {
+        let new_path = format!("{}_reabstracted", path.to_string_lossy());
+        let result =
+            q_learning::reabstract_agent(&path.to_string_lossy(), &new_path, |raw_frame| {
+                // Abstraction feeds the histograms, every state gets the
+                // ones of the current settings instead
+                let mut char1_pixel_probability = self.char1_pixel_probability.clone();
+                let mut char2_pixel_probability = self.char2_pixel_probability.clone();
+                let (frame_abstraction, _) = vision::get_frame_abstraction(
+                    &vision::uncrop_frame(raw_frame),
+                    self.red_thresholds,
+                    self.green_thresholds,
+                    self.blue_thresholds,
+                    self.dilate_k,
+                    &mut char1_pixel_probability,
+                    &mut char2_pixel_probability,
+                    self.char1_probability_threshold,
+                    self.char2_probability_threshold,
+                    self.char1_dilate_k,
+                    self.char2_dilate_k,
+                    &self.exclusions,
+                );
+                frame_abstraction
+            });
+        match result {
+            Ok(summary) => println!("{}: {}", new_path, summary),
+            Err(e) => eprintln!("Error re-abstracting agent: {}", e),
+        }
+    }
+
+    fn export_report(&self, path: &str) {
+        let agent = self.agent.agent();
+        let states_per_iteration = agent.get_states_per_iteration();
+        let max_q_per_iteration = agent.get_max_q_per_iteration();
+        let smoothed_max_q = moving_average(&max_q_per_iteration, self.q_smoothing);
+        let training_win_rates: Vec<[f64; 2]> = (1..=self.episodes.len())
+            .map(|i| {
+                let window = &self.episodes[i.saturating_sub(10)..i];
+                [i as f64, q_learning::win_rate(window) as f64]
+            })
+            .collect();
+
+        let total_seconds = agent.get_training_time().as_secs();
+        let report = report::Report {
+            config: vec![
+                ("AI agent", format!("{:?}", self.character1)),
+                ("Opponent", format!("{:?}", self.character2)),
+                ("Observation", format!("{:?}", self.observation)),
+                ("Mirrored", format!("{}", self.mirrored)),
+                ("Side Swap", format!("{}", self.swap_sides)),
+                ("Obs Freq (Hz)", format!("{}", self.observation_frequency)),
+                (
+                    "Neutral Obs Freq (Hz)",
+                    match self.adaptive_frequency {
+                        true => format!("{}", self.neutral_frequency),
+                        false => "-".to_string(),
+                    },
+                ),
+                ("Red Thresholds", format!("{:?}", self.red_thresholds)),
+                ("Green Thresholds", format!("{:?}", self.green_thresholds)),
+                ("Blue Thresholds", format!("{:?}", self.blue_thresholds)),
+                ("Dilate", format!("{}", self.dilate_k)),
+                ("Exclusions", format!("{:?}", self.exclusions)),
+                ("Trace", format!("{}", self.trace)),
+                ("Radius", format!("{:.3}", self.radius)),
+                ("MSE", format!("{}", self.max_mse)),
+                ("Learning Rate", format!("{}", self.learning_rate)),
+                ("Discount Factor", format!("{}", self.discount_factor)),
+            ],
+            metrics: vec![
+                (
+                    "Training Time",
+                    format!(
+                        "{:02}:{:02}:{:02}",
+                        total_seconds / 3600,
+                        (total_seconds % 3600) / 60,
+                        total_seconds % 60
+                    ),
+                ),
+                ("Iterations", format!("{}", agent.get_iteration_number())),
+                ("States", format!("{}", agent.get_number_of_states())),
+                ("Merged States", format!("{}", agent.get_merged_states())),
+                ("Same State", format!("{}", agent.get_same_state_count())),
+                ("Side Swaps", format!("{}", self.side_swaps)),
+                (
+                    "Discarded Observations",
+                    format!("{}", self.discarded_observations),
+                ),
+                ("Episodes", format!("{}", self.episodes.len())),
+                (
+                    "Win Rate",
+                    format!("{:.2}", q_learning::win_rate(&self.episodes)),
+                ),
+            ],
+            plots: vec![
+                report::Plot {
+                    name: "states_plot",
+                    title: "States per iteration",
+                    lines: vec![report::PlotLine {
+                        name: "States",
+                        points: &states_per_iteration,
+                    }],
+                },
+                report::Plot {
+                    name: "q_plot",
+                    title: "Max Q per iteration",
+                    lines: vec![
+                        report::PlotLine {
+                            name: "Max Q",
+                            points: &max_q_per_iteration,
+                        },
+                        report::PlotLine {
+                            name: "Smoothed",
+                            points: &smoothed_max_q,
+                        },
+                    ],
+                },
+                report::Plot {
+                    name: "win_rate_plot",
+                    title: "Win rate per episode",
+                    lines: vec![
+                        report::PlotLine {
+                            name: "Training",
+                            points: &training_win_rates,
+                        },
+                        report::PlotLine {
+                            name: "Evaluation",
+                            points: &self.evaluation_win_rates,
+                        },
+                    ],
+                },
+            ],
+            samples: agent.get_sample_abstractions(8),
+        };
+        report::export_report(&report, path);
+    }
+
     fn process_frame(&mut self) -> bool {
+        if self.macros.is_recording() {
+            let action = self.controller_action();
+            self.macros.record(action);
+        }
+
         // Run frame
         self.run_frame();
         if self.replay.is_some() {
@@ -800,73 +2939,532 @@ impl MyApp {
             return false;
         }
 
+        // Continue screens, demo mode or a fight that never ends
+        if !self.check_combat() {
+            return false;
+        }
+
         // Get life info
-        let lifes_info = vision::get_life_info(self.frame.clone());
+        let lifes_info = vision::get_life_info_gray(&self.frame_gray);
         self.agent_life_info = lifes_info.0;
         self.opponent_life_info = lifes_info.1;
 
         // Check for end of combat
         if self.agent_life_info.life == 0.0 || self.opponent_life_info.life == 0.0 {
             println!("End of combat");
+            let label = match self.agent_life_info.life > 0.0 {
+                true => "KO, the agent won",
+                false => "KO, the agent lost",
+            };
+            self.mark_timeline(EventKind::Ko, label.to_string());
+            self.end_episode(self.agent_life_info.life > 0.0);
             self.replay = Some(Duration::ZERO);
             return false;
         }
 
         self.reset_controller();
 
+        // The human takes over, the agent only watches
+        let overriding = self.is_overriding();
+        if overriding {
+            self.macros.stop();
+            self.set_controller(self.human_action);
+        }
+
+        // Macros run to the end before the agent acts again
+        if let Some(action) = self.macros.next_action() {
+            self.set_controller(self.swap_action(action));
+            return false;
+        }
+        if self.macros.is_recording() {
+            return false;
+        }
+
         // Feed AI agent
         if self.observation_frequency == 0 {
             return false;
         }
         let start_time = Instant::now();
-        self.time_from_last_observation += self.frame_time.total_time;
-        let period = Duration::from_secs_f32(1.0 / self.observation_frequency as f32);
+        let (elapsed, deadline) = match self.pacing {
+            Pacing::DropObservations => (self.frame_time.total_time, AGENT_DEADLINE),
+            Pacing::KeepObservations => (Duration::from_secs_f64(1.0 / PSX_FRAME_RATE), AGENT_WAIT),
+        };
+        self.time_from_last_observation += elapsed;
+        let period = Duration::from_secs_f32(1.0 / self.current_observation_frequency() as f32);
         let mut processed = false;
         if self.time_from_last_observation > period {
+            // Whole periods gone by since the last observation didn't get theirs
+            let missed = self.time_from_last_observation.as_secs_f64() / period.as_secs_f64();
+            self.missed_observations += missed as u64 - 1;
             // VISION PIPELINE
-            let (mut frame_abstraction, vision_stages) = vision::get_frame_abstraction(
-                &self.frame.clone(),
-                self.red_thresholds,
-                self.green_thresholds,
-                self.blue_thresholds,
-                self.dilate_k,
-                &mut self.char1_pixel_probability,
-                &mut self.char2_pixel_probability,
-                self.char1_probability_threshold,
-                self.char2_probability_threshold,
-                self.char1_dilate_k,
-                self.char2_dilate_k,
-            );
-            if self.previous_trace_abstraction.is_empty() {
-                self.previous_trace_abstraction = RgbImage::new(
-                    frame_abstraction.frame.width(),
-                    frame_abstraction.frame.height(),
-                )
+            let (frame_abstraction, vision_stages) = self.observe();
+            if !self.is_trusted(&frame_abstraction, &vision_stages) {
+                // Neither learned from nor acted on, the reward of this
+                // period goes to the next observation
+                self.discarded_observations += 1;
+                self.last_vision_stages = vision_stages;
+                self.time_from_last_observation = Duration::ZERO;
+                self.frame_time.agent_time = Instant::now() - start_time;
+                return false;
+            }
+            self.update_sides(&frame_abstraction);
+            let info = RewardInfo {
+                frame: self
+                    .system
+                    .as_ref()
+                    .map_or(0, |system| system.get_frame_count()),
+                agent_life: self.agent_life_info.life,
+                agent_damage: self.agent_life_info.damage,
+                opponent_life: self.opponent_life_info.life,
+                opponent_damage: self.opponent_life_info.damage,
+                agent_centroid: frame_abstraction.char1_centroid,
+                opponent_centroid: frame_abstraction.char2_centroid,
             };
-            let trace_abstraction = vision::add_to_trace(
-                &frame_abstraction.frame,
-                &self.previous_trace_abstraction,
-                self.trace,
-            );
-            self.previous_trace_abstraction = trace_abstraction.clone();
-            frame_abstraction.frame = trace_abstraction;
+            self.observed_lifes = (info.agent_life, info.opponent_life);
+            self.observed_distance = (info.agent_centroid.0 - info.opponent_centroid.0).abs()
+                + (info.agent_centroid.1 - info.opponent_centroid.1).abs();
 
             // REWARD
-            let reward = self.opponent_life_info.damage - self.agent_life_info.damage;
-            let reward = if reward < 0.0 { reward * 4.0 } else { reward };
-            let action = self
-                .agent
-                .visit_state(frame_abstraction, reward, self.max_mse);
-            self.set_controller(action);
+            let reward = self.rewards.reward(info.clone());
+            let greedy = self.evaluation_left > 0 || self.simulation.is_running();
+            if self.simulation.is_running() {
+                self.simulation.match_reward += reward;
+            }
+            let action = if overriding {
+                // Demonstrations are only learned from while training
+                let learn = self.learn_from_override && !greedy;
+                let agent_action = if learn {
+                    // Learned as the agent would have pressed it
+                    let action = self.swap_action(self.human_action);
+                    self.agent.demonstrate(
+                        frame_abstraction,
+                        reward,
+                        self.max_mse,
+                        action,
+                        deadline,
+                    )
+                } else {
+                    self.agent.act(frame_abstraction, self.max_mse, deadline)
+                };
+                self.log_demonstration(info.clone(), agent_action, reward, learn);
+                self.human_action
+            } else if greedy {
+                self.agent.act(frame_abstraction, self.max_mse, deadline)
+            } else {
+                self.agent
+                    .visit_state(frame_abstraction, reward, self.max_mse, deadline)
+            };
+            self.log_step(info, action, reward);
+            if !overriding {
+                let action = self.macros.expand(action);
+                self.set_controller(self.swap_action(action));
+            }
             self.last_reward = reward;
             self.last_vision_stages = vision_stages;
             self.time_from_last_observation = Duration::ZERO;
+            self.throughput.observations += 1;
             processed = true;
         }
         self.frame_time.agent_time = Instant::now() - start_time;
+        // Not part of the agent time, it runs the emulator on its own
+        if processed && self.input_latency.enabled && self.controller_action() != 0 {
+            if let Some(system) = self.system.as_ref() {
+                self.input_latency.probe(system);
+            }
+        }
         processed
     }
 
+    fn is_playing_audio(&self) -> bool {
+        self.audio.is_some() && !self.muted
+    }
+
+    fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+        if let Some(audio) = self.audio.as_mut() {
+            audio.set_muted(muted);
+        }
+        let playing = self.is_playing_audio();
+        if let Some(system) = self.system.as_mut() {
+            system.set_spu_reverb(playing);
+        }
+    }
+
+    fn emulated_time(&self) -> Duration {
+        self.system
+            .as_ref()
+            .map_or(Duration::ZERO, |system| system.get_emulated_time())
+    }
+
+    // Hits being dealt (damage still showing or life bars changed since the
+    // last observation) or the characters close enough to deal them
+    fn is_exchange(&self) -> bool {
+        let lifes = (self.agent_life_info.life, self.opponent_life_info.life);
+        self.agent_life_info.damage > 0.0
+            || self.opponent_life_info.damage > 0.0
+            || lifes != self.observed_lifes
+            || self.observed_distance < EXCHANGE_DISTANCE
+    }
+
+    fn current_observation_frequency(&self) -> u32 {
+        if !self.adaptive_frequency || self.is_exchange() {
+            return self.observation_frequency;
+        }
+        self.neutral_frequency.clamp(1, self.observation_frequency)
+    }
+
+    fn is_overriding(&self) -> bool {
+        self.hold_override || self.human_action != 0
+    }
+
+    // Appends the step to DEMONSTRATIONS_PATH, opened on the first one
+    fn log_demonstration(
+        &mut self,
+        info: RewardInfo,
+        agent_action: u8,
+        reward: f32,
+        learned: bool,
+    ) {
+        self.demonstrations += 1;
+        if self.demonstration_log.is_none() {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(DEMONSTRATIONS_PATH);
+            match file {
+                Ok(file) => self.demonstration_log = Some(BufWriter::new(file)),
+                Err(e) => {
+                    eprintln!("Error opening {}: {}", DEMONSTRATIONS_PATH, e);
+                    return;
+                }
+            }
+        }
+        let demonstration = Demonstration {
+            info,
+            time: self.emulated_time().as_secs_f64(),
+            round: self.episodes.len(),
+            action: self.human_action,
+            agent_action,
+            reward,
+            learned,
+        };
+        let demonstration_log = self.demonstration_log.as_mut().unwrap();
+        let result = serde_json::to_writer(&mut *demonstration_log, &demonstration)
+            .map_err(|e| e.to_string())
+            .and_then(|_| writeln!(demonstration_log).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("Error logging demonstration: {}", e);
+        }
+    }
+
+    fn log_step(&mut self, info: RewardInfo, action: u8, reward: f32) {
+        // Locking the agent waits for its thread, only worth it when logging
+        if self.step_log.is_none() {
+            return;
+        }
+        let last_state = self.agent.agent().get_last_state();
+        let step_info = StepInfo {
+            info,
+            time: self.emulated_time().as_secs_f64(),
+            round: self.episodes.len(),
+            evaluation: self.evaluation_left > 0 || self.simulation.is_running(),
+            action,
+            sides_swapped: self.swap_sides && self.sides_swapped,
+            reward,
+            state_index: last_state.map(|(index, _)| index),
+            new_state: last_state.is_some_and(|(_, new)| new),
+        };
+        let step_log = self.step_log.as_mut().unwrap();
+        let result = serde_json::to_writer(&mut *step_log, &step_info)
+            .map_err(|e| e.to_string())
+            .and_then(|_| writeln!(step_log).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("Error logging step, logging stopped: {}", e);
+            self.step_log = None;
+        }
+    }
+
+    // False while the life bars are gone, restarting the combat once that
+    // or the combat itself lasts too long. Those fights aren't counted.
+    fn check_combat(&mut self) -> bool {
+        let now = self.emulated_time();
+        if self.max_combat_duration > 0
+            && now.saturating_sub(self.combat_start)
+                > Duration::from_secs(self.max_combat_duration as u64)
+        {
+            println!("Combat lasting too long, restarting");
+            self.reset_combat();
+            return false;
+        }
+        if self.display.can_show_hud() && vision::is_hud_visible_gray(&self.frame_gray) {
+            self.hud_lost_since = None;
+            return true;
+        }
+        let hud_lost_since = *self.hud_lost_since.get_or_insert(now);
+        self.reset_controller();
+        if self.hud_lost_limit > 0
+            && now.saturating_sub(hud_lost_since) > Duration::from_secs(self.hud_lost_limit as u64)
+        {
+            println!("Life bars lost, restarting combat");
+            self.reset_combat();
+        }
+        false
+    }
+
+    // Abstraction as the agent sees it, advancing the trace
+    fn observe(&mut self) -> (FrameAbstraction, VisionStages) {
+        let (mut frame_abstraction, vision_stages) = self.get_frame_abstraction(true);
+        if self.previous_trace_abstraction.is_empty() {
+            self.previous_trace_abstraction = RgbImage::new(
+                frame_abstraction.frame.width(),
+                frame_abstraction.frame.height(),
+            )
+        };
+        let trace_abstraction = vision::add_to_trace(
+            &frame_abstraction.frame,
+            &self.previous_trace_abstraction,
+            self.trace,
+        );
+        self.previous_trace_abstraction = trace_abstraction.clone();
+        frame_abstraction.frame = vision::compose_observation(
+            self.observation,
+            &vision_stages.cropped_frame,
+            &frame_abstraction.frame,
+            &trace_abstraction,
+        );
+        // Features from RAM instead of the picture, positions as centroids.
+        // The pixels are still used while game_data.json can't be read.
+        if let (Observation::Memory, Ok(game_data), Some(system)) =
+            (self.observation, &self.game_data, self.system.as_mut())
+        {
+            let features = game_data.features(system, self.mirrored);
+            frame_abstraction.frame = game_data::feature_image(&features);
+            frame_abstraction.char1_centroid = features.agent_position;
+            frame_abstraction.char2_centroid = features.opponent_position;
+            // Every feature counts, not only where characters are drawn
+            frame_abstraction.roi = None;
+        }
+        // Before exclusions, so it can be abstracted again with other ones
+        if self.store_raw_frames {
+            frame_abstraction.raw_frame = Some(vision::crop_frame(&self.frame));
+        }
+        (frame_abstraction, vision_stages)
+    }
+
+    // Scores the abstraction, which is trusted when the score reaches
+    // min_abstraction_quality. Features read from RAM always are.
+    fn is_trusted(
+        &mut self,
+        frame_abstraction: &FrameAbstraction,
+        vision_stages: &VisionStages,
+    ) -> bool {
+        let centroids = [
+            frame_abstraction.char1_centroid,
+            frame_abstraction.char2_centroid,
+        ];
+        let previous_centroids = self.previous_centroids.replace(centroids);
+        if self.observation == Observation::Memory && self.game_data.is_ok() {
+            return true;
+        }
+        self.abstraction_quality = AbstractionQuality::new(
+            &vision_stages.segmented_frame,
+            centroids,
+            previous_centroids,
+        );
+        self.abstraction_quality.score >= self.min_abstraction_quality
+    }
+
+    // Abandons the fight without recording an episode
+    fn reset_combat(&mut self) {
+        self.replay = None;
+        self.simulation.match_reward = 0.0;
+        self.reset_controller();
+        self.load_current_combat();
+    }
+
+    // Ends the round now, won by whoever has more life left. During the KO
+    // replay the episode is already recorded, so it only cuts the replay.
+    fn skip_round(&mut self) {
+        if self.replay.is_none() {
+            self.end_episode(self.agent_life_info.life > self.opponent_life_info.life);
+        }
+        self.reset_combat();
+    }
+
+    // Loses the episode, and while training the agent is penalised for it
+    fn forfeit(&mut self) {
+        if self.replay.is_none() {
+            let training = self.evaluation_left == 0 && !self.simulation.is_running();
+            if training && !self.frame.is_empty() {
+                let (frame_abstraction, _) = self.observe();
+                self.agent.visit_state(
+                    frame_abstraction,
+                    -FORFEIT_PENALTY,
+                    self.max_mse,
+                    AGENT_DEADLINE,
+                );
+            }
+            self.end_episode(false);
+        }
+        self.reset_combat();
+    }
+
+    fn end_episode(&mut self, won: bool) {
+        let result = match won {
+            true => "won",
+            false => "lost",
+        };
+        if self.simulation.is_running() {
+            self.mark_timeline(EventKind::Episode, format!("Simulation match {}", result));
+            if self.simulation.end_match(won) {
+                let (win_rate, reward) = self.simulation.result.unwrap();
+                println!(
+                    "Simulation win rate: {:.2}, average reward: {:.3}",
+                    win_rate, reward
+                );
+                self.end_simulation();
+            }
+            return;
+        }
+
+        // Evaluation matches don't count as training
+        if self.evaluation_left > 0 {
+            self.mark_timeline(EventKind::Episode, format!("Evaluation match {}", result));
+            self.evaluation_wins += won as usize;
+            self.evaluation_left -= 1;
+            if self.evaluation_left == 0 {
+                let win_rate = self.evaluation_wins as f64 / self.evaluation_matches as f64;
+                println!("Evaluation win rate: {:.2}", win_rate);
+                self.evaluation_win_rates
+                    .push([self.episodes.len() as f64, win_rate]);
+            }
+            return;
+        }
+
+        self.episodes.push(won);
+        self.throughput.episodes += 1;
+        let label = format!("Episode {} {}", self.episodes.len(), result);
+        self.mark_timeline(EventKind::Episode, label);
+        if self.evaluation_interval > 0
+            && self.episodes.len().is_multiple_of(self.evaluation_interval)
+        {
+            println!("Evaluating agent ({} matches)", self.evaluation_matches);
+            self.evaluation_left = self.evaluation_matches;
+            self.evaluation_wins = 0;
+        }
+    }
+
+    fn start_simulation(&mut self) {
+        println!(
+            "Simulating {} matches against {:?}",
+            self.simulation.matches, self.simulation.opponent
+        );
+        self.simulation.training_opponent = self.character2.clone();
+        self.simulation.left = self.simulation.matches;
+        self.simulation.wins = 0;
+        self.simulation.match_reward = 0.0;
+        self.simulation.total_reward = 0.0;
+        self.set_opponent(self.simulation.opponent.clone());
+        self.replay = None;
+        self.reset_controller();
+        self.is_running = self.load_current_combat();
+        if !self.is_running {
+            self.end_simulation();
+        }
+    }
+
+    // Back to the training opponent, the combat is reloaded by the caller
+    fn end_simulation(&mut self) {
+        self.simulation.left = 0;
+        self.set_opponent(self.simulation.training_opponent.clone());
+    }
+
+    // Swaps the opponent pixel probabilities along with it
+    fn set_opponent(&mut self, opponent: Character) {
+        if self.character2 == opponent {
+            return;
+        }
+        save_character_pixel_probability(&self.character2, &self.char2_pixel_probability);
+        self.char2_pixel_probability = load_character_pixel_probability(&opponent);
+        self.abstraction_cache.clear();
+        self.character2 = opponent;
+    }
+
+    fn check_training_budget(&mut self) {
+        let agent = self.agent.agent();
+        if let Some(reason) = self.training_budget.check(&agent, &self.episodes) {
+            println!("Stopping training: {}", reason);
+            let path = format!("agents/final_{}", agent.get_iteration_number());
+            q_learning::save_final_checkpoint(&agent, &self.episodes, &reason, &path);
+            drop(agent);
+            self.mark_timeline(EventKind::Checkpoint, format!("Saved {}", path));
+            self.is_running = false;
+            self.step = None;
+        }
+    }
+
+    fn get_frame_abstraction(
+        &mut self,
+        update_probabilities: bool,
+    ) -> (FrameAbstraction, VisionStages) {
+        // Paused or between rounds the same frame comes again and again
+        let key = vision::hash_cropped_frame(
+            &self.frame,
+            (
+                self.red_thresholds,
+                self.green_thresholds,
+                self.blue_thresholds,
+                self.dilate_k,
+                self.char1_probability_threshold.to_bits(),
+                self.char2_probability_threshold.to_bits(),
+                self.char1_dilate_k,
+                self.char2_dilate_k,
+                &self.exclusions,
+            ),
+        );
+        // A running agent has to feed the histograms every frame, and what
+        // was cached before they moved is stale
+        if update_probabilities {
+            self.abstraction_cache.clear();
+        } else if let Some(cached) = self.abstraction_cache.get(key) {
+            return cached;
+        }
+
+        // Only a running agent feeds the probability histograms
+        let mut char1_pixel_probability_copy;
+        let mut char2_pixel_probability_copy;
+        let (char1_pixel_probability, char2_pixel_probability) = if update_probabilities {
+            (
+                &mut self.char1_pixel_probability,
+                &mut self.char2_pixel_probability,
+            )
+        } else {
+            char1_pixel_probability_copy = self.char1_pixel_probability.clone();
+            char2_pixel_probability_copy = self.char2_pixel_probability.clone();
+            (
+                &mut char1_pixel_probability_copy,
+                &mut char2_pixel_probability_copy,
+            )
+        };
+        let (frame_abstraction, vision_stages) = vision::get_frame_abstraction(
+            &self.frame,
+            self.red_thresholds,
+            self.green_thresholds,
+            self.blue_thresholds,
+            self.dilate_k,
+            char1_pixel_probability,
+            char2_pixel_probability,
+            self.char1_probability_threshold,
+            self.char2_probability_threshold,
+            self.char1_dilate_k,
+            self.char2_dilate_k,
+            &self.exclusions,
+        );
+        self.abstraction_cache
+            .insert(key, frame_abstraction.clone(), vision_stages.clone());
+        (frame_abstraction, vision_stages)
+    }
+
     fn run_frame(&mut self) {
         let system = self
             .system
@@ -874,12 +3472,44 @@ impl MyApp {
             .expect("Trying to run a frame with no system!");
         let start_time = Instant::now();
         system.run_frame();
+        self.throughput.frames += 1;
+        // Drained even without an output, they pile up otherwise
+        let samples = system.get_audio_samples();
         self.frame_time.psx_time = Instant::now() - start_time;
-        // Get frame buffer
-        let (width, height) = system.get_display_size();
-        let mut framebuffer = vec![0; width as usize * height as usize * 3].into_boxed_slice();
-        system.get_framebuffer(&mut framebuffer, false);
-        self.frame = convert_framebuffer_to_rgb_image(&framebuffer, width, height);
+        let queued_samples = match self.audio.as_mut() {
+            Some(audio) => {
+                audio.push(&samples);
+                let queued_samples = audio.queued_samples();
+                audio.set_resample_ratio(self.speed_controller.resample_ratio(queued_samples));
+                queued_samples
+            }
+            None => 0,
+        };
+        self.speed_controller.regulate(queued_samples);
+        // Get frame buffer, fitted to the layout vision was measured on
+        self.display = vision::DisplayInfo {
+            origin: system.get_display_origin(),
+            size: system.get_display_size(),
+            is_24bit: system.get_24bit(),
+        };
+        let frame = system.with_framebuffer(|framebuffer, width, height| {
+            RgbImage::from_raw(width, height, framebuffer.to_vec())
+                .expect("Framebuffer doesn't match the display size")
+        });
+        self.frame = vision::fit_frame(frame);
+        let (width, height) = self.display.size;
+        if self.frame_gray.dimensions() != (width, height) {
+            self.frame_gray = GrayImage::new(width, height);
+        }
+        system.get_framebuffer_gray(&mut self.frame_gray);
+        if !self.display.is_native() {
+            self.frame_gray = vision::fit_frame(std::mem::take(&mut self.frame_gray));
+        }
+        // Player 2's life bar lands exactly on player 1's
+        if self.mirrored {
+            image::imageops::flip_horizontal_in_place(&mut self.frame);
+            image::imageops::flip_horizontal_in_place(&mut self.frame_gray);
+        }
     }
 
     fn update_replay(&mut self, delta_time: Duration) {
@@ -908,7 +3538,66 @@ impl MyApp {
         }
     }
 
+    // The agent buttons currently held, as an action
+    fn controller_action(&mut self) -> u8 {
+        let Some(system) = self.system.as_mut() else {
+            return 0;
+        };
+        let controller = system.get_controller();
+        let buttons = [
+            controller.button_dpad_up,
+            controller.button_dpad_down,
+            controller.button_dpad_left,
+            controller.button_dpad_right,
+            controller.button_triangle,
+            controller.button_square,
+            controller.button_circle,
+            controller.button_cross,
+        ];
+        let action = buttons
+            .iter()
+            .enumerate()
+            .fold(0, |action, (bit, pressed)| {
+                action | ((*pressed as u8) << bit)
+            });
+        self.mirror_action(action)
+    }
+
+    // Actions are always as seen from player 1's side, the pad gets them
+    // the other way round when mirrored
+    fn mirror_action(&self, action: u8) -> u8 {
+        if !self.mirrored {
+            return action;
+        }
+        swap_left_right(action)
+    }
+
+    // Characters crossed sides when their centroids are the other way round.
+    // Equal centroids, e.g. nothing found, leave the sides as they were.
+    fn update_sides(&mut self, frame_abstraction: &FrameAbstraction) {
+        let agent_x = frame_abstraction.char1_centroid.0;
+        let opponent_x = frame_abstraction.char2_centroid.0;
+        if agent_x == opponent_x {
+            return;
+        }
+        let sides_swapped = agent_x > opponent_x;
+        if sides_swapped != self.sides_swapped {
+            self.side_swaps += 1;
+            self.sides_swapped = sides_swapped;
+        }
+    }
+
+    // Agent actions are as if facing right, on the pad or from it
+    fn swap_action(&self, action: u8) -> u8 {
+        if self.swap_sides && self.sides_swapped {
+            swap_left_right(action)
+        } else {
+            action
+        }
+    }
+
     fn set_controller(&mut self, action: u8) {
+        let action = self.mirror_action(action);
         if let Some(system) = self.system.as_mut() {
             system.get_controller().button_dpad_up = (action & 1 << 0) != 0;
             system.get_controller().button_dpad_down = (action & 1 << 1) != 0;
@@ -922,14 +3611,100 @@ impl MyApp {
     }
 }
 
-fn convert_framebuffer_to_rgb_image(framebuffer: &[u8], width: u32, height: u32) -> RgbImage {
-    let mut img = RgbImage::new(width, height);
-    for (x, y, pixel) in img.enumerate_pixels_mut() {
-        let offset = ((y as u32 * width + x) * 3) as usize;
-        let r = framebuffer[offset];
-        let g = framebuffer[offset + 1];
-        let b = framebuffer[offset + 2];
-        *pixel = Rgb([r, g, b]);
+fn swap_left_right(action: u8) -> u8 {
+    let left = action & 1 << 2;
+    let right = action & 1 << 3;
+    action & !(1 << 2 | 1 << 3) | left << 1 | right >> 1
+}
+
+// Mean of the last `window` values at every point
+fn moving_average(points: &[[f64; 2]], window: usize) -> Vec<[f64; 2]> {
+    let window = window.max(1);
+    let mut sum = 0.0;
+    points
+        .iter()
+        .enumerate()
+        .map(|(i, [x, y])| {
+            sum += y;
+            if i >= window {
+                sum -= points[i - window][1];
+            }
+            [*x, sum / (i + 1).min(window) as f64]
+        })
+        .collect()
+}
+
+// Rectangle spanning two dragged corners, in any order
+fn exclusion_from_corners([start, end]: [(u32, u32); 2]) -> Exclusion {
+    let x = start.0.min(end.0);
+    let y = start.1.min(end.1);
+    [x, y, start.0.max(end.0) - x, start.1.max(end.1) - y]
+}
+
+fn character_combo_box(ui: &mut egui::Ui, id: &str, character: &mut Character) {
+    egui::ComboBox::from_id_source(id)
+        .selected_text(format!("{:?}", character))
+        .show_ui(ui, |ui| {
+            ui.selectable_value(character, Character::Eddy, "Eddy");
+            ui.selectable_value(character, Character::Jin, "Jin");
+            ui.selectable_value(character, Character::King, "King");
+            ui.selectable_value(character, Character::Law, "Law");
+            ui.selectable_value(character, Character::Lei, "Lei");
+            ui.selectable_value(character, Character::Paul, "Paul");
+            ui.selectable_value(character, Character::Xiaoyu, "Xiaoyu");
+            ui.selectable_value(character, Character::Nina, "Nina");
+            ui.selectable_value(character, Character::Yoshimitsu, "Yoshimitsu");
+        });
+}
+
+fn vision_combo_box(ui: &mut egui::Ui, id: &str, vision: &mut Vision) {
+    egui::ComboBox::from_id_source(id)
+        .selected_text(format!("{:?}", vision))
+        .show_ui(ui, |ui| {
+            ui.selectable_value(vision, Vision::PSX, "PSX");
+            ui.selectable_value(vision, Vision::Life, "Life");
+            ui.selectable_value(vision, Vision::Agent, "Agent");
+            ui.selectable_value(vision, Vision::Crop, "Crop");
+            ui.selectable_value(vision, Vision::Contrast, "Contrast");
+            ui.selectable_value(vision, Vision::Mask, "Mask");
+            ui.selectable_value(vision, Vision::Masked, "Masked");
+            ui.selectable_value(vision, Vision::Centroids, "Centroids HUD");
+            ui.selectable_value(vision, Vision::Chars, "Chars HUD");
+            ui.selectable_value(vision, Vision::Segmented, "Segmented");
+        });
+}
+
+fn character_pixel_probability_path(character: &Character) -> PathBuf {
+    let name = format!("{:?}", character).to_lowercase();
+    Path::new(PIXEL_PROBABILITIES_DIR).join(format!("{}.bin", name))
+}
+
+// Empty if the character has no model yet
+fn load_character_pixel_probability(character: &Character) -> HashMap<Rgb<u8>, (u64, u64)> {
+    let path = character_pixel_probability_path(character);
+    if !path.exists() {
+        return HashMap::new();
+    }
+    vision::load_pixel_probability(&path).unwrap_or_else(|e| {
+        eprintln!("Error loading pixel probabilities: {}", e);
+        HashMap::new()
+    })
+}
+
+fn save_character_pixel_probability(
+    character: &Character,
+    pixel_probability: &HashMap<Rgb<u8>, (u64, u64)>,
+) {
+    // Nothing learned, don't overwrite a previous model
+    if pixel_probability.is_empty() {
+        return;
+    }
+    if let Err(e) = fs::create_dir_all(PIXEL_PROBABILITIES_DIR) {
+        eprintln!("Error creating {}: {}", PIXEL_PROBABILITIES_DIR, e);
+        return;
+    }
+    let path = character_pixel_probability_path(character);
+    if let Err(e) = vision::save_pixel_probability(pixel_probability, &path) {
+        eprintln!("Error saving pixel probabilities: {}", e);
     }
-    img
 }
@@ -0,0 +1,388 @@
+// Dojo Learning Environment
+// Copyright (C) 2023-2024 Carlos Perez-Lopez
+//
+// This project is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>
+//
+// You can contact the author via carlospzlz@gmail.com
+
+use image::{GrayImage, Rgb, RgbImage};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use super::game_data::{self, GameData};
+use super::psx::System;
+use super::reward::{RewardInfo, Rewards};
+use super::vision::{self, Exclusion, FrameAbstraction, Observation};
+
+// Actions are u8 masks: up, down, left, right, triangle, square, circle and
+// cross, lowest bit first
+pub const ACTION_SPACE: usize = 256;
+
+// Everything the GUI lets you tweak, with the same defaults
+pub struct EnvConfig {
+    // A state saved in a combat, e.g. states/xiaoyu_vs_lei.bin
+    pub state_path: String,
+    // Overrides the game path embedded in the state
+    pub game_path: Option<String>,
+    // Frames run per step, 4 is the GUI's 15 observations per second
+    pub frames_per_step: u32,
+    pub observation: Observation,
+    // Where the game keeps what the Memory observation reads
+    pub game_data_path: String,
+    pub trace: u8,
+    pub reward_script_path: String,
+    // Frames without life bars before giving up on the episode, 0 never
+    pub hud_lost_limit: u32,
+    pub red_thresholds: [u8; 2],
+    pub green_thresholds: [u8; 2],
+    pub blue_thresholds: [u8; 2],
+    pub dilate_k: u8,
+    pub char1_pixel_probability_path: Option<String>,
+    pub char2_pixel_probability_path: Option<String>,
+    pub char1_probability_threshold: f64,
+    pub char2_probability_threshold: f64,
+    pub char1_dilate_k: u8,
+    pub char2_dilate_k: u8,
+    pub exclusions: Vec<Exclusion>,
+    // Bytes of compressed states kept to rewind steps, 0 can't rewind
+    pub rewind_budget: usize,
+}
+
+impl Default for EnvConfig {
+    fn default() -> Self {
+        Self {
+            state_path: String::new(),
+            game_path: None,
+            frames_per_step: 4,
+            observation: Observation::Trace,
+            game_data_path: "game_data.json".to_string(),
+            trace: 3,
+            reward_script_path: "reward.txt".to_string(),
+            hud_lost_limit: 600,
+            red_thresholds: [0, 173],
+            green_thresholds: [15, 165],
+            blue_thresholds: [15, 156],
+            dilate_k: 12,
+            char1_pixel_probability_path: None,
+            char2_pixel_probability_path: None,
+            char1_probability_threshold: 0.7,
+            char2_probability_threshold: 0.7,
+            char1_dilate_k: 2,
+            char2_dilate_k: 2,
+            exclusions: Vec::new(),
+            rewind_budget: 0,
+        }
+    }
+}
+
+// What a step was taken with and ended in, the GUI's StepInfo without
+// evaluation and sides_swapped, which the environment has no notion of, or
+// state_index and new_state, which belong to the agent picking the actions
+#[derive(Clone, Serialize)]
+pub struct StepInfo {
+    #[serde(flatten)]
+    pub info: RewardInfo,
+    // Emulated seconds, see System::get_emulated_time
+    pub time: f64,
+    // Episodes started before this one
+    pub round: usize,
+    pub action: u8,
+    pub reward: f32,
+}
+
+// The combat as a Gym-style environment, no window involved. Every reset
+// loads the state again, every step holds the action for one frame (a tap,
+// like the GUI's agent) and runs the rest of the step with no buttons.
+pub struct DojoEnv {
+    config: EnvConfig,
+    state: Vec<u8>,
+    system: Option<System>,
+    frame: RgbImage,
+    frame_gray: GrayImage,
+    display: vision::DisplayInfo,
+    previous_trace_abstraction: RgbImage,
+    char1_pixel_probability: HashMap<Rgb<u8>, (u64, u64)>,
+    char2_pixel_probability: HashMap<Rgb<u8>, (u64, u64)>,
+    rewards: Rewards,
+    // Only with the Memory observation
+    game_data: Option<GameData>,
+    observation_size: Option<(u32, u32)>,
+    // Frame every step ended at since the reset, the first one is the reset
+    step_frames: Vec<u64>,
+    // Resets so far
+    episodes: usize,
+}
+
+impl DojoEnv {
+    pub fn new(config: EnvConfig) -> Result<Self, String> {
+        let state =
+            fs::read(&config.state_path).map_err(|e| format!("{}: {}", config.state_path, e))?;
+        let char1_pixel_probability = load_pixel_probability(&config.char1_pixel_probability_path)?;
+        let char2_pixel_probability = load_pixel_probability(&config.char2_pixel_probability_path)?;
+        let rewards = Rewards::new(&config.reward_script_path);
+        // No falling back to the pixels like the GUI, nobody would notice
+        let game_data = match config.observation {
+            Observation::Memory => Some(GameData::load(&config.game_data_path)?),
+            _ => None,
+        };
+        Ok(Self {
+            config,
+            state,
+            system: None,
+            frame: RgbImage::default(),
+            frame_gray: GrayImage::default(),
+            display: vision::DisplayInfo::default(),
+            previous_trace_abstraction: RgbImage::default(),
+            char1_pixel_probability,
+            char2_pixel_probability,
+            rewards,
+            game_data,
+            observation_size: None,
+            step_frames: Vec::new(),
+            episodes: 0,
+        })
+    }
+
+    pub fn action_space(&self) -> usize {
+        ACTION_SPACE
+    }
+
+    // Width and height of the observation image, known after the first reset
+    pub fn observation_space(&self) -> Option<(u32, u32)> {
+        self.observation_size
+    }
+
+    pub fn reset(&mut self) -> Result<FrameAbstraction, String> {
+        let mut system = bincode::deserialize::<System>(&self.state).map_err(|e| {
+            format!(
+                "Error reading emulator state: {}: {}",
+                self.config.state_path, e
+            )
+        })?;
+        if let Some(game_path) = &self.config.game_path {
+            system.set_game_filepath(game_path);
+        }
+        system.check_game_file().map_err(|e| e.to_string())?;
+        system.set_spu_reverb(false);
+        // Only at the end of steps, those are the only places to go back to
+        system.set_rewind(self.config.rewind_budget, 0);
+        self.system = Some(system);
+        self.rewards.reset();
+        self.previous_trace_abstraction = RgbImage::default();
+        self.step_frames.clear();
+        self.episodes += 1;
+        // The state may be saved before the life bars show up
+        self.run_frame();
+        if !self.run_until_hud(self.config.hud_lost_limit) {
+            return Err("Life bars never showed up".to_string());
+        }
+        let observation = self.observe();
+        self.observation_size = Some(observation.frame.dimensions());
+        self.end_step();
+        Ok(observation)
+    }
+
+    // Observation, reward, whether the episode is over and the step info
+    pub fn step(&mut self, action: u8) -> (FrameAbstraction, f32, bool, StepInfo) {
+        self.set_controller(action);
+        for _ in 0..self.config.frames_per_step {
+            self.run_frame();
+            self.set_controller(0);
+        }
+        // Between rounds the life bars go away for a while
+        let hud_visible = self.run_until_hud(self.config.hud_lost_limit);
+
+        let (agent_life_info, opponent_life_info) = vision::get_life_info_gray(&self.frame_gray);
+        let observation = self.observe();
+        let info = RewardInfo {
+            frame: self.system().get_frame_count(),
+            agent_life: agent_life_info.life,
+            agent_damage: agent_life_info.damage,
+            opponent_life: opponent_life_info.life,
+            opponent_damage: opponent_life_info.damage,
+            agent_centroid: observation.char1_centroid,
+            opponent_centroid: observation.char2_centroid,
+        };
+        let reward = self.rewards.reward(info.clone());
+        let done = !hud_visible || info.agent_life == 0.0 || info.opponent_life == 0.0;
+        self.end_step();
+        let step_info = StepInfo {
+            info,
+            time: self.system().get_emulated_time().as_secs_f64(),
+            round: self.episodes - 1,
+            action,
+            reward,
+        };
+        (observation, reward, done, step_info)
+    }
+
+    // Goes back to the end of the step taken that many steps ago, 0 is the
+    // end of the last one, and observes again. Stepping from there with the
+    // same actions is the same episode, except the first reward, which has
+    // nothing to compare with. Errs if the state is gone, over the budget.
+    pub fn rewind(&mut self, steps: usize) -> Result<FrameAbstraction, String> {
+        let index = self
+            .step_frames
+            .len()
+            .checked_sub(steps + 1)
+            .ok_or("Not that many steps since the reset")?;
+        let target = self.step_frames[index];
+        let system = self.system();
+        let frames = system.get_frame_count() - target;
+        let (kept, _) = system.get_rewind_usage().ok_or("Rewind is off")?;
+        if frames > kept {
+            return Err(format!("Frame {} is no longer kept", target));
+        }
+        system.rewind(frames)?;
+        self.step_frames.truncate(index + 1);
+        self.rewards.reset();
+        self.previous_trace_abstraction = RgbImage::default();
+        self.read_frame();
+        Ok(self.observe())
+    }
+
+    fn end_step(&mut self) {
+        let system = self.system();
+        system.push_rewind();
+        let frame = system.get_frame_count();
+        if self.step_frames.last() != Some(&frame) {
+            self.step_frames.push(frame);
+        }
+    }
+
+    pub fn system(&mut self) -> &mut System {
+        self.system
+            .as_mut()
+            .expect("The environment must be reset first!")
+    }
+
+    // False if the life bars didn't come back within limit frames
+    fn run_until_hud(&mut self, limit: u32) -> bool {
+        let mut frames = 0;
+        loop {
+            if self.display.can_show_hud() && vision::is_hud_visible_gray(&self.frame_gray) {
+                return true;
+            }
+            if limit > 0 && frames >= limit {
+                return false;
+            }
+            self.run_frame();
+            frames += 1;
+        }
+    }
+
+    fn run_frame(&mut self) {
+        let system = self
+            .system
+            .as_mut()
+            .expect("Trying to run a frame with no system!");
+        system.run_frame();
+        // Nothing plays them, but they pile up otherwise
+        system.get_audio_samples();
+        self.read_frame();
+    }
+
+    fn read_frame(&mut self) {
+        let system = self
+            .system
+            .as_mut()
+            .expect("Trying to read a frame with no system!");
+        self.display = vision::DisplayInfo {
+            origin: system.get_display_origin(),
+            size: system.get_display_size(),
+            is_24bit: system.get_24bit(),
+        };
+        let frame = system.with_framebuffer(|framebuffer, width, height| {
+            RgbImage::from_raw(width, height, framebuffer.to_vec())
+                .expect("Framebuffer doesn't match the display size")
+        });
+        self.frame = vision::fit_frame(frame);
+        let (width, height) = self.display.size;
+        if self.frame_gray.dimensions() != (width, height) {
+            self.frame_gray = GrayImage::new(width, height);
+        }
+        system.get_framebuffer_gray(&mut self.frame_gray);
+        if !self.display.is_native() {
+            self.frame_gray = vision::fit_frame(std::mem::take(&mut self.frame_gray));
+        }
+    }
+
+    fn observe(&mut self) -> FrameAbstraction {
+        let (mut frame_abstraction, vision_stages) = vision::get_frame_abstraction(
+            &self.frame,
+            self.config.red_thresholds,
+            self.config.green_thresholds,
+            self.config.blue_thresholds,
+            self.config.dilate_k,
+            &mut self.char1_pixel_probability,
+            &mut self.char2_pixel_probability,
+            self.config.char1_probability_threshold,
+            self.config.char2_probability_threshold,
+            self.config.char1_dilate_k,
+            self.config.char2_dilate_k,
+            &self.config.exclusions,
+        );
+        if self.previous_trace_abstraction.is_empty() {
+            self.previous_trace_abstraction = RgbImage::new(
+                frame_abstraction.frame.width(),
+                frame_abstraction.frame.height(),
+            )
+        };
+        let trace_abstraction = vision::add_to_trace(
+            &frame_abstraction.frame,
+            &self.previous_trace_abstraction,
+            self.config.trace,
+        );
+        self.previous_trace_abstraction = trace_abstraction.clone();
+        frame_abstraction.frame = vision::compose_observation(
+            self.config.observation,
+            &vision_stages.cropped_frame,
+            &frame_abstraction.frame,
+            &trace_abstraction,
+        );
+        // Features from RAM instead of the picture, positions as centroids.
+        // The agent is always player 1 here.
+        if let (Some(game_data), Some(system)) = (&self.game_data, self.system.as_mut()) {
+            let features = game_data.features(system, false);
+            frame_abstraction.frame = game_data::feature_image(&features);
+            frame_abstraction.char1_centroid = features.agent_position;
+            frame_abstraction.char2_centroid = features.opponent_position;
+            // Every feature counts, not only where characters are drawn
+            frame_abstraction.roi = None;
+        }
+        frame_abstraction
+    }
+
+    fn set_controller(&mut self, action: u8) {
+        let controller = self.system().get_controller();
+        controller.button_dpad_up = (action & 1 << 0) != 0;
+        controller.button_dpad_down = (action & 1 << 1) != 0;
+        controller.button_dpad_left = (action & 1 << 2) != 0;
+        controller.button_dpad_right = (action & 1 << 3) != 0;
+        controller.button_triangle = (action & 1 << 4) != 0;
+        controller.button_square = (action & 1 << 5) != 0;
+        controller.button_circle = (action & 1 << 6) != 0;
+        controller.button_cross = (action & 1 << 7) != 0;
+    }
+}
+
+// Empty without a model, characters are then told apart by contrast only
+fn load_pixel_probability(path: &Option<String>) -> Result<HashMap<Rgb<u8>, (u64, u64)>, String> {
+    match path {
+        Some(path) => vision::load_pixel_probability(Path::new(path)),
+        None => Ok(HashMap::new()),
+    }
+}
@@ -0,0 +1,87 @@
+// Dojo Learning Environment
+// Copyright (C) 2023-2024 Carlos Perez-Lopez
+//
+// This project is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>
+//
+// You can contact the author via carlospzlz@gmail.com
+
+use egui::{Color32, RichText, Vec2};
+use egui_file::FileDialog;
+use std::path::PathBuf;
+
+pub enum PromptAction {
+    Retry(String),
+    Cancel,
+}
+
+// Error window shown when the BIOS or the game can't be used, with a file
+// picker to point to the right one and try again
+pub struct FilePrompt {
+    title: String,
+    error: String,
+    path: String,
+    cancel: String,
+    dialog: Option<FileDialog>,
+}
+
+impl FilePrompt {
+    // `cancel` names what giving up does, e.g. "Quit"
+    pub fn new(title: &str, path: &str, error: &str, cancel: &str) -> Self {
+        Self {
+            title: title.to_string(),
+            error: error.to_string(),
+            path: path.to_string(),
+            cancel: cancel.to_string(),
+            dialog: None,
+        }
+    }
+
+    pub fn show(&mut self, ctx: &egui::Context) -> Option<PromptAction> {
+        let mut action = None;
+        egui::Window::new(&self.title)
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, Vec2::ZERO)
+            .show(ctx, |ui| {
+                ui.label(RichText::new(&self.error).color(Color32::RED));
+                ui.separator();
+                ui.horizontal(|ui| {
+                    ui.add(egui::TextEdit::singleline(&mut self.path).desired_width(300.0));
+                    if ui.button("Browse").clicked() {
+                        let mut dialog = FileDialog::open_file(Some(PathBuf::from(&self.path)))
+                            .title(&self.title)
+                            .default_size(Vec2 { x: 300.0, y: 200.0 });
+                        dialog.open();
+                        self.dialog = Some(dialog);
+                    }
+                });
+                ui.horizontal(|ui| {
+                    if ui.button("Retry").clicked() {
+                        action = Some(PromptAction::Retry(self.path.clone()));
+                    }
+                    if ui.button(&self.cancel).clicked() {
+                        action = Some(PromptAction::Cancel);
+                    }
+                });
+            });
+        if let Some(dialog) = &mut self.dialog {
+            if dialog.show(ctx).selected() {
+                if let Some(path) = dialog.path() {
+                    self.path = path.to_string_lossy().to_string();
+                }
+            }
+        }
+        action
+    }
+}
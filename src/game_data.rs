@@ -0,0 +1,178 @@
+// Dojo Learning Environment
+// Copyright (C) 2023-2024 Carlos Perez-Lopez
+//
+// This project is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>
+//
+// You can contact the author via carlospzlz@gmail.com
+
+use image::{Rgb, RgbImage};
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+use super::psx::System;
+
+// Side of the square each feature is drawn as. The agent ranks candidates on
+// 1/8 thumbnails, so every feature keeps a pixel of its own there.
+const FEATURE_CELL: u32 = 8;
+// Move IDs are categories, consecutive ones are spread over the pixel range
+// so any change counts as much as a big one
+const MOVE_ID_SPREAD: u32 = 97;
+
+// A value in RAM, normalized to [0, 1] between min and max
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Field {
+    // Hex, e.g. "0x800a1234"
+    pub address: String,
+    // In bytes: 1, 2 or 4
+    pub width: usize,
+    #[serde(default)]
+    pub signed: bool,
+    #[serde(default)]
+    pub min: f32,
+    #[serde(default = "default_max")]
+    pub max: f32,
+}
+
+fn default_max() -> f32 {
+    1.0
+}
+
+impl Field {
+    fn address(&self) -> Result<u32, String> {
+        let digits = self.address.trim().trim_start_matches("0x");
+        u32::from_str_radix(digits, 16).map_err(|_| format!("Invalid address: {}", self.address))
+    }
+
+    fn read(&self, system: &mut System) -> f32 {
+        let address = self.address().unwrap_or(0);
+        let Some(bytes) = system.read_ram(address, self.width) else {
+            return 0.0;
+        };
+        let mut raw = [0; 4];
+        raw[..bytes.len()].copy_from_slice(&bytes);
+        let unsigned = u32::from_le_bytes(raw);
+        match self.signed {
+            true => {
+                let shift = 32 - 8 * self.width as u32;
+                ((unsigned << shift) as i32 >> shift) as f32
+            }
+            false => unsigned as f32,
+        }
+    }
+
+    fn normalized(&self, system: &mut System) -> f32 {
+        let range = self.max - self.min;
+        if range == 0.0 {
+            return 0.0;
+        }
+        ((self.read(system) - self.min) / range).clamp(0.0, 1.0)
+    }
+}
+
+// What is known of one player, anything missing is left out
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlayerData {
+    pub x: Option<Field>,
+    pub y: Option<Field>,
+    pub health: Option<Field>,
+    // Spread over [0, 1] by ID, min and max don't apply
+    pub move_id: Option<Field>,
+}
+
+// Where the game keeps what the memory observation is built from, one entry
+// per player as they are on screen. The addresses can be found with RAM Diff
+// in psx-gui.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct GameData {
+    pub players: [PlayerData; 2],
+}
+
+// Read from RAM on one observation, agent first
+#[derive(Clone, Default)]
+pub struct Features {
+    pub agent_position: (f32, f32),
+    pub opponent_position: (f32, f32),
+    // All in [0, 1]
+    pub values: Vec<f32>,
+}
+
+impl GameData {
+    pub fn load(path: &str) -> Result<GameData, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let game_data: GameData =
+            serde_json::from_str(&text).map_err(|e| format!("{}: {}", path, e))?;
+        for player in game_data.players.iter() {
+            let fields = [&player.x, &player.y, &player.health, &player.move_id];
+            for field in fields.into_iter().flatten() {
+                field.address()?;
+                if ![1, 2, 4].contains(&field.width) {
+                    return Err(format!("Invalid width: {}", field.width));
+                }
+            }
+        }
+        Ok(game_data)
+    }
+
+    // Player 2 is the agent when mirrored, seen from player 1's side like
+    // the flipped frame
+    pub fn features(&self, system: &mut System, mirrored: bool) -> Features {
+        let (agent, opponent) = match mirrored {
+            false => (&self.players[0], &self.players[1]),
+            true => (&self.players[1], &self.players[0]),
+        };
+        let flip = |x: f32| if mirrored { 1.0 - x } else { x };
+        let mut features = Features::default();
+        for (i, player) in [agent, opponent].into_iter().enumerate() {
+            let x = player.x.as_ref().map(|x| flip(x.normalized(system)));
+            let y = player.y.as_ref().map(|y| y.normalized(system));
+            let position = (x.unwrap_or(0.0), y.unwrap_or(0.0));
+            match i {
+                0 => features.agent_position = position,
+                _ => features.opponent_position = position,
+            }
+            features.values.extend(x);
+            features.values.extend(y);
+            features.values.extend(
+                player
+                    .health
+                    .as_ref()
+                    .map(|health| health.normalized(system)),
+            );
+            features
+                .values
+                .extend(player.move_id.as_ref().map(|move_id| {
+                    let id = move_id.read(system) as u32;
+                    (id.wrapping_mul(MOVE_ID_SPREAD) % 256) as f32 / 255.0
+                }));
+        }
+        // Manhattan, halved to stay in [0, 1]
+        let (agent_x, agent_y) = features.agent_position;
+        let (opponent_x, opponent_y) = features.opponent_position;
+        let distance = ((agent_x - opponent_x).abs() + (agent_y - opponent_y).abs()) / 2.0;
+        features.values.push(distance);
+        features
+    }
+}
+
+// One grey square per feature, left to right, so the agent can compare
+// feature vectors like any other observation: the MSE radius is what
+// discretizes them
+pub fn feature_image(features: &Features) -> RgbImage {
+    let width = features.values.len() as u32 * FEATURE_CELL;
+    RgbImage::from_fn(width, FEATURE_CELL, |x, _| {
+        let value = (features.values[(x / FEATURE_CELL) as usize] * 255.0).round() as u8;
+        Rgb([value, value, value])
+    })
+}
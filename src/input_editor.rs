@@ -0,0 +1,347 @@
+// Dojo Learning Environment
+// Copyright (C) 2023-2024 Carlos Perez-Lopez
+//
+// This project is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>
+//
+// You can contact the author via carlospzlz@gmail.com
+
+use egui::{Color32, RichText, SelectableLabel};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs;
+
+use super::psx::System;
+
+// Same bit order as Controller::buttons
+pub const BUTTON_NAMES: [&str; 16] = [
+    "SEL", "L3", "R3", "STA", "⏶", "⏵", "⏷", "⏴", "L2", "R2", "L1", "R1", "∆", "○", "🗙", "◻",
+];
+
+const ROW_HEIGHT: f32 = 18.0;
+const CELL_WIDTH: f32 = 28.0;
+
+// Frames of input kept for savestates, 10 seconds
+const HISTORY_FRAMES: usize = 600;
+// Marks the input history after the system in a savestate. Bincode ignores
+// trailing bytes, so anything that only reads the system still can.
+const HISTORY_TAG: &[u8; 8] = b"DLEINPUT";
+
+// A savestate and the buttons held on every frame after it
+#[derive(Clone, Serialize, Deserialize)]
+pub struct InputMovie {
+    state: Vec<u8>,
+    frames: Vec<u16>,
+}
+
+impl InputMovie {
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let bytes = bincode::serialize(self).map_err(|e| e.to_string())?;
+        fs::write(path, bytes).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn load(path: &str) -> Result<InputMovie, String> {
+        let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        bincode::deserialize(&bytes).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+// What was going on with the input when a state was saved
+#[derive(Serialize, Deserialize)]
+pub struct InputHistory {
+    // Buttons held on the frames right before, oldest first
+    recent: Vec<u16>,
+    // Movie open in the editor and the frame it was at
+    movie: Option<(InputMovie, usize)>,
+}
+
+pub fn save_state(
+    path: &str,
+    system: &System,
+    history: Option<&InputHistory>,
+) -> Result<(), String> {
+    let mut bytes = bincode::serialize(system).map_err(|e| e.to_string())?;
+    if let Some(history) = history {
+        bytes.extend_from_slice(HISTORY_TAG);
+        bincode::serialize_into(&mut bytes, history).map_err(|e| e.to_string())?;
+    }
+    fs::write(path, bytes).map_err(|e| format!("{}: {}", path, e))
+}
+
+// The input history is None if the state has none
+pub fn load_state(path: &str) -> Result<(System, Option<InputHistory>), String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+    let mut rest = &bytes[..];
+    let system: System = bincode::deserialize_from(&mut rest).map_err(|e| e.to_string())?;
+    let history = match rest.strip_prefix(HISTORY_TAG) {
+        Some(history) => Some(bincode::deserialize(history).map_err(|e| e.to_string())?),
+        None => None,
+    };
+    Ok((system, history))
+}
+
+// Frame by frame input editor. While a movie is open every frame the
+// emulator runs plays its input, or records what is pressed once past the
+// end. Going back replays the movie from its savestate, so edits to frames
+// already run take effect right away.
+pub struct InputEditor {
+    movie: Option<InputMovie>,
+    // Frames run since the movie state, the next one to play
+    cursor: usize,
+    follow_cursor: bool,
+    // Buttons of the last frames run, movie or not
+    recent: VecDeque<u16>,
+    // Whether savestates carry the recent input and the movie
+    pub embed_in_states: bool,
+}
+
+impl InputEditor {
+    pub fn new() -> Self {
+        Self {
+            movie: None,
+            cursor: 0,
+            follow_cursor: true,
+            recent: VecDeque::with_capacity(HISTORY_FRAMES),
+            embed_in_states: false,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.movie.is_some()
+    }
+
+    pub fn close(&mut self) {
+        self.movie = None;
+        self.cursor = 0;
+        self.recent.clear();
+    }
+
+    // To embed in a savestate, None if that is disabled
+    pub fn history(&self) -> Option<InputHistory> {
+        if !self.embed_in_states {
+            return None;
+        }
+        let movie = self
+            .movie
+            .as_ref()
+            .map(|movie| (movie.clone(), self.cursor));
+        Some(InputHistory {
+            recent: self.recent.iter().copied().collect(),
+            movie,
+        })
+    }
+
+    // Picks up where the state was saved, the movie carries on playing or
+    // recording from the same frame
+    pub fn restore(&mut self, history: InputHistory) {
+        self.recent = history.recent.into();
+        match history.movie {
+            Some((movie, cursor)) => {
+                self.cursor = cursor.min(movie.frames.len());
+                self.movie = Some(movie);
+            }
+            None => {
+                self.movie = None;
+                self.cursor = 0;
+            }
+        }
+        self.follow_cursor = true;
+    }
+
+    // New movie from the current state, keeping the inputs ahead
+    pub fn branch(&mut self, system: &System) {
+        let state = bincode::serialize(system).expect("Error serializing the system");
+        let frames = match &self.movie {
+            Some(movie) => movie.frames[self.cursor.min(movie.frames.len())..].to_vec(),
+            None => Vec::new(),
+        };
+        self.movie = Some(InputMovie { state, frames });
+        self.cursor = 0;
+    }
+
+    // Call right before every run_frame
+    pub fn before_frame(&mut self, system: &mut System) {
+        let controller = system.get_controller();
+        if let Some(movie) = &mut self.movie {
+            match movie.frames.get(self.cursor) {
+                Some(buttons) => controller.set_buttons(*buttons),
+                None => movie.frames.push(controller.buttons()),
+            }
+            self.cursor += 1;
+            self.follow_cursor = true;
+        }
+        if self.recent.len() == HISTORY_FRAMES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(controller.buttons());
+    }
+
+    // The system went back that many frames, e.g. a rewind. A movie plays
+    // again from there, what it recorded is kept.
+    pub fn rewind(&mut self, frames: usize) {
+        self.cursor = self.cursor.saturating_sub(frames);
+        self.follow_cursor = true;
+        let len = self.recent.len();
+        self.recent.truncate(len.saturating_sub(frames));
+    }
+
+    // The system as it was after `frame` frames of the movie
+    fn seek(&mut self, frame: usize) -> Option<System> {
+        let movie = self.movie.as_ref()?;
+        let mut system: System = match bincode::deserialize(&movie.state) {
+            Ok(system) => system,
+            Err(e) => {
+                eprintln!("Error restoring the movie state: {}", e);
+                return None;
+            }
+        };
+        let frame = frame.min(movie.frames.len());
+        for buttons in movie.frames[..frame].iter() {
+            system.get_controller().set_buttons(*buttons);
+            system.run_frame();
+        }
+        // Whatever came before the movie state is gone
+        self.recent = movie.frames[frame.saturating_sub(HISTORY_FRAMES)..frame]
+            .iter()
+            .copied()
+            .collect();
+        self.cursor = frame;
+        self.follow_cursor = true;
+        Some(system)
+    }
+
+    // Returns a system to replace the current one after seeking. Editing is
+    // only allowed while the emulator is paused.
+    pub fn show(
+        &mut self,
+        ctx: &egui::Context,
+        open: &mut bool,
+        system: &System,
+        editable: bool,
+        movie_path: &str,
+    ) -> Option<System> {
+        let mut seek_to = None;
+        egui::Window::new("Input Editor")
+            .open(open)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    if ui.button("Branch Here").clicked() {
+                        self.branch(system);
+                    }
+                    if ui.button("Save").clicked() {
+                        if let Some(movie) = &self.movie {
+                            match movie.save(movie_path) {
+                                Ok(_) => println!("Input movie saved to {}", movie_path),
+                                Err(e) => eprintln!("Error saving input movie: {}", e),
+                            }
+                        }
+                    }
+                    if ui.button("Load").clicked() {
+                        match InputMovie::load(movie_path) {
+                            Ok(movie) => {
+                                self.movie = Some(movie);
+                                seek_to = Some(0);
+                            }
+                            Err(e) => eprintln!("Error loading input movie: {}", e),
+                        }
+                    }
+                    if ui.button("Close").clicked() {
+                        self.close();
+                    }
+                    ui.checkbox(&mut self.embed_in_states, "Embed in States")
+                        .on_hover_text("Saved states carry the last frames of input and the movie");
+                });
+                let Some(movie) = &mut self.movie else {
+                    ui.label("Branch to start a movie from the current state");
+                    return;
+                };
+                ui.add_enabled_ui(editable, |ui| {
+                    ui.horizontal(|ui| {
+                        if ui.button("Rewind").clicked() {
+                            seek_to = Some(0);
+                        }
+                        if ui.button("Insert").clicked() && self.cursor <= movie.frames.len() {
+                            movie.frames.insert(self.cursor, 0);
+                        }
+                        if ui.button("Delete").clicked() && self.cursor < movie.frames.len() {
+                            movie.frames.remove(self.cursor);
+                        }
+                        if ui.button("Truncate").clicked() {
+                            movie.frames.truncate(self.cursor);
+                        }
+                        ui.label(format!("Frame {} of {}", self.cursor, movie.frames.len()));
+                    });
+                });
+                ui.separator();
+
+                // Header
+                ui.horizontal(|ui| {
+                    ui.add_sized([48.0, ROW_HEIGHT], egui::Label::new("Frame"));
+                    for name in BUTTON_NAMES {
+                        ui.add_sized([CELL_WIDTH, ROW_HEIGHT], egui::Label::new(name));
+                    }
+                });
+
+                // One row per frame plus the one that records next
+                let mut edited = None;
+                let total_rows = movie.frames.len() + 1;
+                let mut scroll_area = egui::ScrollArea::vertical().max_height(300.0);
+                if self.follow_cursor {
+                    let row_spacing = ROW_HEIGHT + ui.spacing().item_spacing.y;
+                    let offset = (self.cursor as f32 * row_spacing - 100.0).max(0.0);
+                    scroll_area = scroll_area.vertical_scroll_offset(offset);
+                    self.follow_cursor = false;
+                }
+                scroll_area.show_rows(ui, ROW_HEIGHT, total_rows, |ui, rows| {
+                    for frame in rows {
+                        ui.horizontal(|ui| {
+                            let mut text = RichText::new(format!("{}", frame));
+                            if frame == self.cursor {
+                                text = text.color(Color32::LIGHT_GREEN);
+                            }
+                            let frame_label = ui.add_enabled(
+                                editable,
+                                egui::Button::new(text).min_size([48.0, ROW_HEIGHT].into()),
+                            );
+                            if frame_label.clicked() {
+                                seek_to = Some(frame);
+                            }
+                            let Some(buttons) = movie.frames.get_mut(frame) else {
+                                return;
+                            };
+                            for (bit, name) in BUTTON_NAMES.iter().enumerate() {
+                                let pressed = (*buttons & (1 << bit)) != 0;
+                                let cell = ui
+                                    .add_enabled_ui(editable, |ui| {
+                                        ui.add_sized(
+                                            [CELL_WIDTH, ROW_HEIGHT],
+                                            SelectableLabel::new(pressed, *name),
+                                        )
+                                    })
+                                    .inner;
+                                if cell.clicked() {
+                                    *buttons ^= 1 << bit;
+                                    edited = Some(frame);
+                                }
+                            }
+                        });
+                    }
+                });
+                if seek_to.is_none() && edited.is_some_and(|frame| frame < self.cursor) {
+                    // The current state came from the old input, run it again
+                    seek_to = Some(self.cursor);
+                }
+            });
+        seek_to.and_then(|frame| self.seek(frame))
+    }
+}
@@ -0,0 +1,146 @@
+// Dojo Learning Environment
+// Copyright (C) 2023-2024 Carlos Perez-Lopez
+//
+// This project is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>
+//
+// You can contact the author via carlospzlz@gmail.com
+
+use std::collections::VecDeque;
+
+use super::psx::System;
+
+// A probe gives up after this many frames without a difference
+const MAX_FRAMES: u32 = 30;
+// Probes run the emulator twice over, so at most one every this many frames
+const PROBE_INTERVAL: u64 = 60;
+// Latest samples the averages are taken from
+const MAX_SAMPLES: usize = 100;
+
+// Frames run until each copy saw a difference, None if it didn't within
+// MAX_FRAMES
+#[derive(Clone, Copy)]
+struct Sample {
+    memory: Option<u32>,
+    display: Option<u32>,
+}
+
+// Frames between pressing buttons and the game responding. The state is
+// copied twice, one copy keeps the buttons and the other gets none. Being
+// deterministic, the first frame they differ at is the response: in RAM
+// when the game reads the pad, on screen when it shows.
+pub struct InputLatency {
+    pub enabled: bool,
+    samples: VecDeque<Sample>,
+    last_probe: Option<u64>,
+}
+
+impl InputLatency {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            samples: VecDeque::new(),
+            last_probe: None,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+        self.last_probe = None;
+    }
+
+    // Measures the buttons currently held, unless a probe ran recently
+    pub fn probe(&mut self, system: &System) {
+        let frame = system.get_frame_count();
+        // The frame count starts over with every combat loaded
+        if let Some(last_probe) = self.last_probe {
+            if frame >= last_probe && frame - last_probe < PROBE_INTERVAL {
+                return;
+            }
+        }
+        self.last_probe = Some(frame);
+
+        let Some((mut pressed, mut released)) = twin_systems(system) else {
+            return;
+        };
+        released.get_controller().set_buttons(0);
+
+        let mut sample = Sample {
+            memory: None,
+            display: None,
+        };
+        for frames in 1..=MAX_FRAMES {
+            pressed.run_frame();
+            released.run_frame();
+            // Only a tap, like the agent actions
+            pressed.get_controller().set_buttons(0);
+
+            if sample.memory.is_none() && pressed.get_ram_hash() != released.get_ram_hash() {
+                sample.memory = Some(frames);
+            }
+            if sample.display.is_none() {
+                let shown = pressed.with_framebuffer(|framebuffer, _, _| framebuffer.to_vec());
+                if released.with_framebuffer(|framebuffer, _, _| framebuffer != shown.as_slice()) {
+                    sample.display = Some(frames);
+                }
+            }
+            if sample.memory.is_some() && sample.display.is_some() {
+                break;
+            }
+        }
+
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    // Mean frames until the game read the input and until it showed
+    pub fn mean(&self) -> (Option<f64>, Option<f64>) {
+        let memory = mean(self.samples.iter().filter_map(|sample| sample.memory));
+        let display = mean(self.samples.iter().filter_map(|sample| sample.display));
+        (memory, display)
+    }
+
+    pub fn max_display(&self) -> Option<u32> {
+        self.samples
+            .iter()
+            .filter_map(|sample| sample.display)
+            .max()
+    }
+
+    // Probes and how many of them never showed a response
+    pub fn counts(&self) -> (usize, usize) {
+        let missed = self
+            .samples
+            .iter()
+            .filter(|sample| sample.display.is_none())
+            .count();
+        (self.samples.len(), missed)
+    }
+}
+
+fn twin_systems(system: &System) -> Option<(System, System)> {
+    let bytes = bincode::serialize(system).ok()?;
+    let pressed = bincode::deserialize(&bytes).ok()?;
+    let released = bincode::deserialize(&bytes).ok()?;
+    Some((pressed, released))
+}
+
+fn mean(values: impl Iterator<Item = u32>) -> Option<f64> {
+    let (sum, count) = values.fold((0, 0), |(sum, count), value| (sum + value, count + 1));
+    match count {
+        0 => None,
+        _ => Some(sum as f64 / count as f64),
+    }
+}
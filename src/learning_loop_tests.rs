@@ -0,0 +1,240 @@
+// Dojo Learning Environment
+// Copyright (C) 2023-2024 Carlos Perez-Lopez
+//
+// This project is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>
+//
+// You can contact the author via carlospzlz@gmail.com
+
+// Smoke tests of the vision, reward and agent loop on synthetic frames, no
+// BIOS or disc needed: two rectangles walking towards each other over the
+// combat area and life bars going down on a script
+
+use std::collections::HashMap;
+
+use image::{DynamicImage, Rgb, RgbImage};
+use imageproc::drawing::draw_filled_rect_mut;
+use imageproc::rect::Rect;
+
+use super::q_learning::Agent;
+use super::reward::{self, RewardInfo};
+use super::vision;
+
+const MAX_MSE: f64 = 2000.0;
+// Same as the GUI and dojo-env defaults
+const RED_THRESHOLDS: [u8; 2] = [0, 173];
+const GREEN_THRESHOLDS: [u8; 2] = [15, 165];
+const BLUE_THRESHOLDS: [u8; 2] = [15, 156];
+const DILATE_K: u8 = 12;
+
+// Life bar row and bars, as vision reads them
+const LIFE_BAR_Y: u32 = 54;
+const LIFE_BARS: [[u32; 2]; 2] = [[12, 164], [204, 356]];
+
+// Remaining, hit and taken life, one per luma class
+const LIFE: Rgb<u8> = Rgb([150, 150, 150]);
+const HIT: Rgb<u8> = Rgb([240, 240, 240]);
+const TAKEN: Rgb<u8> = Rgb([20, 20, 20]);
+const HIT_PIXELS: u32 = 16;
+// Frames until the characters are back where they started
+const WALK_FRAMES: u64 = 20;
+
+// A combat the opponent loses: the agent hits every 4 frames, 16 pixels of
+// its bar each time, and takes a hit every 12
+struct SyntheticCombat {
+    frame: u64,
+    // Pixels of each bar taken, and hit in the current frame
+    taken: [u32; 2],
+    hit: [u32; 2],
+}
+
+impl SyntheticCombat {
+    fn new() -> Self {
+        Self {
+            frame: 0,
+            taken: [0; 2],
+            hit: [0; 2],
+        }
+    }
+
+    fn step(&mut self) {
+        self.frame += 1;
+        self.taken = [self.taken[0] + self.hit[0], self.taken[1] + self.hit[1]];
+        let bar = LIFE_BARS[0][1] - LIFE_BARS[0][0];
+        let hit = |every: u64, taken: u32| match self.frame.is_multiple_of(every) {
+            true => HIT_PIXELS.min(bar - taken),
+            false => 0,
+        };
+        self.hit = [hit(12, self.taken[0]), hit(4, self.taken[1])];
+    }
+
+    fn is_over(&self) -> bool {
+        let bar = LIFE_BARS[0][1] - LIFE_BARS[0][0];
+        self.taken
+            .iter()
+            .zip(self.hit.iter())
+            .any(|(t, h)| t + h == bar)
+    }
+
+    // Player 1 is the agent, on the left
+    fn render(&self) -> RgbImage {
+        let mut frame = RgbImage::from_pixel(
+            vision::DISPLAY_WIDTH,
+            vision::DISPLAY_HEIGHT,
+            Rgb([90, 90, 90]),
+        );
+
+        for (player, [start, end]) in LIFE_BARS.into_iter().enumerate() {
+            // The bars empty towards the middle of the screen
+            for x in start..end {
+                let from_middle = match player {
+                    0 => end - 1 - x,
+                    _ => x - start,
+                };
+                let pixel = match from_middle {
+                    x if x < self.taken[player] => TAKEN,
+                    x if x < self.taken[player] + self.hit[player] => HIT,
+                    _ => LIFE,
+                };
+                for y in LIFE_BAR_Y - 4..LIFE_BAR_Y + 4 {
+                    frame.put_pixel(x, y, pixel);
+                }
+            }
+        }
+
+        let walked = (self.frame % WALK_FRAMES * 4) as i32;
+        let characters = [
+            (40 + walked, Rgb([250, 40, 40])),
+            (280 - walked, Rgb([40, 40, 250])),
+        ];
+        for (x, colour) in characters {
+            draw_filled_rect_mut(&mut frame, Rect::at(x, 250).of_size(40, 150), colour);
+        }
+
+        frame
+    }
+}
+
+// What the reward is computed from, the centroids left out
+fn life_info(frame: &RgbImage) -> (RewardInfo, bool) {
+    let gray = DynamicImage::ImageRgb8(frame.clone()).to_luma8();
+    let (agent, opponent) = vision::get_life_info_gray(&gray);
+    let info = RewardInfo {
+        agent_life: agent.life,
+        agent_damage: agent.damage,
+        opponent_life: opponent.life,
+        opponent_damage: opponent.damage,
+        ..RewardInfo::default()
+    };
+    (info, vision::is_hud_visible_gray(&gray))
+}
+
+// The slow part in debug builds, the rest of the step is cheap
+fn abstraction(frame: &RgbImage) -> vision::FrameAbstraction {
+    let (abstraction, _) = vision::get_frame_abstraction(
+        frame,
+        RED_THRESHOLDS,
+        GREEN_THRESHOLDS,
+        BLUE_THRESHOLDS,
+        DILATE_K,
+        &mut HashMap::new(),
+        &mut HashMap::new(),
+        0.7,
+        0.7,
+        2,
+        2,
+        &[],
+    );
+    abstraction
+}
+
+#[test]
+fn rewards_follow_the_scripted_hits() {
+    let mut combat = SyntheticCombat::new();
+    let (mut previous, _) = life_info(&combat.render());
+    while !combat.is_over() {
+        combat.step();
+        let (info, hud_visible) = life_info(&combat.render());
+        assert!(hud_visible, "frame {}", combat.frame);
+
+        let reward = reward::damage_reward(&previous, &info);
+        match combat.hit {
+            [0, 0] => assert_eq!(reward, 0.0, "frame {}", combat.frame),
+            [0, _] => assert!(reward > 0.0, "frame {}", combat.frame),
+            [_, 0] => assert!(reward < 0.0, "frame {}", combat.frame),
+            _ => (),
+        }
+        previous = info;
+    }
+
+    // The opponent is out, the agent isn't
+    assert_eq!(previous.opponent_life, 0.0);
+    assert!(previous.agent_life > 0.5);
+}
+
+#[test]
+fn centroids_follow_the_characters() {
+    let mut combat = SyntheticCombat::new();
+    let first = abstraction(&combat.render());
+    for _ in 0..WALK_FRAMES / 2 {
+        combat.step();
+    }
+    let later = abstraction(&combat.render());
+
+    // Closer to each other, agent on the left
+    assert!(first.char1_centroid.0 < first.char2_centroid.0);
+    assert!(later.char1_centroid.0 > first.char1_centroid.0);
+    assert!(later.char2_centroid.0 < first.char2_centroid.0);
+}
+
+#[test]
+fn agent_grows_over_an_episode() {
+    // The combat area only changes with the walk, so those are all the
+    // abstractions there are
+    let mut abstractions = HashMap::new();
+    let mut observe = |combat: &SyntheticCombat| {
+        let frame = combat.render();
+        let abstraction = abstractions
+            .entry(combat.frame % WALK_FRAMES)
+            .or_insert_with(|| abstraction(&frame))
+            .clone();
+        (abstraction, life_info(&frame).0)
+    };
+
+    let mut agent = Agent::new();
+    let mut combat = SyntheticCombat::new();
+    let (_, mut previous) = observe(&combat);
+    let mut steps = 0;
+    while !combat.is_over() {
+        combat.step();
+        let (abstraction, info) = observe(&combat);
+        let reward = reward::damage_reward(&previous, &info);
+        agent.visit_state(abstraction, reward, MAX_MSE);
+        previous = info;
+        steps += 1;
+    }
+
+    // The characters walk back and forth, so states are revisited
+    let states = agent.get_number_of_states();
+    assert!(steps > WALK_FRAMES);
+    assert!(states > 1);
+    assert!(states <= WALK_FRAMES as usize);
+
+    // A second episode over the same frames adds nothing new
+    let mut combat = SyntheticCombat::new();
+    while !combat.is_over() {
+        combat.step();
+        agent.visit_state(observe(&combat).0, 0.0, MAX_MSE);
+    }
+    assert_eq!(agent.get_number_of_states(), states);
+}
@@ -0,0 +1,325 @@
+// Dojo Learning Environment
+// Copyright (C) 2023-2024 Carlos Perez-Lopez
+//
+// This project is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>
+//
+// You can contact the author via carlospzlz@gmail.com
+
+use egui::{Color32, RichText, SelectableLabel};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+
+// Same bit order as the agent actions
+const BUTTON_NAMES: [&str; 8] = ["⏶", "⏷", "⏴", "⏵", "∆", "◻", "○", "🗙"];
+
+const ROW_HEIGHT: f32 = 18.0;
+const CELL_WIDTH: f32 = 28.0;
+
+// A short input sequence, e.g. a throw or a combo
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Macro {
+    pub name: String,
+    // Buttons held on every frame, as an agent action
+    pub frames: Vec<u8>,
+    // Agent action played as this macro instead
+    pub action: Option<u8>,
+}
+
+pub fn action_name(action: u8) -> String {
+    let names: Vec<&str> = BUTTON_NAMES
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| (action & (1 << bit)) != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    if names.is_empty() {
+        "-".to_string()
+    } else {
+        names.join("")
+    }
+}
+
+// Macros kept as one JSON file each in a directory. Recording takes the
+// buttons pressed on every frame, playing feeds them back one per frame.
+pub struct Macros {
+    dir: String,
+    macros: Vec<Macro>,
+    selected: Option<usize>,
+    name: String,
+    recording: Option<Vec<u8>>,
+    playing: VecDeque<u8>,
+}
+
+impl Macros {
+    pub fn new(dir: &str) -> Self {
+        let mut macros = Self {
+            dir: dir.to_string(),
+            macros: Vec::new(),
+            selected: None,
+            name: "combo".to_string(),
+            recording: None,
+            playing: VecDeque::new(),
+        };
+        macros.refresh();
+        macros
+    }
+
+    pub fn refresh(&mut self) {
+        self.macros.clear();
+        self.selected = None;
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            // Nothing recorded yet
+            Err(_) => return,
+        };
+        for entry in dir.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+                continue;
+            }
+            match load_macro(&path) {
+                Ok(m) => self.macros.push(m),
+                Err(e) => error!("Error loading macro: {}", e),
+            }
+        }
+        self.macros.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    fn save(&self, m: &Macro) {
+        if let Err(e) = fs::create_dir_all(&self.dir) {
+            error!("Error creating {}: {}", self.dir, e);
+            return;
+        }
+        let path = format!("{}/{}.json", self.dir, m.name);
+        let result = serde_json::to_string_pretty(m)
+            .map_err(|e| e.to_string())
+            .and_then(|json| fs::write(&path, json).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            error!("Error saving macro {}: {}", path, e);
+        }
+    }
+
+    fn delete(&mut self, index: usize) {
+        let path = format!("{}/{}.json", self.dir, self.macros[index].name);
+        if let Err(e) = fs::remove_file(&path) {
+            error!("Error deleting macro {}: {}", path, e);
+        }
+        self.macros.remove(index);
+        self.selected = None;
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.macros.iter().map(|m| m.name.clone()).collect()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    // Call once per frame with the buttons it ran with
+    pub fn record(&mut self, action: u8) {
+        if let Some(frames) = &mut self.recording {
+            frames.push(action);
+        }
+    }
+
+    fn stop_recording(&mut self) {
+        let Some(mut frames) = self.recording.take() else {
+            return;
+        };
+        // Idle frames before the first press and after the last one
+        while frames.last() == Some(&0) {
+            frames.pop();
+        }
+        let start = frames.iter().position(|action| *action != 0).unwrap_or(0);
+        frames.drain(..start);
+        if frames.is_empty() {
+            println!("Nothing pressed, macro discarded");
+            return;
+        }
+        let name = self.name.trim().to_string();
+        let action = match self.macros.iter().position(|m| m.name == name) {
+            Some(index) => self.macros.remove(index).action,
+            None => None,
+        };
+        let m = Macro {
+            name,
+            frames,
+            action,
+        };
+        self.save(&m);
+        self.macros.push(m);
+        self.macros.sort_by(|a, b| a.name.cmp(&b.name));
+        self.selected = self.macros.iter().position(|m| m.name == self.name.trim());
+    }
+
+    pub fn play(&mut self, index: usize) {
+        self.playing = self.macros[index].frames.iter().copied().collect();
+    }
+
+    pub fn stop(&mut self) {
+        self.playing.clear();
+    }
+
+    // Buttons for the next frame while a macro is playing
+    pub fn next_action(&mut self) -> Option<u8> {
+        self.playing.pop_front()
+    }
+
+    // The macro bound to an agent action starts playing, returning its
+    // first frame. Unbound actions are returned as they are.
+    pub fn expand(&mut self, action: u8) -> u8 {
+        match self.macros.iter().position(|m| m.action == Some(action)) {
+            Some(index) => {
+                self.play(index);
+                self.next_action().unwrap_or(0)
+            }
+            None => action,
+        }
+    }
+
+    pub fn show(&mut self, ctx: &egui::Context, open: &mut bool) {
+        egui::Window::new("Macros").open(open).show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Name:");
+                ui.add_enabled(
+                    !self.is_recording(),
+                    egui::TextEdit::singleline(&mut self.name).desired_width(100.0),
+                );
+                if self.is_recording() {
+                    if ui.button("Stop").clicked() {
+                        self.stop_recording();
+                    }
+                    let frames = self.recording.as_ref().map_or(0, |frames| frames.len());
+                    ui.label(RichText::new(format!("Recording {}", frames)).color(Color32::RED));
+                } else {
+                    let valid = !self.name.trim().is_empty();
+                    if ui.add_enabled(valid, egui::Button::new("Record")).clicked() {
+                        self.recording = Some(Vec::new());
+                    }
+                }
+                if ui.button("Refresh").clicked() {
+                    self.refresh();
+                }
+            });
+            ui.label("Records the pad while the emulator runs, the agent waits meanwhile");
+            ui.separator();
+
+            let mut delete = None;
+            let mut play = None;
+            let mut changed = None;
+            egui::Grid::new("macros").striped(true).show(ui, |ui| {
+                ui.label("Name");
+                ui.label("Frames");
+                ui.label("Agent action");
+                ui.end_row();
+                for (index, m) in self.macros.iter_mut().enumerate() {
+                    let selected = self.selected == Some(index);
+                    if ui.selectable_label(selected, &m.name).clicked() {
+                        self.selected = if selected { None } else { Some(index) };
+                    }
+                    ui.label(format!("{}", m.frames.len()));
+                    ui.horizontal(|ui| {
+                        let mut bound = m.action.is_some();
+                        if ui.checkbox(&mut bound, "").changed() {
+                            m.action = if bound { Some(0) } else { None };
+                            changed = Some(index);
+                        }
+                        if let Some(action) = m.action.as_mut() {
+                            if ui.add(egui::DragValue::new(action)).changed() {
+                                changed = Some(index);
+                            }
+                            ui.label(action_name(*action));
+                        }
+                    });
+                    if ui.button("Play").clicked() {
+                        play = Some(index);
+                    }
+                    if ui.button("Delete").clicked() {
+                        delete = Some(index);
+                    }
+                    ui.end_row();
+                }
+            });
+            if let Some(index) = play {
+                self.play(index);
+            }
+            if let Some(index) = changed {
+                self.save(&self.macros[index]);
+            }
+            if let Some(index) = delete {
+                self.delete(index);
+            }
+
+            // Frames of the selected macro, click to toggle a button
+            let Some(index) = self.selected else {
+                return;
+            };
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.add_sized([48.0, ROW_HEIGHT], egui::Label::new("Frame"));
+                for name in BUTTON_NAMES {
+                    ui.add_sized([CELL_WIDTH, ROW_HEIGHT], egui::Label::new(name));
+                }
+            });
+            let m = &mut self.macros[index];
+            let mut edited = false;
+            egui::ScrollArea::vertical().max_height(200.0).show_rows(
+                ui,
+                ROW_HEIGHT,
+                m.frames.len(),
+                |ui, rows| {
+                    for frame in rows {
+                        ui.horizontal(|ui| {
+                            ui.add_sized([48.0, ROW_HEIGHT], egui::Label::new(frame.to_string()));
+                            let action = &mut m.frames[frame];
+                            for (bit, name) in BUTTON_NAMES.iter().enumerate() {
+                                let pressed = (*action & (1 << bit)) != 0;
+                                let cell = ui.add_sized(
+                                    [CELL_WIDTH, ROW_HEIGHT],
+                                    SelectableLabel::new(pressed, *name),
+                                );
+                                if cell.clicked() {
+                                    *action ^= 1 << bit;
+                                    edited = true;
+                                }
+                            }
+                        });
+                    }
+                },
+            );
+            ui.horizontal(|ui| {
+                if ui.button("Add Frame").clicked() {
+                    m.frames.push(0);
+                    edited = true;
+                }
+                if ui.button("Remove Frame").clicked() && m.frames.len() > 1 {
+                    m.frames.pop();
+                    edited = true;
+                }
+            });
+            if edited {
+                self.save(&self.macros[index]);
+            }
+        });
+    }
+}
+
+fn load_macro(path: &Path) -> Result<Macro, String> {
+    let json = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    serde_json::from_str(&json).map_err(|e| format!("{}: {}", path.display(), e))
+}
@@ -1,12 +1,19 @@
+use std::sync::Arc;
+
 use byteorder::{ByteOrder, LittleEndian};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use super::cdrom::Cdrom;
 use super::exp2::Exp2;
 use super::gpu::Gpu;
 use super::intc::Intc;
 use super::mdec::Mdec;
+use super::memory_trace::{AccessKind, MemoryTrace};
+use super::peripherals::controller::InputProvider;
 use super::peripherals::Peripherals;
+use super::region::BiosInfo;
+use super::rom_cache;
+use super::sio1::{LinkTransport, Sio1};
 use super::spu::Spu;
 use super::timekeeper::{Device, Timekeeper};
 use super::timers::Timers;
@@ -19,9 +26,141 @@ pub enum BusWidth {
     WORD,
 }
 
+/// What to do with guest accesses to unmapped addresses (e.g. KUSEG above
+/// 512MB). The hardware raises a bus error, but some buggy code only works
+/// if they are silently dropped.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum UnmappedAccess {
+    #[default]
+    BusError,
+    Ignore,
+}
+
+/// A device mapped into the I/O region of the bus. Loads return the value
+/// and whether a bus error happened, stores just the bus error. `tick`
+/// catches up on CPU cycles, with the interrupt controller and the one
+/// device it drives (`Peer`, `()` for none) at hand.
+pub trait BusDevice {
+    type Peer;
+
+    fn load(&mut self, width: &BusWidth, address: u32) -> (u32, bool);
+    fn store(&mut self, width: &BusWidth, address: u32, value: u32) -> bool;
+    fn tick(&mut self, _intc: &mut Intc, _peer: &mut Self::Peer, _cycles: usize) {}
+}
+
+/* BusDevice without the peer, so loads and stores can pick the device at
+ * runtime */
+trait MmioDevice {
+    fn load(&mut self, width: &BusWidth, address: u32) -> (u32, bool);
+    fn store(&mut self, width: &BusWidth, address: u32, value: u32) -> bool;
+}
+
+impl<T: BusDevice> MmioDevice for T {
+    fn load(&mut self, width: &BusWidth, address: u32) -> (u32, bool) {
+        BusDevice::load(self, width, address)
+    }
+
+    fn store(&mut self, width: &BusWidth, address: u32, value: u32) -> bool {
+        BusDevice::store(self, width, address, value)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum MmioId {
+    Peripherals,
+    Sio1,
+    Intc,
+    Timers,
+    Cdrom,
+    Gpu,
+    Mdec,
+    Spu,
+    Exp2,
+}
+
+struct MmioRange {
+    start: u32,
+    end: u32,
+    id: MmioId,
+    /* Devices to catch up before the access, to keep timings */
+    load_syncs: &'static [Device],
+    store_syncs: &'static [Device],
+}
+
+const MMIO_RANGES: [MmioRange; 9] = [
+    MmioRange {
+        start: 0x1f80_1040,
+        end: 0x1f80_104f,
+        id: MmioId::Peripherals,
+        load_syncs: &[Device::Peripherals],
+        store_syncs: &[Device::Gpu, Device::Peripherals],
+    },
+    MmioRange {
+        start: 0x1f80_1050,
+        end: 0x1f80_105f,
+        id: MmioId::Sio1,
+        load_syncs: &[Device::Peripherals],
+        store_syncs: &[Device::Peripherals],
+    },
+    MmioRange {
+        start: 0x1f80_1070,
+        end: 0x1f80_1077,
+        id: MmioId::Intc,
+        load_syncs: &[],
+        store_syncs: &[],
+    },
+    MmioRange {
+        start: 0x1f80_1100,
+        end: 0x1f80_112b,
+        id: MmioId::Timers,
+        load_syncs: &[Device::Timers],
+        store_syncs: &[Device::Timers],
+    },
+    MmioRange {
+        start: 0x1f80_1800,
+        end: 0x1f80_1803,
+        id: MmioId::Cdrom,
+        load_syncs: &[Device::Cdrom],
+        store_syncs: &[Device::Cdrom],
+    },
+    MmioRange {
+        start: 0x1f80_1810,
+        end: 0x1f80_1817,
+        id: MmioId::Gpu,
+        load_syncs: &[Device::Gpu],
+        store_syncs: &[Device::Gpu],
+    },
+    MmioRange {
+        start: 0x1f80_1820,
+        end: 0x1f80_1827,
+        id: MmioId::Mdec,
+        load_syncs: &[],
+        store_syncs: &[],
+    },
+    MmioRange {
+        start: 0x1f80_1c00,
+        end: 0x1f80_1fff,
+        id: MmioId::Spu,
+        load_syncs: &[Device::Cdrom, Device::Spu],
+        store_syncs: &[Device::Cdrom, Device::Spu],
+    },
+    MmioRange {
+        start: 0x1f80_2000,
+        end: 0x1f80_207f,
+        id: MmioId::Exp2,
+        load_syncs: &[],
+        store_syncs: &[],
+    },
+];
+
 #[derive(Serialize, Deserialize)]
 pub struct Bus {
-    bios: Box<[u8]>,
+    /* Shared by every Bus running the same BIOS, it is never written to */
+    #[serde(
+        serialize_with = "serialize_bios",
+        deserialize_with = "deserialize_bios"
+    )]
+    bios: Arc<[u8]>,
     ram: Box<[u8]>,
     scratchpad: Box<[u8]>,
 
@@ -36,13 +175,26 @@ pub struct Bus {
     intc: Intc,
 
     timers: Timers,
-}
 
-impl Bus {
-    pub fn new(bios_filepath: &str, game_filepath: &str) -> Bus {
-        let mut bios = util::read_file_to_box(bios_filepath);
+    /* Host side configuration, not part of the emulated state */
+    #[serde(skip)]
+    unmapped_access: UnmappedAccess,
+    #[serde(skip)]
+    input_provider: Option<InputProvider>,
+    #[serde(skip)]
+    memory_trace: Option<Box<MemoryTrace>>,
+
+    /* Newer than the save state format, see Sio1 */
+    #[serde(skip)]
+    sio1: Sio1,
+}
 
-        /* Enable TTY output */
+/* Applied once per BIOS file, see rom_cache::bios */
+fn patch_bios(bios: &mut [u8]) {
+    /* Enable TTY output. The addresses are SCPH-1001's, any other BIOS
+     * would have its code overwritten. */
+    let scph1001 = BiosInfo::from_bytes(bios).is_none_or(|info| info.is_scph1001());
+    if scph1001 {
         bios[0x6f0c] = 0x01;
         bios[0x6f0d] = 0x00;
         bios[0x6f0e] = 0x01;
@@ -51,16 +203,31 @@ impl Bus {
         bios[0x6f15] = 0xa9;
         bios[0x6f16] = 0x81;
         bios[0x6f17] = 0xaf;
+    }
+
+    /* Fast boot */
+    //bios[0x18000] = 0x08;
+    //bios[0x18001] = 0x00;
+    //bios[0x18002] = 0xe0;
+    //bios[0x18003] = 0x03;
+    //bios[0x18004] = 0x00;
+    //bios[0x18005] = 0x00;
+    //bios[0x18006] = 0x00;
+    //bios[0x18007] = 0x00;
+}
 
-        /* Fast boot */
-        //bios[0x18000] = 0x08;
-        //bios[0x18001] = 0x00;
-        //bios[0x18002] = 0xe0;
-        //bios[0x18003] = 0x03;
-        //bios[0x18004] = 0x00;
-        //bios[0x18005] = 0x00;
-        //bios[0x18006] = 0x00;
-        //bios[0x18007] = 0x00;
+/* Same bytes as the boxed slice it used to be, so older states load */
+fn serialize_bios<S: Serializer>(bios: &Arc<[u8]>, serializer: S) -> Result<S::Ok, S::Error> {
+    bios[..].serialize(serializer)
+}
+
+fn deserialize_bios<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Arc<[u8]>, D::Error> {
+    Vec::deserialize(deserializer).map(rom_cache::share_bios)
+}
+
+impl Bus {
+    pub fn new(bios_filepath: &str, game_filepath: &str) -> Bus {
+        let bios = util::read_bios(bios_filepath, patch_bios);
 
         Bus {
             bios: bios,
@@ -78,6 +245,12 @@ impl Bus {
             intc: Intc::new(),
 
             timers: Timers::new(),
+
+            unmapped_access: UnmappedAccess::BusError,
+            input_provider: None,
+            memory_trace: None,
+
+            sio1: Sio1::default(),
         }
     }
 
@@ -96,10 +269,57 @@ impl Bus {
         self.peripherals.reset();
     }
 
+    pub fn set_unmapped_access(&mut self, unmapped_access: UnmappedAccess) {
+        self.unmapped_access = unmapped_access;
+    }
+
+    pub fn set_input_provider(&mut self, input_provider: Option<InputProvider>) {
+        self.input_provider = input_provider;
+    }
+
+    pub fn set_memory_trace(&mut self, memory_trace: Option<Box<MemoryTrace>>) {
+        self.memory_trace = memory_trace;
+    }
+
+    pub fn memory_trace(&self) -> Option<&MemoryTrace> {
+        self.memory_trace.as_deref()
+    }
+
+    pub fn memory_trace_mut(&mut self) -> Option<&mut MemoryTrace> {
+        self.memory_trace.as_deref_mut()
+    }
+
+    pub fn set_link(&mut self, link: Option<Box<dyn LinkTransport>>) {
+        self.sio1.set_link(link);
+    }
+
+    /* Moves the host side configuration over from `old`, e.g. when a
+     * rewind replaces the emulated state */
+    pub fn keep_host_state(&mut self, old: &mut Bus) {
+        self.unmapped_access = old.unmapped_access;
+        self.input_provider = old.input_provider.take();
+        self.memory_trace = old.memory_trace.take();
+        self.sio1 = std::mem::take(&mut old.sio1);
+
+        self.gpu.keep_host_state(&mut old.gpu);
+        self.spu.keep_host_state(&old.spu);
+        self.peripherals
+            .controller()
+            .keep_host_state(old.peripherals.controller());
+    }
+
+    pub fn sio1(&mut self) -> &mut Sio1 {
+        &mut self.sio1
+    }
+
     pub fn ram(&mut self) -> &mut Box<[u8]> {
         &mut self.ram
     }
 
+    pub fn bios(&self) -> &[u8] {
+        &self.bios
+    }
+
     pub fn cdrom(&mut self) -> &mut Cdrom {
         &mut self.cdrom
     }
@@ -128,19 +348,46 @@ impl Bus {
         &mut self.intc
     }
 
+    fn find_mmio(address: u32) -> Option<&'static MmioRange> {
+        MMIO_RANGES
+            .iter()
+            .find(|mmio| address >= mmio.start && address <= mmio.end)
+    }
+
+    fn mmio_device(&mut self, id: MmioId) -> &mut dyn MmioDevice {
+        match id {
+            MmioId::Peripherals => &mut self.peripherals,
+            MmioId::Sio1 => &mut self.sio1,
+            MmioId::Intc => &mut self.intc,
+            MmioId::Timers => &mut self.timers,
+            MmioId::Cdrom => &mut self.cdrom,
+            MmioId::Gpu => &mut self.gpu,
+            MmioId::Mdec => &mut self.mdec,
+            MmioId::Spu => &mut self.spu,
+            MmioId::Exp2 => &mut self.exp2,
+        }
+    }
+
     pub fn tick_device_by_id(&mut self, device: Device, cycles: usize) {
         let intc = &mut self.intc;
 
         match device {
-            Device::Gpu => self.gpu.tick(intc, &mut self.timers, cycles),
-            Device::Cdrom => self.cdrom.tick(intc, &mut self.spu, cycles),
-            Device::Spu => {
-                for _ in 0..cycles {
-                    self.spu.tick(intc);
+            Device::Gpu => BusDevice::tick(&mut self.gpu, intc, &mut self.timers, cycles),
+            Device::Cdrom => BusDevice::tick(&mut self.cdrom, intc, &mut self.spu, cycles),
+            Device::Spu => BusDevice::tick(&mut self.spu, intc, &mut (), cycles),
+            Device::Timers => BusDevice::tick(&mut self.timers, intc, &mut (), cycles),
+            Device::Peripherals => {
+                BusDevice::tick(&mut self.peripherals, intc, &mut (), cycles);
+                BusDevice::tick(&mut self.sio1, intc, &mut (), cycles);
+
+                /* Buttons are sent after the read command, so there's still time */
+                let controller = self.peripherals.controller();
+                if controller.take_polled() {
+                    if let Some(input_provider) = &mut self.input_provider {
+                        input_provider(self.gpu.frame_count(), self.gpu.scanline(), controller);
+                    }
                 }
             }
-            Device::Timers => self.timers.tick(intc, cycles),
-            Device::Peripherals => self.peripherals.tick(intc, cycles),
         };
     }
 
@@ -186,63 +433,21 @@ impl Bus {
             }
             0x1f80_1014 => 0x2009_31e1,
             0x1f80_1060 => 0x0000_0b88,
-            0x1f80_1040 => {
-                tk.sync_device(self, Device::Peripherals);
-                self.peripherals.rx_data()
-            }
-            0x1f80_1044 => {
-                tk.sync_device(self, Device::Peripherals);
-                self.peripherals.status()
-            }
-            //0x1f80_1048 => {
-            //    tk.sync_device(self, Device::Gpu);
-            //    tk.sync_device(self, Device::Peripherals);
-            //    self.peripherals.read_mode()
-            //}
-            0x1f80_104a => {
-                tk.sync_device(self, Device::Peripherals);
-                self.peripherals.read_control()
-            }
-            0x1f80_104e => {
-                tk.sync_device(self, Device::Peripherals);
-                self.peripherals.read_baud()
-            }
-            0x1f80_1070 => self.intc.read_status(),
-            0x1f80_1074 => self.intc.read_mask(),
-            0x1f80_1100..=0x1f80_112b => {
-                tk.sync_device(self, Device::Timers);
-                self.timers.read(address)
-            }
-            0x1f80_1800..=0x1f80_1803 => {
-                tk.sync_device(self, Device::Cdrom);
+            0x1f80_1040..=0x1f80_207f => match Bus::find_mmio(address) {
+                Some(mmio) => {
+                    for &device in mmio.load_syncs {
+                        tk.sync_device(self, device);
+                    }
 
-                if address == 0x1f80_1802 && width == BusWidth::HALF {
-                    self.cdrom.read_data_half() as u32
-                } else {
-                    self.cdrom.read(address) as u32
+                    let (value, err) = self.mmio_device(mmio.id).load(&width, address);
+                    error = err;
+                    value
                 }
-            }
-            0x1f80_1810 => {
-                tk.sync_device(self, Device::Gpu);
-                self.gpu.gpuread()
-            }
-            0x1f80_1814 => {
-                tk.sync_device(self, Device::Gpu);
-                self.gpu.gpustat()
-            }
-            0x1f80_1820 => self.mdec.read_data(),
-            0x1f80_1824 => self.mdec.read_status(),
-            0x1f80_1c00..=0x1f80_1fff => {
-                tk.sync_device(self, Device::Cdrom);
-                tk.sync_device(self, Device::Spu);
-
-                match width {
-                    BusWidth::BYTE => self.spu.read16(address & !0x1) as u32,
-                    BusWidth::HALF => self.spu.read16(address) as u32,
-                    BusWidth::WORD => self.spu.read32(address) as u32,
+                None => {
+                    error = true;
+                    0
                 }
-            }
-            0x1f80_2000..=0x1f80_207f => self.exp2.read8(address) as u32,
+            },
             0x1fc0_0000..=0x1fc7_ffff => {
                 let offset = (address - 0x1fc0_0000) as usize;
 
@@ -264,6 +469,14 @@ impl Bus {
             }
         };
 
+        if let Some(trace) = self.memory_trace.as_mut() {
+            trace.record(AccessKind::Read, &width, address, value);
+        }
+
+        if error && self.unmapped_access == UnmappedAccess::Ignore {
+            return (0, false);
+        }
+
         (value, error)
     }
 
@@ -302,6 +515,10 @@ impl Bus {
     ) -> bool {
         let mut error = false;
 
+        if let Some(trace) = self.memory_trace.as_mut() {
+            trace.record(AccessKind::Write, &width, address, value);
+        }
+
         match address {
             0x0000_0000..=0x007f_ffff => {
                 let offset = (address & 0x1f_ffff) as usize;
@@ -335,66 +552,280 @@ impl Bus {
                 }
             }
             0x1f80_1000..=0x1f80_1023 => (), //println!("[BUS] [INFO] Store to MEM_CTRL region address: 0x{:08x}", address),
-            0x1f80_1040 => {
-                tk.sync_device(self, Device::Gpu);
-                tk.sync_device(self, Device::Peripherals);
-                self.peripherals.tx_data(value)
-            }
-            0x1f80_1048 => {
-                tk.sync_device(self, Device::Gpu);
-                tk.sync_device(self, Device::Peripherals);
-                self.peripherals.write_mode(value as u16)
-            }
-            0x1f80_104a => {
-                tk.sync_device(self, Device::Gpu);
-                tk.sync_device(self, Device::Peripherals);
-                self.peripherals.write_control(value as u16)
-            }
-            0x1f80_104e => {
-                tk.sync_device(self, Device::Gpu);
-                tk.sync_device(self, Device::Peripherals);
-                self.peripherals.write_baud(value as u16)
-            }
             0x1f80_1060 => (), //println!("[BUS] [INFO] Store to MEM_CTRL region address: 0x{:08x}", address),
-            0x1f80_1070 => self.intc.acknowledge_irq(value),
-            0x1f80_1074 => self.intc.write_mask(value),
-            0x1f80_1100..=0x1f80_112b => {
-                tk.sync_device(self, Device::Timers);
-                self.timers.write(address, value)
-            }
-            0x1f80_1800..=0x1f80_1803 => {
-                tk.sync_device(self, Device::Cdrom);
-                self.cdrom.write(address, value as u8)
-            }
-            0x1f80_1810 => {
-                tk.sync_device(self, Device::Gpu);
-                self.gpu.gp0_write(value)
-            }
-            0x1f80_1814 => {
-                tk.sync_device(self, Device::Gpu);
-                self.gpu.execute_gp1_command(value)
-            }
-            0x1f80_1820 => self.mdec.write_command(value),
-            0x1f80_1824 => self.mdec.write_control(value),
-            0x1f80_1c00..=0x1f80_1fff => {
-                tk.sync_device(self, Device::Cdrom);
-                tk.sync_device(self, Device::Spu);
+            0x1f80_1040..=0x1f80_207f => match Bus::find_mmio(address) {
+                Some(mmio) => {
+                    for &device in mmio.store_syncs {
+                        tk.sync_device(self, device);
+                    }
 
-                match width {
-                    BusWidth::HALF => self.spu.write16(address, value as u16),
-                    _ => panic!("[BUS] [ERROR] Unsupported SPU width"),
+                    error = self.mmio_device(mmio.id).store(&width, address, value);
                 }
-            }
-            0x1f80_2000..=0x1f80_207f => self.exp2.write8(address, value as u8),
+                None => error = true,
+            },
             _ => {
                 error = true;
                 //println!("[BUS] [ERROR] Store to unrecognised address 0x{:08x}", address)
             }
         };
 
-        error
+        error && self.unmapped_access == UnmappedAccess::BusError
     }
 
     #[allow(dead_code)]
     pub fn recompiler_store_word(&mut self, _address: u32, _value: u32) {}
 }
+
+impl BusDevice for Peripherals {
+    type Peer = ();
+
+    fn load(&mut self, _width: &BusWidth, address: u32) -> (u32, bool) {
+        match address {
+            0x1f80_1040 => (self.rx_data(), false),
+            0x1f80_1044 => (self.status(), false),
+            0x1f80_104a => (self.read_control(), false),
+            0x1f80_104e => (self.read_baud(), false),
+            _ => (0, true),
+        }
+    }
+
+    fn store(&mut self, _width: &BusWidth, address: u32, value: u32) -> bool {
+        match address {
+            0x1f80_1040 => self.tx_data(value),
+            0x1f80_1048 => self.write_mode(value as u16),
+            0x1f80_104a => self.write_control(value as u16),
+            0x1f80_104e => self.write_baud(value as u16),
+            _ => return true,
+        };
+
+        false
+    }
+
+    fn tick(&mut self, intc: &mut Intc, _peer: &mut (), cycles: usize) {
+        Peripherals::tick(self, intc, cycles);
+    }
+}
+
+impl BusDevice for Sio1 {
+    type Peer = ();
+
+    fn load(&mut self, _width: &BusWidth, address: u32) -> (u32, bool) {
+        match address {
+            0x1f80_1050 => (self.rx_data(), false),
+            0x1f80_1054 => (self.status(), false),
+            0x1f80_1058 => (self.read_mode(), false),
+            0x1f80_105a => (self.read_control(), false),
+            0x1f80_105e => (self.read_baud(), false),
+            _ => (0, true),
+        }
+    }
+
+    fn store(&mut self, _width: &BusWidth, address: u32, value: u32) -> bool {
+        match address {
+            0x1f80_1050 => self.tx_data(value),
+            0x1f80_1058 => self.write_mode(value as u16),
+            0x1f80_105a => self.write_control(value as u16),
+            0x1f80_105e => self.write_baud(value as u16),
+            _ => return true,
+        };
+
+        false
+    }
+
+    fn tick(&mut self, intc: &mut Intc, _peer: &mut (), cycles: usize) {
+        Sio1::tick(self, intc, cycles);
+    }
+}
+
+impl BusDevice for Intc {
+    type Peer = ();
+
+    fn load(&mut self, width: &BusWidth, address: u32) -> (u32, bool) {
+        let value = match address {
+            0x1f80_1070..=0x1f80_1073 => self.read_status(),
+            _ => self.read_mask(),
+        };
+
+        (util::read_sub_word(value, address, width), false)
+    }
+
+    fn store(&mut self, width: &BusWidth, address: u32, value: u32) -> bool {
+        match address {
+            0x1f80_1070..=0x1f80_1073 => {
+                // Lanes not being written must not acknowledge anything
+                let value = util::write_sub_word(0xffff_ffff, value, address, width);
+                self.acknowledge_irq(value)
+            }
+            _ => {
+                let value = util::write_sub_word(self.read_mask(), value, address, width);
+                self.write_mask(value)
+            }
+        };
+
+        false
+    }
+}
+
+impl BusDevice for Timers {
+    type Peer = ();
+
+    fn load(&mut self, width: &BusWidth, address: u32) -> (u32, bool) {
+        (
+            util::read_sub_word(self.read(address), address, width),
+            false,
+        )
+    }
+
+    fn store(&mut self, _width: &BusWidth, address: u32, value: u32) -> bool {
+        self.write(address, value);
+        false
+    }
+
+    fn tick(&mut self, intc: &mut Intc, _peer: &mut (), cycles: usize) {
+        Timers::tick(self, intc, cycles);
+    }
+}
+
+impl BusDevice for Cdrom {
+    type Peer = Spu;
+
+    fn load(&mut self, width: &BusWidth, address: u32) -> (u32, bool) {
+        if address == 0x1f80_1802 && *width == BusWidth::HALF {
+            (self.read_data_half() as u32, false)
+        } else {
+            (self.read(address) as u32, false)
+        }
+    }
+
+    fn store(&mut self, _width: &BusWidth, address: u32, value: u32) -> bool {
+        self.write(address, value as u8);
+        false
+    }
+
+    fn tick(&mut self, intc: &mut Intc, spu: &mut Spu, cycles: usize) {
+        Cdrom::tick(self, intc, spu, cycles);
+    }
+}
+
+impl BusDevice for Gpu {
+    type Peer = Timers;
+
+    fn load(&mut self, _width: &BusWidth, address: u32) -> (u32, bool) {
+        match address {
+            0x1f80_1810 => (self.gpuread(), false),
+            0x1f80_1814 => (self.gpustat(), false),
+            _ => (0, true),
+        }
+    }
+
+    fn store(&mut self, _width: &BusWidth, address: u32, value: u32) -> bool {
+        match address {
+            0x1f80_1810 => self.gp0_write(value),
+            0x1f80_1814 => self.gp1_write(value),
+            _ => return true,
+        };
+
+        false
+    }
+
+    fn tick(&mut self, intc: &mut Intc, timers: &mut Timers, cycles: usize) {
+        Gpu::tick(self, intc, timers, cycles);
+    }
+}
+
+impl BusDevice for Mdec {
+    type Peer = ();
+
+    fn load(&mut self, _width: &BusWidth, address: u32) -> (u32, bool) {
+        match address {
+            0x1f80_1820 => (self.read_data(), false),
+            0x1f80_1824 => (self.read_status(), false),
+            _ => (0, true),
+        }
+    }
+
+    fn store(&mut self, _width: &BusWidth, address: u32, value: u32) -> bool {
+        match address {
+            0x1f80_1820 => self.write_command(value),
+            0x1f80_1824 => self.write_control(value),
+            _ => return true,
+        };
+
+        false
+    }
+}
+
+impl BusDevice for Spu {
+    type Peer = ();
+
+    fn load(&mut self, width: &BusWidth, address: u32) -> (u32, bool) {
+        let value = match width {
+            BusWidth::BYTE => {
+                let half = self.read16(address & !0x1) as u32;
+                util::read_sub_word(half, address & 0x1, width)
+            }
+            BusWidth::HALF => self.read16(address) as u32,
+            BusWidth::WORD => self.read32(address),
+        };
+
+        (value, false)
+    }
+
+    fn store(&mut self, width: &BusWidth, address: u32, value: u32) -> bool {
+        match width {
+            BusWidth::BYTE => {
+                let half = self.read16(address & !0x1) as u32;
+                let half = util::write_sub_word(half, value, address & 0x1, width);
+                self.write16(address & !0x1, half as u16);
+            }
+            BusWidth::HALF => self.write16(address, value as u16),
+            BusWidth::WORD => {
+                self.write16(address, value as u16);
+                self.write16(address + 2, (value >> 16) as u16);
+            }
+        }
+
+        false
+    }
+
+    fn tick(&mut self, intc: &mut Intc, _peer: &mut (), cycles: usize) {
+        for _ in 0..cycles {
+            Spu::tick(self, intc);
+        }
+    }
+}
+
+impl BusDevice for Exp2 {
+    type Peer = ();
+
+    fn load(&mut self, _width: &BusWidth, address: u32) -> (u32, bool) {
+        (self.read8(address) as u32, false)
+    }
+
+    fn store(&mut self, _width: &BusWidth, address: u32, value: u32) -> bool {
+        self.write8(address, value as u8);
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CD_VOLUME_LEFT: u32 = 0x1f80_1db0;
+
+    #[test]
+    fn spu_byte_stores_keep_the_other_lane() {
+        let mut spu = Spu::new();
+        BusDevice::store(&mut spu, &BusWidth::HALF, CD_VOLUME_LEFT, 0x1234);
+
+        BusDevice::store(&mut spu, &BusWidth::BYTE, CD_VOLUME_LEFT + 1, 0xff56);
+        assert_eq!(spu.read16(CD_VOLUME_LEFT), 0x5634);
+
+        BusDevice::store(&mut spu, &BusWidth::BYTE, CD_VOLUME_LEFT, 0x78);
+        assert_eq!(spu.read16(CD_VOLUME_LEFT), 0x5678);
+
+        let (value, _) = BusDevice::load(&mut spu, &BusWidth::BYTE, CD_VOLUME_LEFT + 1);
+        assert_eq!(value, 0x56);
+    }
+}
@@ -1,10 +1,13 @@
-use std::io::{self, Read, Seek};
-use std::{fs, path};
+use std::path;
+use std::sync::Arc;
+
+use memmap2::Mmap;
 
 use super::Container;
+use crate::psx::rom_cache;
 
 pub struct Bin {
-    file: fs::File,
+    data: Arc<Mmap>,
 }
 
 impl Container for Bin {
@@ -13,27 +16,20 @@ impl Container for Bin {
             return Err("File does not exist.".to_string());
         }
 
-        let file;
-
-        match fs::File::open(filepath) {
-            Ok(f) => file = f,
-            Err(e) => return Err(e.to_string()),
-        };
+        let data = rom_cache::map(filepath)?;
 
-        Ok(Box::new(Self { file: file }))
+        Ok(Box::new(Self { data }))
     }
 
     fn read(&mut self, lba: usize, buffer: &mut [u8; 2352]) -> Result<(), String> {
-        let offset = (lba * 2352) as u64;
-
-        if let Err(e) = self.file.seek(io::SeekFrom::Start(offset)) {
-            return Err(e.to_string());
+        let offset = lba * 2352;
+
+        match self.data.get(offset..offset + 2352) {
+            Some(sector) => {
+                buffer.copy_from_slice(sector);
+                Ok(())
+            }
+            None => Err(format!("Sector {} is past the end of the image", lba)),
         }
-
-        if let Err(e) = self.file.read_exact(buffer) {
-            return Err(e.to_string());
-        }
-
-        Ok(())
     }
 }
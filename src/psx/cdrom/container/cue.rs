@@ -0,0 +1,224 @@
+use std::sync::Arc;
+use std::{fs, path};
+
+use memmap2::Mmap;
+
+use super::Container;
+use crate::psx::rom_cache;
+
+const BYTES_PER_SECTOR: u64 = 2352;
+const SECTORS_PER_SECOND: usize = 75;
+
+/* A stretch of the disc, either sectors of one of the BIN files or a
+ * pregap that isn't stored anywhere */
+struct Extent {
+    start: usize,
+    length: usize,
+    /* Index in files and first sector in it, None for a pregap */
+    source: Option<(usize, u64)>,
+}
+
+struct Track {
+    file: usize,
+    pregap: usize,
+    /* Sector in the file of the first index, INDEX 00 if there is one */
+    start: u64,
+}
+
+/* A .cue sheet and the BIN files it lists, with every track laid out one
+ * after the other as the drive sees them. Only raw 2352 byte tracks
+ * (AUDIO, MODE1/2352 and MODE2/2352) can be read. */
+pub struct Cue {
+    files: Vec<(path::PathBuf, Arc<Mmap>)>,
+    extents: Vec<Extent>,
+}
+
+impl Container for Cue {
+    fn open(filepath: &path::Path) -> Result<Box<Self>, String> {
+        let sheet = match fs::read_to_string(filepath) {
+            Ok(sheet) => sheet,
+            Err(e) => return Err(e.to_string()),
+        };
+
+        /* BIN files are relative to the sheet */
+        let directory = filepath.parent().unwrap_or(path::Path::new(""));
+
+        let mut files = Vec::new();
+        let mut file_sectors = Vec::new();
+        let mut tracks: Vec<Track> = Vec::new();
+
+        for (line_number, line) in sheet.lines().enumerate() {
+            let error =
+                |message: &str| format!("{}:{}: {}", filepath.display(), line_number + 1, message);
+            let line = line.trim();
+            let (command, arguments) = line.split_once(' ').unwrap_or((line, ""));
+
+            match command.to_uppercase().as_str() {
+                "FILE" => {
+                    let name = file_name(arguments).ok_or_else(|| error("Missing file name"))?;
+                    let path = directory.join(name);
+                    let data = rom_cache::map(&path).map_err(|e| error(&e))?;
+                    let size = data.len() as u64;
+
+                    if !size.is_multiple_of(BYTES_PER_SECTOR) {
+                        return Err(error(&format!(
+                            "{} is not whole {} byte sectors",
+                            path.display(),
+                            BYTES_PER_SECTOR
+                        )));
+                    }
+
+                    files.push((path, data));
+                    file_sectors.push(size / BYTES_PER_SECTOR);
+                }
+                "TRACK" => {
+                    if files.is_empty() {
+                        return Err(error("TRACK before any FILE"));
+                    }
+
+                    let mode = arguments.split_whitespace().nth(1).unwrap_or("");
+
+                    match mode.to_uppercase().as_str() {
+                        "AUDIO" | "MODE1/2352" | "MODE2/2352" => (),
+                        _ => return Err(error(&format!("Unsupported track mode {}", mode))),
+                    }
+
+                    tracks.push(Track {
+                        file: files.len() - 1,
+                        pregap: 0,
+                        start: u64::MAX,
+                    });
+                }
+                "PREGAP" => {
+                    let track = tracks
+                        .last_mut()
+                        .ok_or_else(|| error("PREGAP before any TRACK"))?;
+                    track.pregap = timecode(arguments).ok_or_else(|| error("Invalid time"))?;
+                }
+                "INDEX" => {
+                    let track = tracks
+                        .last_mut()
+                        .ok_or_else(|| error("INDEX before any TRACK"))?;
+                    let time = arguments.split_whitespace().nth(1).unwrap_or("");
+                    let sector = timecode(time).ok_or_else(|| error("Invalid time"))?;
+
+                    /* Indices come in order, the first one is where the
+                     * track begins */
+                    track.start = track.start.min(sector as u64);
+                }
+                _ => (),
+            }
+        }
+
+        if tracks.is_empty() {
+            return Err(format!("{}: No tracks", filepath.display()));
+        }
+
+        if tracks.iter().any(|track| track.start == u64::MAX) {
+            return Err(format!("{}: Track without INDEX", filepath.display()));
+        }
+
+        /* Each track runs until the next one in the same file, or to the
+         * end of it. Whatever comes before the first track of a file (there
+         * shouldn't be anything) belongs to it. */
+        let mut extents = Vec::new();
+        let mut lba = 0;
+
+        for (i, track) in tracks.iter().enumerate() {
+            if track.pregap > 0 {
+                extents.push(Extent {
+                    start: lba,
+                    length: track.pregap,
+                    source: None,
+                });
+
+                lba += track.pregap;
+            }
+
+            let first_in_file = i == 0 || tracks[i - 1].file != track.file;
+            let start = if first_in_file { 0 } else { track.start };
+            let end = match tracks.get(i + 1) {
+                Some(next) if next.file == track.file => next.start,
+                _ => file_sectors[track.file],
+            };
+
+            if end < start {
+                return Err(format!("{}: Tracks out of order", filepath.display()));
+            }
+
+            let length = (end - start) as usize;
+
+            extents.push(Extent {
+                start: lba,
+                length,
+                source: Some((track.file, start)),
+            });
+
+            lba += length;
+        }
+
+        Ok(Box::new(Self { files, extents }))
+    }
+
+    fn read(&mut self, lba: usize, buffer: &mut [u8; 2352]) -> Result<(), String> {
+        let extent = self
+            .extents
+            .iter()
+            .find(|extent| lba >= extent.start && lba < extent.start + extent.length);
+
+        let (file, first_sector) = match extent {
+            Some(Extent {
+                source: Some(source),
+                ..
+            }) => *source,
+            /* Pregaps are silence */
+            Some(_) => {
+                buffer.fill(0);
+                return Ok(());
+            }
+            None => return Err(format!("Sector {} is past the end of the disc", lba)),
+        };
+
+        let sector = first_sector + (lba - extent.unwrap().start) as u64;
+
+        let (path, data) = &self.files[file];
+        let offset = (sector * BYTES_PER_SECTOR) as usize;
+
+        match data.get(offset..offset + BYTES_PER_SECTOR as usize) {
+            Some(sector) => {
+                buffer.copy_from_slice(sector);
+                Ok(())
+            }
+            None => Err(format!("{}: truncated", path.display())),
+        }
+    }
+}
+
+/* Quoted or not, followed by the file type */
+fn file_name(arguments: &str) -> Option<&str> {
+    let arguments = arguments.trim();
+
+    if let Some(quoted) = arguments.strip_prefix('"') {
+        return quoted.split('"').next();
+    }
+
+    arguments.split_whitespace().next()
+}
+
+/* mm:ss:ff, in sectors */
+fn timecode(text: &str) -> Option<usize> {
+    let mut fields = text
+        .trim()
+        .split(':')
+        .map(|field| field.parse::<usize>().ok());
+
+    let minutes = fields.next()??;
+    let seconds = fields.next()??;
+    let frames = fields.next()??;
+
+    if fields.next().is_some() || seconds >= 60 || frames >= SECTORS_PER_SECOND {
+        return None;
+    }
+
+    Some((minutes * 60 + seconds) * SECTORS_PER_SECOND + frames)
+}
@@ -1,11 +1,45 @@
 mod bin;
+mod cue;
 mod no_disk;
 
 use std::path;
 
+use self::bin::Bin;
+use self::cue::Cue;
+
 pub trait Container {
     #[allow(dead_code)]
-    fn open(filepath: &path::Path) -> Result<Box<Self>, String>;
+    fn open(filepath: &path::Path) -> Result<Box<Self>, String>
+    where
+        Self: Sized;
     #[allow(dead_code)]
     fn read(&mut self, lba: usize, buffer: &mut [u8; 2352]) -> Result<(), String>;
 }
+
+/* Sheets go by their extension, anything else is a raw image */
+pub fn is_cue(filepath: &str) -> bool {
+    path::Path::new(filepath)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("cue"))
+}
+
+/* A sheet with its BIN files, or a raw image */
+pub fn open(filepath: &str) -> Result<Box<dyn Container + Send>, String> {
+    let path = path::Path::new(filepath);
+
+    if is_cue(filepath) {
+        Ok(Cue::open(path)?)
+    } else {
+        Ok(Bin::open(path)?)
+    }
+}
+
+/* Opens the image for a single read, Cdrom keeps it open instead */
+pub fn read_sector(filepath: &str, lba: usize, buffer: &mut [u8; 2352]) -> Result<(), String> {
+    open(filepath)?.read(lba, buffer)
+}
+
+/* Parses the sheet and checks every file it lists */
+pub fn check_cue(filepath: &str) -> Result<(), String> {
+    Cue::open(path::Path::new(filepath)).map(|_| ())
+}
@@ -3,14 +3,15 @@ mod headers;
 mod helpers;
 mod timecode;
 
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
 
 use serde::{Deserialize, Serialize};
 
 use timecode::Timecode;
 
+use self::container::Container;
+pub use self::container::{check_cue, is_cue, read_sector};
+
 use super::queue::Queue;
 use crate::psx::adpcm::{ADPCM_FILTERS, ADPCM_ZIGZAG_TABLE};
 
@@ -307,6 +308,9 @@ pub struct Cdrom {
     last_subq: CdromSubchannelQ,
 
     game_filepath: String,
+    /* Opened on the first read, a .cue sheet is only parsed then */
+    #[serde(skip)]
+    container: Option<Box<dyn Container + Send>>,
 
     sixstep: usize,
     ringbuf: [[i16; 0x20]; 2],
@@ -393,6 +397,7 @@ impl Cdrom {
             last_subq: CdromSubchannelQ::new(),
 
             game_filepath: game_filepath.to_string(), //File::open(path).unwrap(),
+            container: None,
 
             sixstep: 0,
             ringbuf: [[0; 0x20]; 2],
@@ -401,6 +406,17 @@ impl Cdrom {
 
     pub fn reset(&mut self) {}
 
+    pub fn game_filepath(&self) -> &str {
+        &self.game_filepath
+    }
+
+    /* Opened again on the next read, so it can be swapped for another copy
+     * of the same image */
+    pub fn set_game_filepath(&mut self, game_filepath: &str) {
+        self.game_filepath = game_filepath.to_string();
+        self.container = None;
+    }
+
     pub fn tick(&mut self, intc: &mut Intc, spu: &mut Spu, clocks: usize) {
         self.tick_second_response(clocks);
         self.tick_drive(spu, clocks);
@@ -614,13 +630,7 @@ impl Cdrom {
                     return;
                 }
 
-                let cursor = self.get_seek_location();
-                let mut game_file = File::open(&self.game_filepath).unwrap();
-                game_file.seek(SeekFrom::Start(cursor)).unwrap();
-
-                let mut data = [0u8; 0x930];
-                // TODO: Error handling?
-                let _ = game_file.read_exact(&mut data).unwrap();
+                let data = self.read_sector();
 
                 for i in 0..0x24c {
                     let left = (data[i * 4] as u16) | ((data[i * 4 + 1] as u16) << 8);
@@ -702,15 +712,8 @@ impl Cdrom {
 
                 self.data_busy = true;
 
-                let mut game_file = match File::open(&self.game_filepath) {
-                    Ok(file) => file,
-                    Err(e) => panic!("Error opening game: {}: {}", self.game_filepath, e),
-                };
-                let cursor = self.get_seek_location();
-                game_file.seek(SeekFrom::Start(cursor)).unwrap();
-
-                let mut info = [0u8; 0x18];
-                game_file.read_exact(&mut info).unwrap();
+                let data = self.read_sector();
+                let info = &data[..0x18];
 
                 let header = CdromHeader::from_slice(&info[0xc..]);
                 let subheader = CdromSubheader::from_slice(&info[0x10..]);
@@ -794,9 +797,7 @@ impl Cdrom {
                         let channels = subheader.channels();
                         let sampling_rate = subheader.sampling_rate();
 
-                        let mut game_file = File::open(&self.game_filepath).unwrap();
-                        let mut data = [0u8; 0x914];
-                        game_file.read_exact(&mut data).unwrap();
+                        let data = &data[0x18..0x18 + 0x914];
 
                         for i in 0..0x12 {
                             self.decode_adpcm_blocks(&data[i * 0x80..], channels);
@@ -841,9 +842,7 @@ impl Cdrom {
                         self.adpcm_buffers[1].clear();
                     }
                     CdromSectorMode::Data => {
-                        let mut game_file = File::open(&self.game_filepath).unwrap();
-                        game_file.seek(SeekFrom::Start(cursor)).unwrap();
-                        game_file.read_exact(&mut self.sector).unwrap();
+                        self.sector.copy_from_slice(&data);
 
                         // TODO: stat
                         if self.drive_interrupt_pending {
@@ -1214,6 +1213,26 @@ impl Cdrom {
         }
     }
 
+    /* From a raw image or the tracks of a .cue sheet */
+    fn read_sector(&mut self) -> [u8; BYTES_PER_SECTOR as usize] {
+        let lba = self.get_seek_location() / BYTES_PER_SECTOR;
+        let mut data = [0u8; BYTES_PER_SECTOR as usize];
+
+        let container = match &mut self.container {
+            Some(container) => container,
+            None => match container::open(&self.game_filepath) {
+                Ok(container) => self.container.insert(container),
+                Err(e) => panic!("Error reading game: {}: {}", self.game_filepath, e),
+            },
+        };
+
+        if let Err(e) = container.read(lba as usize, &mut data) {
+            panic!("Error reading game: {}: {}", self.game_filepath, e);
+        }
+
+        data
+    }
+
     fn get_seek_location(&self) -> u64 {
         let mut sector = ((self.drive_seek_minute as u64) * SECTORS_PER_MINUTE)
             + ((self.drive_seek_second as u64) * SECTORS_PER_SECOND)
@@ -1282,13 +1301,8 @@ impl Cdrom {
                 value = self.read_data();
             }
             3 => match self.index {
-                Index0 => value = 0xe0 | self.interrupt_enable,
-                Index1 => value = 0xe0 | self.interrupt_flags,
-                _ => panic!(
-                    "[CDROM] [ERROR] Read from CDROM_REG_{}_{:?}",
-                    address & 0x3,
-                    self.index
-                ),
+                Index0 | Index2 => value = 0xe0 | self.interrupt_enable,
+                Index1 | Index3 => value = 0xe0 | self.interrupt_flags,
             },
             _ => panic!(
                 "[CDROM] [ERROR] Read from CDROM_REG_{}_{:?}",
@@ -1358,12 +1372,9 @@ impl Cdrom {
                     Index0 => {
                         self.command = Some(value);
                     }
+                    Index1 => (), // Sound Map Data Out
+                    Index2 => (), // Sound Map Coding Info
                     Index3 => (), // Right-CD to Right-SPU
-                    _ => panic!(
-                        "[CDROM] [ERROR] Write to CDROM_REG_{}_{:?}",
-                        address & 0x3,
-                        self.index
-                    ),
                 }
             }
             2 => {
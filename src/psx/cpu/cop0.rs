@@ -10,6 +10,7 @@ pub enum Exception {
     Syscall = 8,
     Breakpoint = 9,
     Reserved = 10,
+    CoprocessorUnusable = 11,
     Overflow = 12,
 }
 
@@ -302,6 +303,14 @@ pub struct Cop0 {
     status: Status,
     cause: Cause,
     epc: u32,
+
+    /* Host debugger breakpoints, not part of the emulated state */
+    #[serde(skip)]
+    host_breakpoints: Vec<u32>,
+    #[serde(skip)]
+    host_hit: Option<u32>,
+    #[serde(skip)]
+    host_resume: bool,
 }
 
 impl Cop0 {
@@ -317,6 +326,10 @@ impl Cop0 {
             status: Status::new(),
             cause: Cause::new(),
             epc: 0,
+
+            host_breakpoints: Vec::new(),
+            host_hit: None,
+            host_resume: false,
         }
     }
 
@@ -341,10 +354,13 @@ impl Cop0 {
             13 => self.cause.read(),
             14 => self.epc,
             15 => 0x0000_0002,
-            _ => panic!(
-                "[COP0] [ERROR] Read from unimplemented Cop0 register {}",
-                index
-            ),
+            _ => {
+                println!(
+                    "[COP0] [WARN] Read from unimplemented Cop0 register {}",
+                    index
+                );
+                0
+            }
         }
     }
 
@@ -370,8 +386,8 @@ impl Cop0 {
             }
             12 => self.status.write(value),
             13 => self.cause.write(value),
-            _ => panic!(
-                "[COP0] [ERROR] Write to unimplemented Cop0 register {}",
+            _ => println!(
+                "[COP0] [WARN] Write to unimplemented Cop0 register {}",
                 index
             ),
         }
@@ -402,6 +418,15 @@ impl Cop0 {
         self.bad_vaddr = value;
     }
 
+    pub fn coprocessor_usable(&self, coprocessor: usize) -> bool {
+        /* Cop0 is always usable in kernel mode */
+        if coprocessor == 0 && !self.status.kernel_user_current {
+            return true;
+        }
+
+        self.status.coprocessor_usability[coprocessor]
+    }
+
     pub fn iec(&self) -> bool {
         self.status.interrupt_enable_current
     }
@@ -440,6 +465,53 @@ impl Cop0 {
         self.dcic.trap
     }
 
+    pub fn add_host_breakpoint(&mut self, address: u32) {
+        if !self.host_breakpoints.contains(&address) {
+            self.host_breakpoints.push(address);
+        }
+    }
+
+    pub fn remove_host_breakpoint(&mut self, address: u32) {
+        self.host_breakpoints
+            .retain(|&breakpoint| breakpoint != address);
+    }
+
+    pub fn has_host_breakpoints(&self) -> bool {
+        !self.host_breakpoints.is_empty()
+    }
+
+    pub fn keep_host_state(&mut self, old: &mut Cop0) {
+        self.host_breakpoints = std::mem::take(&mut old.host_breakpoints);
+    }
+
+    pub fn host_hit(&self) -> Option<u32> {
+        self.host_hit
+    }
+
+    pub fn resume_host_break(&mut self) {
+        if self.host_hit.take().is_some() {
+            self.host_resume = true;
+        }
+    }
+
+    /* Same as test_code, but for the host debugger. R3000A only calls it
+     * while there are breakpoints */
+    pub fn test_host_code(&mut self, test: u32) -> bool {
+        /* Let the instruction we stopped at execute */
+        if self.host_resume {
+            self.host_resume = false;
+            return false;
+        }
+
+        if !self.host_breakpoints.contains(&test) {
+            return false;
+        }
+
+        self.host_hit = Some(test);
+
+        true
+    }
+
     pub fn test_read(&mut self, test: u32) -> bool {
         if !self.dcic.master_debug {
             return false;
@@ -0,0 +1,65 @@
+use log::debug;
+
+use super::instruction::Instruction;
+use super::stats;
+
+/* Which instructions get logged. Only those within `pc_range` (inclusive)
+ * are counted, every `every`th of them is logged, `limit` lines at most */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InstructionLogConfig {
+    pub every: u64,
+    pub pc_range: Option<(u32, u32)>,
+    pub limit: u64,
+}
+
+/* Sampled instruction logging to the `log` crate at debug level, so it
+ * shows up with RUST_LOG=debug. Stops by itself after `limit` lines. */
+pub struct InstructionLog {
+    config: InstructionLogConfig,
+    seen: u64,
+    logged: u64,
+}
+
+impl InstructionLog {
+    pub fn new(config: InstructionLogConfig) -> InstructionLog {
+        InstructionLog {
+            config: InstructionLogConfig {
+                every: config.every.max(1),
+                ..config
+            },
+            seen: 0,
+            logged: 0,
+        }
+    }
+
+    pub fn record(&mut self, pc: u32, instruction: Instruction) {
+        if self.logged >= self.config.limit {
+            return;
+        }
+
+        if let Some((start, end)) = self.config.pc_range {
+            if pc < start || pc > end {
+                return;
+            }
+        }
+
+        let sampled = self.seen.is_multiple_of(self.config.every);
+        self.seen += 1;
+        if !sampled {
+            return;
+        }
+
+        self.logged += 1;
+        debug!(
+            "[CPU] {:08x}: {:08x} {}",
+            pc,
+            instruction.0,
+            stats::mnemonic(instruction).unwrap_or("???")
+        );
+    }
+
+    /* Lines written so far */
+    pub fn logged(&self) -> u64 {
+        self.logged
+    }
+}
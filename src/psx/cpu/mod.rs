@@ -2,6 +2,8 @@ mod cop0;
 mod dmac;
 mod gte;
 mod instruction;
+mod instruction_log;
+mod stats;
 
 use super::bus::{Bus, BusWidth};
 use super::timekeeper::Timekeeper;
@@ -10,9 +12,17 @@ use self::cop0::{Cop0, Exception};
 use self::dmac::Dmac;
 use self::gte::Gte;
 use self::instruction::Instruction;
+use self::instruction_log::InstructionLog;
+
+pub use self::instruction_log::InstructionLogConfig;
+pub use self::stats::InstructionStats;
 
 use serde::{Deserialize, Serialize};
 
+/* HI/LO latency after the issuing cycle, DIV/DIVU take the same time
+ * whatever the operands */
+const DIV_CYCLES: u64 = 35;
+
 #[derive(Clone, Copy, Serialize, Deserialize)]
 struct ICacheLine {
     valid: usize,
@@ -64,6 +74,14 @@ pub struct R3000A {
     hi: u32,
     lo: u32,
 
+    /* Cycle at which a running MULT/DIV writes HI/LO, MFHI/MFLO wait for
+     * it. Not kept in save states, at worst a stall is skipped there. */
+    #[serde(skip)]
+    hilo_ready: u64,
+    /* Skips the MFHI/MFLO interlock, results are available right away */
+    #[serde(skip)]
+    hilo_interlock_bypass: bool,
+
     icache: ICache,
     cache_control: u32,
 
@@ -71,6 +89,17 @@ pub struct R3000A {
     gte: Gte,
 
     dmac: Dmac,
+
+    /* Only counted when enabled, costs a branch per instruction otherwise */
+    #[serde(skip)]
+    stats: Option<Box<InstructionStats>>,
+    /* Same for the sampled instruction log */
+    #[serde(skip)]
+    log: Option<Box<InstructionLog>>,
+    /* Set while the host debugger has breakpoints, only then is the PC
+     * checked against them */
+    #[serde(skip)]
+    host_breakpoints: bool,
 }
 
 impl R3000A {
@@ -94,6 +123,9 @@ impl R3000A {
             hi: 0,
             lo: 0,
 
+            hilo_ready: 0,
+            hilo_interlock_bypass: false,
+
             icache: ICache::new(),
             cache_control: 0,
 
@@ -101,6 +133,10 @@ impl R3000A {
             gte: Gte::new(),
 
             dmac: Dmac::new(),
+
+            stats: None,
+            log: None,
+            host_breakpoints: false,
         }
     }
 
@@ -153,12 +189,22 @@ impl R3000A {
             }
         }
 
+        if self.host_breakpoints && self.cop0.test_host_code(self.pc) {
+            return;
+        }
+
         self.current_pc = self.pc;
         self.exception_branch_delay = self.branch_delay;
         self.exception_branch_taken = self.branch_taken;
 
         tk.tick(1);
 
+        /* One instruction raises at most one exception. Highest first: a
+         * misaligned fetch, an interrupt (the instruction is aborted before
+         * it runs), a code breakpoint, a fetch bus error, then whatever the
+         * instruction raises itself. Those are exclusive, except for loads
+         * and stores where a misaligned address wins over a data breakpoint,
+         * and both over a data bus error. */
         let cop0_break = self.cop0.test_code(self.pc);
 
         if self.pc & 0x3 != 0 {
@@ -211,6 +257,18 @@ impl R3000A {
         self.pc = self.new_pc;
         self.new_pc += 4;
 
+        if let Some(stats) = self.stats.as_mut() {
+            stats.record(instruction);
+        }
+
+        if let Some(log) = self.log.as_mut() {
+            log.record(self.current_pc, instruction);
+        }
+
+        if let Some(trace) = bus.memory_trace_mut() {
+            trace.set_pc(self.current_pc);
+        }
+
         if ins == 0 {
             self.execute_load_delay();
             return;
@@ -219,6 +277,66 @@ impl R3000A {
         self.execute(bus, tk, instruction);
     }
 
+    pub fn add_breakpoint(&mut self, address: u32) {
+        self.cop0.add_host_breakpoint(address);
+        self.host_breakpoints = true;
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u32) {
+        self.cop0.remove_host_breakpoint(address);
+        self.host_breakpoints = self.cop0.has_host_breakpoints();
+    }
+
+    pub fn breakpoint_hit(&self) -> Option<u32> {
+        self.cop0.host_hit()
+    }
+
+    pub fn resume_breakpoint(&mut self) {
+        self.cop0.resume_host_break();
+    }
+
+    /* Starts counting from zero, or stops and drops the counters */
+    pub fn set_instruction_stats(&mut self, enabled: bool) {
+        self.stats = match enabled {
+            true => Some(Box::new(InstructionStats::new())),
+            false => None,
+        };
+    }
+
+    pub fn instruction_stats(&self) -> Option<&InstructionStats> {
+        self.stats.as_deref()
+    }
+
+    /* Starts logging from zero with `config`, or stops with None */
+    pub fn set_instruction_log(&mut self, config: Option<InstructionLogConfig>) {
+        self.log = config.map(|config| Box::new(InstructionLog::new(config)));
+    }
+
+    pub fn instructions_logged(&self) -> Option<u64> {
+        self.log.as_ref().map(|log| log.logged())
+    }
+
+    pub fn set_hilo_interlock_bypass(&mut self, bypass: bool) {
+        self.hilo_interlock_bypass = bypass;
+    }
+
+    /* Not in save states, but rewinding must land on the same cycle */
+    pub fn hilo_ready(&self) -> u64 {
+        self.hilo_ready
+    }
+
+    pub fn set_hilo_ready(&mut self, hilo_ready: u64) {
+        self.hilo_ready = hilo_ready;
+    }
+
+    pub fn keep_host_state(&mut self, old: &mut R3000A) {
+        self.hilo_interlock_bypass = old.hilo_interlock_bypass;
+        self.stats = old.stats.take();
+        self.log = old.log.take();
+        self.host_breakpoints = old.host_breakpoints;
+        self.cop0.keep_host_state(&mut old.cop0);
+    }
+
     fn update_irq(&mut self, bus: &mut Bus) {
         if bus.intc().pending() {
             self.cop0.set_interrupt_bit();
@@ -229,7 +347,7 @@ impl R3000A {
 
     fn execute(&mut self, bus: &mut Bus, tk: &mut Timekeeper, i: Instruction) {
         match i.opcode() {
-            0x00 => self.op_special(i),
+            0x00 => self.op_special(tk, i),
             0x01 => self.op_bcond(i.rs(), i.rt(), i.imm_se()),
             0x02 => self.op_j(i.target()),
             0x03 => self.op_jal(i.target()),
@@ -246,9 +364,9 @@ impl R3000A {
             0x0e => self.op_xori(i.rt(), i.rs(), i.imm()),
             0x0f => self.op_lui(i.rt(), i.imm()),
             0x10 => self.op_cop0(i),
-            0x11 => (),
+            0x11 => self.op_cop_unusable(1),
             0x12 => self.op_cop2(i),
-            0x13 => (),
+            0x13 => self.op_cop_unusable(3),
             0x20 => self.op_lb(bus, tk, i.rt(), i.rs(), i.imm_se()),
             0x21 => self.op_lh(bus, tk, i.rt(), i.rs(), i.imm_se()),
             0x22 => self.op_lwl(bus, tk, i.rt(), i.rs(), i.imm_se()),
@@ -273,7 +391,7 @@ impl R3000A {
         }
     }
 
-    fn op_special(&mut self, i: Instruction) {
+    fn op_special(&mut self, tk: &mut Timekeeper, i: Instruction) {
         match i.function() {
             0x00 => self.op_sll(i.rd(), i.rt(), i.shift()),
             0x02 => self.op_srl(i.rd(), i.rt(), i.shift()),
@@ -285,14 +403,14 @@ impl R3000A {
             0x09 => self.op_jalr(i.rd(), i.rs()),
             0x0c => self.op_syscall(),
             0x0d => self.op_break(),
-            0x10 => self.op_mfhi(i.rd()),
+            0x10 => self.op_mfhi(tk, i.rd()),
             0x11 => self.op_mthi(i.rs()),
-            0x12 => self.op_mflo(i.rd()),
+            0x12 => self.op_mflo(tk, i.rd()),
             0x13 => self.op_mtlo(i.rs()),
-            0x18 => self.op_mult(i.rs(), i.rt()),
-            0x19 => self.op_multu(i.rs(), i.rt()),
-            0x1a => self.op_div(i.rs(), i.rt()),
-            0x1b => self.op_divu(i.rs(), i.rt()),
+            0x18 => self.op_mult(tk, i.rs(), i.rt()),
+            0x19 => self.op_multu(tk, i.rs(), i.rt()),
+            0x1a => self.op_div(tk, i.rs(), i.rt()),
+            0x1b => self.op_divu(tk, i.rs(), i.rt()),
             0x20 => self.op_add(i.rd(), i.rs(), i.rt()),
             0x21 => self.op_addu(i.rd(), i.rs(), i.rt()),
             0x22 => self.op_sub(i.rd(), i.rs(), i.rt()),
@@ -388,7 +506,31 @@ impl R3000A {
         self.enter_exception(Exception::Breakpoint);
     }
 
-    fn op_mfhi(&mut self, rd: usize) {
+    /* Results land in HI/LO `cycles` after the issuing instruction */
+    fn start_hilo(&mut self, tk: &Timekeeper, cycles: u64) {
+        self.hilo_ready = tk.cycles() + cycles;
+    }
+
+    /* Stalls until a running MULT/DIV is done */
+    fn wait_hilo(&mut self, tk: &mut Timekeeper) {
+        if self.hilo_interlock_bypass {
+            return;
+        }
+
+        let now = tk.cycles();
+        if now < self.hilo_ready {
+            let stall = self.hilo_ready - now;
+            tk.tick(stall);
+
+            if let Some(stats) = self.stats.as_mut() {
+                stats.record_hilo_stall(stall);
+            }
+        }
+    }
+
+    fn op_mfhi(&mut self, tk: &mut Timekeeper, rd: usize) {
+        self.wait_hilo(tk);
+
         let hi = self.hi;
 
         self.execute_load_delay();
@@ -402,7 +544,9 @@ impl R3000A {
         self.execute_load_delay();
     }
 
-    fn op_mflo(&mut self, rd: usize) {
+    fn op_mflo(&mut self, tk: &mut Timekeeper, rd: usize) {
+        self.wait_hilo(tk);
+
         let lo = self.lo;
 
         self.execute_load_delay();
@@ -416,7 +560,14 @@ impl R3000A {
         self.execute_load_delay();
     }
 
-    fn op_mult(&mut self, rs: usize, rt: usize) {
+    fn op_mult(&mut self, tk: &mut Timekeeper, rs: usize, rt: usize) {
+        /* Same early out as MULTU, on the magnitude of rs */
+        let busy = match self.reg(rs) as i32 {
+            rs if rs < 0 => mult_cycles(!rs as u32),
+            rs => mult_cycles(rs as u32),
+        };
+        self.start_hilo(tk, busy);
+
         let m1 = (self.reg(rs) as i32) as i64;
         let m2 = (self.reg(rt) as i32) as i64;
 
@@ -428,7 +579,9 @@ impl R3000A {
         self.execute_load_delay();
     }
 
-    fn op_multu(&mut self, rs: usize, rt: usize) {
+    fn op_multu(&mut self, tk: &mut Timekeeper, rs: usize, rt: usize) {
+        self.start_hilo(tk, mult_cycles(self.reg(rs)));
+
         let r = self.reg(rs) as u64 * self.reg(rt) as u64;
 
         self.hi = (r >> 32) as u32;
@@ -437,7 +590,9 @@ impl R3000A {
         self.execute_load_delay();
     }
 
-    fn op_div(&mut self, rs: usize, rt: usize) {
+    fn op_div(&mut self, tk: &mut Timekeeper, rs: usize, rt: usize) {
+        self.start_hilo(tk, DIV_CYCLES);
+
         let n = self.reg(rs) as i32;
         let d = self.reg(rt) as i32;
 
@@ -460,7 +615,9 @@ impl R3000A {
         self.execute_load_delay();
     }
 
-    fn op_divu(&mut self, rs: usize, rt: usize) {
+    fn op_divu(&mut self, tk: &mut Timekeeper, rs: usize, rt: usize) {
+        self.start_hilo(tk, DIV_CYCLES);
+
         let n = self.reg(rs);
         let d = self.reg(rt);
 
@@ -710,11 +867,16 @@ impl R3000A {
     }
 
     fn op_cop0(&mut self, i: Instruction) {
+        if !self.cop0.coprocessor_usable(0) {
+            self.op_unusable();
+            return;
+        }
+
         match i.rs() {
             0x00 => self.op_mfc0(i.rd(), i.rt()),
             0x04 => self.op_mtc0(i.rd(), i.rt()),
             0x10 => self.op_rfe(),
-            _ => panic!("[CPU] [ERROR] Unrecognised instruction 0x{:08x}", i.0),
+            _ => self.op_illegal(),
         };
     }
 
@@ -751,13 +913,18 @@ impl R3000A {
     }
 
     fn op_cop2(&mut self, i: Instruction) {
+        if !self.cop0.coprocessor_usable(2) {
+            self.op_unusable();
+            return;
+        }
+
         match i.rs() & 0x10 {
             0x00 => match i.rs() & 0x0f {
                 0x00 => self.op_mfc2(i.rd(), i.rt()),
                 0x02 => self.op_cfc2(i.rd(), i.rt()),
                 0x04 => self.op_mtc2(i.rd(), i.rt()),
                 0x06 => self.op_ctc2(i.rd(), i.rt()),
-                _ => panic!("[CPU] [ERROR] Unrecognised instruction 0x{:08x}", i.0),
+                _ => self.op_illegal(),
             },
             0x10 => self.op_cop2_command(i.target()),
             _ => unreachable!(),
@@ -826,6 +993,8 @@ impl R3000A {
         if addr & 0x01 != 0 {
             self.cop0.set_bad_vaddr(addr);
             self.enter_exception(Exception::AddrLoad);
+
+            self.execute_load_delay();
             return;
         }
 
@@ -893,6 +1062,8 @@ impl R3000A {
         if addr & 0x03 != 0 {
             self.cop0.set_bad_vaddr(addr);
             self.enter_exception(Exception::AddrLoad);
+
+            self.execute_load_delay();
             return;
         }
 
@@ -945,6 +1116,8 @@ impl R3000A {
         if addr & 0x01 != 0 {
             self.cop0.set_bad_vaddr(addr);
             self.enter_exception(Exception::AddrLoad);
+
+            self.execute_load_delay();
             return;
         }
 
@@ -1152,6 +1325,13 @@ impl R3000A {
     }
 
     fn op_lwcx(&mut self, bus: &mut Bus, tk: &mut Timekeeper, _: usize, rs: usize, offset: u32) {
+        let coprocessor = ((self.current_instruction >> 26) & 0x3) as usize;
+
+        if !self.cop0.coprocessor_usable(coprocessor) {
+            self.op_unusable();
+            return;
+        }
+
         let addr = self.reg(rs).wrapping_add(offset);
 
         self.execute_load_delay();
@@ -1178,6 +1358,11 @@ impl R3000A {
     }
 
     fn op_lwc2(&mut self, bus: &mut Bus, tk: &mut Timekeeper, rt: usize, rs: usize, offset: u32) {
+        if !self.cop0.coprocessor_usable(2) {
+            self.op_unusable();
+            return;
+        }
+
         let addr = self.reg(rs).wrapping_add(offset);
 
         self.execute_load_delay();
@@ -1206,6 +1391,13 @@ impl R3000A {
     }
 
     fn op_swcx(&mut self, bus: &mut Bus, tk: &mut Timekeeper, _: usize, rs: usize, offset: u32) {
+        let coprocessor = ((self.current_instruction >> 26) & 0x3) as usize;
+
+        if !self.cop0.coprocessor_usable(coprocessor) {
+            self.op_unusable();
+            return;
+        }
+
         let addr = self.reg(rs).wrapping_add(offset);
 
         self.execute_load_delay();
@@ -1232,6 +1424,11 @@ impl R3000A {
     }
 
     fn op_swc2(&mut self, bus: &mut Bus, tk: &mut Timekeeper, rt: usize, rs: usize, offset: u32) {
+        if !self.cop0.coprocessor_usable(2) {
+            self.op_unusable();
+            return;
+        }
+
         let addr = self.reg(rs).wrapping_add(offset);
         let v = self.gte.read_data(rt);
 
@@ -1258,6 +1455,19 @@ impl R3000A {
         }
     }
 
+    fn op_cop_unusable(&mut self, coprocessor: usize) {
+        /* There is nothing behind Cop1 and Cop3, even when usable */
+        if !self.cop0.coprocessor_usable(coprocessor) {
+            self.op_unusable();
+        }
+    }
+
+    fn op_unusable(&mut self) {
+        self.execute_load_delay();
+
+        self.enter_exception(Exception::CoprocessorUnusable);
+    }
+
     fn op_illegal(&mut self) {
         self.execute_load_delay();
 
@@ -1505,3 +1715,175 @@ impl R3000A {
         self.store(bus, tk, BusWidth::WORD, address, value)
     }
 }
+
+/* MULT/MULTU finish early for small multipliers */
+fn mult_cycles(rs: u32) -> u64 {
+    match rs {
+        0..=0x7ff => 5,
+        0x800..=0xfffff => 8,
+        _ => 12,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use super::super::intc::Interrupt;
+
+    const OVERFLOW: u32 = Exception::Overflow as u32;
+
+    fn cpu_with(rs: u32, rt: u32) -> R3000A {
+        let mut cpu = R3000A::new();
+        cpu.pc = 0x8001_0004;
+        cpu.current_pc = 0x8001_0000;
+        cpu.regs[1] = rs;
+        cpu.regs[2] = rt;
+        cpu.regs[3] = 0xdead_beef;
+        cpu
+    }
+
+    fn exception_code(cpu: &R3000A) -> Option<u32> {
+        match cpu.pc == 0x8001_0004 {
+            true => None,
+            false => Some((cpu.cop0.read(13) >> 2) & 0x1f),
+        }
+    }
+
+    /* Either the result in r3 or the overflow, with r3 left untouched */
+    fn check(cpu: &R3000A, expected: Option<u32>) {
+        match expected {
+            Some(value) => {
+                assert_eq!(exception_code(cpu), None);
+                assert_eq!(cpu.regs[3], value);
+            }
+            None => {
+                assert_eq!(exception_code(cpu), Some(OVERFLOW));
+                assert_eq!(cpu.cop0.read(14), 0x8001_0000);
+                assert_eq!(cpu.regs[3], 0xdead_beef);
+            }
+        }
+    }
+
+    #[test]
+    fn add_overflows_on_signed_boundaries() {
+        let cases = [
+            (0x7fff_fffe, 1, Some(0x7fff_ffff)),
+            (0x7fff_ffff, 1, None),
+            (0x7fff_ffff, 0x7fff_ffff, None),
+            (0x8000_0000, 0xffff_ffff, None),
+            (0x8000_0000, 0x8000_0000, None),
+            (0x8000_0001, 0xffff_ffff, Some(0x8000_0000)),
+            (0xffff_ffff, 1, Some(0)),
+            (0x7fff_ffff, 0x8000_0000, Some(0xffff_ffff)),
+        ];
+
+        for (rs, rt, expected) in cases {
+            let mut cpu = cpu_with(rs, rt);
+            cpu.op_add(3, 1, 2);
+            check(&cpu, expected);
+
+            let mut cpu = cpu_with(rs, rt);
+            cpu.op_addu(3, 1, 2);
+            check(&cpu, Some(rs.wrapping_add(rt)));
+        }
+    }
+
+    #[test]
+    fn addi_overflows_on_signed_boundaries() {
+        let cases = [
+            (0x7fff_7fff, 0x0000_7fff, Some(0x7fff_fffe)),
+            (0x7fff_8000, 0x0000_7fff, Some(0x7fff_ffff)),
+            (0x7fff_8001, 0x0000_7fff, None),
+            (0x8000_8000, 0xffff_8000, Some(0x8000_0000)),
+            (0x8000_7fff, 0xffff_8000, None),
+            (0x8000_0000, 0xffff_ffff, None),
+            (0xffff_ffff, 0x0000_0001, Some(0)),
+        ];
+
+        for (rs, imm, expected) in cases {
+            let mut cpu = cpu_with(rs, 0);
+            cpu.op_addi(3, 1, imm);
+            check(&cpu, expected);
+
+            let mut cpu = cpu_with(rs, 0);
+            cpu.op_addiu(3, 1, imm);
+            check(&cpu, Some(rs.wrapping_add(imm)));
+        }
+    }
+
+    #[test]
+    fn sub_overflows_on_signed_boundaries() {
+        let cases = [
+            (0x8000_0001, 1, Some(0x8000_0000)),
+            (0x8000_0000, 1, None),
+            (0x7fff_ffff, 0xffff_ffff, None),
+            (0x0000_0000, 0x8000_0000, None),
+            (0xffff_ffff, 0x8000_0000, Some(0x7fff_ffff)),
+            (0x7fff_fffe, 0xffff_ffff, Some(0x7fff_ffff)),
+            (0, 1, Some(0xffff_ffff)),
+        ];
+
+        for (rs, rt, expected) in cases {
+            let mut cpu = cpu_with(rs, rt);
+            cpu.op_sub(3, 1, 2);
+            check(&cpu, expected);
+
+            let mut cpu = cpu_with(rs, rt);
+            cpu.op_subu(3, 1, 2);
+            check(&cpu, Some(rs.wrapping_sub(rt)));
+        }
+    }
+
+    type Load = fn(&mut R3000A, &mut Bus, &mut Timekeeper, usize, usize, u32);
+
+    /* The load in the delay slot still lands, as with any other exception */
+    #[test]
+    fn misaligned_loads_complete_the_load_delay() {
+        let mut system = super::super::tests::test_system();
+        let loads: [Load; 3] = [R3000A::op_lh, R3000A::op_lhu, R3000A::op_lw];
+
+        for load in loads {
+            let mut cpu = cpu_with(0x8000_0001, 0);
+            cpu.ld_slot = (4, 0x1234);
+
+            load(&mut cpu, &mut system.bus, &mut system.timekeeper, 5, 1, 0);
+            assert_eq!(exception_code(&cpu), Some(Exception::AddrLoad as u32));
+            assert_eq!(cpu.cop0.read(8), 0x8000_0001);
+            assert_eq!(cpu.regs[4], 0x1234);
+            assert_eq!(cpu.regs[5], 0);
+        }
+    }
+
+    /* An overflowing ADD with an interrupt pending never runs */
+    #[test]
+    fn interrupts_win_over_the_instruction() {
+        let add = (1 << 21) | (2 << 16) | (3 << 11) | 0x20;
+
+        for pending in [true, false] {
+            let mut system = super::super::tests::test_system();
+            LittleEndian::write_u32(&mut system.bus.ram()[0x1_0000..], add);
+            system.bus.intc().write_mask(1);
+            system.bus.intc().assert_irq(Interrupt::Vblank);
+            if !pending {
+                system.bus.intc().acknowledge_irq(0);
+            }
+
+            let mut cpu = cpu_with(0x7fff_ffff, 1);
+            cpu.pc = 0x8001_0000;
+            cpu.new_pc = 0x8001_0004;
+            cpu.cop0.write(12, 0x401);
+
+            cpu.run(&mut system.bus, &mut system.timekeeper);
+            let expected = match pending {
+                true => Exception::Interrupt,
+                false => Exception::Overflow,
+            };
+            assert_eq!(exception_code(&cpu), Some(expected as u32));
+            assert_eq!(cpu.cop0.read(14), 0x8001_0000);
+            assert_eq!(cpu.regs[3], 0xdead_beef);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::cmp;
+use std::fmt::Write;
+
+use super::instruction::Instruction;
+
+/* Mnemonics by primary opcode, None where the CPU raises a reserved
+ * instruction exception */
+#[rustfmt::skip]
+const PRIMARY_NAMES: [Option<&str>; 64] = [
+    Some("special"), Some("bcond"), Some("j"), Some("jal"),
+    Some("beq"), Some("bne"), Some("blez"), Some("bgtz"),
+    Some("addi"), Some("addiu"), Some("slti"), Some("sltiu"),
+    Some("andi"), Some("ori"), Some("xori"), Some("lui"),
+    Some("cop0"), Some("cop1"), Some("cop2"), Some("cop3"),
+    None, None, None, None,
+    None, None, None, None,
+    None, None, None, None,
+    Some("lb"), Some("lh"), Some("lwl"), Some("lw"),
+    Some("lbu"), Some("lhu"), Some("lwr"), None,
+    Some("sb"), Some("sh"), Some("swl"), Some("sw"),
+    None, None, Some("swr"), None,
+    Some("lwc0"), Some("lwc1"), Some("lwc2"), Some("lwc3"),
+    None, None, None, None,
+    Some("swc0"), Some("swc1"), Some("swc2"), Some("swc3"),
+    None, None, None, None,
+];
+
+/* Same for the function field of special (opcode 0) instructions */
+#[rustfmt::skip]
+const SPECIAL_NAMES: [Option<&str>; 64] = [
+    Some("sll"), None, Some("srl"), Some("sra"),
+    Some("sllv"), None, Some("srlv"), Some("srav"),
+    Some("jr"), Some("jalr"), None, None,
+    Some("syscall"), Some("break"), None, None,
+    Some("mfhi"), Some("mthi"), Some("mflo"), Some("mtlo"),
+    None, None, None, None,
+    Some("mult"), Some("multu"), Some("div"), Some("divu"),
+    None, None, None, None,
+    Some("add"), Some("addu"), Some("sub"), Some("subu"),
+    Some("and"), Some("or"), Some("xor"), Some("nor"),
+    None, None, Some("slt"), Some("sltu"),
+    None, None, None, None,
+    None, None, None, None,
+    None, None, None, None,
+    None, None, None, None,
+    None, None, None, None,
+];
+
+const HOTTEST: usize = 16;
+
+/* Name of an instruction, None for reserved encodings */
+pub fn mnemonic(instruction: Instruction) -> Option<&'static str> {
+    match instruction.opcode() {
+        0 => SPECIAL_NAMES[instruction.function()],
+        opcode => PRIMARY_NAMES[opcode],
+    }
+}
+
+/* Per opcode execution counters. Special instructions are counted by
+ * function, coprocessor instructions by their primary opcode only. */
+pub struct InstructionStats {
+    primary: [u64; 64],
+    special: [u64; 64],
+    hilo_stalls: u64,
+}
+
+impl InstructionStats {
+    pub fn new() -> InstructionStats {
+        InstructionStats {
+            primary: [0; 64],
+            special: [0; 64],
+            hilo_stalls: 0,
+        }
+    }
+
+    pub fn record(&mut self, instruction: Instruction) {
+        match instruction.opcode() {
+            0 => self.special[instruction.function()] += 1,
+            opcode => self.primary[opcode] += 1,
+        }
+    }
+
+    /* Cycles MFHI/MFLO waited for a MULT/DIV to finish */
+    pub fn record_hilo_stall(&mut self, cycles: u64) {
+        self.hilo_stalls += cycles;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.primary.iter().sum::<u64>() + self.special.iter().sum::<u64>()
+    }
+
+    /* (name, count, implemented) of everything that ran, unimplemented
+     * encodings are named by their hex value */
+    pub fn counts(&self) -> Vec<(String, u64, bool)> {
+        let primary = self
+            .primary
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(opcode, count)| match PRIMARY_NAMES[opcode] {
+                Some(name) => (name.to_string(), *count, true),
+                None => (format!("op 0x{:02x}", opcode), *count, false),
+            });
+        let special =
+            self.special.iter().enumerate().map(|(function, count)| {
+                match SPECIAL_NAMES[function] {
+                    Some(name) => (name.to_string(), *count, true),
+                    None => (format!("special 0x{:02x}", function), *count, false),
+                }
+            });
+
+        primary
+            .chain(special)
+            .filter(|(_, count, _)| *count > 0)
+            .collect()
+    }
+
+    pub fn report(&self) -> String {
+        let counts = self.counts();
+        let implemented =
+            PRIMARY_NAMES.iter().skip(1).flatten().count() + SPECIAL_NAMES.iter().flatten().count();
+        let executed = counts.iter().filter(|(_, _, known)| *known).count();
+        let total = cmp::max(self.total(), 1);
+
+        let mut report = String::new();
+        let _ = writeln!(report, "Instructions executed: {}", self.total());
+        let _ = writeln!(
+            report,
+            "Coverage: {} of {} opcodes ({:.1}%)",
+            executed,
+            implemented,
+            executed as f64 * 100.0 / implemented as f64
+        );
+        let _ = writeln!(report, "HI/LO stall cycles: {}", self.hilo_stalls);
+
+        let mut hottest = counts.clone();
+        hottest.sort_by_key(|entry| cmp::Reverse(entry.1));
+        let _ = writeln!(report, "\nHottest:");
+        for (name, count, _) in hottest.iter().take(HOTTEST) {
+            let share = *count as f64 * 100.0 / total as f64;
+            let _ = writeln!(report, "  {:<12} {:>12} {:>6.2}%", name, count, share);
+        }
+
+        let _ = writeln!(report, "\nNever executed:");
+        let never: Vec<&str> = PRIMARY_NAMES
+            .iter()
+            .zip(self.primary.iter())
+            .skip(1)
+            .chain(SPECIAL_NAMES.iter().zip(self.special.iter()))
+            .filter_map(|(name, count)| name.filter(|_| *count == 0))
+            .collect();
+        let _ = writeln!(report, "  {}", never.join(" "));
+
+        let _ = writeln!(report, "\nUnimplemented (reserved instruction):");
+        for (name, count, _) in counts.iter().filter(|(_, _, known)| !known) {
+            let _ = writeln!(report, "  {:<12} {:>12}", name, count);
+        }
+
+        report
+    }
+}
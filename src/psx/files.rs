@@ -0,0 +1,117 @@
+use std::fmt;
+use std::fs;
+use std::io;
+
+use super::cdrom::{self, BYTES_PER_SECTOR};
+
+/* Every retail BIOS is a 512 KiB ROM */
+const BIOS_SIZE: u64 = 512 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileKind {
+    Bios,
+    Game,
+}
+
+#[derive(Debug)]
+pub enum FileProblem {
+    Missing,
+    Unreadable(io::Error),
+    /* Size in bytes, not what the kind of file should have */
+    Size(u64),
+    /* A .cue sheet that can't be parsed, or one of its tracks */
+    Invalid(String),
+}
+
+/* Why the BIOS or the game can't be used, checked before the emulator
+ * gets to them so it doesn't panic half way through a boot */
+#[derive(Debug)]
+pub struct FileError {
+    pub kind: FileKind,
+    pub path: String,
+    pub problem: FileProblem,
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            FileKind::Bios => "BIOS",
+            FileKind::Game => "Game",
+        };
+
+        match (&self.problem, self.kind) {
+            (FileProblem::Missing, _) => write!(f, "{} not found: {}", kind, self.path),
+            (FileProblem::Unreadable(e), _) => {
+                write!(f, "{} can't be read: {}: {}", kind, self.path, e)
+            }
+            (FileProblem::Invalid(e), _) => write!(f, "{} can't be used: {}", kind, e),
+            (FileProblem::Size(size), FileKind::Bios) => write!(
+                f,
+                "BIOS is {} bytes, expected {}: {}",
+                size, BIOS_SIZE, self.path
+            ),
+            (FileProblem::Size(size), FileKind::Game) => write!(
+                f,
+                "Game is {} bytes, not whole {} byte sectors of a raw .bin: {}",
+                size, BYTES_PER_SECTOR, self.path
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FileError {}
+
+#[allow(dead_code)]
+pub fn check_bios(filepath: &str) -> Result<(), FileError> {
+    let size = file_size(FileKind::Bios, filepath)?;
+
+    if size != BIOS_SIZE {
+        return Err(FileError {
+            kind: FileKind::Bios,
+            path: filepath.to_string(),
+            problem: FileProblem::Size(size),
+        });
+    }
+
+    Ok(())
+}
+
+/* Raw images, 2352 bytes per sector, or .cue sheets listing them. A
+ * truncated dump ends in the middle of one */
+#[allow(dead_code)]
+pub fn check_game(filepath: &str) -> Result<(), FileError> {
+    let size = file_size(FileKind::Game, filepath)?;
+
+    if cdrom::is_cue(filepath) {
+        return cdrom::check_cue(filepath).map_err(|e| FileError {
+            kind: FileKind::Game,
+            path: filepath.to_string(),
+            problem: FileProblem::Invalid(e),
+        });
+    }
+
+    if size == 0 || size % BYTES_PER_SECTOR != 0 {
+        return Err(FileError {
+            kind: FileKind::Game,
+            path: filepath.to_string(),
+            problem: FileProblem::Size(size),
+        });
+    }
+
+    Ok(())
+}
+
+fn file_size(kind: FileKind, filepath: &str) -> Result<u64, FileError> {
+    let error = |problem| FileError {
+        kind,
+        path: filepath.to_string(),
+        problem,
+    };
+
+    match fs::metadata(filepath) {
+        Ok(metadata) if metadata.is_file() => Ok(metadata.len()),
+        Ok(_) => Err(error(FileProblem::Missing)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(error(FileProblem::Missing)),
+        Err(e) => Err(error(FileProblem::Unreadable(e))),
+    }
+}
@@ -1,11 +1,11 @@
 use std::cmp;
 use std::fs::File;
-use std::io::Write;
+use std::io::{self, Write};
 
 use byteorder::{ByteOrder, LittleEndian};
 use serde::{Deserialize, Serialize};
 
-use super::gpu_viewer::{GpuFrame, GpuPolygon};
+use super::gpu_viewer::{GpuCapture, GpuFrame, GpuPolygon, GpuWrite};
 use super::intc::{Intc, Interrupt};
 use super::rasteriser::{Colour, Vector2i, Vector3i};
 use super::timers::Timers;
@@ -26,6 +26,19 @@ pub const CMD_SIZE: [usize; 256] = [
     1, 1,
 ];
 
+/* How 480i output is read back from VRAM */
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InterlaceMode {
+    /* Both fields as they are in VRAM, combs if only one field is redrawn */
+    #[default]
+    Weave,
+    /* Only the current field, every line doubled */
+    Bob,
+    /* Always the even field, doubled. Half the detail but steady */
+    Progressive,
+}
+
 #[derive(Serialize, Deserialize)]
 struct Transfer {
     x: u32,
@@ -246,6 +259,19 @@ pub struct Gpu {
 
     frame: GpuFrame,
     frame_complete: bool,
+    #[serde(skip)]
+    frame_count: u64,
+
+    #[serde(skip)]
+    capture: Option<GpuCapture>,
+
+    #[serde(skip)]
+    interlace_mode: InterlaceMode,
+
+    /* Primitives update the GPU state but draw nothing, VRAM transfers
+     * and fills still happen */
+    #[serde(skip)]
+    rasterise_bypass: bool,
 }
 
 impl Gpu {
@@ -342,6 +368,13 @@ impl Gpu {
 
             frame: GpuFrame::new(),
             frame_complete: false,
+            frame_count: 0,
+
+            capture: None,
+
+            interlace_mode: InterlaceMode::Weave,
+
+            rasterise_bypass: false,
         }
     }
 
@@ -372,6 +405,7 @@ impl Gpu {
 
             if self.scanline == (self.lines - 20) {
                 self.frame_complete = true;
+                self.frame_count += 1;
                 intc.assert_irq(Interrupt::Vblank);
             }
 
@@ -444,6 +478,36 @@ impl Gpu {
         }
     }
 
+    #[allow(dead_code)]
+    pub fn set_rasterise_bypass(&mut self, bypass: bool) {
+        self.rasterise_bypass = bypass;
+    }
+
+    pub fn set_interlace_mode(&mut self, interlace_mode: InterlaceMode) {
+        self.interlace_mode = interlace_mode;
+    }
+
+    pub fn keep_host_state(&mut self, old: &mut Gpu) {
+        self.capture = old.capture.take();
+        self.interlace_mode = old.interlace_mode;
+        self.rasterise_bypass = old.rasterise_bypass;
+    }
+
+    /* VRAM line shown at display line `y` of a display starting at `ys` */
+    fn source_line(&self, ys: u32, y: u32) -> u32 {
+        if !(self.vertical_interlace && self.vres == 480) {
+            return y;
+        }
+
+        let line = y - ys;
+
+        match self.interlace_mode {
+            InterlaceMode::Weave => y,
+            InterlaceMode::Bob => ys + (line & !1) + self.interlace_field as u32,
+            InterlaceMode::Progressive => ys + (line & !1),
+        }
+    }
+
     pub fn get_24bit(&self) -> bool {
         self.colour_depth
     }
@@ -494,21 +558,19 @@ impl Gpu {
         let mut framebuffer_address = 0;
 
         for y in ys..ys + h {
-            for x in xs..xs + w {
-                let address = match !draw_full_vram && self.colour_depth {
-                    true => Gpu::vram_address_24bit(x, y),
-                    false => Gpu::vram_address(x, y),
-                };
+            let y = match draw_full_vram {
+                true => y,
+                false => self.source_line(ys, y),
+            };
 
+            for x in xs..xs + w {
                 let col;
 
                 if !draw_full_vram && self.colour_depth {
-                    let r = self.vram[address];
-                    let g = self.vram[address + 1];
-                    let b = self.vram[address + 2];
+                    let (r, g, b) = self.read_24bit(xs, x, y);
                     col = Colour::new(r, g, b, false);
                 } else {
-                    let colour = LittleEndian::read_u16(&self.vram[address..]);
+                    let colour = LittleEndian::read_u16(&self.vram[Gpu::vram_address(x, y)..]);
                     col = Colour::from_u16(colour);
                 }
 
@@ -520,10 +582,99 @@ impl Gpu {
         }
     }
 
+    /* Display area as native 15-bit colours, 24-bit mode is packed down */
+    #[allow(dead_code)]
+    pub fn get_framebuffer_rgb555(&self, framebuffer: &mut [u16]) {
+        let (xs, ys) = self.get_display_origin();
+        let (w, h) = self.get_display_size();
+
+        let mut framebuffer_address = 0;
+
+        for y in ys..ys + h {
+            let y = self.source_line(ys, y);
+
+            for x in xs..xs + w {
+                framebuffer[framebuffer_address] = if self.colour_depth {
+                    let (r, g, b) = self.read_24bit(xs, x, y);
+                    let (r, g, b) = ((r >> 3) as u16, (g >> 3) as u16, (b >> 3) as u16);
+                    (b << 10) | (g << 5) | r
+                } else {
+                    LittleEndian::read_u16(&self.vram[Gpu::vram_address(x, y)..]) & 0x7fff
+                };
+                framebuffer_address += 1;
+            }
+        }
+    }
+
+    /* Display area as 8-bit luma, same weights as the image crate */
+    #[allow(dead_code)]
+    pub fn get_framebuffer_gray(&self, framebuffer: &mut [u8]) {
+        let (xs, ys) = self.get_display_origin();
+        let (w, h) = self.get_display_size();
+
+        let mut framebuffer_address = 0;
+
+        for y in ys..ys + h {
+            let y = self.source_line(ys, y);
+
+            for x in xs..xs + w {
+                let col = if self.colour_depth {
+                    let (r, g, b) = self.read_24bit(xs, x, y);
+                    Colour::new(r, g, b, false)
+                } else {
+                    let colour = LittleEndian::read_u16(&self.vram[Gpu::vram_address(x, y)..]);
+                    Colour::from_u16(colour)
+                };
+
+                let luma = 2126 * col.r as u32 + 7152 * col.g as u32 + 722 * col.b as u32;
+                framebuffer[framebuffer_address] = ((luma + 5000) / 10000) as u8;
+                framebuffer_address += 1;
+            }
+        }
+    }
+
+    pub fn vram(&self) -> &[u8] {
+        &self.vram
+    }
+
     pub fn get_frame_data(&mut self) -> &mut GpuFrame {
         &mut self.frame
     }
 
+    /* Snapshots the state and records GP0/GP1 writes from now on */
+    pub fn start_capture(&mut self) {
+        self.capture = None;
+
+        if !cfg!(feature = "gpu-capture") {
+            return;
+        }
+
+        let state = bincode::serialize(self).expect("[GPU] [ERROR] Unable to snapshot state");
+        self.capture = Some(GpuCapture {
+            state,
+            writes: Vec::new(),
+        });
+    }
+
+    pub fn take_capture(&mut self) -> Option<GpuCapture> {
+        self.capture.take()
+    }
+
+    /* Rebuilds the GPU from a capture, with all its writes applied */
+    pub fn replay(capture: &GpuCapture) -> io::Result<Gpu> {
+        let mut gpu: Gpu = bincode::deserialize(&capture.state)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        for write in capture.writes.iter() {
+            match *write {
+                GpuWrite::Gp0(word) => gpu.gp0_write(word),
+                GpuWrite::Gp1(word) => gpu.gp1_write(word),
+            }
+        }
+
+        Ok(gpu)
+    }
+
     pub fn dump_vram(&self) {
         let mut file = File::create("vram.bin").unwrap();
         file.write_all(&self.vram).unwrap();
@@ -538,6 +689,19 @@ impl Gpu {
         false
     }
 
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /* Not in save states, a rewind puts back the one it went to */
+    pub fn set_frame_count(&mut self, frame_count: u64) {
+        self.frame_count = frame_count;
+    }
+
+    pub fn scanline(&self) -> usize {
+        self.scanline
+    }
+
     pub fn gpuread(&mut self) -> u32 {
         if self.gpu_to_cpu_transfer.active {
             let lo = self.vram_read_transfer() as u32;
@@ -605,6 +769,11 @@ impl Gpu {
     }
 
     pub fn gp0_write(&mut self, word: u32) {
+        #[cfg(feature = "gpu-capture")]
+        if let Some(capture) = self.capture.as_mut() {
+            capture.writes.push(GpuWrite::Gp0(word));
+        }
+
         if self.cpu_to_gpu_transfer.active {
             self.vram_write_transfer(word as u16);
 
@@ -660,8 +829,16 @@ impl Gpu {
         2 * ((x & 0x3ff) + 1024 * (y & 0x1ff)) as usize
     }
 
-    fn vram_address_24bit(x: u32, y: u32) -> usize {
-        (3 * (x & 0x3ff) + 2048 * (y & 0x1ff)) as usize
+    /* 24-bit pixel `x` of a display starting at `xs`. The display start is
+     * in 16-bit units, pixels are 3 bytes from there and wrap around the
+     * 2048 byte VRAM row, even halfway through a pixel. */
+    fn read_24bit(&self, xs: u32, x: u32, y: u32) -> (u8, u8, u8) {
+        let row = 2048 * (y & 0x1ff) as usize;
+        let offset = (2 * (xs & 0x3ff) + 3 * (x - xs)) as usize;
+
+        let byte = |i: usize| self.vram[row + ((offset + i) & 0x7ff)];
+
+        (byte(0), byte(1), byte(2))
     }
 
     fn vram_read_transfer(&mut self) -> u16 {
@@ -934,6 +1111,16 @@ impl Gpu {
         }
     }
 
+    /* GP1 port, the commands call each other so only this one records */
+    pub fn gp1_write(&mut self, word: u32) {
+        #[cfg(feature = "gpu-capture")]
+        if let Some(capture) = self.capture.as_mut() {
+            capture.writes.push(GpuWrite::Gp1(word));
+        }
+
+        self.execute_gp1_command(word);
+    }
+
     pub fn execute_gp1_command(&mut self, command_word: u32) {
         let command = command_word >> 24;
 
@@ -1044,7 +1231,7 @@ impl Gpu {
                 };
             }
             0x20 => (), //Arcade Texture Disable
-            _ => panic!("[GPU] [ERROR] Unknown command GP1({:02x})", command),
+            _ => println!("[GPU] [ERROR] Unknown command GP1({:02x})", command),
         }
     }
 
@@ -1260,6 +1447,10 @@ impl Gpu {
             _ => unreachable!(),
         };
 
+        if self.rasterise_bypass {
+            return;
+        }
+
         for y in 0..size.y {
             for x in 0..size.x {
                 let p = Vector2i::new(vertex.x + x, vertex.y + y);
@@ -1334,6 +1525,10 @@ impl Gpu {
         blend: bool,
         transparency: bool,
     ) {
+        if self.rasterise_bypass {
+            return;
+        }
+
         let mut v = [vertices[0], vertices[1], vertices[2]];
         let mut c = [colours[0], colours[1], colours[2]];
         let mut t = [texcoords[0], texcoords[1], texcoords[2]];
@@ -1628,3 +1823,187 @@ impl Gpu {
         (Colour::from_u16(texture), texture == 0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: u32 = 320;
+    const HEIGHT: u32 = 240;
+
+    /* Stand-in for a decoded MDEC frame, every pixel different */
+    fn fmv_pixel(x: u32, y: u32) -> (u8, u8, u8) {
+        (x as u8, y as u8, (x ^ y) as u8 ^ 0x5a)
+    }
+
+    /* 320x240 24-bit display at (xs, ys), the frame uploaded the way the
+     * MDEC DMA leaves it, packed RGB bytes in 16-bit VRAM words */
+    fn gpu_showing_fmv(xs: u32, ys: u32) -> Gpu {
+        let mut gpu = Gpu::new();
+
+        gpu.gp1_write(0x05 << 24 | (ys << 10) | xs);
+        gpu.gp1_write(0x08 << 24 | 0x10 | 0x1);
+
+        let halfwords = WIDTH * 3 / 2;
+        gpu.gp0_write(0xa0 << 24);
+        gpu.gp0_write((ys << 16) | xs);
+        gpu.gp0_write((HEIGHT << 16) | halfwords);
+
+        for y in 0..HEIGHT {
+            let mut bytes = Vec::with_capacity((WIDTH * 3) as usize);
+
+            for x in 0..WIDTH {
+                let (r, g, b) = fmv_pixel(x, y);
+                bytes.extend_from_slice(&[r, g, b]);
+            }
+
+            for word in bytes.chunks(4) {
+                gpu.gp0_write(LittleEndian::read_u32(word));
+            }
+        }
+
+        gpu
+    }
+
+    fn check_fmv(xs: u32, ys: u32) {
+        let gpu = gpu_showing_fmv(xs, ys);
+
+        assert!(gpu.get_24bit());
+        assert_eq!(gpu.get_display_origin(), (xs, ys));
+        assert_eq!(gpu.get_display_size(), (WIDTH, HEIGHT));
+
+        let mut framebuffer = vec![0; (WIDTH * HEIGHT * 3) as usize];
+        gpu.get_framebuffer(&mut framebuffer, false);
+
+        let mut rgb555 = vec![0; (WIDTH * HEIGHT) as usize];
+        gpu.get_framebuffer_rgb555(&mut rgb555);
+
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let (r, g, b) = fmv_pixel(x, y);
+                let i = (x + WIDTH * y) as usize;
+
+                assert_eq!(
+                    &framebuffer[3 * i..3 * i + 3],
+                    &[r, g, b],
+                    "({}, {}) from ({}, {})",
+                    x,
+                    y,
+                    xs,
+                    ys
+                );
+
+                let expected = Colour::new(r, g, b, false).to_u16();
+                assert_eq!(rgb555[i], expected, "({}, {}) from ({}, {})", x, y, xs, ys);
+            }
+        }
+    }
+
+    #[test]
+    fn fmv_frame_at_the_vram_origin() {
+        check_fmv(0, 0);
+    }
+
+    /* The display start X counts 16-bit words, not 24-bit pixels */
+    #[test]
+    fn fmv_frame_away_from_the_vram_origin() {
+        check_fmv(320, 240);
+    }
+
+    /* Rows wrap within their 2048 bytes instead of running into the next */
+    #[test]
+    fn fmv_frame_wrapping_around_the_row() {
+        check_fmv(800, 16);
+    }
+
+    fn fill(gpu: &mut Gpu, colour: u32, x: u32, y: u32, width: u32, height: u32) {
+        gpu.gp0_write(0x02 << 24 | colour);
+        gpu.gp0_write((y << 16) | x);
+        gpu.gp0_write((height << 16) | width);
+    }
+
+    #[cfg(feature = "gpu-capture")]
+    #[test]
+    fn capture_replays_to_the_same_vram() {
+        let mut gpu = Gpu::new();
+        fill(&mut gpu, 0x0010_2030, 0, 0, 64, 64);
+
+        gpu.start_capture();
+        assert!(gpu.capture.is_some());
+
+        gpu.gp1_write(0x05 << 24 | (16 << 10) | 32);
+        gpu.gp0_write(0xe3 << 24);
+        gpu.gp0_write(0xe4 << 24 | (64 << 10) | 64);
+        gpu.gp0_write(0x30 << 24 | 0x0000_00ff);
+        gpu.gp0_write((2 << 16) | 2);
+        gpu.gp0_write(0x0000_ff00);
+        gpu.gp0_write((8 << 16) | 60);
+        gpu.gp0_write(0x00ff_0000);
+        gpu.gp0_write((60 << 16) | 10);
+
+        let capture = gpu.take_capture().unwrap();
+        assert_eq!(capture.writes.len(), 9);
+
+        let path =
+            std::env::temp_dir().join(format!("dojo-test-capture-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+        capture.save(path).unwrap();
+        let loaded = GpuCapture::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let replayed = Gpu::replay(&loaded).unwrap();
+        assert!(replayed.vram() == gpu.vram());
+        assert_eq!(replayed.get_display_origin(), (32, 16));
+
+        /* Nothing was drawn before the capture started */
+        let before = Gpu::replay(&GpuCapture {
+            state: loaded.state,
+            writes: Vec::new(),
+        })
+        .unwrap();
+        assert!(before.vram() != gpu.vram());
+    }
+
+    /* A scrolled display area shows the same frame, the HUD rows included */
+    #[test]
+    fn display_origin_moves_the_readout_with_it() {
+        let mut frames = Vec::new();
+
+        for (xs, ys) in [(0, 0), (384, 16), (640, 256)] {
+            let mut gpu = Gpu::new();
+            gpu.gp1_write(0x05 << 24 | (ys << 10) | xs);
+            fill(&mut gpu, 0x0040_4040, xs, ys, WIDTH, HEIGHT);
+            fill(&mut gpu, 0x0000_c000, xs + 16, ys + 27, 64, 1);
+
+            assert_eq!(gpu.get_display_origin(), (xs, ys));
+
+            let mut framebuffer = vec![0; (WIDTH * HEIGHT * 3) as usize];
+            gpu.get_framebuffer(&mut framebuffer, false);
+
+            let bar = 3 * (16 + WIDTH * 27) as usize;
+            assert_eq!(&framebuffer[bar..bar + 3], &[0x00, 0xc6, 0x00]);
+
+            frames.push(framebuffer);
+        }
+
+        assert!(frames.iter().all(|frame| *frame == frames[0]));
+    }
+
+    #[test]
+    fn fifteen_bit_display_is_read_as_is() {
+        let mut gpu = Gpu::new();
+        gpu.gp1_write(0x05 << 24 | (8 << 10) | 16);
+        gpu.gp0_write(0x02 << 24 | 0x00f8_0008);
+        gpu.gp0_write((8 << 16) | 16);
+        gpu.gp0_write((HEIGHT << 16) | WIDTH);
+
+        assert!(!gpu.get_24bit());
+
+        let mut framebuffer = vec![0; (WIDTH * HEIGHT * 3) as usize];
+        gpu.get_framebuffer(&mut framebuffer, false);
+
+        for pixel in framebuffer.chunks(3) {
+            assert_eq!(pixel, &[0x08, 0x00, 0xff]);
+        }
+    }
+}
@@ -1,3 +1,6 @@
+use std::fs;
+use std::io;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Copy, Serialize, Deserialize)]
@@ -122,3 +125,32 @@ impl GpuFrame {
         self.commands = frame.commands.drain(..).collect();
     }
 }
+
+#[derive(Serialize, Deserialize)]
+pub enum GpuWrite {
+    Gp0(u32),
+    Gp1(u32),
+}
+
+/* Everything needed to redraw a frame offline: the GPU state (VRAM
+ * included) when the capture started and every GP0/GP1 write after it */
+#[derive(Serialize, Deserialize)]
+pub struct GpuCapture {
+    pub state: Vec<u8>,
+    pub writes: Vec<GpuWrite>,
+}
+
+impl GpuCapture {
+    #[allow(dead_code)]
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let bytes =
+            bincode::serialize(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, bytes)
+    }
+
+    #[allow(dead_code)]
+    pub fn load(path: &str) -> io::Result<GpuCapture> {
+        let bytes = fs::read(path)?;
+        bincode::deserialize(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
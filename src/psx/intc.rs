@@ -10,7 +10,7 @@ pub enum Interrupt {
     Tmr1,
     Tmr2,
     Controller,
-    //Sio,
+    Sio,
     Spu,
     //Pio,
 }
@@ -28,7 +28,7 @@ impl Interrupt {
             Tmr1 => 0x20,
             Tmr2 => 0x40,
             Controller => 0x80,
-            //Sio => 0x100,
+            Sio => 0x100,
             Spu => 0x200,
             //Pio => 0x400,
         }
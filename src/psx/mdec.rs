@@ -163,7 +163,10 @@ impl Mdec {
             break;
         }
 
-        self.idct(blk);
+        /* Without the mdec feature blocks are only parsed, to keep in sync */
+        if cfg!(feature = "mdec") {
+            self.idct(blk);
+        }
 
         return true;
     }
@@ -192,6 +195,11 @@ impl Mdec {
     }
 
     fn yuv_to_rgb(&mut self, output: &mut [u8], xx: usize, yy: usize) {
+        /* Output stays black */
+        if !cfg!(feature = "mdec") {
+            return;
+        }
+
         for y in 0..8 {
             for x in 0..8 {
                 let mut r = self.blocks[MDEC_BLK_CR].data[((x + xx) >> 1) + ((y + yy) >> 1) * 8];
@@ -238,7 +246,7 @@ impl Mdec {
     fn process_command(&mut self, value: u32) {
         self.data_in.push_back(value as u16);
         self.data_in.push_back((value >> 16) as u16);
-        self.words_remaining -= 1;
+        self.words_remaining = self.words_remaining.wrapping_sub(1);
 
         let mut output = [0; 768];
 
@@ -319,10 +327,10 @@ impl Mdec {
     }
 
     pub fn read_data(&mut self) -> u32 {
-        let b0 = self.data_out.pop_front().unwrap() as u32;
-        let b1 = self.data_out.pop_front().unwrap() as u32;
-        let b2 = self.data_out.pop_front().unwrap() as u32;
-        let b3 = self.data_out.pop_front().unwrap() as u32;
+        let b0 = self.data_out.pop_front().unwrap_or(0) as u32;
+        let b1 = self.data_out.pop_front().unwrap_or(0) as u32;
+        let b2 = self.data_out.pop_front().unwrap_or(0) as u32;
+        let b3 = self.data_out.pop_front().unwrap_or(0) as u32;
 
         b0 | (b1 << 8) | (b2 << 16) | (b3 << 24)
     }
@@ -374,7 +382,8 @@ impl Mdec {
         status |= (self.output_bit15 as u32) << 23;
         status |= (self.current_block as u32) << 16;
 
-        status |= (self.words_remaining - 1) as u32;
+        /* 0xffff once there are none left */
+        status |= self.words_remaining.wrapping_sub(1) as u32;
 
         status
     }
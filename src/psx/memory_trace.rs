@@ -0,0 +1,181 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt::Write;
+
+use super::bus::BusWidth;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryAccess {
+    /* Instruction running when the access happened */
+    pub pc: u32,
+    pub kind: AccessKind,
+    pub address: u32,
+    /* In bytes */
+    pub width: u8,
+    pub value: u32,
+}
+
+/* Inclusive address range to watch. KSEG0/KSEG1 addresses are taken as
+ * their physical address, which is what the bus sees */
+#[derive(Clone, Copy, Debug)]
+pub struct TraceRange {
+    pub start: u32,
+    pub end: u32,
+    pub reads: bool,
+    pub writes: bool,
+}
+
+impl TraceRange {
+    fn physical(self) -> TraceRange {
+        let physical = |address: u32| match address >> 29 {
+            0b100 | 0b101 => address & 0x1fff_ffff,
+            _ => address,
+        };
+
+        TraceRange {
+            start: physical(self.start),
+            end: physical(self.end),
+            ..self
+        }
+    }
+
+    fn matches(&self, kind: AccessKind, address: u32, width: u8) -> bool {
+        let wanted = match kind {
+            AccessKind::Read => self.reads,
+            AccessKind::Write => self.writes,
+        };
+        let last = address.saturating_add(width as u32 - 1);
+
+        wanted && address <= self.end && last >= self.start
+    }
+}
+
+/* Bus accesses within the watched ranges, the newest `capacity` of them.
+ * Only fed while enabled, costs a branch per access otherwise */
+pub struct MemoryTrace {
+    ranges: Vec<TraceRange>,
+    accesses: VecDeque<MemoryAccess>,
+    capacity: usize,
+    pc: u32,
+    /* Accesses pushed out of the ring buffer */
+    dropped: u64,
+}
+
+impl MemoryTrace {
+    pub fn new(ranges: &[TraceRange], capacity: usize) -> MemoryTrace {
+        MemoryTrace {
+            ranges: ranges.iter().map(|range| range.physical()).collect(),
+            accesses: VecDeque::with_capacity(capacity),
+            capacity,
+            pc: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn set_pc(&mut self, pc: u32) {
+        self.pc = pc;
+    }
+
+    pub fn record(&mut self, kind: AccessKind, width: &BusWidth, address: u32, value: u32) {
+        /* Stores get the whole register, only the low bytes are written */
+        let (width, value) = match width {
+            BusWidth::BYTE => (1, value & 0xff),
+            BusWidth::HALF => (2, value & 0xffff),
+            BusWidth::WORD => (4, value),
+        };
+
+        if !self
+            .ranges
+            .iter()
+            .any(|range| range.matches(kind, address, width))
+        {
+            return;
+        }
+
+        if self.capacity == 0 {
+            self.dropped += 1;
+            return;
+        }
+
+        if self.accesses.len() == self.capacity {
+            self.accesses.pop_front();
+            self.dropped += 1;
+        }
+
+        self.accesses.push_back(MemoryAccess {
+            pc: self.pc,
+            kind,
+            address,
+            width,
+            value,
+        });
+    }
+
+    /* Oldest first */
+    pub fn accesses(&self) -> impl Iterator<Item = &MemoryAccess> {
+        self.accesses.iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.accesses.clear();
+        self.dropped = 0;
+    }
+
+    /* One line per access, oldest first, plus which instructions did
+     * them and how often */
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+
+        let _ = writeln!(
+            report,
+            "Memory trace: {} accesses ({} older ones dropped)",
+            self.accesses.len(),
+            self.dropped
+        );
+
+        for access in self.accesses.iter() {
+            let kind = match access.kind {
+                AccessKind::Read => 'R',
+                AccessKind::Write => 'W',
+            };
+            let _ = writeln!(
+                report,
+                "  pc 0x{:08x} {}{} 0x{:08x} = 0x{:0width$x}",
+                access.pc,
+                kind,
+                access.width * 8,
+                access.address,
+                access.value,
+                width = access.width as usize * 2
+            );
+        }
+
+        let mut by_pc: Vec<(u32, AccessKind, usize)> = Vec::new();
+
+        for access in self.accesses.iter() {
+            match by_pc
+                .iter_mut()
+                .find(|(pc, kind, _)| *pc == access.pc && *kind == access.kind)
+            {
+                Some((_, _, count)) => *count += 1,
+                None => by_pc.push((access.pc, access.kind, 1)),
+            }
+        }
+
+        by_pc.sort_by_key(|(_, _, count)| cmp::Reverse(*count));
+
+        let _ = writeln!(report, "By instruction:");
+
+        for (pc, kind, count) in by_pc {
+            let _ = writeln!(report, "  pc 0x{:08x} {:?}: {}", pc, kind, count);
+        }
+
+        report
+    }
+}
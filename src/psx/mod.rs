@@ -1,32 +1,108 @@
 pub mod bus;
 pub mod cpu;
 pub mod rasteriser;
+pub mod speed;
 
 mod adpcm;
 mod cdrom;
 mod exp2;
+mod files;
 mod gpu;
 mod gpu_viewer;
 mod intc;
 mod mdec;
+mod memory_trace;
+mod netplay;
 mod peripherals;
 mod queue;
+mod ram_snapshot;
+mod region;
+mod rewind;
+mod rom_cache;
 mod scheduler;
+mod sio1;
 mod spu;
 mod timekeeper;
 mod timers;
 mod util;
+mod wav;
 
+use std::cmp;
 use std::fs::File;
 use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use byteorder::{ByteOrder, LittleEndian};
 
 use serde::{Deserialize, Serialize};
 
-use self::bus::Bus;
+use self::bus::{Bus, BusWidth, UnmappedAccess};
 use self::cpu::R3000A;
+use self::gpu::Gpu;
 use self::gpu_viewer::GpuFrame;
-use self::peripherals::controller::Controller;
+use self::memory_trace::MemoryTrace;
+use self::peripherals::controller::{Controller, InputProvider};
+use self::rewind::RewindBuffer;
 use self::timekeeper::Timekeeper;
+use self::wav::WavWriter;
+
+const RAM_SIZE: usize = 0x20_0000;
+const VRAM_WIDTH: u32 = 1024;
+const VRAM_HEIGHT: u32 = 512;
+
+/* Cargo features of the core, all on by default. Without them the state
+ * (and so the save state format) is the same, only work is skipped:
+ *
+ *   spu          off: voices keep their timing (ADSR, ENDX, IRQs) but
+ *                     aren't interpolated, mixed nor reverberated, no
+ *                     samples come out. Pitch modulation sees silence.
+ *   mdec         off: FMV blocks are parsed but not decoded, frames
+ *                     come out black.
+ *   gpu-capture  off: start_gpu_capture does nothing and GP0/GP1 writes
+ *                     aren't recorded.
+ *
+ * Training only needs the picture and the life bars:
+ * cargo build --release --no-default-features
+ *
+ * System::set_fast_path skips the same SPU work plus GPU rasterisation at
+ * runtime, for when nothing looks at the picture either.
+ */
+
+/* CPU cycles run between device syncs */
+const SYNC_CYCLES: u64 = 128;
+
+pub use self::cpu::InstructionLogConfig;
+#[allow(unused_imports)]
+pub use self::files::{FileError, FileKind, FileProblem};
+pub use self::gpu::InterlaceMode;
+pub use self::gpu_viewer::GpuCapture;
+#[allow(unused_imports)]
+pub use self::memory_trace::{AccessKind, MemoryAccess, TraceRange};
+#[allow(unused_imports)]
+pub use self::netplay::UdpLink;
+#[allow(unused_imports)]
+pub use self::ram_snapshot::{diff_report, hex, RamChange, RamSnapshot};
+#[allow(unused_imports)]
+pub use self::region::{BiosInfo, Region};
+#[allow(unused_imports)]
+pub use self::sio1::{link_cable, LinkPort, LinkTransport};
+
+pub type FrameCallback = Box<dyn FnMut(&System) + Send>;
+
+/// Where `run_until` stops. Hitting a breakpoint always stops it early.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// The GPU entered vblank, same point `run_frame` stops at.
+    FrameComplete,
+    /// Any scanline starts.
+    NewScanline,
+    /// The given scanline starts.
+    Scanline(usize),
+    /// At least this many CPU cycles ran.
+    Cycles(u64),
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct System {
@@ -36,10 +112,60 @@ pub struct System {
     cpu: R3000A,
 
     timekeeper: Timekeeper,
+
+    #[serde(skip)]
+    vblank_callbacks: Vec<FrameCallback>,
+    #[serde(skip)]
+    frame_complete_callbacks: Vec<FrameCallback>,
+
+    /* RGB display area, converted at most once per frame */
+    #[serde(skip)]
+    framebuffer: Vec<u8>,
+    #[serde(skip)]
+    framebuffer_key: Option<(u64, u32, u32)>,
+
+    #[serde(skip)]
+    audio_capture: Option<WavWriter>,
+
+    #[serde(skip)]
+    rewind: Option<RewindBuffer>,
 }
 
 impl System {
     #![allow(dead_code)]
+    /// Same as `new`, but the BIOS and the game are checked first and
+    /// what's wrong with them is returned instead of panicking.
+    pub fn try_new(bios_filepath: &str, game_filepath: &str) -> Result<System, FileError> {
+        files::check_bios(bios_filepath)?;
+        files::check_game(game_filepath)?;
+        Ok(System::new(bios_filepath, game_filepath))
+    }
+
+    /// Checks the game a loaded state reads from is still there. The BIOS
+    /// is saved within the state, the game is only referenced by path.
+    pub fn check_game_file(&mut self) -> Result<(), FileError> {
+        files::check_game(self.bus.cdrom().game_filepath())
+    }
+
+    /// Version and region of the BIOS, None for the first ones, which have
+    /// no version string.
+    pub fn get_bios_info(&self) -> Option<BiosInfo> {
+        BiosInfo::from_bytes(self.bus.bios())
+    }
+
+    /// Why the disc will likely not boot with this BIOS, None if both are
+    /// for the same region or either can't be told.
+    pub fn get_region_mismatch(&mut self) -> Option<String> {
+        let game_filepath = self.bus.cdrom().game_filepath().to_string();
+        region::region_mismatch(self.bus.bios(), &game_filepath)
+    }
+
+    /// Reads the game from another path, e.g. for states saved on another
+    /// machine. It must be the same image.
+    pub fn set_game_filepath(&mut self, game_filepath: &str) {
+        self.bus.cdrom().set_game_filepath(game_filepath);
+    }
+
     pub fn new(bios_filepath: &str, game_filepath: &str) -> System {
         System {
             running: true,
@@ -48,26 +174,309 @@ impl System {
             cpu: R3000A::new(),
 
             timekeeper: Timekeeper::new(),
+
+            vblank_callbacks: Vec::new(),
+            frame_complete_callbacks: Vec::new(),
+
+            framebuffer: Vec::new(),
+            framebuffer_key: None,
+
+            audio_capture: None,
+
+            rewind: None,
         }
     }
 
     pub fn reset(&mut self) {
         self.bus.reset();
         self.cpu.reset();
+        self.framebuffer_key = None;
 
         self.timekeeper.reset();
+
+        if let Some(rewind) = &mut self.rewind {
+            *rewind = RewindBuffer::new(rewind.budget(), rewind.interval());
+        }
     }
 
     pub fn run_frame(&mut self) {
-        while !self.bus.gpu_mut().frame_complete() {
-            while self.timekeeper.elapsed() < 128 {
+        self.run_until(Event::FrameComplete);
+    }
+
+    /// Runs at least `cycles` CPU cycles, see `run_until`.
+    pub fn run_cycles(&mut self, cycles: u64) -> bool {
+        self.run_until(Event::Cycles(cycles))
+    }
+
+    /// Runs until `event` happens, returns false if a breakpoint stopped it
+    /// first. Frames completed on the way still run the callbacks and sync
+    /// the pads, so mixing this with `run_frame` is fine.
+    pub fn run_until(&mut self, event: Event) -> bool {
+        self.cpu.resume_breakpoint();
+
+        let start = self.timekeeper.cycles();
+
+        loop {
+            let limit = match event {
+                Event::Cycles(cycles) => {
+                    let ran = self.timekeeper.cycles() - start;
+                    if ran >= cycles {
+                        return true;
+                    }
+                    cmp::min(SYNC_CYCLES, self.timekeeper.elapsed() + cycles - ran)
+                }
+                _ => SYNC_CYCLES,
+            };
+
+            while self.timekeeper.elapsed() < limit {
                 self.cpu.run(&mut self.bus, &mut self.timekeeper);
+
+                // Stop early, next call carries on from the breakpoint
+                if self.cpu.breakpoint_hit().is_some() {
+                    return false;
+                }
             }
 
+            let old_scanline = self.bus.gpu().scanline();
             self.timekeeper.sync_all(&mut self.bus);
+            let scanline = self.bus.gpu().scanline();
+
+            let frame_complete = self.bus.gpu_mut().frame_complete();
+            if frame_complete {
+                self.end_frame();
+            }
+
+            let reached = match event {
+                Event::FrameComplete => frame_complete,
+                Event::NewScanline => scanline != old_scanline,
+                Event::Scanline(target) => scanline != old_scanline && scanline == target,
+                Event::Cycles(_) => false,
+            };
+            if reached {
+                return true;
+            }
         }
+    }
+
+    fn end_frame(&mut self) {
+        self.bus.sio1().end_frame();
+
+        let mut callbacks = std::mem::take(&mut self.vblank_callbacks);
+        callbacks.iter_mut().for_each(|callback| callback(self));
+        self.vblank_callbacks = callbacks;
 
         self.bus.peripherals().sync();
+
+        let mut callbacks = std::mem::take(&mut self.frame_complete_callbacks);
+        callbacks.iter_mut().for_each(|callback| callback(self));
+        self.frame_complete_callbacks = callbacks;
+
+        let frame = self.get_frame_count();
+        if self
+            .rewind
+            .as_ref()
+            .is_some_and(|rewind| rewind.is_due(frame))
+        {
+            self.push_rewind();
+        }
+    }
+
+    /// Records the current state for `rewind` right away, whatever the
+    /// interval, e.g. at the end of every RL step.
+    pub fn push_rewind(&mut self) {
+        let frame = self.get_frame_count();
+        match &self.rewind {
+            Some(rewind) if !rewind.has(frame) => (),
+            _ => return,
+        }
+
+        /* Save states leave these out, replaying from a rewind must be the
+         * same run though */
+        let hilo_ready = self.cpu.hilo_ready();
+        let turbo_polls = self.bus.peripherals().controller().turbo_polls();
+
+        let result = bincode::serialize(&(&*self, hilo_ready, turbo_polls))
+            .map_err(|e| e.to_string())
+            .and_then(|state| self.rewind.as_mut().unwrap().push(frame, &state));
+        if let Err(e) = result {
+            println!("[REWIND] [WARN] Rewind stopped: {}", e);
+            self.rewind = None;
+        }
+    }
+
+    /// Keeps a compressed save state every `interval` frames, dropping the
+    /// oldest ones past `budget` bytes, for `rewind`. An interval of 0 only
+    /// keeps the ones from `push_rewind`, a budget of 0 turns it off.
+    /// Starts empty, and not kept in save states either.
+    pub fn set_rewind(&mut self, budget: usize, interval: u32) {
+        self.rewind = match budget {
+            0 => None,
+            _ => Some(RewindBuffer::new(budget, interval)),
+        };
+    }
+
+    /// Frames it can go back right now and the bytes used, None if off.
+    pub fn get_rewind_usage(&self) -> Option<(u64, usize)> {
+        let rewind = self.rewind.as_ref()?;
+        let oldest = rewind.oldest_frame().unwrap_or(self.get_frame_count());
+        Some((self.get_frame_count() - oldest, rewind.size()))
+    }
+
+    /// Goes back to the newest state recorded at least `frames` frames ago
+    /// and returns its frame. Host side settings (callbacks, input
+    /// provider, traces, bypasses, breakpoints, link) stay as they are, so
+    /// running again from there with the same inputs is the same run.
+    /// States newer than it are dropped.
+    pub fn rewind(&mut self, frames: u64) -> Result<u64, String> {
+        let target = self.get_frame_count().saturating_sub(frames);
+        let rewind = self.rewind.as_mut().ok_or("Rewind is off")?;
+
+        let (frame, state) = match rewind.rewind_to(target) {
+            Some(result) => result?,
+            None => return Err(format!("Nothing recorded at frame {} or before", target)),
+        };
+        let (system, hilo_ready, turbo_polls): (System, u64, u32) =
+            bincode::deserialize(&state).map_err(|e| e.to_string())?;
+
+        let mut old = std::mem::replace(self, system);
+        self.keep_host_state(&mut old);
+        self.bus.gpu_mut().set_frame_count(frame);
+        self.cpu.set_hilo_ready(hilo_ready);
+        self.bus
+            .peripherals()
+            .controller()
+            .set_turbo_polls(turbo_polls);
+
+        Ok(frame)
+    }
+
+    fn keep_host_state(&mut self, old: &mut System) {
+        self.running = old.running;
+
+        self.bus.keep_host_state(&mut old.bus);
+        self.cpu.keep_host_state(&mut old.cpu);
+
+        self.vblank_callbacks = std::mem::take(&mut old.vblank_callbacks);
+        self.frame_complete_callbacks = std::mem::take(&mut old.frame_complete_callbacks);
+
+        self.audio_capture = old.audio_capture.take();
+        self.rewind = old.rewind.take();
+    }
+
+    /// Called once per frame for the GPU entering vblank, noticed at the end
+    /// of the sync window it happened in (`SYNC_CYCLES` at most). That is
+    /// where `run_frame` stops, so it runs along with `on_frame_complete`,
+    /// only earlier: before the memory cards are flushed and before those.
+    pub fn on_vblank(&mut self, callback: FrameCallback) {
+        self.vblank_callbacks.push(callback);
+    }
+
+    /// Called at the end of every frame, after the `on_vblank` callbacks and
+    /// the memory card flush. A breakpoint stopping `run_frame` early leaves
+    /// it for the call that finishes the frame.
+    pub fn on_frame_complete(&mut self, callback: FrameCallback) {
+        self.frame_complete_callbacks.push(callback);
+    }
+
+    pub fn clear_callbacks(&mut self) {
+        self.vblank_callbacks.clear();
+        self.frame_complete_callbacks.clear();
+    }
+
+    pub fn set_unmapped_access(&mut self, unmapped_access: UnmappedAccess) {
+        self.bus.set_unmapped_access(unmapped_access);
+    }
+
+    /// Replaces the latched controller state: the provider fills in the
+    /// buttons right when the game polls the pad. `None` goes back to latching.
+    pub fn set_input_provider(&mut self, input_provider: Option<InputProvider>) {
+        self.bus.set_input_provider(input_provider);
+    }
+
+    /// Frames completed since the system was created or loaded, the frame
+    /// the input provider and observations are stamped with.
+    pub fn get_frame_count(&self) -> u64 {
+        self.bus.gpu().frame_count()
+    }
+
+    /// Emulated time since reset, from the CPU cycles run. It is kept in
+    /// save states, so it goes on from where the state was saved.
+    pub fn get_emulated_time(&self) -> Duration {
+        self.timekeeper.time()
+    }
+
+    pub fn add_breakpoint(&mut self, address: u32) {
+        self.cpu.add_breakpoint(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u32) {
+        self.cpu.remove_breakpoint(address);
+    }
+
+    pub fn get_breakpoint_hit(&self) -> Option<u32> {
+        self.cpu.breakpoint_hit()
+    }
+
+    /// Per opcode execution counters, off by default. Enabling them again
+    /// starts from zero.
+    pub fn set_instruction_stats(&mut self, enabled: bool) {
+        self.cpu.set_instruction_stats(enabled);
+    }
+
+    /// Logs executed instructions (PC, encoding and mnemonic) at debug
+    /// level, see `InstructionLogConfig` for which ones. Can be changed
+    /// between frames, `None` stops logging.
+    pub fn set_instruction_log(&mut self, config: Option<InstructionLogConfig>) {
+        self.cpu.set_instruction_log(config);
+    }
+
+    /// Lines the instruction log wrote so far, `None` unless logging.
+    pub fn get_instructions_logged(&self) -> Option<u64> {
+        self.cpu.instructions_logged()
+    }
+
+    /// Makes MFHI/MFLO wait for a running MULT/DIV like the hardware does,
+    /// on by default. Some timing loops depend on it, turning it off saves
+    /// a little time. Not kept in save states, so set it again after
+    /// loading one.
+    pub fn set_hilo_interlock(&mut self, enabled: bool) {
+        self.cpu.set_hilo_interlock_bypass(!enabled);
+    }
+
+    /// Starts logging bus accesses within `ranges` to a ring buffer of the
+    /// newest `capacity` ones, with the PC of the instruction doing them.
+    /// An empty list stops tracing and drops the buffer.
+    pub fn set_memory_trace(&mut self, ranges: &[TraceRange], capacity: usize) {
+        let trace = match ranges.is_empty() {
+            true => None,
+            false => Some(Box::new(MemoryTrace::new(ranges, capacity))),
+        };
+        self.bus.set_memory_trace(trace);
+    }
+
+    /// Traced accesses, oldest first. Empty unless tracing.
+    pub fn get_memory_accesses(&self) -> Vec<MemoryAccess> {
+        self.bus
+            .memory_trace()
+            .map_or(Vec::new(), |trace| trace.accesses().copied().collect())
+    }
+
+    /// Every traced access and a summary per instruction, `None` unless
+    /// tracing.
+    pub fn get_memory_trace_report(&self) -> Option<String> {
+        self.bus.memory_trace().map(|trace| trace.report())
+    }
+
+    pub fn clear_memory_trace(&mut self) {
+        if let Some(trace) = self.bus.memory_trace_mut() {
+            trace.clear();
+        }
+    }
+
+    /// Coverage report (hottest, never executed and unimplemented opcodes),
+    /// `None` unless stats are enabled.
+    pub fn get_instruction_stats_report(&self) -> Option<String> {
+        self.cpu.instruction_stats().map(|stats| stats.report())
     }
 
     #[allow(dead_code)]
@@ -100,9 +509,61 @@ impl System {
         Ok(())
     }
 
-    #[allow(dead_code)]
+    /// SPU reverb is only worth its cost when someone listens. Not kept in
+    /// save states, so set it again after loading one.
+    pub fn set_spu_reverb(&mut self, enabled: bool) {
+        self.bus.spu().set_reverb_bypass(!enabled);
+    }
+
+    /// Skips GPU rasterisation and SPU mixing, for training that only reads
+    /// RAM. Timing and IRQs are the same, but the picture stops being drawn
+    /// (VRAM uploads still land) and no samples come out. Not kept in save
+    /// states, so set it again after loading one.
+    pub fn set_fast_path(&mut self, enabled: bool) {
+        self.bus.gpu_mut().set_rasterise_bypass(enabled);
+        self.bus.spu().set_mix_bypass(enabled);
+    }
+
+    /// Plugs a transport, e.g. one end of a `link_cable` or a `UdpLink`,
+    /// into the serial link port, or unplugs it with `None`. Not part of
+    /// save states, plug it again after loading one.
+    pub fn set_link(&mut self, link: Option<Box<dyn LinkTransport>>) {
+        self.bus.set_link(link);
+    }
+
+    /// Drains the SPU output, interleaved stereo at 44.1kHz. Frontends call
+    /// it every frame even without audio output, the samples pile up
+    /// otherwise and audio capture only sees what is drained.
     pub fn get_audio_samples(&mut self) -> Vec<i16> {
-        self.bus.spu().drain_samples()
+        let samples = self.bus.spu().drain_samples();
+
+        if let Some(capture) = &mut self.audio_capture {
+            if let Err(e) = capture.write(&samples) {
+                println!("[AUDIO] [WARN] Audio capture stopped: {}", e);
+                self.audio_capture = None;
+            }
+        }
+
+        samples
+    }
+
+    /// Writes every sample drained from now on to a WAV file, until
+    /// `stop_audio_capture`. Anything already pending is dropped.
+    pub fn start_audio_capture<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.bus.spu().drain_samples();
+        self.audio_capture = Some(WavWriter::create(path)?);
+        Ok(())
+    }
+
+    pub fn stop_audio_capture(&mut self) -> io::Result<()> {
+        match self.audio_capture.take() {
+            Some(capture) => capture.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_capturing_audio(&self) -> bool {
+        self.audio_capture.is_some()
     }
 
     pub fn get_controller(&mut self) -> &mut Controller {
@@ -119,6 +580,12 @@ impl System {
         self.bus.gpu().get_display_origin()
     }
 
+    /// Weave, bob or force progressive for 480i output.
+    pub fn set_interlace_mode(&mut self, interlace_mode: InterlaceMode) {
+        self.bus.gpu_mut().set_interlace_mode(interlace_mode);
+        self.framebuffer_key = None;
+    }
+
     pub fn get_display_size(&self) -> (u32, u32) {
         self.bus.gpu().get_display_size()
     }
@@ -127,13 +594,256 @@ impl System {
         self.bus.gpu().get_framebuffer(data, draw_full_vram)
     }
 
+    /// Native 15-bit colours of the display area, `width * height` entries.
+    pub fn get_framebuffer_rgb555(&self, data: &mut [u16]) {
+        self.bus.gpu().get_framebuffer_rgb555(data)
+    }
+
+    /// 8-bit luma of the display area, `width * height` bytes.
+    pub fn get_framebuffer_gray(&self, data: &mut [u8]) {
+        self.bus.gpu().get_framebuffer_gray(data)
+    }
+
+    /// Lends the RGB888 display area with its width and height. The buffer is
+    /// owned by the system and only converted again once a new frame is out.
+    pub fn with_framebuffer<R>(&mut self, f: impl FnOnce(&[u8], u32, u32) -> R) -> R {
+        let (width, height) = self.get_display_size();
+        let key = (self.bus.gpu().frame_count(), width, height);
+
+        if self.framebuffer_key != Some(key) {
+            self.framebuffer.resize((width * height * 3) as usize, 0);
+            self.bus.gpu().get_framebuffer(&mut self.framebuffer, false);
+            self.framebuffer_key = Some(key);
+        }
+
+        f(&self.framebuffer, width, height)
+    }
+
     #[allow(dead_code)]
     pub fn get_frame_data(&mut self) -> &mut GpuFrame {
         self.bus.gpu_mut().get_frame_data()
     }
 
+    /// Reads `len` bytes of main RAM. KSEG0/KSEG1 addresses are accepted, but
+    /// the range must fit in the 2MB of RAM (no mirrors).
+    pub fn read_ram(&mut self, address: u32, len: usize) -> Option<Vec<u8>> {
+        let offset = (address & 0x1fff_ffff) as usize;
+        if offset.saturating_add(len) > RAM_SIZE {
+            return None;
+        }
+        Some(self.bus.ram()[offset..offset + len].to_vec())
+    }
+
+    /// One load as the CPU would do it, at a physical address, e.g. to
+    /// audit the devices with random accesses. Returns the value and
+    /// whether it was a bus error.
+    pub fn bus_load(&mut self, width: BusWidth, address: u32) -> (u32, bool) {
+        unsafe { self.bus.load(&mut self.timekeeper, width, address) }
+    }
+
+    /// Same as `bus_load` for stores, returns whether it was a bus error.
+    pub fn bus_store(&mut self, width: BusWidth, address: u32, value: u32) -> bool {
+        unsafe { self.bus.store(&mut self.timekeeper, width, address, value) }
+    }
+
+    /// Writes `data` to main RAM, same addressing as `read_ram`. Returns false
+    /// (and writes nothing) if the range doesn't fit.
+    pub fn write_ram(&mut self, address: u32, data: &[u8]) -> bool {
+        let offset = (address & 0x1fff_ffff) as usize;
+        if offset.saturating_add(data.len()) > RAM_SIZE {
+            return false;
+        }
+        self.bus.ram()[offset..offset + data.len()].copy_from_slice(data);
+        true
+    }
+
+    /// Copy of the whole main RAM, see `RamSnapshot::diff` to find what
+    /// changed between two of them.
+    pub fn snapshot_ram(&mut self) -> RamSnapshot {
+        let frame = self.get_frame_count();
+        RamSnapshot::new(self.bus.ram(), frame)
+    }
+
+    /// Hash of the whole main RAM. Systems run from the same state with the
+    /// same inputs hash the same, so comparing this between frames is a
+    /// cheap determinism check. Stable across runs and builds.
+    pub fn get_ram_hash(&mut self) -> u64 {
+        util::fnv1a(self.bus.ram())
+    }
+
+    /// Same as `get_ram_hash`, for the whole VRAM.
+    pub fn get_vram_hash(&self) -> u64 {
+        util::fnv1a(self.bus.gpu().vram())
+    }
+
+    /// Reads a rectangle of raw 16-bit VRAM pixels, row by row.
+    pub fn read_vram_rect(&self, x: u32, y: u32, width: u32, height: u32) -> Option<Vec<u16>> {
+        if x.saturating_add(width) > VRAM_WIDTH || y.saturating_add(height) > VRAM_HEIGHT {
+            return None;
+        }
+        let vram = self.bus.gpu().vram();
+        let mut pixels = Vec::with_capacity((width * height) as usize);
+        for row in y..y + height {
+            for column in x..x + width {
+                let address = ((row * VRAM_WIDTH + column) * 2) as usize;
+                pixels.push(LittleEndian::read_u16(&vram[address..]));
+            }
+        }
+        Some(pixels)
+    }
+
+    /// Starts recording the GPU: its state now plus every GP0/GP1 write.
+    /// Does nothing without the gpu-capture feature.
+    pub fn start_gpu_capture(&mut self) {
+        self.bus.gpu_mut().start_capture();
+    }
+
+    /// Stops recording, `None` if no capture was started.
+    pub fn take_gpu_capture(&mut self) -> Option<GpuCapture> {
+        self.bus.gpu_mut().take_capture()
+    }
+
+    /// Replays a capture without the rest of the system and returns the raw
+    /// 16-bit VRAM it ends up with, for pixel exact comparisons.
+    pub fn replay_gpu_capture(capture: &GpuCapture) -> io::Result<Vec<u8>> {
+        Ok(Gpu::replay(capture)?.vram().to_vec())
+    }
+
     #[allow(dead_code)]
     pub fn dump_vram(&self) {
         self.bus.gpu().dump_vram();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::sync::OnceLock;
+
+    /* A BIOS of zeros is all NOPs, enough to step the system through
+     * frames without a real one. The disc is never read. */
+    pub fn test_system() -> System {
+        static FILES: OnceLock<(String, String)> = OnceLock::new();
+
+        let (bios, game) = FILES.get_or_init(|| {
+            let write = |name: &str, size: usize| {
+                let path = env::temp_dir().join(name);
+                let partial = path.with_extension(format!("{}", std::process::id()));
+                fs::write(&partial, vec![0; size]).unwrap();
+                fs::rename(&partial, &path).unwrap();
+                path.to_string_lossy().into_owned()
+            };
+
+            (
+                write("dojo-test-bios.bin", 512 * 1024),
+                write("dojo-test-game.bin", 2352 * 16),
+            )
+        });
+
+        System::new(bios, game)
+    }
+
+    /* Systems own no threads, each one is dropped where it is */
+    #[test]
+    fn many_systems_come_and_go() {
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    for _ in 0..8 {
+                        let mut system = test_system();
+                        system.run_frame();
+                        assert_eq!(system.get_frame_count(), 1);
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    /* Patched once, then the same bytes for every System and state */
+    #[test]
+    fn systems_share_the_bios() {
+        let first = test_system();
+        let second = test_system();
+
+        let bios = first.bus.bios();
+        assert_eq!(&bios[0x6f0c..0x6f10], &[0x01, 0x00, 0x01, 0x24]);
+        assert_eq!(bios.as_ptr(), second.bus.bios().as_ptr());
+
+        let state = bincode::serialize(&first).unwrap();
+        let loaded: System = bincode::deserialize(&state).unwrap();
+        assert_eq!(bios.as_ptr(), loaded.bus.bios().as_ptr());
+    }
+
+    #[test]
+    fn breakpoints_stop_and_carry_on() {
+        let mut system = test_system();
+        system.reset();
+
+        system.add_breakpoint(0xbfc0_0010);
+        assert!(!system.run_until(Event::FrameComplete));
+        assert_eq!(system.get_breakpoint_hit(), Some(0xbfc0_0010));
+        assert_eq!(system.cpu.pc, 0xbfc0_0010);
+
+        assert!(system.run_until(Event::FrameComplete));
+        assert_eq!(system.get_breakpoint_hit(), None);
+
+        /* Back at the same PC, nothing stops it once removed */
+        system.remove_breakpoint(0xbfc0_0010);
+        system.reset();
+        assert!(system.run_until(Event::FrameComplete));
+    }
+
+    #[test]
+    fn run_cycles_stops_right_after_the_count() {
+        let mut system = test_system();
+
+        for cycles in [1, 100, 127, 128, 129, 5000] {
+            let start = system.timekeeper.cycles();
+            assert!(system.run_cycles(cycles));
+
+            let ran = system.timekeeper.cycles() - start;
+            assert!(ran >= cycles, "{} of {}", ran, cycles);
+            assert!(ran < cycles + 16, "{} of {}", ran, cycles);
+        }
+    }
+
+    #[test]
+    fn run_until_scanlines() {
+        let mut system = test_system();
+
+        let scanline = system.bus.gpu().scanline();
+        assert!(system.run_until(Event::NewScanline));
+        assert_ne!(system.bus.gpu().scanline(), scanline);
+
+        assert!(system.run_until(Event::Scanline(100)));
+        assert_eq!(system.bus.gpu().scanline(), 100);
+
+        assert!(system.run_until(Event::Scanline(50)));
+        assert_eq!(system.bus.gpu().scanline(), 50);
+    }
+
+    #[test]
+    fn run_until_mixes_with_run_frame() {
+        let mut system = test_system();
+
+        system.run_frame();
+        let frame = system.get_frame_count();
+
+        system.run_cycles(1000);
+        system.run_until(Event::Scanline(10));
+        assert_eq!(system.get_frame_count(), frame);
+
+        system.run_frame();
+        assert_eq!(system.get_frame_count(), frame + 1);
+
+        assert!(system.run_until(Event::FrameComplete));
+        assert_eq!(system.get_frame_count(), frame + 2);
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::VecDeque;
+use std::io::{self, Cursor, Read};
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::sio1::LinkTransport;
+
+/* Packets go up to this size, unless a single frame needs more */
+const MAX_PACKET_SIZE: usize = 1200;
+const RECEIVE_BUFFER_SIZE: usize = 65536;
+
+/* How often unacknowledged frames go out again while waiting */
+const RESEND_INTERVAL: Duration = Duration::from_millis(5);
+
+/* After this long without the frame we need the peer is considered gone */
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/* What one end did with the link during one frame */
+struct FrameRecord {
+    frame: u32,
+    lines: u8,
+    bytes: Vec<u8>,
+}
+
+impl FrameRecord {
+    fn encoded_size(&self) -> usize {
+        4 + 1 + 2 + self.bytes.len()
+    }
+}
+
+/// Delay-based link cable over UDP. Each end sends what its link port did
+/// every frame, tagged with the frame number, and sees the other end's
+/// frame N during its own frame N + delay. Frames are resent until
+/// acknowledged, and `end_frame` blocks until the peer's frame needed next
+/// arrives, so both systems advance in lockstep. There is no rollback.
+pub struct UdpLink {
+    socket: UdpSocket,
+    delay: u32,
+
+    /* Frames run since connecting, i.e. the one running now */
+    frame: u32,
+
+    outputs: u8,
+    current: Vec<u8>,
+    unacknowledged: VecDeque<FrameRecord>,
+    last_send: Instant,
+
+    /* Peer frames received so far, they arrive in order */
+    received: u32,
+    pending: VecDeque<FrameRecord>,
+
+    inputs: u8,
+    rx: VecDeque<u8>,
+
+    connected: bool,
+}
+
+impl UdpLink {
+    /// Binds `local` and waits up to `timeout` for the peer at `remote` to
+    /// do the same. `delay` is in frames, at least 1.
+    #[allow(dead_code)]
+    pub fn connect<A: ToSocketAddrs, B: ToSocketAddrs>(
+        local: A,
+        remote: B,
+        delay: u32,
+        timeout: Duration,
+    ) -> io::Result<UdpLink> {
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(remote)?;
+        socket.set_nonblocking(true)?;
+
+        let mut link = UdpLink {
+            socket,
+            delay: delay.max(1),
+
+            frame: 0,
+
+            outputs: 0,
+            current: Vec::new(),
+            unacknowledged: VecDeque::new(),
+            last_send: Instant::now(),
+
+            received: 0,
+            pending: VecDeque::new(),
+
+            inputs: 0,
+            rx: VecDeque::new(),
+
+            connected: true,
+        };
+
+        /* Any packet from the peer means it is there, its hellos are empty */
+        let start = Instant::now();
+        let mut buffer = vec![0; RECEIVE_BUFFER_SIZE];
+
+        loop {
+            link.send_packet();
+
+            match link.socket.recv(&mut buffer) {
+                Ok(size) => {
+                    link.handle_packet(&buffer[..size]);
+                    break;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                /* Refused until the peer binds its socket */
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => (),
+                Err(e) => return Err(e),
+            }
+
+            if start.elapsed() > timeout {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "no answer from the netplay peer",
+                ));
+            }
+
+            thread::sleep(RESEND_INTERVAL);
+        }
+
+        /* Make sure the peer gets at least one packet before we run */
+        link.send_packet();
+
+        Ok(link)
+    }
+
+    /// Whether the peer still answers. Once it is gone the link carries on
+    /// unplugged instead of blocking the emulator.
+    #[allow(dead_code)]
+    pub fn connected(&self) -> bool {
+        self.connected
+    }
+
+    /* ack (peer frames received), count, then count frame records */
+    fn send_packet(&mut self) {
+        let mut packet = Vec::with_capacity(MAX_PACKET_SIZE);
+        packet.write_u32::<LittleEndian>(self.received).unwrap();
+        packet.write_u8(0).unwrap();
+
+        let mut count = 0;
+
+        for record in self.unacknowledged.iter() {
+            let full = packet.len() + record.encoded_size() > MAX_PACKET_SIZE;
+
+            if count == u8::MAX || (count > 0 && full) {
+                break;
+            }
+
+            packet.write_u32::<LittleEndian>(record.frame).unwrap();
+            packet.write_u8(record.lines).unwrap();
+            packet
+                .write_u16::<LittleEndian>(record.bytes.len() as u16)
+                .unwrap();
+            packet.extend_from_slice(&record.bytes);
+
+            count += 1;
+        }
+
+        packet[4] = count;
+
+        /* Lost packets are sent again anyway */
+        let _ = self.socket.send(&packet);
+        self.last_send = Instant::now();
+    }
+
+    fn handle_packet(&mut self, packet: &[u8]) {
+        if let Err(e) = self.parse_packet(packet) {
+            println!("[NETPLAY] [WARN] Dropping bad packet: {}", e);
+        }
+    }
+
+    fn parse_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        let mut cursor = Cursor::new(packet);
+
+        let ack = cursor.read_u32::<LittleEndian>()?;
+        let count = cursor.read_u8()?;
+
+        while let Some(record) = self.unacknowledged.front() {
+            if record.frame >= ack {
+                break;
+            }
+
+            self.unacknowledged.pop_front();
+        }
+
+        for _ in 0..count {
+            let frame = cursor.read_u32::<LittleEndian>()?;
+            let lines = cursor.read_u8()?;
+            let len = cursor.read_u16::<LittleEndian>()? as usize;
+
+            let mut bytes = vec![0; len];
+            cursor.read_exact(&mut bytes)?;
+
+            /* Older ones are resends, newer ones come again after a gap */
+            if frame == self.received {
+                self.pending.push_back(FrameRecord {
+                    frame,
+                    lines,
+                    bytes,
+                });
+                self.received += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn poll(&mut self) {
+        let mut buffer = vec![0; RECEIVE_BUFFER_SIZE];
+
+        while let Ok(size) = self.socket.recv(&mut buffer) {
+            self.handle_packet(&buffer[..size]);
+        }
+    }
+
+    /* Hands over the peer frames due by now */
+    fn release(&mut self) {
+        while let Some(record) = self.pending.front() {
+            if record.frame + self.delay > self.frame {
+                break;
+            }
+
+            let record = self.pending.pop_front().unwrap();
+            self.inputs = record.lines;
+            self.rx.extend(record.bytes);
+        }
+    }
+}
+
+impl LinkTransport for UdpLink {
+    fn send(&mut self, byte: u8) {
+        self.current.push(byte);
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.rx.pop_front()
+    }
+
+    fn set_outputs(&mut self, dtr: bool, rts: bool) {
+        self.outputs = (dtr as u8) | ((rts as u8) << 1);
+    }
+
+    fn inputs(&self) -> (bool, bool) {
+        ((self.inputs & 0x1) != 0, (self.inputs & 0x2) != 0)
+    }
+
+    fn end_frame(&mut self) {
+        let bytes = std::mem::take(&mut self.current);
+
+        if !self.connected {
+            return;
+        }
+
+        self.unacknowledged.push_back(FrameRecord {
+            frame: self.frame,
+            lines: self.outputs,
+            bytes,
+        });
+
+        self.frame += 1;
+
+        self.send_packet();
+
+        /* The next frame needs the peer's frame + 1 - delay */
+        let needed = (self.frame + 1).saturating_sub(self.delay);
+        let start = Instant::now();
+
+        loop {
+            self.poll();
+
+            if self.received >= needed {
+                break;
+            }
+
+            if start.elapsed() > DISCONNECT_TIMEOUT {
+                println!("[NETPLAY] [WARN] Peer stopped answering, unplugging");
+                self.connected = false;
+                break;
+            }
+
+            if self.last_send.elapsed() > RESEND_INTERVAL {
+                self.send_packet();
+            }
+
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        self.release();
+    }
+}
@@ -1,9 +1,23 @@
 use serde::{Deserialize, Serialize};
 
+/* Called with (frame, scanline) when the game starts reading the pad */
+pub type InputProvider = Box<dyn FnMut(u64, usize, &mut Controller) + Send>;
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Controller {
     state: usize,
     pub digital_mode: bool,
+    #[serde(skip)]
+    polled: bool,
+
+    /* Held buttons in this mask (see `buttons`) read as released every
+     * other `turbo_period` polls. A frontend setting, not saved. */
+    #[serde(skip)]
+    turbo: u16,
+    #[serde(skip)]
+    turbo_period: u32,
+    #[serde(skip)]
+    turbo_polls: u32,
 
     pub button_select: bool,
     pub button_l3: bool,
@@ -33,6 +47,11 @@ impl Controller {
         Controller {
             state: 0,
             digital_mode: false,
+            polled: false,
+
+            turbo: 0,
+            turbo_period: 1,
+            turbo_polls: 0,
 
             button_select: false,
             button_l3: false,
@@ -65,6 +84,8 @@ impl Controller {
             0 => self.state = 1,
             1 => {
                 if command == 0x42 {
+                    self.polled = true;
+                    self.turbo_polls = self.turbo_polls.wrapping_add(1);
                     self.state = 2;
                     reply = if self.digital_mode { 0x41 } else { 0x73 };
                 } else {
@@ -76,11 +97,11 @@ impl Controller {
                 self.state = 3;
             }
             3 => {
-                reply = self.get_switch_state_lo();
+                reply = self.get_switch_state_lo() | self.turbo_released() as u8;
                 self.state = 4;
             }
             4 => {
-                reply = self.get_switch_state_hi();
+                reply = self.get_switch_state_hi() | (self.turbo_released() >> 8) as u8;
                 self.state = if self.digital_mode { 0 } else { 5 };
             }
             5 => {
@@ -105,6 +126,73 @@ impl Controller {
         reply
     }
 
+    /* All buttons as one mask, bit 0 is select and bit 15 square like on the
+     * wire, except that 1 means pressed */
+    #[allow(dead_code)]
+    pub fn buttons(&self) -> u16 {
+        let lo = !self.get_switch_state_lo() as u16;
+        let hi = !self.get_switch_state_hi() as u16;
+
+        (hi << 8) | lo
+    }
+
+    #[allow(dead_code)]
+    pub fn set_buttons(&mut self, buttons: u16) {
+        self.button_select = (buttons & 0x1) != 0;
+        self.button_l3 = (buttons & 0x2) != 0;
+        self.button_r3 = (buttons & 0x4) != 0;
+        self.button_start = (buttons & 0x8) != 0;
+        self.button_dpad_up = (buttons & 0x10) != 0;
+        self.button_dpad_right = (buttons & 0x20) != 0;
+        self.button_dpad_down = (buttons & 0x40) != 0;
+        self.button_dpad_left = (buttons & 0x80) != 0;
+        self.button_l2 = (buttons & 0x100) != 0;
+        self.button_r2 = (buttons & 0x200) != 0;
+        self.button_l1 = (buttons & 0x400) != 0;
+        self.button_r1 = (buttons & 0x800) != 0;
+        self.button_triangle = (buttons & 0x1000) != 0;
+        self.button_circle = (buttons & 0x2000) != 0;
+        self.button_cross = (buttons & 0x4000) != 0;
+        self.button_square = (buttons & 0x8000) != 0;
+    }
+
+    /* Autofire for the buttons in `buttons` (same mask as `buttons()`):
+     * while held they alternate `period` polls pressed and `period`
+     * released. Games poll once per frame, so 1 taps as fast as they can
+     * notice. An empty mask turns it off. */
+    #[allow(dead_code)]
+    pub fn set_turbo(&mut self, buttons: u16, period: u32) {
+        self.turbo = buttons;
+        self.turbo_period = period.max(1);
+    }
+
+    /* Same as `hilo_ready`, the turbo phase is part of a rewind */
+    pub fn turbo_polls(&self) -> u32 {
+        self.turbo_polls
+    }
+
+    pub fn set_turbo_polls(&mut self, turbo_polls: u32) {
+        self.turbo_polls = turbo_polls;
+    }
+
+    pub fn keep_host_state(&mut self, old: &Controller) {
+        self.turbo = old.turbo;
+        self.turbo_period = old.turbo_period;
+    }
+
+    /* Turbo buttons to report as released on this poll, 1 means released
+     * on the wire */
+    fn turbo_released(&self) -> u16 {
+        match (self.turbo_polls / self.turbo_period.max(1)) % 2 {
+            0 => 0,
+            _ => self.turbo,
+        }
+    }
+
+    pub fn take_polled(&mut self) -> bool {
+        std::mem::take(&mut self.polled)
+    }
+
     pub fn ack(&self) -> bool {
         self.state != 0
     }
@@ -250,13 +250,17 @@ impl Peripherals {
     }
 
     pub fn tx_data(&mut self, value: u32) {
+        /* Software waits for the previous byte to be acknowledged, anything
+         * written before that is dropped */
+        if self.in_transfer || self.in_acknowledge {
+            println!("[SIO0] [WARN] Write to TX data during a transfer");
+            return;
+        }
+
         self.tx_fifo.push(value as u8);
         self.tx_ready_1 = true;
         self.tx_ready_2 = false;
 
-        assert!(!self.in_transfer);
-        assert!(!self.in_acknowledge);
-
         self.ticks_left = (self.baudrate as isize & !1) * 8;
         self.in_transfer = true;
     }
@@ -0,0 +1,103 @@
+use std::fmt::Write;
+
+/* Where main RAM shows up in KSEG0, the addresses games use */
+#[allow(dead_code)]
+const KSEG0_RAM: u32 = 0x8000_0000;
+
+/* Copy of main RAM at some point in time, to diff against another one */
+#[allow(dead_code)]
+#[derive(Clone)]
+pub struct RamSnapshot {
+    ram: Box<[u8]>,
+    frame: u64,
+}
+
+/* Run of consecutive changed bytes (or words), the ones in between
+ * included so before/after can be read as a whole */
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct RamChange {
+    /* KSEG0 address of the first changed byte */
+    pub address: u32,
+    pub before: Vec<u8>,
+    pub after: Vec<u8>,
+}
+
+#[allow(dead_code)]
+impl RamSnapshot {
+    pub fn new(ram: &[u8], frame: u64) -> RamSnapshot {
+        RamSnapshot {
+            ram: ram.into(),
+            frame,
+        }
+    }
+
+    /* Frame the snapshot was taken at */
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /* Changes from `self` to `other`, compared `unit` bytes at a time (1
+     * for bytes, 4 for aligned words). Changed units closer than `gap`
+     * bytes are joined in a single range */
+    pub fn diff(&self, other: &RamSnapshot, unit: usize, gap: usize) -> Vec<RamChange> {
+        let unit = unit.max(1);
+        let len = self.ram.len().min(other.ram.len());
+
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+
+        for start in (0..len).step_by(unit) {
+            let end = (start + unit).min(len);
+
+            if self.ram[start..end] == other.ram[start..end] {
+                continue;
+            }
+
+            match ranges.last_mut() {
+                Some((_, last_end)) if start <= *last_end + gap => *last_end = end,
+                _ => ranges.push((start, end)),
+            }
+        }
+
+        ranges
+            .into_iter()
+            .map(|(start, end)| RamChange {
+                address: KSEG0_RAM + start as u32,
+                before: self.ram[start..end].to_vec(),
+                after: other.ram[start..end].to_vec(),
+            })
+            .collect()
+    }
+}
+
+/* One line per range, before and after in hex */
+#[allow(dead_code)]
+pub fn diff_report(changes: &[RamChange]) -> String {
+    let mut report = String::new();
+    let bytes: usize = changes.iter().map(|change| change.before.len()).sum();
+
+    let _ = writeln!(
+        report,
+        "RAM diff: {} ranges, {} bytes",
+        changes.len(),
+        bytes
+    );
+
+    for change in changes {
+        let _ = writeln!(
+            report,
+            "  0x{:08x} +{:<4} {} -> {}",
+            change.address,
+            change.before.len(),
+            hex(&change.before),
+            hex(&change.after)
+        );
+    }
+
+    report
+}
+
+#[allow(dead_code)]
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
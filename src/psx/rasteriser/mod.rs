@@ -5,3 +5,6 @@ pub mod vector3i;
 pub use self::colour::Colour;
 pub use self::vector2i::Vector2i;
 pub use self::vector3i::Vector3i;
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,319 @@
+/* Canonical primitives drawn through GP0 and compared to the committed
+ * reference images in reference/. After a deliberate change to the
+ * rasteriser, run with UPDATE_REFERENCE=1 to write them again and check
+ * the new ones by eye before committing them. */
+
+use std::env;
+use std::path::PathBuf;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::super::gpu::Gpu;
+use super::Colour;
+
+const SIZE: u32 = 64;
+
+/* One 5-bit step in 8-bit, what dithering may move a channel by */
+const TOLERANCE: i32 = 8;
+
+const TEXTURE_PAGE: u32 = 8;
+const TEXTURE_X: u32 = TEXTURE_PAGE * 64;
+const TEXTURE_SIZE: u32 = 16;
+
+fn vertex(x: i32, y: i32) -> u32 {
+    ((y as u32 & 0x7ff) << 16) | (x as u32 & 0x7ff)
+}
+
+fn rgb(r: u8, g: u8, b: u8) -> u32 {
+    ((b as u32) << 16) | ((g as u32) << 8) | r as u32
+}
+
+fn write(gpu: &mut Gpu, words: &[u32]) {
+    for &word in words {
+        gpu.gp0_write(word);
+    }
+}
+
+/* Drawing area over the whole test image, a grey background and a 15-bit
+ * checker texture at TEXTURE_X with a transparent (0x0000) corner and its
+ * right half flagged semi-transparent */
+fn new_gpu(texpage: u32) -> Gpu {
+    let mut gpu = Gpu::new();
+
+    write(
+        &mut gpu,
+        &[
+            0xe1 << 24 | texpage,
+            0xe3 << 24,
+            0xe4 << 24 | (SIZE << 10) | SIZE,
+            0xe5 << 24,
+            0x02 << 24 | rgb(0x40, 0x40, 0x40),
+            0,
+            (SIZE << 16) | SIZE,
+        ],
+    );
+
+    write(
+        &mut gpu,
+        &[0xa0 << 24, TEXTURE_X, (TEXTURE_SIZE << 16) | TEXTURE_SIZE],
+    );
+
+    let mut texels = Vec::new();
+
+    for v in 0..TEXTURE_SIZE {
+        for u in 0..TEXTURE_SIZE {
+            let texel = match ((u / 4) + (v / 4)) % 2 {
+                0 => Colour::new(0xf8, 0x80, 0x00, false),
+                _ => Colour::new(0x00, 0x80, 0xf8, false),
+            };
+
+            let mut texel = texel.to_u16();
+
+            if u >= TEXTURE_SIZE / 2 {
+                texel |= 0x8000;
+            }
+
+            if u < 4 && v < 4 {
+                texel = 0;
+            }
+
+            texels.push(texel as u32);
+        }
+    }
+
+    let words: Vec<u32> = texels
+        .chunks(2)
+        .map(|pair| pair[0] | (pair[1] << 16))
+        .collect();
+    write(&mut gpu, &words);
+
+    gpu
+}
+
+/* 15-bit texture page with the given semi-transparency mode */
+fn texpage(semi_transparency: u32) -> u32 {
+    (2 << 7) | (semi_transparency << 5) | TEXTURE_PAGE
+}
+
+fn flat_triangle(gpu: &mut Gpu, semi_transparent: bool) {
+    let command = 0x20 | ((semi_transparent as u32) << 1);
+
+    write(
+        gpu,
+        &[
+            command << 24 | rgb(0xc0, 0x20, 0x60),
+            vertex(4, 4),
+            vertex(60, 12),
+            vertex(20, 58),
+        ],
+    );
+}
+
+fn gouraud_triangle(gpu: &mut Gpu) {
+    write(
+        gpu,
+        &[
+            0x30 << 24 | rgb(0xff, 0x00, 0x00),
+            vertex(2, 2),
+            rgb(0x00, 0xff, 0x00),
+            vertex(62, 8),
+            rgb(0x00, 0x00, 0xff),
+            vertex(10, 62),
+        ],
+    );
+}
+
+/* Quad over most of the image, the texture stretched over it */
+fn textured_quad(gpu: &mut Gpu, command: u32, semi_transparency: u32) {
+    let clut = 0;
+    let page = texpage(semi_transparency);
+    let end = TEXTURE_SIZE - 1;
+
+    write(
+        gpu,
+        &[
+            command << 24 | rgb(0x60, 0x80, 0xa0),
+            vertex(8, 8),
+            clut << 16,
+            vertex(56, 8),
+            (page << 16) | end,
+            vertex(8, 56),
+            end << 8,
+            vertex(56, 56),
+            (end << 8) | end,
+        ],
+    );
+}
+
+fn render(gpu: &Gpu) -> Vec<u8> {
+    let vram = gpu.vram();
+    let mut image = Vec::with_capacity((SIZE * SIZE * 3) as usize);
+
+    for y in 0..SIZE {
+        for x in 0..SIZE {
+            let address = (2 * (x + 1024 * y)) as usize;
+            let colour = Colour::from_u16(LittleEndian::read_u16(&vram[address..]));
+            image.extend_from_slice(&[colour.r, colour.g, colour.b]);
+        }
+    }
+
+    image
+}
+
+fn reference_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("src/psx/rasteriser/reference")
+        .join(format!("{}.png", name))
+}
+
+fn compare(gpu: &Gpu, name: &str) {
+    let image = render(gpu);
+    let path = reference_path(name);
+
+    if env::var_os("UPDATE_REFERENCE").is_some() {
+        image::save_buffer(&path, &image, SIZE, SIZE, image::ColorType::Rgb8).unwrap();
+        return;
+    }
+
+    let reference = image::open(&path)
+        .unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+        .into_rgb8();
+    assert_eq!(reference.dimensions(), (SIZE, SIZE), "{}", name);
+
+    for (i, (&a, &b)) in image.iter().zip(reference.as_raw().iter()).enumerate() {
+        let pixel = i / 3;
+        assert!(
+            (a as i32 - b as i32).abs() <= TOLERANCE,
+            "{}: pixel ({}, {}) channel {} is {}, {} in the reference",
+            name,
+            pixel as u32 % SIZE,
+            pixel as u32 / SIZE,
+            i % 3,
+            a,
+            b
+        );
+    }
+}
+
+fn pixel(gpu: &Gpu, x: u32, y: u32) -> Colour {
+    let address = (2 * (x + 1024 * y)) as usize;
+    Colour::from_u16(LittleEndian::read_u16(&gpu.vram()[address..]))
+}
+
+#[test]
+fn flat_triangle_matches_reference() {
+    let mut gpu = new_gpu(texpage(0));
+    flat_triangle(&mut gpu, false);
+    compare(&gpu, "flat_triangle");
+}
+
+#[test]
+fn gouraud_triangle_matches_reference() {
+    let mut gpu = new_gpu(texpage(0));
+    gouraud_triangle(&mut gpu);
+    compare(&gpu, "gouraud_triangle");
+}
+
+#[test]
+fn textured_quad_matches_reference() {
+    let mut gpu = new_gpu(texpage(0));
+    textured_quad(&mut gpu, 0x2c, 0);
+    compare(&gpu, "textured_quad");
+}
+
+#[test]
+fn raw_textured_quad_matches_reference() {
+    let mut gpu = new_gpu(texpage(0));
+    textured_quad(&mut gpu, 0x2d, 0);
+    compare(&gpu, "textured_quad_raw");
+}
+
+#[test]
+fn semi_transparent_textured_quads_match_reference() {
+    let names = [
+        "textured_quad_half",
+        "textured_quad_add",
+        "textured_quad_subtract",
+        "textured_quad_add_quarter",
+    ];
+
+    for (mode, name) in names.iter().enumerate() {
+        let mut gpu = new_gpu(texpage(0));
+        gouraud_triangle(&mut gpu);
+        textured_quad(&mut gpu, 0x2e, mode as u32);
+        compare(&gpu, name);
+    }
+}
+
+#[test]
+fn semi_transparent_flat_triangles_match_reference() {
+    let names = [
+        "flat_triangle_half",
+        "flat_triangle_add",
+        "flat_triangle_subtract",
+        "flat_triangle_add_quarter",
+    ];
+
+    for (mode, name) in names.iter().enumerate() {
+        let mut gpu = new_gpu(texpage(mode as u32));
+        gouraud_triangle(&mut gpu);
+        flat_triangle(&mut gpu, true);
+        compare(&gpu, name);
+    }
+}
+
+/* Same as the references, but from the blend equations directly */
+#[test]
+fn blend_modes_follow_their_equations() {
+    let back = Colour::new(0x40, 0x40, 0x40, false);
+    let front = Colour::new(0xc0, 0x20, 0x60, false);
+
+    let equations: [fn(i32, i32) -> i32; 4] = [
+        |b, f| (b + f) / 2,
+        |b, f| b + f,
+        |b, f| b - f,
+        |b, f| b + f / 4,
+    ];
+
+    for (mode, equation) in equations.iter().enumerate() {
+        let mut gpu = new_gpu(texpage(mode as u32));
+        flat_triangle(&mut gpu, true);
+
+        let drawn = pixel(&gpu, 20, 20);
+        let expected = Colour::new(
+            equation(back.r(), front.r()).clamp(0, 255) as u8,
+            equation(back.g(), front.g()).clamp(0, 255) as u8,
+            equation(back.b(), front.b()).clamp(0, 255) as u8,
+            false,
+        );
+        assert_eq!(drawn.to_u16(), expected.to_u16(), "mode {}", mode);
+
+        let untouched = pixel(&gpu, 62, 62);
+        assert_eq!(untouched.to_u16(), back.to_u16(), "mode {}", mode);
+    }
+}
+
+/* Dithering moves a channel by less than a 5-bit step, so with it on the
+ * undithered reference still matches within the tolerance */
+#[test]
+fn dithering_stays_within_a_step_of_the_reference() {
+    let dither = 0x200;
+
+    let mut gpu = new_gpu(texpage(0) | dither);
+    gouraud_triangle(&mut gpu);
+    compare(&gpu, "gouraud_triangle");
+
+    let mut gpu = new_gpu(texpage(0) | dither);
+    textured_quad(&mut gpu, 0x2c, 0);
+    compare(&gpu, "textured_quad");
+}
+
+#[test]
+fn transparent_texels_are_skipped() {
+    let mut gpu = new_gpu(texpage(0));
+    textured_quad(&mut gpu, 0x2d, 0);
+
+    let background = Colour::new(0x40, 0x40, 0x40, false);
+    assert_eq!(pixel(&gpu, 9, 9).to_u16(), background.to_u16());
+    assert_ne!(pixel(&gpu, 30, 9).to_u16(), background.to_u16());
+}
@@ -0,0 +1,121 @@
+use std::fmt;
+
+use super::cdrom;
+
+/* Every BIOS but the very first ones ends with e.g.
+ * "System ROM Version 4.1 12/16/97 A" */
+const BIOS_VERSION_PREFIX: &[u8] = b"System ROM Version ";
+/* Sector the disc licence string is in */
+const LICENCE_LBA: usize = 4;
+const LICENCE_PREFIX: &[u8] = b"Sony Computer Entertainment ";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Region {
+    NorthAmerica,
+    Europe,
+    Japan,
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Region::NorthAmerica => "North America (NTSC-U)",
+            Region::Europe => "Europe (PAL)",
+            Region::Japan => "Japan (NTSC-J)",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BiosInfo {
+    /* e.g. "4.1" */
+    pub version: String,
+    pub date: String,
+    pub region: Option<Region>,
+}
+
+impl BiosInfo {
+    /* Read from the version string, None if there is none */
+    pub fn from_bytes(bios: &[u8]) -> Option<BiosInfo> {
+        let start = find(bios, BIOS_VERSION_PREFIX)? + BIOS_VERSION_PREFIX.len();
+        let end = bios[start..]
+            .iter()
+            .position(|byte| !byte.is_ascii_graphic() && *byte != b' ')
+            .map_or(bios.len(), |length| start + length);
+        let text = String::from_utf8_lossy(&bios[start..end]);
+        let mut fields = text.split_whitespace();
+
+        let version = fields.next()?.to_string();
+        let date = fields.next().unwrap_or("").to_string();
+        let region = match fields.next() {
+            Some("A") => Some(Region::NorthAmerica),
+            Some("E") => Some(Region::Europe),
+            Some("J") => Some(Region::Japan),
+            _ => None,
+        };
+
+        Some(BiosInfo {
+            version,
+            date,
+            region,
+        })
+    }
+
+    /* SCPH-1001, the one the TTY patch was written for */
+    pub fn is_scph1001(&self) -> bool {
+        self.version == "2.2" && self.region == Some(Region::NorthAmerica)
+    }
+}
+
+impl fmt::Display for BiosInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BIOS {} {}", self.version, self.date)?;
+
+        match self.region {
+            Some(region) => write!(f, ", {}", region),
+            None => write!(f, ", unknown region"),
+        }
+    }
+}
+
+/* From the licence string every disc carries, None if it can't be read or
+ * isn't there (e.g. a homebrew disc) */
+pub fn disc_region(game_filepath: &str) -> Option<Region> {
+    let mut sector = [0u8; 2352];
+    cdrom::read_sector(game_filepath, LICENCE_LBA, &mut sector).ok()?;
+
+    let start = find(&sector, LICENCE_PREFIX)? + LICENCE_PREFIX.len();
+
+    /* Spaced out on the disc, e.g. "Amer  ica" */
+    match &sector[start..] {
+        text if text.starts_with(b"Amer") => Some(Region::NorthAmerica),
+        text if text.starts_with(b"Euro") => Some(Region::Europe),
+        text if text.starts_with(b"Inc") => Some(Region::Japan),
+        _ => None,
+    }
+}
+
+/* A retail BIOS refuses discs of other regions, which otherwise just looks
+ * like a boot that never gets past the logo */
+pub fn region_mismatch(bios: &[u8], game_filepath: &str) -> Option<String> {
+    let bios_info = BiosInfo::from_bytes(bios)?;
+    let bios_region = bios_info.region?;
+    let disc_region = disc_region(game_filepath)?;
+
+    if bios_region == disc_region {
+        return None;
+    }
+
+    Some(format!(
+        "The disc is for {} but BIOS {} is for {}, it will likely not boot",
+        disc_region, bios_info.version, bios_region
+    ))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
@@ -0,0 +1,101 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+/* Save state taken at the end of a frame, deflated */
+struct Snapshot {
+    frame: u64,
+    data: Vec<u8>,
+}
+
+/* Save states of the last frames, oldest first, one every `interval`
+ * frames (0 only when asked to). The oldest ones are dropped to stay
+ * within `budget` bytes. */
+pub struct RewindBuffer {
+    budget: usize,
+    interval: u64,
+    snapshots: VecDeque<Snapshot>,
+    size: usize,
+}
+
+impl RewindBuffer {
+    pub fn new(budget: usize, interval: u32) -> RewindBuffer {
+        RewindBuffer {
+            budget,
+            interval: interval as u64,
+            snapshots: VecDeque::new(),
+            size: 0,
+        }
+    }
+
+    /* Whether a snapshot should be taken at the end of `frame` */
+    pub fn is_due(&self, frame: u64) -> bool {
+        self.interval > 0 && frame.is_multiple_of(self.interval)
+    }
+
+    pub fn has(&self, frame: u64) -> bool {
+        self.snapshots.back().map(|snapshot| snapshot.frame) == Some(frame)
+    }
+
+    pub fn push(&mut self, frame: u64, state: &[u8]) -> Result<(), String> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(state).map_err(|e| e.to_string())?;
+        let data = encoder.finish().map_err(|e| e.to_string())?;
+
+        self.size += data.len();
+        self.snapshots.push_back(Snapshot { frame, data });
+
+        /* The newest one stays, even over budget */
+        while self.size > self.budget && self.snapshots.len() > 1 {
+            let oldest = self.snapshots.pop_front().unwrap();
+            self.size -= oldest.data.len();
+        }
+
+        Ok(())
+    }
+
+    /* Newest snapshot at or before `frame`, with the frame it was taken at.
+     * The ones after it are dropped, they belong to a future that is about
+     * to be replaced. */
+    pub fn rewind_to(&mut self, frame: u64) -> Option<Result<(u64, Vec<u8>), String>> {
+        let index = self
+            .snapshots
+            .iter()
+            .rposition(|snapshot| snapshot.frame <= frame)?;
+
+        for snapshot in self.snapshots.drain(index + 1..) {
+            self.size -= snapshot.data.len();
+        }
+
+        let snapshot = &self.snapshots[index];
+        let mut state = Vec::new();
+
+        let result = DeflateDecoder::new(&snapshot.data[..])
+            .read_to_end(&mut state)
+            .map(|_| (snapshot.frame, state))
+            .map_err(|e| e.to_string());
+
+        Some(result)
+    }
+
+    /* Frame of the oldest snapshot, as far back as it can go */
+    pub fn oldest_frame(&self) -> Option<u64> {
+        self.snapshots.front().map(|snapshot| snapshot.frame)
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    pub fn interval(&self) -> u32 {
+        self.interval as u32
+    }
+
+    /* Bytes used, compressed */
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
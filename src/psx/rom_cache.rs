@@ -0,0 +1,94 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::SystemTime;
+
+use memmap2::Mmap;
+
+/* Every file mapped so far, shared by all the Systems in the process */
+static ROMS: Mutex<Vec<Rom>> = Mutex::new(Vec::new());
+
+/* Size and modification time, a file replaced on disk changes either */
+type Fingerprint = (u64, Option<SystemTime>);
+
+struct Rom {
+    path: PathBuf,
+    fingerprint: Fingerprint,
+    data: Arc<Mmap>,
+    /* The BIOS as the Bus runs it, see bios */
+    bios: Option<Weak<[u8]>>,
+}
+
+/* The BIOS, a game image or one of the BIN files of a .cue sheet, mapped
+ * the first time and shared from then on. New Systems, loaded states and
+ * every sector read reuse the same bytes instead of reading the file
+ * again. Mappings stay until the file changes on disk, they only take
+ * address space, the pages are the OS file cache. */
+pub fn map(path: &Path) -> Result<Arc<Mmap>, String> {
+    let mut roms = ROMS.lock().unwrap();
+
+    map_locked(&mut roms, path).map(|rom| rom.data.clone())
+}
+
+/* A copy of the file with `patch` applied, made the first time and shared
+ * by every Bus running it. The mapping can't be written to, and the Bus
+ * patches the BIOS, so it keeps a copy instead. Gone with the last Bus
+ * using it, or when the file changes on disk. */
+pub fn bios(path: &Path, patch: fn(&mut [u8])) -> Result<Arc<[u8]>, String> {
+    let mut roms = ROMS.lock().unwrap();
+    let rom = map_locked(&mut roms, path)?;
+
+    if let Some(bios) = rom.bios.as_ref().and_then(Weak::upgrade) {
+        return Ok(bios);
+    }
+
+    let mut bios = rom.data.to_vec();
+    patch(&mut bios);
+
+    let bios: Arc<[u8]> = Arc::from(bios);
+    rom.bios = Some(Arc::downgrade(&bios));
+
+    Ok(bios)
+}
+
+/* Save states carry their own BIOS. Swapped for the shared copy when it is
+ * the same one, so loading states doesn't keep a copy each. */
+pub fn share_bios(bytes: Vec<u8>) -> Arc<[u8]> {
+    let roms = ROMS.lock().unwrap();
+
+    let shared = roms
+        .iter()
+        .filter_map(|rom| rom.bios.as_ref()?.upgrade())
+        .find(|bios| **bios == *bytes);
+
+    shared.unwrap_or_else(|| Arc::from(bytes))
+}
+
+fn map_locked<'a>(roms: &'a mut Vec<Rom>, path: &Path) -> Result<&'a mut Rom, String> {
+    let error = |e: std::io::Error| format!("{}: {}", path.display(), e);
+
+    let metadata = fs::metadata(path).map_err(error)?;
+    let fingerprint = (metadata.len(), metadata.modified().ok());
+
+    if let Some(index) = roms.iter().position(|rom| rom.path == path) {
+        if roms[index].fingerprint == fingerprint {
+            return Ok(&mut roms[index]);
+        }
+    }
+
+    let file = fs::File::open(path).map_err(error)?;
+
+    /* Only sound while the file isn't truncated under the mapping. ROMs are
+     * never written to, and a replaced one gets a new mapping. */
+    let data = Arc::new(unsafe { Mmap::map(&file) }.map_err(error)?);
+
+    roms.retain(|rom| rom.path != path);
+    roms.push(Rom {
+        path: path.to_path_buf(),
+        fingerprint,
+        data,
+        bios: None,
+    });
+
+    Ok(roms.last_mut().unwrap())
+}
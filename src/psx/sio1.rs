@@ -0,0 +1,276 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use super::intc::{Intc, Interrupt};
+
+const SIO1_FIFO_SIZE: usize = 8;
+
+/* Start, 8 data and stop bits */
+const SIO1_FRAME_BITS: isize = 10;
+
+/* One direction of the cable: the bytes in flight plus the handshake lines
+ * driven by the sending end */
+#[derive(Default)]
+struct Wire {
+    bytes: VecDeque<u8>,
+    dtr: bool,
+    rts: bool,
+}
+
+/// Whatever is on the other side of the link connector. `end_frame` is
+/// called once per emulated frame, for transports that need a clock.
+pub trait LinkTransport: Send {
+    fn send(&mut self, byte: u8);
+    fn receive(&mut self) -> Option<u8>;
+    /* Our DTR and RTS outputs */
+    fn set_outputs(&mut self, dtr: bool, rts: bool);
+    /* Our DSR and CTS inputs, the other end's DTR and RTS */
+    fn inputs(&self) -> (bool, bool);
+    fn end_frame(&mut self) {}
+}
+
+/// One end of a link cable, plugged into a `System` with
+/// `System::set_link`. Both ends can live in different threads.
+pub struct LinkPort {
+    tx: Arc<Mutex<Wire>>,
+    rx: Arc<Mutex<Wire>>,
+}
+
+impl LinkTransport for LinkPort {
+    fn send(&mut self, byte: u8) {
+        self.tx.lock().unwrap().bytes.push_back(byte);
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        self.rx.lock().unwrap().bytes.pop_front()
+    }
+
+    fn set_outputs(&mut self, dtr: bool, rts: bool) {
+        let mut tx = self.tx.lock().unwrap();
+        tx.dtr = dtr;
+        tx.rts = rts;
+    }
+
+    fn inputs(&self) -> (bool, bool) {
+        let rx = self.rx.lock().unwrap();
+        (rx.dtr, rx.rts)
+    }
+}
+
+/// A cable between two in-process systems. Bytes arrive as soon as the
+/// sender finishes shifting them out, so run both systems in small slices
+/// (e.g. `run_cycles`) for protocols that expect a quick reply.
+#[allow(dead_code)]
+pub fn link_cable() -> (LinkPort, LinkPort) {
+    let a = Arc::new(Mutex::new(Wire::default()));
+    let b = Arc::new(Mutex::new(Wire::default()));
+
+    (
+        LinkPort {
+            tx: a.clone(),
+            rx: b.clone(),
+        },
+        LinkPort { tx: b, rx: a },
+    )
+}
+
+/* The serial port behind the link connector. Not part of save states, a
+ * loaded state has the port idle and unplugged */
+#[derive(Default)]
+pub struct Sio1 {
+    mode: u16,
+    control: u16,
+    baud: u16,
+
+    rx_fifo: VecDeque<u8>,
+    rx_overrun: bool,
+
+    tx_data: Option<u8>,
+    ticks_left: isize,
+
+    interrupt_request: bool,
+
+    dsr: bool,
+    cts: bool,
+
+    link: Option<Box<dyn LinkTransport>>,
+}
+
+impl Sio1 {
+    pub fn set_link(&mut self, link: Option<Box<dyn LinkTransport>>) {
+        self.link = link;
+        self.update_lines();
+    }
+
+    pub fn end_frame(&mut self) {
+        if let Some(link) = &mut self.link {
+            link.end_frame();
+        }
+    }
+
+    fn tx_enable(&self) -> bool {
+        (self.control & 0x1) != 0
+    }
+
+    fn rx_enable(&self) -> bool {
+        (self.control & 0x4) != 0
+    }
+
+    fn rx_interrupt_count(&self) -> usize {
+        1 << ((self.control & 0x300) >> 8)
+    }
+
+    fn bit_cycles(&self) -> isize {
+        let factor = match self.mode & 0x3 {
+            2 => 16,
+            3 => 64,
+            _ => 1,
+        };
+
+        cmp::max(self.baud as isize * factor, 1)
+    }
+
+    fn interrupt(&mut self, intc: &mut Intc) {
+        if !self.interrupt_request {
+            self.interrupt_request = true;
+            intc.assert_irq(Interrupt::Sio);
+        }
+    }
+
+    /* Our DTR and RTS outputs drive the other end's DSR and CTS inputs */
+    fn update_lines(&mut self) {
+        let dtr = (self.control & 0x2) != 0;
+        let rts = (self.control & 0x20) != 0;
+
+        if let Some(link) = &mut self.link {
+            link.set_outputs(dtr, rts);
+        }
+    }
+
+    pub fn tick(&mut self, intc: &mut Intc, clocks: usize) {
+        if let Some(byte) = self.tx_data {
+            self.ticks_left -= clocks as isize;
+
+            if self.ticks_left <= 0 {
+                self.tx_data = None;
+
+                /* Nobody listens without a cable */
+                if let Some(link) = &mut self.link {
+                    link.send(byte);
+                }
+
+                if (self.control & 0x400) != 0 {
+                    self.interrupt(intc);
+                }
+            }
+        }
+
+        let rx_enable = self.rx_enable();
+
+        let link = match &mut self.link {
+            Some(link) => link,
+            None => return,
+        };
+
+        let (dsr, cts) = link.inputs();
+        self.cts = cts;
+
+        if rx_enable {
+            while let Some(byte) = link.receive() {
+                if self.rx_fifo.len() < SIO1_FIFO_SIZE {
+                    self.rx_fifo.push_back(byte);
+                } else {
+                    self.rx_overrun = true;
+                }
+            }
+        }
+
+        if self.rx_fifo.len() >= self.rx_interrupt_count() && (self.control & 0x800) != 0 {
+            self.interrupt(intc);
+        }
+
+        if dsr && !self.dsr && (self.control & 0x1000) != 0 {
+            self.interrupt(intc);
+        }
+
+        self.dsr = dsr;
+    }
+
+    pub fn rx_data(&mut self) -> u32 {
+        self.rx_fifo.pop_front().unwrap_or(0) as u32
+    }
+
+    pub fn tx_data(&mut self, value: u32) {
+        if !self.tx_enable() {
+            return;
+        }
+
+        if self.tx_data.is_some() {
+            println!("[SIO1] [WARN] TX data written while busy");
+        }
+
+        self.tx_data = Some(value as u8);
+        self.ticks_left = self.bit_cycles() * SIO1_FRAME_BITS;
+    }
+
+    pub fn status(&self) -> u32 {
+        let mut value = 0;
+
+        value |= (self.interrupt_request as u32) << 9;
+        value |= (self.cts as u32) << 8;
+        value |= (self.dsr as u32) << 7;
+        value |= (self.rx_overrun as u32) << 4;
+        value |= (self.tx_data.is_none() as u32) << 2;
+        value |= (!self.rx_fifo.is_empty() as u32) << 1;
+        value |= self.tx_data.is_none() as u32;
+
+        value
+    }
+
+    pub fn read_mode(&self) -> u32 {
+        self.mode as u32
+    }
+
+    pub fn write_mode(&mut self, value: u16) {
+        self.mode = value;
+    }
+
+    pub fn read_control(&self) -> u32 {
+        self.control as u32
+    }
+
+    pub fn write_control(&mut self, value: u16) {
+        if (value & 0x40) != 0 {
+            self.mode = 0;
+            self.control = 0;
+            self.baud = 0;
+
+            self.rx_fifo.clear();
+            self.rx_overrun = false;
+            self.tx_data = None;
+            self.interrupt_request = false;
+
+            self.update_lines();
+            return;
+        }
+
+        /* Acknowledge, the bit itself is not kept */
+        if (value & 0x10) != 0 {
+            self.interrupt_request = false;
+            self.rx_overrun = false;
+        }
+
+        self.control = value & !0x50;
+
+        self.update_lines();
+    }
+
+    pub fn read_baud(&self) -> u32 {
+        self.baud as u32
+    }
+
+    pub fn write_baud(&mut self, value: u16) {
+        self.baud = value;
+    }
+}
@@ -0,0 +1,81 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+const FRAME_DURATION: Duration = Duration::from_micros(16_667);
+
+const SAMPLE_RATE: usize = 44100;
+/* Two frames worth of stereo samples waiting in the output */
+const AUDIO_TARGET_LEVEL: usize = 2 * 2 * SAMPLE_RATE / 60;
+/* Max pitch change when resampling to track the target level */
+const AUDIO_MAX_ADJUSTMENT: f64 = 0.005;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpeedMode {
+    Unlimited,
+    Video,
+    Audio,
+    Percentage(u32),
+}
+
+pub struct SpeedController {
+    mode: SpeedMode,
+    last_frame: Instant,
+}
+
+impl SpeedController {
+    pub fn new(mode: SpeedMode) -> SpeedController {
+        SpeedController {
+            mode,
+            last_frame: Instant::now(),
+        }
+    }
+
+    pub fn mode(&self) -> SpeedMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: SpeedMode) {
+        self.mode = mode;
+        self.last_frame = Instant::now();
+    }
+
+    /* Call once per emulated frame. `queued_samples` is how many samples the
+     * audio output still has to play, only used in audio mode. */
+    pub fn regulate(&mut self, queued_samples: usize) {
+        let target = match self.mode {
+            SpeedMode::Unlimited => None,
+            SpeedMode::Video => Some(FRAME_DURATION),
+            SpeedMode::Audio => {
+                /* Only hold back once the output has more than it needs */
+                let excess = queued_samples.saturating_sub(AUDIO_TARGET_LEVEL) / 2;
+                Some(Duration::from_secs_f64(excess as f64 / SAMPLE_RATE as f64))
+            }
+            SpeedMode::Percentage(0) => None,
+            SpeedMode::Percentage(percentage) => Some(FRAME_DURATION * 100 / percentage),
+        };
+
+        if let Some(target) = target {
+            let elapsed = self.last_frame.elapsed();
+            if self.mode == SpeedMode::Audio {
+                thread::sleep(target);
+            } else if elapsed < target {
+                thread::sleep(target - elapsed);
+            }
+        }
+
+        self.last_frame = Instant::now();
+    }
+
+    /* Output/input sample ratio for the audio output to resample by, so the
+     * buffer level drifts back to the target instead of under/overrunning.
+     * Only in real time modes, otherwise the level is never near it. */
+    pub fn resample_ratio(&self, queued_samples: usize) -> f64 {
+        if !matches!(self.mode, SpeedMode::Video | SpeedMode::Audio) {
+            return 1.0;
+        }
+
+        let error = (queued_samples as f64 - AUDIO_TARGET_LEVEL as f64) / AUDIO_TARGET_LEVEL as f64;
+
+        1.0 - error.clamp(-1.0, 1.0) * AUDIO_MAX_ADJUSTMENT
+    }
+}
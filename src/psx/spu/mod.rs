@@ -24,12 +24,7 @@ const SPU_WORD_SIZE: usize = 2;
 
 const SPU_NR_VOICES: usize = 24;
 
-const NOISE_WAVE_TABLE: [isize; 64] = [
-    1, 0, 0, 1, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0,
-    0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0, 0, 1, 0, 1, 1, 0, 1, 0, 0, 1,
-];
-
-const NOISE_FREQ_TABLE: [isize; 5] = [0, 84, 140, 180, 210];
+const NOISE_TIMER_PERIOD: isize = 0x20000;
 
 #[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
 enum SpuTransferMode {
@@ -216,6 +211,13 @@ pub struct Spu {
     cd_volume: Volume,
     extern_volume: Volume,
     current_volume: Volume,
+
+    /* Skips reverb processing, for when nobody is listening */
+    #[serde(skip)]
+    reverb_bypass: bool,
+    /* Same for all the mixing, timing and IRQs still run */
+    #[serde(skip)]
+    mix_bypass: bool,
 }
 
 impl Spu {
@@ -262,9 +264,25 @@ impl Spu {
             cd_volume: Volume::default(),
             extern_volume: Volume::default(),
             current_volume: Volume::default(),
+
+            reverb_bypass: false,
+            mix_bypass: false,
         }
     }
 
+    pub fn set_reverb_bypass(&mut self, bypass: bool) {
+        self.reverb_bypass = bypass;
+    }
+
+    pub fn set_mix_bypass(&mut self, bypass: bool) {
+        self.mix_bypass = bypass;
+    }
+
+    pub fn keep_host_state(&mut self, old: &Spu) {
+        self.reverb_bypass = old.reverb_bypass;
+        self.mix_bypass = old.mix_bypass;
+    }
+
     fn update_key_on(&mut self) {
         for i in 0..24 {
             if (self.key_on & (1 << i)) != 0 {
@@ -288,9 +306,7 @@ impl Spu {
 
     fn update_echo(&mut self) {
         for i in 0..24 {
-            if (self.echo_on & (1 << i)) != 0 {
-                self.voice[i].echo_on();
-            }
+            self.voice[i].set_reverb((self.echo_on & (1 << i)) != 0);
         }
     }
 
@@ -307,24 +323,23 @@ impl Spu {
             self.voice[i].set_noise((self.noise_on & (1 << i)) != 0);
         }
 
-        let noise_clock = (self.control.noise_clock & 0x3) as usize;
+        let step = (self.control.noise_clock & 0x3) as isize + 4;
+        let shift = (self.control.noise_clock >> 2) & 0xf;
 
-        let mut level = 0x8000 >> (self.control.noise_clock >> 2);
-        level <<= 16;
+        self.noise_timer -= step;
 
-        self.noise_timer += 0x10000;
-        self.noise_timer += NOISE_FREQ_TABLE[noise_clock];
+        if self.noise_timer < 0 {
+            let level = self.noise_level as u16;
+            let parity = ((level >> 15) ^ (level >> 12) ^ (level >> 11) ^ (level >> 10) ^ 1) & 1;
 
-        if (self.noise_timer & 0xffff) >= NOISE_FREQ_TABLE[4] {
-            self.noise_timer += 0x10000;
-            self.noise_timer -= NOISE_FREQ_TABLE[noise_clock];
-        }
+            self.noise_level = ((level << 1) | parity) as i16;
 
-        if self.noise_timer >= level {
-            self.noise_timer %= level;
+            /* Twice at most, the lowest shifts are faster than the step */
+            self.noise_timer += NOISE_TIMER_PERIOD >> shift;
 
-            let bit = NOISE_WAVE_TABLE[((self.noise_level >> 10) & 0x3f) as usize] as i16;
-            self.noise_level = (self.noise_level << 1) | bit;
+            if self.noise_timer < 0 {
+                self.noise_timer += NOISE_TIMER_PERIOD >> shift;
+            }
         }
     }
 
@@ -355,6 +370,7 @@ impl Spu {
 
         let mut modulator = 0;
         let noise_level = i16_to_f32(self.noise_level);
+        let mix = cfg!(feature = "spu") && !self.mix_bypass;
 
         for i in 0..self.voice.len() {
             let voice = &mut self.voice[i];
@@ -362,10 +378,12 @@ impl Spu {
             let noise = (self.noise_on & (1 << i)) != 0;
 
             if voice.disabled() {
+                /* A silent voice modulates the next one with 0 */
+                modulator = 0;
                 continue;
             }
 
-            let (sample_left, sample_right) = voice.get_samples(noise, noise_level);
+            let (sample_left, sample_right) = voice.get_samples(noise, noise_level, mix);
 
             left += sample_left;
             right += sample_right;
@@ -383,7 +401,9 @@ impl Spu {
         left *= self.main_volume.l();
         right *= self.main_volume.r();
 
-        if self.control.reverb_enable {
+        let reverb = mix && self.control.reverb_enable && !self.reverb_bypass;
+
+        if reverb {
             left += self.reverb.output_l() * self.reverb_volume.l();
             right += self.reverb.output_r() * self.reverb_volume.r();
         }
@@ -404,7 +424,7 @@ impl Spu {
         reverb_in_left = clip(reverb_in_left, -1.0, 1.0);
         reverb_in_right = clip(reverb_in_right, -1.0, 1.0);
 
-        if self.control.reverb_enable {
+        if reverb {
             self.reverb
                 .calculate(&mut self.sound_ram, [reverb_in_left, reverb_in_right]);
         }
@@ -427,8 +447,10 @@ impl Spu {
         }
 
         /* TODO: Maybe ringbuffer? */
-        self.output_buffer.push(f32_to_i16(left));
-        self.output_buffer.push(f32_to_i16(right));
+        if mix {
+            self.output_buffer.push(f32_to_i16(left));
+            self.output_buffer.push(f32_to_i16(right));
+        }
     }
 
     pub fn drain_samples(&mut self) -> Vec<i16> {
@@ -507,15 +529,18 @@ impl Spu {
             0x1f801dba => self.current_volume.right as u16,
             0x1f801dc0..=0x1f801dff => self.reverb.read16(address),
             0x1f801e00..=0x1f801fff => 0xffff,
-            _ => panic!(
-                "[SPU] [ERROR] Read from unimplemented register: 0x{:08x}",
-                address
-            ),
+            _ => {
+                println!(
+                    "[SPU] [ERROR] Read from unimplemented register: 0x{:08x}",
+                    address
+                );
+                0
+            }
         }
     }
 
     pub fn read32(&mut self, address: u32) -> u32 {
-        ((self.read16(address + 1) as u32) << 16) | self.read16(address) as u32
+        ((self.read16(address + 2) as u32) << 16) | self.read16(address) as u32
     }
 
     pub fn write16(&mut self, address: u32, value: u16) {
@@ -606,7 +631,7 @@ impl Spu {
             0x1f801db8 => self.current_volume.left = value as i16,
             0x1f801dba => self.current_volume.right = value as i16,
             0x1f801dc0..=0x1f801dff => self.reverb.write16(address, value),
-            _ => panic!(
+            _ => println!(
                 "[SPU] [ERROR] Write to unimplemented register: 0x{:08x}",
                 address
             ),
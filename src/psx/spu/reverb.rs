@@ -56,16 +56,16 @@ impl Reverb {
         for i in 0..2 {
             let mut msame = input[i] * i16_to_f32(self.vin[i]);
             msame += self.read(ram, self.dsame[i]) * i16_to_f32(self.vwall);
-            msame -= self.read(ram, self.msame[i] - 2);
+            msame -= self.read(ram, self.msame[i].wrapping_sub(2));
             msame *= i16_to_f32(self.viir);
-            msame += self.read(ram, self.msame[i] - 2);
+            msame += self.read(ram, self.msame[i].wrapping_sub(2));
             self.write(ram, self.msame[i], msame);
 
             let mut mdiff = input[i] * i16_to_f32(self.vin[i]);
             mdiff += self.read(ram, self.ddiff[1 - i]) * i16_to_f32(self.vwall);
-            mdiff -= self.read(ram, self.mdiff[i] - 2);
+            mdiff -= self.read(ram, self.mdiff[i].wrapping_sub(2));
             mdiff *= i16_to_f32(self.viir);
-            mdiff += self.read(ram, self.mdiff[i] - 2);
+            mdiff += self.read(ram, self.mdiff[i].wrapping_sub(2));
             self.write(ram, self.mdiff[i], mdiff);
 
             self.output[i] = i16_to_f32(self.vcomb1) * self.read(ram, self.mcomb1[i]);
@@ -73,15 +73,17 @@ impl Reverb {
             self.output[i] += i16_to_f32(self.vcomb3) * self.read(ram, self.mcomb3[i]);
             self.output[i] += i16_to_f32(self.vcomb4) * self.read(ram, self.mcomb4[i]);
 
-            self.output[i] -= i16_to_f32(self.vapf1) * self.read(ram, self.mapf1[i] - self.dapf1);
+            self.output[i] -=
+                i16_to_f32(self.vapf1) * self.read(ram, self.mapf1[i].wrapping_sub(self.dapf1));
             self.write(ram, self.mapf1[i], self.output[i]);
             self.output[i] = self.output[i] * i16_to_f32(self.vapf1)
-                + self.read(ram, self.mapf1[i] - self.dapf1);
+                + self.read(ram, self.mapf1[i].wrapping_sub(self.dapf1));
 
-            self.output[i] -= i16_to_f32(self.vapf2) * self.read(ram, self.mapf2[i] - self.dapf2);
+            self.output[i] -=
+                i16_to_f32(self.vapf2) * self.read(ram, self.mapf2[i].wrapping_sub(self.dapf2));
             self.write(ram, self.mapf2[i], self.output[i]);
             self.output[i] = self.output[i] * i16_to_f32(self.vapf2)
-                + self.read(ram, self.mapf2[i] - self.dapf2);
+                + self.read(ram, self.mapf2[i].wrapping_sub(self.dapf2));
         }
 
         self.buffer_address = cmp::max(self.mbase, (self.buffer_address + 2) & 0x7fffe);
@@ -97,11 +99,16 @@ impl Reverb {
         ram.memory_write16(self.calc_addr(address), sample as u16);
     }
 
+    /* Addresses are relative to the current buffer address and wrap
+     * around to the start of the work area, not to 0 */
     fn calc_addr(&self, address: u32) -> u32 {
-        let mut offset = self.buffer_address + address - self.mbase;
-        offset %= 0x80000 - self.mbase;
+        let mut address = self.buffer_address + (address & 0x7fffe);
 
-        (self.mbase + offset) & 0x7fffe
+        if address >= 0x80000 {
+            address = address - 0x80000 + self.mbase;
+        }
+
+        address & 0x7fffe
     }
 
     pub fn output_l(&self) -> f32 {
@@ -123,10 +130,45 @@ impl Reverb {
 
     pub fn read16(&self, address: u32) -> u16 {
         match address {
-            _ => panic!(
-                "[SPU] [ERROR] Read from invalid reverb register: 0x{:08x}",
-                address
-            ),
+            0x1f801dc0 => (self.dapf1 / 8) as u16,
+            0x1f801dc2 => (self.dapf2 / 8) as u16,
+            0x1f801dc4 => self.viir as u16,
+            0x1f801dc6 => self.vcomb1 as u16,
+            0x1f801dc8 => self.vcomb2 as u16,
+            0x1f801dca => self.vcomb3 as u16,
+            0x1f801dcc => self.vcomb4 as u16,
+            0x1f801dce => self.vwall as u16,
+            0x1f801dd0 => self.vapf1 as u16,
+            0x1f801dd2 => self.vapf2 as u16,
+            0x1f801dd4 => (self.msame[0] / 8) as u16,
+            0x1f801dd6 => (self.msame[1] / 8) as u16,
+            0x1f801dd8 => (self.mcomb1[0] / 8) as u16,
+            0x1f801dda => (self.mcomb1[1] / 8) as u16,
+            0x1f801ddc => (self.mcomb2[0] / 8) as u16,
+            0x1f801dde => (self.mcomb2[1] / 8) as u16,
+            0x1f801de0 => (self.dsame[0] / 8) as u16,
+            0x1f801de2 => (self.dsame[1] / 8) as u16,
+            0x1f801de4 => (self.mdiff[0] / 8) as u16,
+            0x1f801de6 => (self.mdiff[1] / 8) as u16,
+            0x1f801de8 => (self.mcomb3[0] / 8) as u16,
+            0x1f801dea => (self.mcomb3[1] / 8) as u16,
+            0x1f801dec => (self.mcomb4[0] / 8) as u16,
+            0x1f801dee => (self.mcomb4[1] / 8) as u16,
+            0x1f801df0 => (self.ddiff[0] / 8) as u16,
+            0x1f801df2 => (self.ddiff[1] / 8) as u16,
+            0x1f801df4 => (self.mapf1[0] / 8) as u16,
+            0x1f801df6 => (self.mapf1[1] / 8) as u16,
+            0x1f801df8 => (self.mapf2[0] / 8) as u16,
+            0x1f801dfa => (self.mapf2[1] / 8) as u16,
+            0x1f801dfc => self.vin[0] as u16,
+            0x1f801dfe => self.vin[1] as u16,
+            _ => {
+                println!(
+                    "[SPU] [ERROR] Read from invalid reverb register: 0x{:08x}",
+                    address
+                );
+                0
+            }
         }
     }
 
@@ -164,7 +206,7 @@ impl Reverb {
             0x1f801dfa => self.mapf2[1] = (value as u32) * 8,
             0x1f801dfc => self.vin[0] = value as i16,
             0x1f801dfe => self.vin[1] = value as i16,
-            _ => panic!(
+            _ => println!(
                 "[SPU] [ERROR] Write to invalid reverb register: 0x{:08x}",
                 address
             ),
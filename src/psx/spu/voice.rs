@@ -112,8 +112,8 @@ impl Voice {
         self.noise = state;
     }
 
-    pub fn echo_on(&mut self) {
-        self.reverb = true;
+    pub fn set_reverb(&mut self, state: bool) {
+        self.reverb = state;
     }
 
     fn sample_index(&self) -> usize {
@@ -149,11 +149,18 @@ impl Voice {
         i16_to_f32(out as i16)
     }
 
-    pub fn get_samples(&mut self, noise: bool, noise_level: f32) -> (f32, f32) {
+    pub fn get_samples(&mut self, noise: bool, noise_level: f32, mix: bool) -> (f32, f32) {
         let index = self.sample_index();
 
         self.adsr.update();
 
+        /* Timing only (see the spu feature and the fast path), a silent
+         * voice modulates with 0 */
+        if !cfg!(feature = "spu") || !mix {
+            self.modulator = 0;
+            return (0.0, 0.0);
+        }
+
         let mut sample;
 
         if noise {
@@ -239,16 +246,13 @@ impl Voice {
         let mut step = self.pitch as u32;
 
         if modulate {
-            let factor = (modulator as i32 + 0x8000) as u32;
-            step = step as i16 as u32;
-            step = (step * factor) >> 15;
+            let factor = modulator as i32 + 0x8000;
+            step = (((step as i16 as i32) * factor) >> 15) as u32;
             step &= 0xffff;
         }
 
         self.counter += cmp::min(step, 0x4000) as usize;
 
-        self.reverb = false;
-
         if self.sample_index() >= NR_SAMPLES {
             self.update_sample_index();
 
@@ -273,10 +277,13 @@ impl Voice {
             0xa => (self.adsr.config >> 16) as u16,
             0xc => self.adsr.volume as u16,
             0xe => (self.repeat_address / 8) as u16,
-            _ => panic!(
-                "[SPU] [ERROR] Read from invalid voice register: 0x{:x}",
-                offset
-            ),
+            _ => {
+                println!(
+                    "[SPU] [ERROR] Read from invalid voice register: 0x{:x}",
+                    offset
+                );
+                0
+            }
         }
     }
 
@@ -313,7 +320,7 @@ impl Voice {
                 self.repeat_address = (value as u32) * 8;
                 self.repeat_address_written = true;
             }
-            _ => panic!(
+            _ => println!(
                 "[SPU] [ERROR] Write to invalid voice register: 0x{:x}",
                 offset
             ),
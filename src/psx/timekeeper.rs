@@ -2,11 +2,16 @@ use super::bus::Bus;
 
 use serde::{Deserialize, Serialize};
 
+use std::time::Duration;
+
 const DEVICE_COUNT: usize = 5;
 const DEVICE_GRANULARITY: [u64; DEVICE_COUNT] = [7, 8448, 8448, 11, 11];
 
 const DMAC_GRANULARITY: u64 = 11;
 
+/* CPU cycles per emulated second */
+const CPU_FREQUENCY: u64 = 33_868_800;
+
 #[derive(Clone, Copy)]
 pub enum Device {
     Gpu,
@@ -85,6 +90,18 @@ impl Timekeeper {
         cycles as usize
     }
 
+    /* CPU cycles since reset */
+    pub fn cycles(&self) -> u64 {
+        self.now / 11
+    }
+
+    /* Emulated time since reset */
+    pub fn time(&self) -> Duration {
+        let cycles = self.cycles();
+        let nanos = (cycles % CPU_FREQUENCY) * 1_000_000_000 / CPU_FREQUENCY;
+        Duration::new(cycles / CPU_FREQUENCY, nanos as u32)
+    }
+
     pub fn elapsed(&self) -> u64 {
         (self.now - self.last_sync) / 11
     }
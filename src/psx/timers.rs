@@ -129,10 +129,13 @@ impl Timers {
                 mode
             }
             8 => counter.target & 0xffff,
-            _ => panic!(
-                "[TIMER] [ERROR] Read from unrecognised address {:#x}",
-                address
-            ),
+            _ => {
+                println!(
+                    "[TIMER] [ERROR] Read from unrecognised address {:#x}",
+                    address
+                );
+                0
+            }
         }
     }
 
@@ -166,8 +169,8 @@ impl Timers {
                 }
             }
             8 => counter.target = value & 0xffff,
-            _ => panic!(
-                "[TIMER] [ERROR] Read from unrecognised address {:#x}",
+            _ => println!(
+                "[TIMER] [ERROR] Write to unrecognised address {:#x}",
                 address
             ),
         }
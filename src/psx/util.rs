@@ -2,9 +2,13 @@ use std::cmp;
 use std::fs::File;
 use std::io::{self, Read};
 use std::path::Path;
+use std::sync::Arc;
 
 use byteorder::{ByteOrder, LittleEndian};
 
+use super::bus::BusWidth;
+use super::rom_cache;
+
 pub fn bcd_to_u8(value: u8) -> u8 {
     ((value >> 4) * 10) + (value & 0xf)
 }
@@ -51,6 +55,33 @@ pub fn sign_extend_i32(value: i32, size: usize) -> i32 {
     return value;
 }
 
+/// Extracts the byte or halfword lane addressed by `address` from a 32-bit
+/// register value.
+pub fn read_sub_word(word: u32, address: u32, width: &BusWidth) -> u32 {
+    let shift = (address & 0x3) * 8;
+
+    match width {
+        BusWidth::BYTE => (word >> shift) & 0xff,
+        BusWidth::HALF => (word >> (shift & 0x10)) & 0xffff,
+        BusWidth::WORD => word,
+    }
+}
+
+/// Merges a byte or halfword store into the lane addressed by `address`,
+/// leaving the rest of `current` untouched.
+pub fn write_sub_word(current: u32, value: u32, address: u32, width: &BusWidth) -> u32 {
+    let shift = (address & 0x3) * 8;
+
+    match width {
+        BusWidth::BYTE => (current & !(0xff << shift)) | ((value & 0xff) << shift),
+        BusWidth::HALF => {
+            let shift = shift & 0x10;
+            (current & !(0xffff << shift)) | ((value & 0xffff) << shift)
+        }
+        BusWidth::WORD => value,
+    }
+}
+
 pub fn clip<T: PartialOrd>(value: T, min: T, max: T) -> T {
     if value <= min {
         return min;
@@ -71,19 +102,27 @@ pub fn max3<T: Ord>(a: T, b: T, c: T) -> T {
     cmp::max(a, cmp::max(b, c))
 }
 
-pub fn read_file_to_box(filepath: &str) -> Box<[u8]> {
+/* 64-bit FNV-1a, stable across builds unlike std's hasher so hashes can
+ * be compared between runs */
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/* The patched copy the ROM cache shares, so only the first read goes to
+ * disk and the first patch makes a copy */
+pub fn read_bios(filepath: &str, patch: fn(&mut [u8])) -> Arc<[u8]> {
     let path = Path::new(filepath);
 
     if !path.is_file() {
         panic!("ERROR: file does not exist: {}", path.display())
     }
 
-    let mut file = File::open(path).unwrap();
-    let mut file_buffer = Vec::new();
-
-    file.read_to_end(&mut file_buffer).unwrap();
-
-    file_buffer.into_boxed_slice()
+    match rom_cache::bios(path, patch) {
+        Ok(bios) => bios,
+        Err(e) => panic!("ERROR: {}", e),
+    }
 }
 
 pub fn discard(file: &mut File, size: usize) -> io::Result<()> {
@@ -107,3 +146,65 @@ pub fn read_u8(file: &mut File) -> io::Result<u8> {
 pub fn read_u32(file: &mut File) -> io::Result<u32> {
     Ok(LittleEndian::read_u32(&read_to_buffer(file, 4)?))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORD: u32 = 0x4433_2211;
+
+    #[test]
+    fn read_sub_word_every_lane() {
+        for offset in 0..4 {
+            let byte = (0x11 * (offset + 1)) & 0xff;
+            assert_eq!(read_sub_word(WORD, offset, &BusWidth::BYTE), byte);
+
+            let half = match offset & 0x2 {
+                0 => 0x2211,
+                _ => 0x4433,
+            };
+            assert_eq!(read_sub_word(WORD, offset, &BusWidth::HALF), half);
+
+            assert_eq!(read_sub_word(WORD, offset, &BusWidth::WORD), WORD);
+        }
+    }
+
+    #[test]
+    fn read_sub_word_ignores_the_region() {
+        for base in [0x1f80_1070, 0x9f80_1070, 0xbf80_1070] {
+            assert_eq!(read_sub_word(WORD, base + 2, &BusWidth::HALF), 0x4433);
+            assert_eq!(read_sub_word(WORD, base + 3, &BusWidth::BYTE), 0x44);
+        }
+    }
+
+    #[test]
+    fn write_sub_word_every_lane() {
+        for offset in 0..4 {
+            let shift = offset * 8;
+            let written = write_sub_word(WORD, 0xffff_ffaa, offset, &BusWidth::BYTE);
+            assert_eq!(written, (WORD & !(0xff << shift)) | (0xaa << shift));
+
+            let shift = (offset & 0x2) * 8;
+            let written = write_sub_word(WORD, 0xffff_bbaa, offset, &BusWidth::HALF);
+            assert_eq!(written, (WORD & !(0xffff << shift)) | (0xbbaa << shift));
+
+            let written = write_sub_word(WORD, 0xdead_beef, offset, &BusWidth::WORD);
+            assert_eq!(written, 0xdead_beef);
+        }
+    }
+
+    #[test]
+    fn write_then_read_sub_word_round_trips() {
+        for offset in 0..4 {
+            for width in [BusWidth::BYTE, BusWidth::HALF, BusWidth::WORD] {
+                let written = write_sub_word(0, 0x8765_4321, offset, &width);
+                let expected = match width {
+                    BusWidth::BYTE => 0x21,
+                    BusWidth::HALF => 0x4321,
+                    BusWidth::WORD => 0x8765_4321,
+                };
+                assert_eq!(read_sub_word(written, offset, &width), expected);
+            }
+        }
+    }
+}
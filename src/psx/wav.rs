@@ -0,0 +1,80 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+const SAMPLE_RATE: u32 = 44100;
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+
+const HEADER_SIZE: u32 = 44;
+
+/* 16-bit stereo PCM at the SPU rate. The sizes in the header are fixed
+ * up on finish(), or on drop if nobody checks the result */
+pub struct WavWriter {
+    file: BufWriter<File>,
+    data_size: u32,
+}
+
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<WavWriter> {
+        let mut writer = WavWriter {
+            file: BufWriter::new(File::create(path)?),
+            data_size: 0,
+        };
+
+        writer.write_header()?;
+
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+        let file = &mut self.file;
+
+        file.write_all(b"RIFF")?;
+        file.write_u32::<LittleEndian>(HEADER_SIZE - 8 + self.data_size)?;
+        file.write_all(b"WAVE")?;
+
+        file.write_all(b"fmt ")?;
+        file.write_u32::<LittleEndian>(16)?;
+        file.write_u16::<LittleEndian>(1)?; /* PCM */
+        file.write_u16::<LittleEndian>(CHANNELS)?;
+        file.write_u32::<LittleEndian>(SAMPLE_RATE)?;
+        file.write_u32::<LittleEndian>(SAMPLE_RATE * block_align as u32)?;
+        file.write_u16::<LittleEndian>(block_align)?;
+        file.write_u16::<LittleEndian>(BITS_PER_SAMPLE)?;
+
+        file.write_all(b"data")?;
+        file.write_u32::<LittleEndian>(self.data_size)
+    }
+
+    /* Interleaved left/right samples, as drained from the SPU */
+    pub fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            self.file.write_i16::<LittleEndian>(*sample)?;
+        }
+
+        self.data_size += (samples.len() * 2) as u32;
+
+        Ok(())
+    }
+
+    fn update_header(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.update_header()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        let _ = self.update_header();
+    }
+}
@@ -0,0 +1,174 @@
+// Dojo Learning Environment
+// Copyright (C) 2023-2024 Carlos Perez-Lopez
+//
+// This project is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>
+//
+// You can contact the author via carlospzlz@gmail.com
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::BTreeMap;
+use std::env;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::sync::Mutex;
+
+// Emu system
+#[allow(dead_code)]
+mod psx;
+
+use psx::bus::{BusWidth, UnmappedAccess};
+use psx::System;
+
+// I/O ports, every address is tried there with every width
+const IO_START: u32 = 0x1f80_1000;
+const IO_END: u32 = 0x1f80_2080;
+const DEFAULT_ACCESSES: u64 = 1_000_000;
+
+// Where the last panic happened, the hook is the only one who knows
+static PANIC_LOCATION: Mutex<String> = Mutex::new(String::new());
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Load,
+    Store,
+}
+
+// First access that panicked at a given place, and how many did
+struct Report {
+    kind: Kind,
+    width: u8,
+    address: u32,
+    message: String,
+    count: u64,
+}
+
+// Random loads and stores of every width over the whole physical address
+// space, and all of the I/O ports, with unmapped accesses ignored. Every
+// place in the cores that panics is reported once, with the first access
+// that got there: none should, long unattended runs depend on it.
+fn main() {
+    env_logger::init();
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        eprintln!("Usage: {} <bios> <game> [accesses] [seed]", args[0]);
+        process::exit(2);
+    }
+    let accesses = args
+        .get(3)
+        .and_then(|accesses| accesses.parse().ok())
+        .unwrap_or(DEFAULT_ACCESSES);
+    let seed = args
+        .get(4)
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or_else(rand::random);
+    let new_system = || match System::try_new(&args[1], &args[2]) {
+        Ok(mut system) => {
+            system.set_unmapped_access(UnmappedAccess::Ignore);
+            system
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+
+    println!("Seed {}", seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut system = new_system();
+    // The default hook would print every one of them
+    panic::set_hook(Box::new(|info| {
+        if let Some(location) = info.location() {
+            *PANIC_LOCATION.lock().unwrap() = location.to_string();
+        }
+    }));
+    let mut panics: BTreeMap<String, Report> = BTreeMap::new();
+
+    let io_accesses = (IO_START..IO_END).flat_map(|address| {
+        [Kind::Load, Kind::Store]
+            .into_iter()
+            .flat_map(move |kind| [1, 2, 4].map(|width| (kind, width, address)))
+    });
+    let random_accesses = (0..accesses).map(|_| {
+        let kind = match rng.gen::<bool>() {
+            true => Kind::Load,
+            false => Kind::Store,
+        };
+        let width = [1, 2, 4][rng.gen_range(0..3)];
+        (kind, width, rng.gen::<u32>())
+    });
+    let all: Vec<(Kind, u8, u32)> = io_accesses.chain(random_accesses).collect();
+
+    for (kind, width, address) in all {
+        // Misaligned ones raise an address error in the CPU, never get here
+        let address = address & !(width as u32 - 1);
+        let value = rand::random();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| match kind {
+            Kind::Load => {
+                system.bus_load(bus_width(width), address);
+            }
+            Kind::Store => {
+                system.bus_store(bus_width(width), address, value);
+            }
+        }));
+        if let Err(payload) = result {
+            let message = payload
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_default();
+            let location = PANIC_LOCATION.lock().unwrap().clone();
+            panics
+                .entry(location)
+                .or_insert(Report {
+                    kind,
+                    width,
+                    address,
+                    message,
+                    count: 0,
+                })
+                .count += 1;
+            // Whatever panicked may be left half way through
+            system = new_system();
+        }
+    }
+
+    let _ = panic::take_hook();
+    if panics.is_empty() {
+        println!("No panics");
+        return;
+    }
+    let mut total = 0;
+    for (location, report) in panics.iter() {
+        let kind = match report.kind {
+            Kind::Load => "load",
+            Kind::Store => "store",
+        };
+        println!(
+            "{}: {} ({} accesses, first {} {} bytes at 0x{:08x})",
+            location, report.message, report.count, kind, report.width, report.address
+        );
+        total += report.count;
+    }
+    println!("{} accesses panicked", total);
+    process::exit(1);
+}
+
+fn bus_width(width: u8) -> BusWidth {
+    match width {
+        1 => BusWidth::BYTE,
+        2 => BusWidth::HALF,
+        _ => BusWidth::WORD,
+    }
+}
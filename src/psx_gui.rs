@@ -18,19 +18,41 @@
 
 use egui::{Color32, ColorImage, RichText, Vec2};
 use egui_file::FileDialog;
-use image::{Rgb, RgbImage};
+use image::{ImageBuffer, Rgb};
 use log::error;
 use std::env;
 use std::fs;
-use std::fs::File;
-use std::io::Read;
-use std::io::Write;
 use std::path::{Path, PathBuf};
 
 // Emu system
 mod psx;
 
-use psx::System;
+// SPU output, only with the audio feature
+#[cfg_attr(not(feature = "audio"), allow(dead_code))]
+mod audio;
+mod breakpoints;
+mod cpu_log;
+mod file_prompt;
+mod input_editor;
+mod ram_diff;
+
+use audio::AudioOutput;
+use breakpoints::Breakpoints;
+use cpu_log::CpuLog;
+use file_prompt::{FilePrompt, PromptAction};
+use input_editor::{InputEditor, InputHistory, BUTTON_NAMES};
+use psx::speed::{SpeedController, SpeedMode};
+use psx::{Event, FileKind, InterlaceMode, System};
+use ram_diff::RamDiff;
+
+const GPU_CAPTURE_PATH: &str = "gpu_capture.bin";
+const AUDIO_CAPTURE_PATH: &str = "audio_capture.wav";
+const INPUT_MOVIE_PATH: &str = "input_movie.bin";
+// Compressed states kept to rewind, one every REWIND_INTERVAL frames
+const REWIND_BUDGET: usize = 64 << 20;
+const REWIND_INTERVAL: u32 = 10;
+// How far back the Rewind button goes, a second
+const REWIND_FRAMES: u64 = 60;
 
 fn main() -> Result<(), eframe::Error> {
     env_logger::init(); // Log to stderr (if you run with `RUST_LOG=debug`)
@@ -40,88 +62,199 @@ fn main() -> Result<(), eframe::Error> {
         return Ok(());
     }
     let options = eframe::NativeOptions {
-        initial_window_size: Some(egui::vec2(480.0, 460.0)),
+        initial_window_size: Some(egui::vec2(730.0, 460.0)),
         ..Default::default()
     };
     eframe::run_native(
         "PSX GUI",
         options,
-        Box::new(move |cc| {
+        Box::new(move |_cc| {
             let bios = args[1].clone();
             let game = args[2].clone();
-            Box::new(MyApp::new(cc, bios, game))
+            Box::new(Launcher::new(bios, game))
         }),
     )
 }
 
+// Asks for the BIOS or the game again until both can be used, then runs
+// the emulator
+struct Launcher {
+    bios: String,
+    game: String,
+    prompt: Option<(FileKind, FilePrompt)>,
+    app: Option<MyApp>,
+}
+
+impl Launcher {
+    fn new(bios: String, game: String) -> Self {
+        let mut launcher = Self {
+            bios,
+            game,
+            prompt: None,
+            app: None,
+        };
+        launcher.launch();
+        launcher
+    }
+
+    fn launch(&mut self) {
+        // Make game path absolute, so state can be loaded from anywhere.
+        // A missing game is reported below.
+        let game_path = match fs::canonicalize(Path::new(&self.game)) {
+            Ok(game_path) => game_path.to_string_lossy().to_string(),
+            Err(_) => self.game.clone(),
+        };
+        match System::try_new(&self.bios, &game_path) {
+            Ok(system) => {
+                self.app = Some(MyApp::new(self.bios.clone(), game_path, system));
+                self.prompt = None;
+            }
+            Err(e) => {
+                error!("{}", e);
+                let title = match e.kind {
+                    FileKind::Bios => "BIOS Error",
+                    FileKind::Game => "Game Error",
+                };
+                let prompt = FilePrompt::new(title, &e.path, &e.to_string(), "Quit");
+                self.prompt = Some((e.kind, prompt));
+            }
+        }
+    }
+}
+
+impl eframe::App for Launcher {
+    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
+        if let Some(app) = &mut self.app {
+            app.update(ctx, frame);
+            return;
+        }
+        let Some((kind, prompt)) = &mut self.prompt else {
+            return;
+        };
+        match prompt.show(ctx) {
+            Some(PromptAction::Retry(path)) => {
+                match kind {
+                    FileKind::Bios => self.bios = path,
+                    FileKind::Game => self.game = path,
+                }
+                self.launch();
+            }
+            Some(PromptAction::Cancel) => frame.close(),
+            None => (),
+        }
+    }
+}
+
 struct MyApp {
     bios: String,
     game: String,
     system: System,
     is_running: bool,
+    speed_controller: SpeedController,
+    // None without a device, or built without the audio feature
+    audio: Option<AudioOutput>,
+    muted: bool,
+    interlace_mode: InterlaceMode,
+    input_editor: InputEditor,
+    show_input_editor: bool,
+    ram_diff: RamDiff,
+    show_ram_diff: bool,
+    cpu_log: CpuLog,
+    show_cpu_log: bool,
+    breakpoints: Breakpoints,
+    show_breakpoints: bool,
+    // Autofire buttons (Controller::buttons mask) and polls per half period
+    turbo: u16,
+    turbo_period: u32,
     opened_file: Option<PathBuf>,
     open_file_dialog: Option<FileDialog>,
     saved_file: Option<PathBuf>,
     save_file_dialog: Option<FileDialog>,
+    // Shown until dismissed, the BIOS may still boot it if modded
+    region_mismatch: Option<String>,
 }
 
 impl MyApp {
-    fn new(_cc: &eframe::CreationContext<'_>, bios: String, game: String) -> Self {
-        // Make game path absolute, so state can be loaded from anywhere
-        let game_path = match fs::canonicalize(Path::new(&game)) {
-            Ok(game_path) => game_path,
-            Err(e) => {
-                panic!("Error resolving to absolute path: {}: {}", game, e);
-            }
-        };
-        let mut system = System::new(&bios, &game_path.to_string_lossy());
+    fn new(bios: String, game: String, mut system: System) -> Self {
         system.reset();
+        system.set_rewind(REWIND_BUDGET, REWIND_INTERVAL);
+        if let Some(bios_info) = system.get_bios_info() {
+            println!("{}", bios_info);
+        }
+        let region_mismatch = system.get_region_mismatch();
         Self {
             bios,
             game,
             system,
             is_running: true,
+            speed_controller: SpeedController::new(SpeedMode::Video),
+            audio: match AudioOutput::new() {
+                Ok(audio) => Some(audio),
+                Err(e) => {
+                    println!("No audio output: {}", e);
+                    None
+                }
+            },
+            muted: false,
+            interlace_mode: InterlaceMode::Weave,
+            input_editor: InputEditor::new(),
+            show_input_editor: false,
+            ram_diff: RamDiff::new(),
+            show_ram_diff: false,
+            cpu_log: CpuLog::new(),
+            show_cpu_log: false,
+            breakpoints: Breakpoints::new(),
+            show_breakpoints: false,
+            turbo: 0,
+            turbo_period: 1,
             opened_file: None,
             open_file_dialog: None,
             saved_file: None,
             save_file_dialog: None,
+            region_mismatch,
         }
     }
 }
 
+impl MyApp {
+    // Every frame goes through the input editor, which may be playing a movie
+    fn run_frame(&mut self) {
+        self.input_editor.before_frame(&mut self.system);
+        self.system.run_frame();
+    }
+
+    // Replaces the system, keeping the frontend settings
+    fn set_system(&mut self, system: System) {
+        self.system = system;
+        self.system.set_interlace_mode(self.interlace_mode);
+        self.system.set_rewind(REWIND_BUDGET, REWIND_INTERVAL);
+        self.system
+            .get_controller()
+            .set_turbo(self.turbo, self.turbo_period);
+        self.breakpoints.apply(&mut self.system);
+    }
+}
+
 impl eframe::App for MyApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         egui::CentralPanel::default().show(ctx, |ui| {
-            // Get frame buffer
-            let (width, height) = self.system.get_display_size();
-            let (width, height) = (width as usize, height as usize);
-            let mut framebuffer = vec![0; width * height * 3].into_boxed_slice();
-            self.system.get_framebuffer(&mut framebuffer, false);
-
-            // Scale up
-            let mut img = RgbImage::new(width as u32, height as u32);
-            for (x, y, pixel) in img.enumerate_pixels_mut() {
-                let offset = ((y as u32 * width as u32 + x as u32) * 3) as usize;
-                let r = framebuffer[offset];
-                let g = framebuffer[offset + 1];
-                let b = framebuffer[offset + 2];
-                *pixel = Rgb([r, g, b]);
-            }
-
             let asize = ui.available_size();
             // Adjust so other panels don't occlude it
             let bottom_panel_height = 110;
             let new_width = asize[0].round() as u32;
             let new_height = asize[1].round() as u32 - bottom_panel_height;
 
-            // Load texture
-            //let img = ColorImage::from_rgb([width, height], &framebuffer);
-            let img = image::imageops::resize(
-                &img,
-                new_width,
-                new_height,
-                image::imageops::FilterType::Lanczos3,
-            );
+            // Scale up straight from the system frame buffer
+            let img = self.system.with_framebuffer(|framebuffer, width, height| {
+                let img = ImageBuffer::<Rgb<u8>, &[u8]>::from_raw(width, height, framebuffer)
+                    .expect("Framebuffer doesn't match the display size");
+                image::imageops::resize(
+                    &img,
+                    new_width,
+                    new_height,
+                    image::imageops::FilterType::Lanczos3,
+                )
+            });
             let img = ColorImage::from_rgb([new_width as usize, new_height as usize], img.as_raw());
             let texture = ctx.load_texture("psx_screen", img, Default::default());
 
@@ -144,15 +277,34 @@ impl eframe::App for MyApp {
                 }
                 if ui.button("Next").clicked() {
                     if !self.is_running {
-                        self.system.run_frame();
+                        self.run_frame();
+                    }
+                }
+                if ui.button("Next Line").clicked() {
+                    // A movie only knows whole frames
+                    if !self.is_running && !self.input_editor.is_active() {
+                        self.system.run_until(Event::NewScanline);
+                    }
+                }
+                if ui.button("Rewind").clicked() {
+                    let frame = self.system.get_frame_count();
+                    match self.system.rewind(REWIND_FRAMES) {
+                        Ok(to) => self.input_editor.rewind((frame - to) as usize),
+                        Err(e) => error!("Error rewinding: {}", e),
                     }
                 }
                 if ui.button("Reset").clicked() {
                     self.system.reset();
                 }
                 if ui.button("Hard Reset").clicked() {
-                    self.system = System::new(&self.bios, &self.game);
-                    self.system.reset();
+                    match System::try_new(&self.bios, &self.game) {
+                        Ok(mut system) => {
+                            system.reset();
+                            self.set_system(system);
+                            self.input_editor.close();
+                        }
+                        Err(e) => error!("{}", e),
+                    }
                 }
                 // File Controls
                 if ui.button("Load").clicked() {
@@ -173,10 +325,130 @@ impl eframe::App for MyApp {
                     dialog.open();
                     self.save_file_dialog = Some(dialog);
                 }
-                let emu_controls_width = 350.0;
+                if ui.button("Capture GPU").clicked() {
+                    // Records the next frame for an offline replay
+                    self.is_running = false;
+                    self.system.start_gpu_capture();
+                    self.run_frame();
+                    if let Some(capture) = self.system.take_gpu_capture() {
+                        match capture.save(GPU_CAPTURE_PATH) {
+                            Ok(_) => println!("GPU capture saved to {}", GPU_CAPTURE_PATH),
+                            Err(e) => error!("Error saving GPU capture: {}", e),
+                        }
+                    }
+                }
+                if ui.button("Inputs").clicked() {
+                    self.show_input_editor = !self.show_input_editor;
+                }
+                if ui.button("RAM Diff").clicked() {
+                    self.show_ram_diff = !self.show_ram_diff;
+                }
+                if ui.button("CPU Log").clicked() {
+                    self.show_cpu_log = !self.show_cpu_log;
+                }
+                if ui.button("Breakpoints").clicked() {
+                    self.show_breakpoints = !self.show_breakpoints;
+                }
+                // Autofire, held buttons are tapped on their own
+                let turbo = (self.turbo, self.turbo_period);
+                ui.menu_button("Turbo", |ui| {
+                    for (bit, name) in BUTTON_NAMES.iter().enumerate() {
+                        let mut enabled = (self.turbo & (1 << bit)) != 0;
+                        if ui.checkbox(&mut enabled, *name).changed() {
+                            self.turbo ^= 1 << bit;
+                        }
+                    }
+                    ui.add(
+                        egui::DragValue::new(&mut self.turbo_period)
+                            .clamp_range(1..=30)
+                            .suffix(" frames"),
+                    );
+                });
+                if (self.turbo, self.turbo_period) != turbo {
+                    self.system
+                        .get_controller()
+                        .set_turbo(self.turbo, self.turbo_period);
+                }
+                let record_label = match self.system.is_capturing_audio() {
+                    true => "Stop Audio",
+                    false => "Record Audio",
+                };
+                if ui.button(record_label).clicked() {
+                    if self.system.is_capturing_audio() {
+                        match self.system.stop_audio_capture() {
+                            Ok(_) => println!("Audio saved to {}", AUDIO_CAPTURE_PATH),
+                            Err(e) => error!("Error saving audio: {}", e),
+                        }
+                    } else if let Err(e) = self.system.start_audio_capture(AUDIO_CAPTURE_PATH) {
+                        error!("Error recording audio: {}", e);
+                    }
+                }
+                let mut speed_mode = self.speed_controller.mode();
+                egui::ComboBox::from_id_source("speed")
+                    .selected_text(match speed_mode {
+                        SpeedMode::Percentage(percentage) => format!("{}%", percentage),
+                        _ => format!("{:?}", speed_mode),
+                    })
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut speed_mode, SpeedMode::Unlimited, "Unlimited");
+                        ui.selectable_value(&mut speed_mode, SpeedMode::Video, "Video");
+                        // Paced by the output, so only with one
+                        if self.audio.is_some() {
+                            ui.selectable_value(&mut speed_mode, SpeedMode::Audio, "Audio");
+                        }
+                        ui.selectable_value(&mut speed_mode, SpeedMode::Percentage(50), "50%");
+                        ui.selectable_value(&mut speed_mode, SpeedMode::Percentage(25), "25%");
+                    });
+                if speed_mode != self.speed_controller.mode() {
+                    self.speed_controller.set_mode(speed_mode);
+                }
+                ui.add_enabled_ui(self.audio.is_some(), |ui| {
+                    let mute = ui
+                        .checkbox(&mut self.muted, "Mute")
+                        .on_disabled_hover_text("No audio output, see the README");
+                    if mute.changed() {
+                        if let Some(audio) = self.audio.as_mut() {
+                            audio.set_muted(self.muted);
+                        }
+                    }
+                });
+                // How 480i output is shown, it doesn't affect the emulation
+                let interlace_mode = self.interlace_mode;
+                egui::ComboBox::from_id_source("interlace_mode")
+                    .selected_text(format!("{:?}", self.interlace_mode))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(
+                            &mut self.interlace_mode,
+                            InterlaceMode::Weave,
+                            "Weave",
+                        );
+                        ui.selectable_value(&mut self.interlace_mode, InterlaceMode::Bob, "Bob");
+                        ui.selectable_value(
+                            &mut self.interlace_mode,
+                            InterlaceMode::Progressive,
+                            "Progressive",
+                        );
+                    });
+                if self.interlace_mode != interlace_mode {
+                    self.system.set_interlace_mode(self.interlace_mode);
+                }
+                let emu_controls_width = 970.0;
                 let space = available_width - emu_controls_width;
                 let space = space.max(0.0);
                 ui.add_space(space);
+                // 24-bit is used by FMVs (MDEC output)
+                let (width, height) = self.system.get_display_size();
+                let depth = match self.system.get_24bit() {
+                    true => 24,
+                    false => 15,
+                };
+                ui.label(format!("{}x{} {}-bit", width, height, depth));
+                // Frames since loaded, emulated time since reset (kept in states)
+                ui.label(format!(
+                    "#{} {:.1}s",
+                    self.system.get_frame_count(),
+                    self.system.get_emulated_time().as_secs_f64()
+                ));
                 if self.is_running {
                     ui.label(RichText::new("⏺").color(Color32::LIGHT_GREEN));
                 } else {
@@ -241,12 +513,18 @@ impl eframe::App for MyApp {
                 if let Some(file) = dialog.path() {
                     let filepath = file.to_str().unwrap();
                     println!("Loading {} ...", filepath);
-                    let mut bytes = Vec::new();
-                    let mut file = File::open(&filepath).unwrap();
-                    let _ = file.read_to_end(&mut bytes).unwrap();
                     // 'bios' and 'game' filepaths will come from the state
-                    self.system = bincode::deserialize(&bytes).unwrap();
-                    self.is_running = true;
+                    match load_state(filepath) {
+                        Ok((system, history)) => {
+                            self.set_system(system);
+                            match history {
+                                Some(history) => self.input_editor.restore(history),
+                                None => self.input_editor.close(),
+                            }
+                            self.is_running = true;
+                        }
+                        Err(e) => error!("Error loading {}: {}", filepath, e),
+                    }
                 }
             }
         }
@@ -255,12 +533,9 @@ impl eframe::App for MyApp {
                 if let Some(file) = dialog.path() {
                     let filepath = file.to_str().unwrap();
                     println!("Saving {} ...", filepath);
-                    match File::create(&filepath) {
-                        Ok(mut file) => {
-                            let bytes = bincode::serialize(&self.system).unwrap();
-                            let _ = file.write_all(&bytes).unwrap();
-                            self.is_running = true;
-                        }
+                    let history = self.input_editor.history();
+                    match input_editor::save_state(filepath, &self.system, history.as_ref()) {
+                        Ok(_) => self.is_running = true,
                         Err(err) => {
                             error!("{}", err);
                         }
@@ -269,9 +544,60 @@ impl eframe::App for MyApp {
             }
         }
 
+        // Input editor, seeking replaces the system
+        if let Some(system) = self.input_editor.show(
+            ctx,
+            &mut self.show_input_editor,
+            &self.system,
+            !self.is_running,
+            INPUT_MOVIE_PATH,
+        ) {
+            self.set_system(system);
+        }
+
+        self.ram_diff
+            .show(ctx, &mut self.show_ram_diff, &mut self.system);
+        self.cpu_log
+            .show(ctx, &mut self.show_cpu_log, &mut self.system);
+        self.breakpoints.show(
+            ctx,
+            &mut self.show_breakpoints,
+            &mut self.system,
+            &mut self.is_running,
+        );
+        if let Some(region_mismatch) = &self.region_mismatch {
+            let mut dismissed = false;
+            egui::Window::new("Region Mismatch")
+                .collapsible(false)
+                .show(ctx, |ui| {
+                    ui.label(region_mismatch);
+                    dismissed = ui.button("OK").clicked();
+                });
+            if dismissed {
+                self.region_mismatch = None;
+            }
+        }
+
         // Processing
         if self.is_running {
-            self.system.run_frame();
+            self.run_frame();
+            // Paused where it stopped, until Continue
+            if self.system.get_breakpoint_hit().is_some() {
+                self.is_running = false;
+                self.show_breakpoints = true;
+            }
+            // Drained even without an output, recording needs them
+            let samples = self.system.get_audio_samples();
+            let queued_samples = match self.audio.as_mut() {
+                Some(audio) => {
+                    audio.push(&samples);
+                    let queued_samples = audio.queued_samples();
+                    audio.set_resample_ratio(self.speed_controller.resample_ratio(queued_samples));
+                    queued_samples
+                }
+                None => 0,
+            };
+            self.speed_controller.regulate(queued_samples);
             ctx.request_repaint();
         }
 
@@ -288,3 +614,9 @@ impl eframe::App for MyApp {
         self.system.get_controller().button_select = false;
     }
 }
+
+fn load_state(filepath: &str) -> Result<(System, Option<InputHistory>), String> {
+    let (mut system, history) = input_editor::load_state(filepath)?;
+    system.check_game_file().map_err(|e| e.to_string())?;
+    Ok((system, history))
+}
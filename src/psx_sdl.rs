@@ -0,0 +1,435 @@
+// Dojo Learning Environment
+// Copyright (C) 2023-2024 Carlos Perez-Lopez
+//
+// This project is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>
+//
+// You can contact the author via carlospzlz@gmail.com
+
+use log::error;
+use sdl2::controller::{Button, GameController};
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::PixelFormatEnum;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+// Emu system
+mod psx;
+
+// SPU output, only with the audio feature
+#[cfg_attr(not(feature = "audio"), allow(dead_code))]
+mod audio;
+
+use audio::AudioOutput;
+
+use psx::speed::{SpeedController, SpeedMode};
+use psx::{RamSnapshot, System, TraceRange, UdpLink};
+
+const WINDOW_WIDTH: u32 = 640;
+const WINDOW_HEIGHT: u32 = 480;
+
+// Frames, enough for a LAN. Both ends must use the same
+const NETPLAY_DELAY: u32 = 2;
+const NETPLAY_TIMEOUT: Duration = Duration::from_secs(60);
+// Newest accesses kept by --trace-memory
+const MEMORY_TRACE_CAPACITY: usize = 4096;
+
+fn main() {
+    env_logger::init(); // Log to stderr (if you run with `RUST_LOG=debug`)
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        error!(
+            "Usage: {} <bios> <game> [--instruction-stats] [--no-reverb] [--no-hilo-interlock] \
+             [--record-audio <wav>] [--netplay <local-address> <peer-address>] \
+             [--netplay-delay <frames>] [--trace-memory <start>-<end>[:r|:w]]... \
+             [--speed <unlimited|video|audio|percentage>]",
+            args[0]
+        );
+        return;
+    }
+    let instruction_stats = args[3..].iter().any(|arg| arg == "--instruction-stats");
+    let reverb = !args[3..].iter().any(|arg| arg == "--no-reverb");
+    let hilo_interlock = !args[3..].iter().any(|arg| arg == "--no-hilo-interlock");
+    let audio_path = args[3..]
+        .iter()
+        .position(|arg| arg == "--record-audio")
+        .and_then(|i| args.get(3 + i + 1));
+    let netplay = args[3..]
+        .iter()
+        .position(|arg| arg == "--netplay")
+        .and_then(|i| Some((args.get(3 + i + 1)?, args.get(3 + i + 2)?)));
+    let netplay_delay = args[3..]
+        .iter()
+        .position(|arg| arg == "--netplay-delay")
+        .and_then(|i| args.get(3 + i + 1))
+        .and_then(|delay| delay.parse().ok())
+        .unwrap_or(NETPLAY_DELAY);
+    let speed_mode = match args[3..]
+        .iter()
+        .position(|arg| arg == "--speed")
+        .and_then(|i| args.get(3 + i + 1))
+        .map(|speed| parse_speed_mode(speed))
+    {
+        Some(Ok(speed_mode)) => Some(speed_mode),
+        Some(Err(e)) => {
+            error!("Invalid speed: {}", e);
+            return;
+        }
+        None => None,
+    };
+    let mut trace_ranges = Vec::new();
+    for (i, arg) in args.iter().enumerate().skip(3) {
+        if arg != "--trace-memory" {
+            continue;
+        }
+        match args.get(i + 1).map(|range| parse_trace_range(range)) {
+            Some(Ok(range)) => trace_ranges.push(range),
+            Some(Err(e)) => {
+                error!("Invalid memory trace range: {}", e);
+                return;
+            }
+            None => {
+                error!("Missing memory trace range");
+                return;
+            }
+        }
+    }
+
+    // Make game path absolute, so state can be loaded from anywhere.
+    // A missing game is reported below.
+    let game_path = match fs::canonicalize(Path::new(&args[2])) {
+        Ok(game_path) => game_path.to_string_lossy().to_string(),
+        Err(_) => args[2].clone(),
+    };
+    let mut system = match System::try_new(&args[1], &game_path) {
+        Ok(system) => system,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    if let Some(bios_info) = system.get_bios_info() {
+        println!("{}", bios_info);
+    }
+    // Still launched, it may be a modded BIOS
+    if let Some(mismatch) = system.get_region_mismatch() {
+        eprintln!("{}", mismatch);
+    }
+    system.reset();
+    system.set_instruction_stats(instruction_stats);
+    system.set_memory_trace(&trace_ranges, MEMORY_TRACE_CAPACITY);
+    system.set_spu_reverb(reverb);
+    system.set_hilo_interlock(hilo_interlock);
+    if let Some((local, peer)) = netplay {
+        println!("Waiting for {} on {} ...", peer, local);
+        match UdpLink::connect(
+            local.as_str(),
+            peer.as_str(),
+            netplay_delay,
+            NETPLAY_TIMEOUT,
+        ) {
+            Ok(link) => system.set_link(Some(Box::new(link))),
+            Err(e) => {
+                error!("Error connecting to {}: {}", peer, e);
+                return;
+            }
+        }
+    }
+    if let Some(audio_path) = audio_path {
+        match system.start_audio_capture(audio_path) {
+            Ok(_) => println!("Recording audio to {}", audio_path),
+            Err(e) => error!("Error recording audio to {}: {}", audio_path, e),
+        }
+    }
+
+    let sdl_context = sdl2::init().expect("Failed to initialize SDL");
+    let video_subsystem = sdl_context.video().expect("Failed to initialize video");
+    let controller_subsystem = sdl_context
+        .game_controller()
+        .expect("Failed to initialize game controller");
+
+    let window = video_subsystem
+        .window("PSX SDL", WINDOW_WIDTH, WINDOW_HEIGHT)
+        .position_centered()
+        .resizable()
+        .build()
+        .expect("Failed to create window");
+    let mut canvas = window
+        .into_canvas()
+        .present_vsync()
+        .build()
+        .expect("Failed to create canvas");
+    let texture_creator = canvas.texture_creator();
+
+    // Keep the first available gamepad open, otherwise we don't get events
+    let mut gamepad: Option<GameController> = None;
+    let mut event_pump = sdl_context.event_pump().expect("Failed to get event pump");
+    let mut is_running = true;
+    let mut audio = match AudioOutput::new() {
+        Ok(audio) => Some(audio),
+        Err(e) => {
+            println!("No audio output: {}", e);
+            None
+        }
+    };
+    // Paced by the output when there is one
+    let mut speed_mode = speed_mode.unwrap_or(match audio {
+        Some(_) => SpeedMode::Audio,
+        None => SpeedMode::Video,
+    });
+    if speed_mode == SpeedMode::Audio && audio.is_none() {
+        error!("No audio output to pace the emulation with, using video");
+        speed_mode = SpeedMode::Video;
+    }
+    let mut speed_controller = SpeedController::new(speed_mode);
+    let mut muted = false;
+
+    let mut ram_snapshot: Option<RamSnapshot> = None;
+    'main: loop {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. } => break 'main,
+                Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => break 'main,
+                Event::KeyDown {
+                    keycode: Some(Keycode::P),
+                    repeat: false,
+                    ..
+                } => is_running = !is_running,
+                Event::KeyDown {
+                    keycode: Some(Keycode::N),
+                    ..
+                } if !is_running => system.run_frame(),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Tab),
+                    repeat: false,
+                    ..
+                } => {
+                    speed_mode = speed_controller.mode();
+                    speed_controller.set_mode(SpeedMode::Unlimited);
+                }
+                Event::KeyUp {
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => speed_controller.set_mode(speed_mode),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backspace),
+                    repeat: false,
+                    ..
+                } => system.reset(),
+                Event::KeyDown {
+                    keycode: Some(Keycode::U),
+                    repeat: false,
+                    ..
+                } => {
+                    muted = !muted;
+                    if let Some(audio) = audio.as_mut() {
+                        audio.set_muted(muted);
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::M),
+                    repeat: false,
+                    ..
+                } => {
+                    // Diff against the previous snapshot, if any
+                    let snapshot = system.snapshot_ram();
+                    match &ram_snapshot {
+                        Some(previous) => println!(
+                            "Frame {} to {}\n{}",
+                            previous.frame(),
+                            snapshot.frame(),
+                            psx::diff_report(&previous.diff(&snapshot, 1, 0))
+                        ),
+                        None => println!("RAM snapshot at frame {}", snapshot.frame()),
+                    }
+                    ram_snapshot = Some(snapshot);
+                    // Compare these between runs to check they stayed in sync
+                    println!(
+                        "RAM hash {:016x}, VRAM hash {:016x}",
+                        system.get_ram_hash(),
+                        system.get_vram_hash()
+                    );
+                }
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    ..
+                } => set_key(&mut system, keycode, true),
+                Event::KeyUp {
+                    keycode: Some(keycode),
+                    ..
+                } => set_key(&mut system, keycode, false),
+                Event::ControllerDeviceAdded { which, .. } if gamepad.is_none() => {
+                    match controller_subsystem.open(which) {
+                        Ok(controller) => {
+                            println!("Using gamepad: {}", controller.name());
+                            gamepad = Some(controller);
+                        }
+                        Err(e) => error!("Error opening gamepad {}: {}", which, e),
+                    }
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    if let Some(controller) = &gamepad {
+                        if controller.instance_id() == which {
+                            gamepad = None;
+                        }
+                    }
+                }
+                Event::ControllerButtonDown { button, .. } => set_button(&mut system, button, true),
+                Event::ControllerButtonUp { button, .. } => set_button(&mut system, button, false),
+                _ => (),
+            }
+        }
+
+        // Processing
+        if is_running {
+            system.run_frame();
+        }
+        // Drained even without an output, recording needs them
+        let samples = system.get_audio_samples();
+        let queued_samples = match audio.as_mut() {
+            Some(audio) => {
+                audio.push(&samples);
+                let queued_samples = audio.queued_samples();
+                audio.set_resample_ratio(speed_controller.resample_ratio(queued_samples));
+                queued_samples
+            }
+            None => 0,
+        };
+
+        // Show frame, SDL takes care of scaling it up
+        let texture = system.with_framebuffer(|framebuffer, width, height| {
+            let mut texture = texture_creator
+                .create_texture_static(PixelFormatEnum::RGB24, width, height)
+                .expect("Failed to create texture");
+            match texture.update(None, framebuffer, width as usize * 3) {
+                Ok(_) => (),
+                Err(e) => error!("Error updating texture: {}", e),
+            }
+            texture
+        });
+        canvas.clear();
+        match canvas.copy(&texture, None, None) {
+            Ok(_) => (),
+            Err(e) => error!("Error drawing frame: {}", e),
+        }
+        canvas.present();
+
+        // Don't go faster than the PSX if vsync is not available
+        speed_controller.regulate(queued_samples);
+    }
+
+    if let Err(e) = system.stop_audio_capture() {
+        error!("Error finishing audio recording: {}", e);
+    }
+
+    if let Some(report) = system.get_instruction_stats_report() {
+        println!("{}", report);
+    }
+
+    if let Some(report) = system.get_memory_trace_report() {
+        println!("{}", report);
+    }
+}
+
+// Hex addresses, e.g. 0x8001f000-0x8001f003:w. Both reads and writes
+// are traced unless :r or :w is given
+fn parse_trace_range(range: &str) -> Result<TraceRange, String> {
+    let (addresses, kinds) = match range.split_once(':') {
+        Some((addresses, kinds)) => (addresses, kinds),
+        None => (range, "rw"),
+    };
+    let parse_address = |address: &str| {
+        let digits = address.trim_start_matches("0x");
+        u32::from_str_radix(digits, 16).map_err(|e| format!("{}: {}", address, e))
+    };
+    let (start, end) = match addresses.split_once('-') {
+        Some((start, end)) => (parse_address(start)?, parse_address(end)?),
+        None => {
+            let address = parse_address(addresses)?;
+            (address, address)
+        }
+    };
+    if start > end {
+        return Err(format!("{}: start after end", range));
+    }
+    if kinds.is_empty() || kinds.chars().any(|kind| kind != 'r' && kind != 'w') {
+        return Err(format!("{}: expected :r, :w or :rw", range));
+    }
+    Ok(TraceRange {
+        start,
+        end,
+        reads: kinds.contains('r'),
+        writes: kinds.contains('w'),
+    })
+}
+
+// A mode name, or a percentage of the PSX speed
+fn parse_speed_mode(speed: &str) -> Result<SpeedMode, String> {
+    match speed {
+        "unlimited" => Ok(SpeedMode::Unlimited),
+        "video" => Ok(SpeedMode::Video),
+        "audio" => Ok(SpeedMode::Audio),
+        _ => match speed.trim_end_matches('%').parse() {
+            Ok(percentage) => Ok(SpeedMode::Percentage(percentage)),
+            Err(e) => Err(format!("{}: {}", speed, e)),
+        },
+    }
+}
+
+fn set_key(system: &mut System, keycode: Keycode, pressed: bool) {
+    let controller = system.get_controller();
+    match keycode {
+        Keycode::Up | Keycode::W => controller.button_dpad_up = pressed,
+        Keycode::Down | Keycode::S => controller.button_dpad_down = pressed,
+        Keycode::Left | Keycode::A => controller.button_dpad_left = pressed,
+        Keycode::Right | Keycode::D => controller.button_dpad_right = pressed,
+        Keycode::I => controller.button_triangle = pressed,
+        Keycode::J => controller.button_square = pressed,
+        Keycode::L => controller.button_circle = pressed,
+        Keycode::K => controller.button_cross = pressed,
+        Keycode::Q => controller.button_l1 = pressed,
+        Keycode::E => controller.button_r1 = pressed,
+        Keycode::Num1 => controller.button_l2 = pressed,
+        Keycode::Num3 => controller.button_r2 = pressed,
+        Keycode::Return => controller.button_start = pressed,
+        Keycode::RShift => controller.button_select = pressed,
+        _ => (),
+    }
+}
+
+fn set_button(system: &mut System, button: Button, pressed: bool) {
+    let controller = system.get_controller();
+    match button {
+        Button::DPadUp => controller.button_dpad_up = pressed,
+        Button::DPadDown => controller.button_dpad_down = pressed,
+        Button::DPadLeft => controller.button_dpad_left = pressed,
+        Button::DPadRight => controller.button_dpad_right = pressed,
+        Button::Y => controller.button_triangle = pressed,
+        Button::X => controller.button_square = pressed,
+        Button::B => controller.button_circle = pressed,
+        Button::A => controller.button_cross = pressed,
+        Button::LeftShoulder => controller.button_l1 = pressed,
+        Button::RightShoulder => controller.button_r1 = pressed,
+        Button::LeftStick => controller.button_l3 = pressed,
+        Button::RightStick => controller.button_r3 = pressed,
+        Button::Start => controller.button_start = pressed,
+        Button::Back => controller.button_select = pressed,
+        _ => (),
+    }
+}
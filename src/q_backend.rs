@@ -0,0 +1,221 @@
+// Dojo Learning Environment
+// Copyright (C) 2023-2024 Carlos Perez-Lopez
+//
+// This project is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>
+//
+// You can contact the author via carlospzlz@gmail.com
+
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use super::vision;
+
+const FEATURES: usize = 5;
+// Tile coding of agents that pick it in the GUI, 8 MB of weights
+const DEFAULT_TILINGS: u32 = 8;
+const DEFAULT_TILES: u32 = 8;
+const DEFAULT_ROWS: u32 = 8192;
+
+// What a backend sees of an observation, every value in [0, 1]
+#[derive(Clone, Copy, Debug)]
+pub struct Features([f32; FEATURES]);
+
+impl Features {
+    // Both centroids, and how far apart the characters are horizontally,
+    // which is what most moves depend on
+    pub fn new(frame_abstraction: &vision::FrameAbstraction) -> Self {
+        let (x1, y1) = frame_abstraction.char1_centroid;
+        let (x2, y2) = frame_abstraction.char2_centroid;
+        let features = [x1, y1, x2, y2, (x2 - x1 + 1.0) / 2.0];
+        Self(features.map(|value| value.clamp(0.0, 1.0)))
+    }
+}
+
+// Value function of agents that don't keep a Q table per image state. The
+// Q values of an observation come straight from its features, so there is
+// no state search, and the cost doesn't grow with what was learned.
+pub trait QBackend: Send {
+    // Saved with the agent, to load it back with backend_from_bytes
+    fn name(&self) -> &'static str;
+
+    // Q value of every action
+    fn q_values(&self, features: &Features) -> [f32; 256];
+
+    // One step of Q(features, action) toward target
+    fn update(&mut self, features: &Features, action: u8, target: f32, learning_rate: f32);
+
+    // Parts of the feature space learned about so far, the states plot
+    fn learned(&self) -> usize;
+
+    fn to_bytes(&self) -> Result<Vec<u8>, String>;
+
+    fn clone_box(&self) -> Box<dyn QBackend>;
+}
+
+impl Clone for Box<dyn QBackend> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+// A backend as saved with its agent, the name says how to read the weights
+#[derive(Serialize, Deserialize)]
+pub struct SavedBackend {
+    name: String,
+    bytes: Vec<u8>,
+}
+
+impl SavedBackend {
+    pub fn new(backend: &dyn QBackend) -> Result<Self, String> {
+        Ok(Self {
+            name: backend.name().to_string(),
+            bytes: backend.to_bytes()?,
+        })
+    }
+
+    pub fn load(&self) -> Result<Box<dyn QBackend>, String> {
+        backend_from_bytes(&self.name, &self.bytes)
+    }
+}
+
+fn backend_from_bytes(name: &str, bytes: &[u8]) -> Result<Box<dyn QBackend>, String> {
+    match name {
+        TileCoding::NAME => {
+            let tile_coding: TileCoding = bincode::deserialize(bytes).map_err(|e| e.to_string())?;
+            tile_coding.check()?;
+            Ok(Box::new(tile_coding))
+        }
+        _ => Err(format!("Unknown Q backend: {}", name)),
+    }
+}
+
+// Linear in the tiles the features fall in. Every tiling splits each
+// feature in `tiles` intervals, offset from the others, and the tiles are
+// hashed into a fixed number of rows with a weight per action. Nearby
+// observations share most tiles, so they generalise to each other.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TileCoding {
+    tilings: u32,
+    tiles: u32,
+    rows: u32,
+    weights: Vec<f32>,
+    // Rows updated at least once
+    touched: Vec<bool>,
+    learned: usize,
+}
+
+impl TileCoding {
+    pub const NAME: &'static str = "Tile Coding";
+
+    pub fn new(tilings: u32, tiles: u32, rows: u32) -> Self {
+        let tilings = tilings.max(1);
+        let rows = rows.max(1);
+        Self {
+            tilings,
+            tiles: tiles.max(1),
+            rows,
+            weights: vec![0.0; rows as usize * 256],
+            touched: vec![false; rows as usize],
+            learned: 0,
+        }
+    }
+
+    // Loaded ones could have anything
+    fn check(&self) -> Result<(), String> {
+        let valid = self.tilings > 0
+            && self.tiles > 0
+            && self.weights.len() == self.rows as usize * 256
+            && self.touched.len() == self.rows as usize;
+        match valid {
+            true => Ok(()),
+            false => Err("Tile coding weights don't match its size".to_string()),
+        }
+    }
+
+    // Row of the tile the features fall in, for every tiling
+    fn active_rows(&self, features: &Features) -> Vec<usize> {
+        (0..self.tilings)
+            .map(|tiling| {
+                let mut hasher = DefaultHasher::new();
+                tiling.hash(&mut hasher);
+                for (i, value) in features.0.iter().enumerate() {
+                    // Odd steps per feature, so tilings aren't offset along
+                    // the diagonal only
+                    let offset = (tiling * (2 * i as u32 + 1)) % self.tilings;
+                    let offset = offset as f32 / self.tilings as f32;
+                    let tile = (value * self.tiles as f32 + offset).floor() as u32;
+                    tile.hash(&mut hasher);
+                }
+                (hasher.finish() % self.rows as u64) as usize
+            })
+            .collect()
+    }
+
+    fn q_value(&self, rows: &[usize], action: u8) -> f32 {
+        rows.iter()
+            .map(|row| self.weights[row * 256 + action as usize])
+            .sum()
+    }
+}
+
+impl Default for TileCoding {
+    fn default() -> Self {
+        Self::new(DEFAULT_TILINGS, DEFAULT_TILES, DEFAULT_ROWS)
+    }
+}
+
+impl QBackend for TileCoding {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn q_values(&self, features: &Features) -> [f32; 256] {
+        let mut q_values = [0.0; 256];
+        for row in self.active_rows(features) {
+            let weights = &self.weights[row * 256..(row + 1) * 256];
+            for (q, weight) in q_values.iter_mut().zip(weights) {
+                *q += weight;
+            }
+        }
+        q_values
+    }
+
+    fn update(&mut self, features: &Features, action: u8, target: f32, learning_rate: f32) {
+        let rows = self.active_rows(features);
+        let error = target - self.q_value(&rows, action);
+        // Shared between the tilings, so the learning rate means the same
+        // as with a table
+        let step = learning_rate * error / self.tilings as f32;
+        for row in rows {
+            self.weights[row * 256 + action as usize] += step;
+            if !self.touched[row] {
+                self.touched[row] = true;
+                self.learned += 1;
+            }
+        }
+    }
+
+    fn learned(&self) -> usize {
+        self.learned
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        bincode::serialize(self).map_err(|e| e.to_string())
+    }
+
+    fn clone_box(&self) -> Box<dyn QBackend> {
+        Box::new(self.clone())
+    }
+}
@@ -16,23 +16,57 @@
 //
 // You can contact the author via carlospzlz@gmail.com
 
-use image::{Rgb, RgbImage};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::{ColorType, ImageEncoder, Rgb, RgbImage};
 use log::error;
 use rand::Rng;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fmt::Write as FmtWrite;
 use std::fs;
 use std::io::BufRead;
 use std::io::BufReader;
-use std::io::Write;
-use std::path::Path;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
 use std::time::Duration;
 
+use super::q_backend::{Features, QBackend, SavedBackend};
 use super::vision;
 
+// Before adding a state, the closest ones by centroids are checked again
+// regardless of the radius, merging into one that is within a stricter MSE
+const DEDUP_CANDIDATES: usize = 8;
+const DEDUP_MSE_FACTOR: f64 = 0.5;
+// Candidates within the radius are ranked on thumbnails, and only the best
+// ones are compared at full resolution
+const FULL_MSE_CANDIDATES: usize = 4;
+// Raw cropped frames can be kept in any of these
+const RAW_FRAME_EXTENSIONS: [&str; 4] = ["png", "jpg", "jpeg", "webp"];
+// New ones are kept as JPEG, a fraction of the size of a PNG
+const RAW_FRAME_QUALITY: u8 = 85;
+// Random actions taken by agents with a Q backend, which can't tell what
+// was tried already
+const EXPLORATION: f32 = 0.1;
+// Added to the TD error of replayed transitions, so none of them stops being
+// sampled
+const REPLAY_PRIORITY_EPSILON: f32 = 0.01;
+
+#[derive(Clone)]
 pub struct Agent {
     states: Vec<State>,
     number_of_states: usize,
-    radius: u32,
+    // Manhattan distance between normalized centroids, a fraction of the crop
+    radius: f32,
     revisited: bool,
     previous_index: Option<usize>,
     previous_action: Option<u8>,
@@ -43,20 +77,461 @@ pub struct Agent {
     states_per_iteration: Vec<[f64; 2]>,
     max_q_per_iteration: Vec<[f64; 2]>,
     training_time: Duration,
+    characters: Option<[String; 2]>,
+    // Starting Q of new states, from another agent
+    q_prior: Option<[f32; 256]>,
+    same_state: SameState,
+    // Taken from the reward when learning from staying in the same state
+    same_state_penalty: f32,
+    same_state_count: usize,
+    merged_states: usize,
+    // Times a state was followed by another one, by state indices
+    transitions: HashMap<(usize, usize), u64>,
+    // New states keep the cropped frame they were abstracted from
+    store_raw_frames: bool,
+    // How far a demonstration pulls the Q value of the human action toward
+    // the best one of the state, 0 learns from it as any other step
+    correction: f32,
+    state_matching: StateMatching,
+    // First state with each key, kept for any matching
+    occupancy_index: HashMap<vision::OccupancyKey, usize>,
+    // Learns Q over the features of the observations instead of a table per
+    // state, which is then left empty. None for the table.
+    backend: Option<Box<dyn QBackend>>,
+    previous_features: Option<Features>,
+    // Recent transitions, learned from again between observations
+    replay: ReplayMemory,
+}
+
+// What the agent does when an observation matches the state it is already in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SameState {
+    // Release all buttons, without learning
+    NoOp,
+    // Keep pressing the previous action, without learning
+    RepeatPrevious,
+    // Learn from staying and choose again, like in any other state
+    SamplePolicy,
+}
+
+// How an observation is matched to a known state
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StateMatching {
+    // Centroids within the radius, then the lowest MSE under the maximum
+    Mse,
+    // Same coarse grid occupancy and centroid cells, a hash lookup. Much
+    // faster, but it can't tell apart what falls in the same cells.
+    Occupancy,
+}
+
+// How transitions are picked from the replay memory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sampling {
+    // Any transition in memory, equally likely
+    Uniform,
+    // In proportion to their TD error when last learned from, so the
+    // surprising ones come back more often
+    Prioritized,
+}
+
+// Where a transition starts or ends, a state of the table or the features
+// of the observation with a Q backend
+#[derive(Clone, Copy)]
+enum Observed {
+    State(usize),
+    Features(Features),
+}
+
+#[derive(Clone, Copy)]
+struct Transition {
+    from: Observed,
+    action: u8,
+    reward: f32,
+    to: Observed,
+    priority: f32,
+}
+
+// Last `capacity` transitions, oldest first. A capacity of 0 keeps none.
+#[derive(Clone)]
+struct ReplayMemory {
+    capacity: usize,
+    batch_size: usize,
+    sampling: Sampling,
+    transitions: VecDeque<Transition>,
+    // Updates done from memory so far
+    replayed: u64,
+}
+
+impl ReplayMemory {
+    fn new() -> Self {
+        Self {
+            capacity: 0,
+            batch_size: 32,
+            sampling: Sampling::Uniform,
+            transitions: VecDeque::new(),
+            replayed: 0,
+        }
+    }
+
+    fn push(&mut self, from: Observed, action: u8, reward: f32, to: Observed, error: f32) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.transitions.len() == self.capacity {
+            self.transitions.pop_front();
+        }
+        self.transitions.push_back(Transition {
+            from,
+            action,
+            reward,
+            to,
+            priority: error.abs() + REPLAY_PRIORITY_EPSILON,
+        });
+    }
+
+    // Indices of the next mini-batch, with repetition
+    fn sample(&self) -> Vec<usize> {
+        let len = self.transitions.len();
+        if len == 0 {
+            return Vec::new();
+        }
+        let mut rng = rand::thread_rng();
+        match self.sampling {
+            Sampling::Uniform => (0..self.batch_size)
+                .map(|_| rng.gen_range(0..len))
+                .collect(),
+            Sampling::Prioritized => {
+                let cumulative: Vec<f32> = self
+                    .transitions
+                    .iter()
+                    .scan(0.0, |total, transition| {
+                        *total += transition.priority;
+                        Some(*total)
+                    })
+                    .collect();
+                let total = cumulative[len - 1];
+                (0..self.batch_size)
+                    .map(|_| {
+                        let point = rng.gen::<f32>() * total;
+                        cumulative.partition_point(|sum| *sum <= point).min(len - 1)
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+// How Agent::transfer_from seeds an agent with what another one learned
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransferStrategy {
+    // Q rows of the other agent's states, into the matching state here (same
+    // search as when visiting) or as new states when nothing matches
+    MatchedStates,
+    // The other agent's mean Q row, as the starting Q of new states
+    PolicyPriors,
+}
+
+#[derive(Default)]
+pub struct ValidationReport {
+    pub dropped_states: usize,
+    pub resized_q_tables: usize,
+    pub repaired_q_values: usize,
+    pub clamped_centroids: usize,
+    pub messages: Vec<String>,
+}
+
+impl ValidationReport {
+    pub fn is_clean(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    fn merge(&mut self, other: ValidationReport) {
+        self.dropped_states += other.dropped_states;
+        self.resized_q_tables += other.resized_q_tables;
+        self.repaired_q_values += other.repaired_q_values;
+        self.clamped_centroids += other.clamped_centroids;
+        self.messages.extend(other.messages);
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} states dropped, {} Q tables resized, {} Q values reset, {} centroids clamped",
+            self.dropped_states,
+            self.resized_q_tables,
+            self.repaired_q_values,
+            self.clamped_centroids
+        )?;
+        for message in self.messages.iter() {
+            writeln!(f, "  {}", message)?;
+        }
+        Ok(())
+    }
+}
+
+// Spread of the Q values over all states, for dojo-agent inspect
+#[allow(dead_code)]
+pub struct QStats {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    // Mean of the best Q value of every state
+    pub mean_best: f32,
+    // Actions with a Q value, out of 256 per state
+    pub tried_actions: usize,
+    pub states: usize,
+}
+
+impl fmt::Display for QStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Q values: min {:.3}, max {:.3}, mean {:.3}, mean best {:.3}",
+            self.min, self.max, self.mean, self.mean_best
+        )?;
+        let total = self.states * 256;
+        writeln!(
+            f,
+            "Actions tried: {} of {} ({:.1}%)",
+            self.tried_actions,
+            total,
+            100.0 * self.tried_actions as f64 / total.max(1) as f64
+        )
+    }
+}
+
+// Shared between a save or load on another thread and whoever shows it
+#[derive(Default)]
+pub struct Progress {
+    done: AtomicUsize,
+    total: AtomicUsize,
+    cancelled: AtomicBool,
+}
+
+impl Progress {
+    fn start(&self, total: usize) {
+        self.done.store(0, Ordering::Relaxed);
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    fn advance(&self) {
+        self.done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // States done and in total
+    pub fn get(&self) -> (usize, usize) {
+        (
+            self.done.load(Ordering::Relaxed),
+            self.total.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+// What the agent thread does with an observation
+#[derive(Clone, Copy)]
+enum Step {
+    Learn,
+    Act,
+    // The human took this action
+    Demonstration(u8),
+}
+
+// Runs the state search and Q update on its own thread, so the emulation
+// doesn't slow down as the agent grows. If the agent misses the deadline,
+// the previous action is kept and new observations are dropped until the
+// late action comes back
+pub struct AgentWorker {
+    agent: Arc<Mutex<Agent>>,
+    observations: SyncSender<(vision::FrameAbstraction, f32, f64, Step)>,
+    actions: Receiver<u8>,
+    previous_action: u8,
+    in_flight: bool,
+    dropped_observations: u64,
 }
 
+impl AgentWorker {
+    pub fn new(agent: Agent) -> Self {
+        let agent = Arc::new(Mutex::new(agent));
+        let (observations, observations_receiver) = mpsc::sync_channel(1);
+        let (actions_sender, actions) = mpsc::sync_channel(1);
+        let worker_agent = agent.clone();
+        thread::spawn(move || {
+            // Finishes when the worker is dropped
+            for (frame_abstraction, reward, max_mse, step) in observations_receiver {
+                let mut agent = worker_agent.lock().unwrap();
+                let action = match step {
+                    Step::Learn => agent.visit_state(frame_abstraction, reward, max_mse),
+                    Step::Act => agent.act(&frame_abstraction, max_mse),
+                    Step::Demonstration(action) => {
+                        agent.demonstrate(frame_abstraction, reward, max_mse, action)
+                    }
+                };
+                drop(agent);
+                if actions_sender.send(action).is_err() {
+                    break;
+                }
+                // After the action is on its way, so it doesn't wait for it
+                if !matches!(step, Step::Act) {
+                    worker_agent.lock().unwrap().replay();
+                }
+            }
+        });
+        Self {
+            agent,
+            observations,
+            actions,
+            previous_action: 0,
+            in_flight: false,
+            dropped_observations: 0,
+        }
+    }
+
+    pub fn agent(&self) -> MutexGuard<'_, Agent> {
+        self.agent.lock().unwrap()
+    }
+
+    // Observations that came while the agent was still busy
+    pub fn get_dropped_observations(&self) -> u64 {
+        self.dropped_observations
+    }
+
+    pub fn set_agent(&mut self, agent: Agent) {
+        // Wait for the old agent to finish, its action is meaningless now
+        if self.in_flight {
+            let _ = self.actions.recv();
+            self.in_flight = false;
+        }
+        *self.agent() = agent;
+        self.previous_action = 0;
+    }
+
+    pub fn visit_state(
+        &mut self,
+        frame_abstraction: vision::FrameAbstraction,
+        reward: f32,
+        max_mse: f64,
+        deadline: Duration,
+    ) -> u8 {
+        self.submit(frame_abstraction, reward, max_mse, Step::Learn, deadline)
+    }
+
+    // Learns from the human action, returning the one the agent would
+    // have taken
+    pub fn demonstrate(
+        &mut self,
+        frame_abstraction: vision::FrameAbstraction,
+        reward: f32,
+        max_mse: f64,
+        action: u8,
+        deadline: Duration,
+    ) -> u8 {
+        let step = Step::Demonstration(action);
+        self.submit(frame_abstraction, reward, max_mse, step, deadline)
+    }
+
+    // Greedy action without learning, for evaluation
+    pub fn act(
+        &mut self,
+        frame_abstraction: vision::FrameAbstraction,
+        max_mse: f64,
+        deadline: Duration,
+    ) -> u8 {
+        self.submit(frame_abstraction, 0.0, max_mse, Step::Act, deadline)
+    }
+
+    fn submit(
+        &mut self,
+        frame_abstraction: vision::FrameAbstraction,
+        reward: f32,
+        max_mse: f64,
+        step: Step,
+        deadline: Duration,
+    ) -> u8 {
+        if self.in_flight {
+            match self.actions.try_recv() {
+                Ok(action) => {
+                    self.previous_action = action;
+                    self.in_flight = false;
+                }
+                Err(_) => {
+                    self.dropped_observations += 1;
+                    return self.previous_action;
+                }
+            }
+        }
+
+        if self
+            .observations
+            .send((frame_abstraction, reward, max_mse, step))
+            .is_err()
+        {
+            error!("Agent thread is gone");
+            return self.previous_action;
+        }
+
+        match self.actions.recv_timeout(deadline) {
+            Ok(action) => self.previous_action = action,
+            Err(RecvTimeoutError::Timeout) => self.in_flight = true,
+            Err(RecvTimeoutError::Disconnected) => error!("Agent thread is gone"),
+        }
+        self.previous_action
+    }
+}
+
+#[derive(Clone)]
 struct State {
     frame_abstraction: vision::FrameAbstraction,
     q: [f32; 256],
+    // One bit per action taken here at least once
+    tried: [u64; 4],
+    thumbnail: RgbImage,
+    occupancy: vision::OccupancyKey,
+    // Encoded as it is written to disk
+    raw_frame: Option<Vec<u8>>,
 }
 
 impl State {
     fn new(frame_abstraction: vision::FrameAbstraction) -> Self {
+        let thumbnail = vision::thumbnail(&frame_abstraction.frame);
+        let occupancy = vision::OccupancyKey::new(&frame_abstraction);
         Self {
             frame_abstraction,
             q: [0.0; 256],
+            tried: [0; 4],
+            thumbnail,
+            occupancy,
+            raw_frame: None,
         }
     }
+
+    fn set_tried(&mut self, action: u8) {
+        self.tried[action as usize / 64] |= 1 << (action % 64);
+    }
+
+    fn is_tried(&self, action: u8) -> bool {
+        (self.tried[action as usize / 64] & (1 << (action % 64))) != 0
+    }
+
+    // Untried actions go first, their Q values mean nothing yet
+    fn random_untried_action(&self) -> Option<u8> {
+        let tried: u32 = self.tried.iter().map(|bits| bits.count_ones()).sum();
+        if tried == 256 {
+            return None;
+        }
+        let mut rng = rand::thread_rng();
+        let nth = rng.gen_range(0..256 - tried) as usize;
+        (0..=255).filter(|action| !self.is_tried(*action)).nth(nth)
+    }
 }
 
 impl Agent {
@@ -64,7 +539,7 @@ impl Agent {
         Self {
             states: Vec::<State>::new(),
             number_of_states: 0,
-            radius: 30,
+            radius: 0.08,
             revisited: false,
             previous_index: None,
             previous_action: None,
@@ -75,6 +550,20 @@ impl Agent {
             states_per_iteration: Vec::<[f64; 2]>::new(),
             max_q_per_iteration: Vec::<[f64; 2]>::new(),
             training_time: Duration::ZERO,
+            characters: None,
+            q_prior: None,
+            same_state: SameState::NoOp,
+            same_state_penalty: 0.0,
+            same_state_count: 0,
+            merged_states: 0,
+            transitions: HashMap::new(),
+            store_raw_frames: false,
+            correction: 0.0,
+            state_matching: StateMatching::Mse,
+            occupancy_index: HashMap::new(),
+            backend: None,
+            previous_features: None,
+            replay: ReplayMemory::new(),
         }
     }
 
@@ -84,38 +573,103 @@ impl Agent {
         reward: f32,
         max_mse: f64,
     ) -> u8 {
+        self.visit(frame_abstraction, reward, max_mse, None)
+    }
+
+    // Same as visit_state, but the human took `action` instead. Returns the
+    // action the agent would have taken.
+    pub fn demonstrate(
+        &mut self,
+        frame_abstraction: vision::FrameAbstraction,
+        reward: f32,
+        max_mse: f64,
+        action: u8,
+    ) -> u8 {
+        self.visit(frame_abstraction, reward, max_mse, Some(action))
+    }
+
+    fn visit(
+        &mut self,
+        mut frame_abstraction: vision::FrameAbstraction,
+        reward: f32,
+        max_mse: f64,
+        demonstration: Option<u8>,
+    ) -> u8 {
+        if self.backend.is_some() {
+            return self.visit_features(&frame_abstraction, reward, demonstration);
+        }
+
         // We need a way to recognize equivalent states
         // This is one of the most important/challenging parts
 
+        let raw_frame = frame_abstraction.raw_frame.take();
         let state = State::new(frame_abstraction);
 
         // Search or Add
         let current_index: usize;
         let current_action: u8;
         let max_q: f32;
-        if let Some(index) = self.search_state(&state, max_mse) {
-            // Return we are still in the same state
-            if index == self.states.len() - 1 {
-                return 0;
+        let mut reward = reward;
+        let found = self.search_state(&state, max_mse).or_else(|| {
+            let duplicate = self.search_duplicate(&state, max_mse);
+            self.merged_states += duplicate.is_some() as usize;
+            duplicate
+        });
+        if let Some(index) = found {
+            // Still in the same state
+            if Some(index) == self.previous_index {
+                self.same_state_count += 1;
+                match self.same_state {
+                    SameState::NoOp => return 0,
+                    SameState::RepeatPrevious => return self.previous_action.unwrap_or(0),
+                    SameState::SamplePolicy => reward -= self.same_state_penalty,
+                }
             }
-            // Existing state
+            // Existing state, seeded Q values are trusted right away
             let current_state = &self.states[index];
-            (current_action, max_q) = choose_best_action(current_state);
+            let best_action;
+            (best_action, max_q) = choose_best_action(current_state);
+            current_action = match current_state.random_untried_action() {
+                Some(action) if self.q_prior.is_none() => action,
+                _ => best_action,
+            };
             current_index = index;
             self.revisited = true;
         } else {
             // New state
             current_index = self.states.len();
-            self.states.push(state);
-            let mut rng = rand::thread_rng();
-            current_action = rng.gen_range(0..=255);
-            max_q = 0.0;
-            self.number_of_states = self.states.len();
+            let mut state = state;
+            if self.store_raw_frames {
+                state.raw_frame = raw_frame.as_ref().and_then(encode_raw_frame);
+            }
+            if let Some(q_prior) = &self.q_prior {
+                state.q = *q_prior;
+                (current_action, max_q) = choose_best_action(&state);
+            } else {
+                let mut rng = rand::thread_rng();
+                current_action = rng.gen_range(0..=255);
+                max_q = 0.0;
+            }
+            self.push_state(state);
             self.revisited = false;
         }
+        // Learned from as if the agent had taken it
+        let agent_action = current_action;
+        let current_action = demonstration.unwrap_or(current_action);
+        if demonstration.is_some() {
+            let q = &mut self.states[current_index].q[current_action as usize];
+            if *q < max_q {
+                *q += self.correction * (max_q - *q);
+            }
+        }
+        self.states[current_index].set_tried(current_action);
 
         // Heart of Q-Learning
         if let Some(previous_index) = self.previous_index {
+            *self
+                .transitions
+                .entry((previous_index, current_index))
+                .or_insert(0) += 1;
             //let reward = (reward + 1.0) / 2.0;
             //print!("Reward: {}\t", reward);
             let previous_state = &mut self.states[previous_index];
@@ -126,47 +680,282 @@ impl Agent {
             previous_state.q[act] =
                 previous_state.q[act] + self.learning_rate * temporal_difference;
             //println!("Next: {}", previous_state.q[act]);
+            self.replay.push(
+                Observed::State(previous_index),
+                act as u8,
+                reward,
+                Observed::State(current_index),
+                temporal_difference,
+            );
+        }
+
+        self.end_iteration(self.states.len(), max_q);
+
+        self.previous_index = Some(current_index);
+        self.previous_action = Some(current_action);
+        self.previous_q = Some(max_q);
+
+        agent_action
+    }
+
+    // Same as visit, with a Q backend: the features of the observation
+    // take the place of a state
+    fn visit_features(
+        &mut self,
+        frame_abstraction: &vision::FrameAbstraction,
+        reward: f32,
+        demonstration: Option<u8>,
+    ) -> u8 {
+        let features = Features::new(frame_abstraction);
+        let backend = self.backend.as_mut().unwrap();
+        let q_values = backend.q_values(&features);
+        let (best_action, max_q) = choose_best_of(&q_values);
+        println!("Chosen!: 0b{:08b} ({})", best_action, max_q);
+        let mut rng = rand::thread_rng();
+        let agent_action = match rng.gen::<f32>() < EXPLORATION {
+            true => rng.gen(),
+            false => best_action,
+        };
+        let current_action = demonstration.unwrap_or(agent_action);
+        if demonstration.is_some() && q_values[current_action as usize] < max_q {
+            backend.update(&features, current_action, max_q, self.correction);
+        }
+
+        if let (Some(previous_features), Some(previous_action)) =
+            (self.previous_features, self.previous_action)
+        {
+            let target = reward + self.discount_factor * max_q;
+            let error = target - backend.q_values(&previous_features)[previous_action as usize];
+            backend.update(
+                &previous_features,
+                previous_action,
+                target,
+                self.learning_rate,
+            );
+            self.replay.push(
+                Observed::Features(previous_features),
+                previous_action,
+                reward,
+                Observed::Features(features),
+                error,
+            );
         }
 
-        // For plots
+        let learned = backend.learned();
+        self.end_iteration(learned, max_q);
+
+        self.previous_features = Some(features);
+        self.previous_action = Some(current_action);
+        self.previous_q = Some(max_q);
+
+        agent_action
+    }
+
+    // For plots
+    fn end_iteration(&mut self, number_of_states: usize, max_q: f32) {
         let iteration_number = self.iteration_number as f64;
-        let number_of_states = self.states.len() as f64;
         self.states_per_iteration
-            .push([iteration_number, number_of_states]);
+            .push([iteration_number, number_of_states as f64]);
         self.max_q_per_iteration
             .push([iteration_number, max_q.into()]);
         self.iteration_number += 1;
+    }
 
-        self.previous_index = Some(current_index);
-        self.previous_action = Some(current_action);
-        self.previous_q = Some(max_q);
+    // Drops states without a frame, resets NaN/inf Q values to 0 and clamps
+    // centroids into [0, 1]
+    pub fn validate(&mut self) -> ValidationReport {
+        let mut report = ValidationReport::default();
+
+        let number_of_states = self.states.len();
+        let has_frame = |state: &State| {
+            let (width, height) = state.frame_abstraction.frame.dimensions();
+            width > 0 && height > 0
+        };
+        // Where each state ends up, to keep the transitions pointing to them
+        let mut kept = 0;
+        let new_indices: Vec<Option<usize>> = self
+            .states
+            .iter()
+            .map(|state| {
+                has_frame(state).then(|| {
+                    kept += 1;
+                    kept - 1
+                })
+            })
+            .collect();
+        self.states.retain(has_frame);
+        if self.states.len() != number_of_states {
+            self.transitions = remap_transitions(&self.transitions, |index| {
+                new_indices.get(index).copied().flatten()
+            });
+            report.dropped_states += number_of_states - self.states.len();
+            report.messages.push(format!(
+                "{} states with an empty frame dropped",
+                number_of_states - self.states.len()
+            ));
+            self.previous_index = None;
+            self.previous_action = None;
+            self.replay.transitions.clear();
+        }
 
-        current_action
+        for (i, state) in self.states.iter_mut().enumerate() {
+            let invalid_q = state.q.iter().filter(|q| !q.is_finite()).count();
+            if invalid_q > 0 {
+                state
+                    .q
+                    .iter_mut()
+                    .filter(|q| !q.is_finite())
+                    .for_each(|q| *q = 0.0);
+                report.repaired_q_values += invalid_q;
+                report
+                    .messages
+                    .push(format!("State {}: {} NaN/inf Q values reset", i, invalid_q));
+            }
+
+            let frame_abstraction = &mut state.frame_abstraction;
+            for centroid in [
+                &mut frame_abstraction.char1_centroid,
+                &mut frame_abstraction.char2_centroid,
+            ] {
+                let valid = |value: f32| (0.0..=1.0).contains(&value);
+                if !valid(centroid.0) || !valid(centroid.1) {
+                    report.messages.push(format!(
+                        "State {}: centroid {:?} outside [0, 1]",
+                        i, centroid
+                    ));
+                    // NaN becomes 0
+                    centroid.0 = centroid.0.clamp(0.0, 1.0).max(0.0);
+                    centroid.1 = centroid.1.clamp(0.0, 1.0).max(0.0);
+                    report.clamped_centroids += 1;
+                }
+            }
+        }
+
+        self.number_of_states = self.states.len();
+        self.index_states();
+        report
+    }
+
+    fn push_state(&mut self, state: State) {
+        self.occupancy_index
+            .entry(state.occupancy)
+            .or_insert(self.states.len());
+        self.states.push(state);
+        self.number_of_states = self.states.len();
+    }
+
+    fn index_states(&mut self) {
+        self.occupancy_index.clear();
+        for (i, state) in self.states.iter().enumerate() {
+            self.occupancy_index.entry(state.occupancy).or_insert(i);
+        }
+    }
+
+    // Best known action, no exploration and no Q update. Unknown states
+    // get no buttons pressed
+    pub fn act(&self, frame_abstraction: &vision::FrameAbstraction, max_mse: f64) -> u8 {
+        if let Some(backend) = &self.backend {
+            let (action, _) = choose_best_of(&backend.q_values(&Features::new(frame_abstraction)));
+            return action;
+        }
+        let state = State::new(frame_abstraction.clone());
+        match self.search_state(&state, max_mse) {
+            Some(index) => {
+                let (action, _) = choose_best_action(&self.states[index]);
+                action
+            }
+            None => 0,
+        }
+    }
+
+    // Second pass for states just outside the radius, which would otherwise
+    // be added again
+    fn search_duplicate(&self, state: &State, max_mse: f64) -> Option<usize> {
+        // Keys either match or they don't
+        if self.state_matching == StateMatching::Occupancy {
+            return None;
+        }
+        let centroid1 = state.frame_abstraction.char1_centroid;
+        let centroid2 = state.frame_abstraction.char2_centroid;
+        let frame = &state.frame_abstraction.frame;
+        let mut candidates: Vec<(f32, usize)> = self
+            .states
+            .iter()
+            .enumerate()
+            .filter(|(_, candidate)| {
+                candidate.frame_abstraction.frame.dimensions() == frame.dimensions()
+            })
+            .map(|(i, candidate)| {
+                let candidate1 = candidate.frame_abstraction.char1_centroid;
+                let candidate2 = candidate.frame_abstraction.char2_centroid;
+                let distance = (candidate1.0 - centroid1.0).abs()
+                    + (candidate1.1 - centroid1.1).abs()
+                    + (candidate2.0 - centroid2.0).abs()
+                    + (candidate2.1 - centroid2.1).abs();
+                (distance, i)
+            })
+            .collect();
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+        candidates
+            .iter()
+            .take(DEDUP_CANDIDATES)
+            .map(|(_, i)| {
+                let mse = vision::compute_roi_mse(
+                    &state.frame_abstraction,
+                    &self.states[*i].frame_abstraction,
+                );
+                (mse, *i)
+            })
+            .filter(|(mse, _)| *mse < max_mse * DEDUP_MSE_FACTOR)
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, i)| i)
     }
 
     fn search_state(&self, state: &State, max_mse: f64) -> Option<usize> {
+        if self.state_matching == StateMatching::Occupancy {
+            return self.occupancy_index.get(&state.occupancy).copied();
+        }
         let centroid1 = state.frame_abstraction.char1_centroid;
         let centroid2 = state.frame_abstraction.char2_centroid;
-        let mut best_index = 0;
-        let mut min_mse = 255.0 * 255.0;
+        let mut candidates = Vec::new();
         for (i, candidate) in self.states.iter().enumerate() {
             let candidate1 = candidate.frame_abstraction.char1_centroid;
             let candidate2 = candidate.frame_abstraction.char2_centroid;
-            let distance1 = ((candidate1.0 as i32 - centroid1.0 as i32).abs()
-                + (candidate1.1 as i32 - centroid1.1 as i32).abs())
-                as u32;
-            let distance2 = ((candidate2.0 as i32 - centroid2.0 as i32).abs()
-                + (candidate2.1 as i32 - centroid2.1 as i32).abs())
-                as u32;
+            let distance1 = (candidate1.0 - centroid1.0).abs() + (candidate1.1 - centroid1.1).abs();
+            let distance2 = (candidate2.0 - centroid2.0).abs() + (candidate2.1 - centroid2.1).abs();
             if distance1 < self.radius && distance2 < self.radius {
                 let frame = &state.frame_abstraction.frame;
                 let other_frame = &candidate.frame_abstraction.frame;
-                let mse = vision::compute_mse(frame, other_frame);
-                //println!("MSE {}", mse);
-                if mse < min_mse {
-                    best_index = i;
-                    min_mse = mse;
+                // Observation layout changed mid training
+                if frame.dimensions() != other_frame.dimensions() {
+                    continue;
                 }
+                let coarse_mse = vision::compute_coarse_mse(
+                    &state.thumbnail,
+                    &state.frame_abstraction,
+                    &candidate.thumbnail,
+                    &candidate.frame_abstraction,
+                );
+                candidates.push((coarse_mse, i));
+            }
+        }
+
+        // Coarse to fine
+        if candidates.len() > FULL_MSE_CANDIDATES {
+            candidates.select_nth_unstable_by(FULL_MSE_CANDIDATES, |a, b| a.0.total_cmp(&b.0));
+            candidates.truncate(FULL_MSE_CANDIDATES);
+        }
+        let mut best_index = 0;
+        let mut min_mse = 255.0 * 255.0;
+        for (_, i) in candidates {
+            let mse = vision::compute_roi_mse(
+                &state.frame_abstraction,
+                &self.states[i].frame_abstraction,
+            );
+            //println!("MSE {}", mse);
+            if mse < min_mse {
+                best_index = i;
+                min_mse = mse;
             }
         }
 
@@ -177,6 +966,135 @@ impl Agent {
         }
     }
 
+    // Seeds this agent with another one, e.g. trained with a character with
+    // a similar moveset. Returns a summary of what was transferred.
+    pub fn transfer_from(
+        &mut self,
+        other: &Agent,
+        strategy: TransferStrategy,
+        max_mse: f64,
+    ) -> String {
+        match strategy {
+            TransferStrategy::MatchedStates => {
+                let mut matched = 0;
+                let mut added = 0;
+                for other_state in other.states.iter() {
+                    let state = State {
+                        frame_abstraction: other_state.frame_abstraction.clone(),
+                        q: other_state.q,
+                        tried: other_state.tried,
+                        thumbnail: other_state.thumbnail.clone(),
+                        occupancy: other_state.occupancy,
+                        raw_frame: other_state.raw_frame.clone(),
+                    };
+                    match self.search_state(&state, max_mse) {
+                        Some(index) => {
+                            self.states[index].q = state.q;
+                            for (tried, other_tried) in
+                                self.states[index].tried.iter_mut().zip(state.tried)
+                            {
+                                *tried |= other_tried;
+                            }
+                            matched += 1;
+                        }
+                        None => {
+                            self.push_state(state);
+                            added += 1;
+                        }
+                    }
+                }
+                format!("{} states matched, {} added", matched, added)
+            }
+            TransferStrategy::PolicyPriors => {
+                if other.states.is_empty() {
+                    return "Nothing to transfer, the agent has no states".to_string();
+                }
+                let mut q_prior = [0.0; 256];
+                for state in other.states.iter() {
+                    for (prior, q) in q_prior.iter_mut().zip(state.q.iter()) {
+                        *prior += q / other.states.len() as f32;
+                    }
+                }
+                // States never updated are as good as new
+                let mut seeded = 0;
+                for state in self.states.iter_mut() {
+                    if state.q.iter().all(|q| *q == 0.0) {
+                        state.q = q_prior;
+                        seeded += 1;
+                    }
+                }
+                self.q_prior = Some(q_prior);
+                format!(
+                    "Priors from {} states, {} untrained states seeded",
+                    other.states.len(),
+                    seeded
+                )
+            }
+        }
+    }
+
+    // None without states
+    #[allow(dead_code)]
+    pub fn get_q_stats(&self) -> Option<QStats> {
+        if self.states.is_empty() {
+            return None;
+        }
+        let mut stats = QStats {
+            min: f32::MAX,
+            max: f32::MIN,
+            mean: 0.0,
+            mean_best: 0.0,
+            tried_actions: 0,
+            states: self.states.len(),
+        };
+        let mut sum = 0.0;
+        let mut sum_best = 0.0;
+        for state in self.states.iter() {
+            let mut best = f32::MIN;
+            for q in state.q.iter() {
+                stats.min = stats.min.min(*q);
+                stats.max = stats.max.max(*q);
+                best = best.max(*q);
+                sum += *q as f64;
+            }
+            sum_best += best as f64;
+            stats.tried_actions += (0..=255).filter(|action| state.is_tried(*action)).count();
+        }
+        stats.mean = (sum / (self.states.len() * 256) as f64) as f32;
+        stats.mean_best = (sum_best / self.states.len() as f64) as f32;
+        Some(stats)
+    }
+
+    // Up to `count` state abstractions, evenly spread over the states
+    pub fn get_sample_abstractions(&self, count: usize) -> Vec<RgbImage> {
+        if self.states.is_empty() || count == 0 {
+            return Vec::new();
+        }
+        let step = (self.states.len() / count).max(1);
+        self.states
+            .iter()
+            .step_by(step)
+            .take(count)
+            .map(|state| state.frame_abstraction.frame.clone())
+            .collect()
+    }
+
+    // Observed transitions as (from, to, count), most frequent first
+    pub fn get_transitions(&self) -> Vec<(usize, usize, u64)> {
+        let mut transitions: Vec<(usize, usize, u64)> = self
+            .transitions
+            .iter()
+            .map(|((from, to), count)| (*from, *to, *count))
+            .collect();
+        transitions.sort_by(|a, b| b.2.cmp(&a.2).then((a.0, a.1).cmp(&(b.0, b.1))));
+        transitions
+    }
+
+    // Index of the state visited last, and whether it was added then
+    pub fn get_last_state(&self) -> Option<(usize, bool)> {
+        self.previous_index.map(|index| (index, !self.revisited))
+    }
+
     pub fn get_last_state_abstraction(&self) -> RgbImage {
         if let Some(index) = self.previous_index {
             let mut frame = self.states[index].frame_abstraction.frame.clone();
@@ -205,7 +1123,137 @@ impl Agent {
         self.states.len()
     }
 
-    pub fn set_radius(&mut self, radius: u32) {
+    pub fn set_learning_rate(&mut self, learning_rate: f32) {
+        self.learning_rate = learning_rate;
+    }
+
+    pub fn get_learning_rate(&self) -> f32 {
+        self.learning_rate
+    }
+
+    pub fn set_discount_factor(&mut self, discount_factor: f32) {
+        self.discount_factor = discount_factor;
+    }
+
+    pub fn get_discount_factor(&self) -> f32 {
+        self.discount_factor
+    }
+
+    pub fn set_same_state(&mut self, same_state: SameState, penalty: f32) {
+        self.same_state = same_state;
+        self.same_state_penalty = penalty;
+    }
+
+    // New states that went into an existing one found by search_duplicate
+    pub fn get_merged_states(&self) -> usize {
+        self.merged_states
+    }
+
+    // Observations that matched the state the agent was already in
+    pub fn get_same_state_count(&self) -> usize {
+        self.same_state_count
+    }
+
+    pub fn set_correction(&mut self, correction: f32) {
+        self.correction = correction;
+    }
+
+    pub fn set_state_matching(&mut self, state_matching: StateMatching) {
+        self.state_matching = state_matching;
+    }
+
+    pub fn set_store_raw_frames(&mut self, store_raw_frames: bool) {
+        self.store_raw_frames = store_raw_frames;
+    }
+
+    pub fn get_store_raw_frames(&self) -> bool {
+        self.store_raw_frames
+    }
+
+    // States with a raw frame, and how many bytes those take
+    pub fn get_raw_frames_size(&self) -> (usize, usize) {
+        self.states
+            .iter()
+            .filter_map(|state| state.raw_frame.as_ref())
+            .fold((0, 0), |(count, bytes), raw_frame| {
+                (count + 1, bytes + raw_frame.len())
+            })
+    }
+
+    // Only before the agent learns anything, what the table learned doesn't
+    // carry over
+    pub fn set_backend(&mut self, backend: Option<Box<dyn QBackend>>) {
+        self.backend = backend;
+        self.previous_features = None;
+        self.previous_index = None;
+        self.previous_action = None;
+        self.replay.transitions.clear();
+    }
+
+    // Keeps the last `capacity` transitions, and learns from `batch_size` of
+    // them on every call to replay. A capacity of 0 turns it off.
+    pub fn set_replay(&mut self, capacity: usize, batch_size: usize, sampling: Sampling) {
+        let replay = &mut self.replay;
+        replay.capacity = capacity;
+        replay.batch_size = batch_size;
+        replay.sampling = sampling;
+        while replay.transitions.len() > capacity {
+            replay.transitions.pop_front();
+        }
+    }
+
+    // Transitions in memory, and updates done from them so far
+    pub fn get_replay_size(&self) -> (usize, u64) {
+        (self.replay.transitions.len(), self.replay.replayed)
+    }
+
+    // One mini-batch of Q updates from the replay memory, outside the
+    // observations, which only learn from the transition they complete
+    pub fn replay(&mut self) {
+        for i in self.replay.sample() {
+            let transition = self.replay.transitions[i];
+            let action = transition.action as usize;
+            let error = match (transition.from, transition.to) {
+                (Observed::State(from), Observed::State(to)) => {
+                    if from >= self.states.len() || to >= self.states.len() {
+                        continue;
+                    }
+                    let max_q = max_q_of(&self.states[to].q);
+                    let q = &mut self.states[from].q[action];
+                    let temporal_difference = transition.reward + self.discount_factor * max_q - *q;
+                    *q += self.learning_rate * temporal_difference;
+                    temporal_difference
+                }
+                (Observed::Features(from), Observed::Features(to)) => {
+                    let Some(backend) = self.backend.as_mut() else {
+                        continue;
+                    };
+                    let max_q = max_q_of(&backend.q_values(&to));
+                    let target = transition.reward + self.discount_factor * max_q;
+                    let error = target - backend.q_values(&from)[action];
+                    backend.update(&from, transition.action, target, self.learning_rate);
+                    error
+                }
+                _ => continue,
+            };
+            self.replay.transitions[i].priority = error.abs() + REPLAY_PRIORITY_EPSILON;
+            self.replay.replayed += 1;
+        }
+    }
+
+    pub fn get_backend_name(&self) -> &'static str {
+        match &self.backend {
+            Some(backend) => backend.name(),
+            None => "Table",
+        }
+    }
+
+    // Tiles or whatever the backend learns over, None for the table
+    pub fn get_backend_learned(&self) -> Option<usize> {
+        self.backend.as_ref().map(|backend| backend.learned())
+    }
+
+    pub fn set_radius(&mut self, radius: f32) {
         self.radius = radius;
     }
 
@@ -221,47 +1269,245 @@ impl Agent {
         self.training_time += training_time;
     }
 
+    // Agent and opponent, saved along with the agent
+    pub fn set_characters(&mut self, agent_character: String, opponent_character: String) {
+        self.characters = Some([agent_character, opponent_character]);
+    }
+
     pub fn get_training_time(&self) -> Duration {
         // Don't we need clone here?
         self.training_time
     }
 }
 
-fn choose_best_action(state: &State) -> (u8, f32) {
-    let mut max_q = -1.0;
-    let mut best_action = None;
-    for (action, &q) in state.q.iter().enumerate() {
-        if q > max_q {
-            best_action = Some(action as u8);
-            max_q = q;
+// Stopping conditions for a training session, 0 means no limit. They count
+// from the start of the run, so running again after a stop gets the whole
+// budget again.
+pub struct TrainingBudget {
+    pub max_minutes: u64,
+    pub max_episodes: usize,
+    pub max_states: usize,
+    // Stop when the win rate of the last window of episodes is within
+    // PLATEAU_TOLERANCE of the window before
+    pub plateau_window: usize,
+    // Where the agent was when the run started, None between runs
+    start: Option<RunStart>,
+}
+
+#[derive(Clone, Copy)]
+struct RunStart {
+    training_time: Duration,
+    episodes: usize,
+    states: usize,
+}
+
+const PLATEAU_TOLERANCE: f32 = 0.05;
+
+impl TrainingBudget {
+    pub fn new() -> Self {
+        Self {
+            max_minutes: 0,
+            max_episodes: 0,
+            max_states: 0,
+            plateau_window: 0,
+            start: None,
         }
     }
-    if let Some(best_action) = best_action {
-        println!("Chosen!: 0b{:08b} ({})", best_action, max_q);
-        return (best_action, max_q);
+
+    // Call while training, the first call after stop starts a new run
+    pub fn check(&mut self, agent: &Agent, episodes: &[bool]) -> Option<String> {
+        let start = *self.start.get_or_insert(RunStart {
+            training_time: agent.training_time,
+            episodes: episodes.len(),
+            states: agent.states.len(),
+        });
+        let training_time = agent.training_time.saturating_sub(start.training_time);
+        let episodes = &episodes[start.episodes.min(episodes.len())..];
+        let states = agent.states.len().saturating_sub(start.states);
+
+        if self.max_minutes > 0 && training_time.as_secs() >= self.max_minutes * 60 {
+            return Some(format!("Reached {} minutes of training", self.max_minutes));
+        }
+        if self.max_episodes > 0 && episodes.len() >= self.max_episodes {
+            return Some(format!("Reached {} episodes", self.max_episodes));
+        }
+        if self.max_states > 0 && states >= self.max_states {
+            return Some(format!("Reached {} new states", self.max_states));
+        }
+        let window = self.plateau_window;
+        if window > 0 && episodes.len() >= 2 * window {
+            let last = win_rate(&episodes[episodes.len() - window..]);
+            let previous =
+                win_rate(&episodes[episodes.len() - 2 * window..episodes.len() - window]);
+            if (last - previous).abs() < PLATEAU_TOLERANCE {
+                return Some(format!("Win rate plateaued at {:.2}", last));
+            }
+        }
+        None
+    }
+
+    // The run is over, the next check starts another one
+    pub fn stop(&mut self) {
+        self.start = None;
+    }
+
+    // Episodes still to go in this run, all of them between runs
+    pub fn episodes_left(&self, episodes: &[bool]) -> usize {
+        let done = match self.start {
+            Some(start) => episodes.len().saturating_sub(start.episodes),
+            None => 0,
+        };
+        self.max_episodes.saturating_sub(done)
+    }
+}
+
+pub fn win_rate(episodes: &[bool]) -> f32 {
+    if episodes.is_empty() {
+        return 0.0;
     }
+    episodes.iter().filter(|&&won| won).count() as f32 / episodes.len() as f32
+}
+
+#[derive(Serialize)]
+struct TrainingSummary {
+    reason: String,
+    training_time: Duration,
+    iteration_number: usize,
+    number_of_states: usize,
+    episodes: usize,
+    wins: usize,
+    win_rate: f32,
+}
+
+// Final checkpoint plus a summary.json next to it
+pub fn save_final_checkpoint(agent: &Agent, episodes: &[bool], reason: &str, path: &str) {
+    save_agent(agent, path);
+
+    let summary = TrainingSummary {
+        reason: reason.to_string(),
+        training_time: agent.training_time,
+        iteration_number: agent.iteration_number,
+        number_of_states: agent.states.len(),
+        episodes: episodes.len(),
+        wins: episodes.iter().filter(|&&won| won).count(),
+        win_rate: win_rate(episodes),
+    };
+    match fs::File::create(Path::new(path).join("summary.json")) {
+        Ok(summary_file) => {
+            let _ = serde_json::to_writer_pretty(summary_file, &summary);
+        }
+        Err(e) => error!("Error writing training summary: {}", e),
+    }
+}
+
+// Ties are broken at random, otherwise a table of zeros always picks 0, no
+// buttons at all
+fn choose_best_action(state: &State) -> (u8, f32) {
+    let (best_action, max_q) = choose_best_of(&state.q);
+    println!("Chosen!: 0b{:08b} ({})", best_action, max_q);
+    (best_action, max_q)
+}
+
+fn choose_best_of(q: &[f32; 256]) -> (u8, f32) {
+    let max_q = q.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let best_actions: Vec<u8> = (0..=255)
+        .filter(|action| q[*action as usize] == max_q)
+        .collect();
     let mut rng = rand::thread_rng();
-    (rng.gen_range(0..=255), max_q)
+    if best_actions.is_empty() {
+        return (rng.gen_range(0..=255), 0.0);
+    }
+    let best_action = best_actions[rng.gen_range(0..best_actions.len())];
+    (best_action, max_q)
+}
+
+// Value of the best action without choosing it, for replayed transitions,
+// which happen many times per observation
+fn max_q_of(q: &[f32; 256]) -> f32 {
+    let max_q = q.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    match max_q == f32::NEG_INFINITY {
+        true => 0.0,
+        false => max_q,
+    }
+}
+
+// Bump when the saved layout changes, and keep loading the old ones
+// 3: centroids normalized to [0, 1] instead of pixels
+// 4: learning rate and discount factor saved
+// 5: optional Q backend, in backend.bin
+const AGENT_FORMAT_VERSION: u32 = 5;
+
+fn legacy_version() -> u32 {
+    1
+}
+
+// What agents were trained with before version 4
+fn legacy_learning_rate() -> f32 {
+    0.5
+}
+
+fn legacy_discount_factor() -> f32 {
+    0.9
 }
 
 #[derive(Serialize, Deserialize)]
-struct SerDesAgent {
-    number_of_states: usize,
-    iteration_number: usize,
-    training_time: Duration,
+pub struct SerDesAgent {
+    #[serde(default = "legacy_version")]
+    pub version: u32,
+    pub number_of_states: usize,
+    pub iteration_number: usize,
+    pub training_time: Duration,
+    // Missing in agents saved before characters were recorded
+    #[serde(default)]
+    pub characters: Option<[String; 2]>,
+    #[serde(default = "legacy_learning_rate")]
+    pub learning_rate: f32,
+    #[serde(default = "legacy_discount_factor")]
+    pub discount_factor: f32,
+    #[serde(default)]
+    pub store_raw_frames: bool,
 }
 
 impl SerDesAgent {
     pub fn new(agent: &Agent) -> Self {
         Self {
+            version: AGENT_FORMAT_VERSION,
             number_of_states: agent.number_of_states,
             iteration_number: agent.iteration_number,
             training_time: agent.training_time,
+            characters: agent.characters.clone(),
+            learning_rate: agent.learning_rate,
+            discount_factor: agent.discount_factor,
+            store_raw_frames: agent.store_raw_frames,
         }
     }
 }
 
+// Only agent.json, cheap enough to list many agents. Agent files have it
+// first, the rest is not read.
+pub fn load_agent_metadata(agent_path: &Path) -> Option<SerDesAgent> {
+    if is_agent_file(agent_path) {
+        let mut file = BufReader::new(fs::File::open(agent_path).ok()?);
+        file.seek_relative(AGENT_FILE_TAG.len() as i64).ok()?;
+        return bincode::deserialize_from(DeflateDecoder::new(file)).ok();
+    }
+    let agent_file = fs::File::open(agent_path.join("agent.json")).ok()?;
+    serde_json::from_reader(BufReader::new(agent_file)).ok()
+}
+
 pub fn save_agent(agent: &Agent, path: &str) {
+    if let Err(e) = save_agent_with_progress(agent, path, &Progress::default()) {
+        error!("Error saving agent: {}", e);
+    }
+}
+
+// Same as save_agent, reporting how many states are written. State files
+// are encoded in parallel, and a cancelled save leaves nothing behind.
+pub fn save_agent_with_progress(
+    agent: &Agent,
+    path: &str,
+    progress: &Progress,
+) -> Result<(), String> {
     println!("Saving agent to {}...", path);
 
     let agent_path = Path::new(path);
@@ -269,8 +1515,7 @@ pub fn save_agent(agent: &Agent, path: &str) {
     if !agent_path.exists() {
         let _ = fs::create_dir_all(agent_path);
     } else {
-        println!("Path already exists: {}", path);
-        return;
+        return Err(format!("Path already exists: {}", path));
     }
 
     // Serializable data from agent
@@ -281,36 +1526,42 @@ pub fn save_agent(agent: &Agent, path: &str) {
     // States
     let states_path = agent_path.join("states");
     let _ = fs::create_dir_all(states_path.clone());
+    progress.start(agent.states.len());
+    let saved = agent
+        .states
+        .par_iter()
+        .enumerate()
+        .try_for_each(|(i, state)| {
+            if progress.is_cancelled() {
+                return Err("Cancelled".to_string());
+            }
+            save_state_files(state, i, &states_path)?;
+            progress.advance();
+            Ok(())
+        });
+    if let Err(e) = saved {
+        let _ = fs::remove_dir_all(agent_path);
+        return Err(e);
+    }
     let mut data = fs::File::create(states_path.join("data.csv")).unwrap();
     for (i, state) in agent.states.iter().enumerate() {
-        // Frame
-        let frame_path = states_path.join(format!("{:06}.png", i));
-        state
-            .frame_abstraction
-            .frame
-            .save(frame_path.clone())
-            .expect("Failed to save frame");
-
-        // Q
-        let q_path = states_path.join(format!("{:06}_q.csv", i));
-        let mut q_file = fs::File::create(q_path.clone()).unwrap();
-        for q in state.q.iter() {
-            match writeln!(q_file, "{}", q) {
-                Ok(_) => (),
-                Err(e) => error!("Error writing q value: {}", e),
+        // Data, with the region of interest if there is one
+        let roi = match state.frame_abstraction.roi {
+            Some((corner1, corner2)) => {
+                format!(",{},{},{},{}", corner1.0, corner1.1, corner2.0, corner2.1)
             }
-        }
-
-        // Data
+            None => String::new(),
+        };
         match writeln!(
             data,
-            "{},{},{},{},{},{}",
-            frame_path.file_name().unwrap().to_string_lossy(),
+            "{:06}.png,{},{},{},{},{:06}_q.csv{}",
+            i,
             state.frame_abstraction.char1_centroid.0,
             state.frame_abstraction.char1_centroid.1,
             state.frame_abstraction.char2_centroid.0,
             state.frame_abstraction.char2_centroid.1,
-            q_path.file_name().unwrap().to_string_lossy(),
+            i,
+            roi,
         ) {
             Ok(_) => (),
             Err(e) => error!("Error writing state data: {}", e),
@@ -336,95 +1587,874 @@ pub fn save_agent(agent: &Agent, path: &str) {
             Err(e) => error!("Error writing max Q per iteration: {}", e),
         }
     }
+
+    // Transitions between states, by index in states/data.csv
+    let mut transitions_file = fs::File::create(agent_path.join("transitions.csv")).unwrap();
+    for (from, to, count) in agent.get_transitions() {
+        match writeln!(transitions_file, "{},{},{}", from, to, count) {
+            Ok(_) => (),
+            Err(e) => error!("Error writing transitions: {}", e),
+        }
+    }
+
+    // Q prior, only for agents transferred with policy priors
+    if let Some(q_prior) = &agent.q_prior {
+        let mut q_prior_file = fs::File::create(agent_path.join("q_prior.csv")).unwrap();
+        for q in q_prior.iter() {
+            match writeln!(q_prior_file, "{}", q) {
+                Ok(_) => (),
+                Err(e) => error!("Error writing Q prior: {}", e),
+            }
+        }
+    }
+
+    // Q backend, with its name
+    if let Some(backend) = &agent.backend {
+        let backend_path = agent_path.join("backend.bin");
+        let saved = SavedBackend::new(backend.as_ref())
+            .and_then(|saved| bincode::serialize(&saved).map_err(|e| e.to_string()))
+            .and_then(|bytes| fs::write(&backend_path, bytes).map_err(|e| e.to_string()));
+        if let Err(e) = saved {
+            let _ = fs::remove_dir_all(agent_path);
+            return Err(format!("{}: {}", backend_path.display(), e));
+        }
+    }
+
+    println!("{}", size_on_disk_report(agent_path));
+    Ok(())
+}
+
+// Abstraction, raw frame and Q values of the state at `index`
+fn save_state_files(state: &State, index: usize, states_path: &Path) -> Result<(), String> {
+    // Frame
+    let frame_path = states_path.join(format!("{:06}.png", index));
+    state
+        .frame_abstraction
+        .frame
+        .save(&frame_path)
+        .map_err(|e| format!("{}: {}", frame_path.display(), e))?;
+
+    // Raw frame, kept in whatever it was encoded
+    if let Some(raw_frame) = &state.raw_frame {
+        let extension = image::guess_format(raw_frame)
+            .ok()
+            .and_then(|format| format.extensions_str().first().copied())
+            .unwrap_or("jpg");
+        let raw_path = states_path.join(format!("{:06}_raw.{}", index, extension));
+        fs::write(&raw_path, raw_frame).map_err(|e| format!("{}: {}", raw_path.display(), e))?;
+    }
+
+    // Q
+    let q_path = states_path.join(format!("{:06}_q.csv", index));
+    let q_values: String = state.q.iter().map(|q| format!("{}\n", q)).collect();
+    fs::write(&q_path, q_values).map_err(|e| format!("{}: {}", q_path.display(), e))
+}
+
+// Bytes taken by abstractions, raw frames, Q tables and the rest
+fn size_on_disk_report(agent_path: &Path) -> String {
+    let mut abstractions = 0;
+    let mut raw_frames = (0, 0);
+    let mut q_tables = 0;
+    let mut rest = 0;
+    let files = [agent_path.to_path_buf(), agent_path.join("states")]
+        .into_iter()
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.flatten());
+    for entry in files {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if !metadata.is_file() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        let size = metadata.len();
+        if name.contains("_raw.") {
+            raw_frames = (raw_frames.0 + 1, raw_frames.1 + size);
+        } else if name.ends_with("_q.csv") {
+            q_tables += size;
+        } else if name.ends_with(".png") {
+            abstractions += size;
+        } else {
+            rest += size;
+        }
+    }
+    let kib = |bytes: u64| bytes as f64 / 1024.0;
+    format!(
+        "Size on disk: {:.1} KiB abstractions, {:.1} KiB raw frames ({} states), {:.1} KiB Q tables, {:.1} KiB other, {:.1} KiB total",
+        kib(abstractions),
+        kib(raw_frames.1),
+        raw_frames.0,
+        kib(q_tables),
+        kib(rest),
+        kib(abstractions + raw_frames.1 + q_tables + rest)
+    )
 }
 
-pub fn load_agent(path: &str) -> Agent {
+pub fn load_agent(path: &str) -> Result<Agent, String> {
+    load_agent_with_progress(path, &Progress::default())
+}
+
+// Same as load_agent, reporting how many states are read
+pub fn load_agent_with_progress(path: &str, progress: &Progress) -> Result<Agent, String> {
     println!("Loading agent from {}...", path);
 
     let agent_path = Path::new(path);
 
     if !agent_path.exists() {
-        println!("Path doesn't exist: {}", path);
-        return Agent::new();
+        return Err(format!("Path doesn't exist: {}", path));
+    }
+    if agent_path.is_file() {
+        return load_agent_file(agent_path, progress);
     }
 
     // Deserializable data to agent
-    let agent_file = fs::File::open(agent_path.join("agent.json")).unwrap();
+    let agent_json = agent_path.join("agent.json");
+    let agent_file =
+        fs::File::open(&agent_json).map_err(|e| format!("{}: {}", agent_json.display(), e))?;
     let reader = BufReader::new(agent_file);
-    let ser_des_agent: SerDesAgent = serde_json::from_reader(reader).unwrap();
+    let ser_des_agent: SerDesAgent =
+        serde_json::from_reader(reader).map_err(|e| format!("{}: {}", agent_json.display(), e))?;
+
+    // Version 1 had no version nor characters, up to 2 centroids were pixels
+    check_version(ser_des_agent.version)?;
 
-    // Read states
+    // Read states, broken ones are repaired or dropped rather than failing
+    let mut report = ValidationReport::default();
     let mut states = Vec::<State>::new();
+    // Saved index of each loaded state, dropped ones leave a gap
+    let mut saved_indices = HashMap::new();
     let states_path = agent_path.join("states");
-    let data = fs::File::open(states_path.join("data.csv")).unwrap();
-    let reader = BufReader::new(data);
-    for line in reader.lines() {
-        let line = line.unwrap();
-        let tokens: Vec<&str> = line.split(',').collect();
+    let data = read_csv(&states_path.join("data.csv"))?;
+    progress.start(data.len());
+    for (saved_index, (line_number, tokens)) in data.into_iter().enumerate() {
+        if progress.is_cancelled() {
+            return Err("Cancelled".to_string());
+        }
+        progress.advance();
+        let location = format!("{}:{}", states_path.join("data.csv").display(), line_number);
+        if tokens.len() < 6 {
+            return Err(format!(
+                "{}: expected 6 fields, found {}",
+                location,
+                tokens.len()
+            ));
+        }
 
         // Frame abstraction
-        let frame_path = states_path.join(tokens[0].to_string());
-        let frame = image::open(&frame_path).unwrap().to_rgb8();
-        let char1_centroid: (u32, u32) = (
-            tokens[1].trim().parse().unwrap(),
-            tokens[2].trim().parse().unwrap(),
-        );
-        let char2_centroid: (u32, u32) = (
-            tokens[3].trim().parse().unwrap(),
-            tokens[4].trim().parse().unwrap(),
-        );
-        let frame_abstraction =
+        let frame_path = states_path.join(&tokens[0]);
+        let frame = match image::open(&frame_path) {
+            Ok(frame) => frame.to_rgb8(),
+            Err(e) => {
+                report.dropped_states += 1;
+                report.messages.push(format!(
+                    "{}: dropped, {}: {}",
+                    location,
+                    frame_path.display(),
+                    e
+                ));
+                continue;
+            }
+        };
+        let (char1_centroid, char2_centroid) = if ser_des_agent.version < 3 {
+            // Pixels back then
+            let char1_centroid: (u32, u32) = (
+                parse_field(&tokens[1], &location)?,
+                parse_field(&tokens[2], &location)?,
+            );
+            let char2_centroid: (u32, u32) = (
+                parse_field(&tokens[3], &location)?,
+                parse_field(&tokens[4], &location)?,
+            );
+            (
+                vision::normalize_centroid(char1_centroid, &frame),
+                vision::normalize_centroid(char2_centroid, &frame),
+            )
+        } else {
+            (
+                (
+                    parse_field(&tokens[1], &location)?,
+                    parse_field(&tokens[2], &location)?,
+                ),
+                (
+                    parse_field(&tokens[3], &location)?,
+                    parse_field(&tokens[4], &location)?,
+                ),
+            )
+        };
+        let mut frame_abstraction =
             vision::FrameAbstraction::new(frame, char1_centroid, char2_centroid);
+        // Older agents have none, it is taken from the frame instead
+        if tokens.len() >= 10 {
+            frame_abstraction.roi = Some((
+                (
+                    parse_field(&tokens[6], &location)?,
+                    parse_field(&tokens[7], &location)?,
+                ),
+                (
+                    parse_field(&tokens[8], &location)?,
+                    parse_field(&tokens[9], &location)?,
+                ),
+            ));
+        }
 
         let mut state = State::new(frame_abstraction);
 
+        // Raw frame, if it was kept
+        if let Some(raw_path) = find_raw_frame(&states_path, &tokens[0]) {
+            match fs::read(&raw_path) {
+                Ok(raw_frame) => state.raw_frame = Some(raw_frame),
+                Err(e) => report
+                    .messages
+                    .push(format!("{}: {}", raw_path.display(), e)),
+            }
+        }
+
         // Q
-        let q_path = states_path.join(tokens[5].to_string());
-        let q_file = fs::File::open(q_path).unwrap();
-        let reader = BufReader::new(q_file);
-        for (i, line) in reader.lines().enumerate() {
-            let line = line.unwrap();
-            let value: f32 = line.trim().parse().unwrap();
-            state.q[i] = value;
+        let q_path = states_path.join(&tokens[5]);
+        let q_values = match read_csv(&q_path) {
+            Ok(q_values) => q_values,
+            Err(e) => {
+                report.dropped_states += 1;
+                report
+                    .messages
+                    .push(format!("{}: dropped, {}", location, e));
+                continue;
+            }
+        };
+        if q_values.len() != state.q.len() {
+            // Missing values stay at 0, extra ones are ignored
+            report.resized_q_tables += 1;
+            report.messages.push(format!(
+                "{}: expected {} Q values, found {}",
+                q_path.display(),
+                state.q.len(),
+                q_values.len()
+            ));
+        }
+        for (i, (line_number, tokens)) in q_values.iter().take(state.q.len()).enumerate() {
+            let location = format!("{}:{}", q_path.display(), line_number);
+            state.q[i] = parse_field(&tokens[0], &location)?;
+        }
+        // Not saved, but an action taken leaves its Q value behind
+        for action in 0..=255 {
+            if state.q[action as usize] != 0.0 {
+                state.set_tried(action);
+            }
         }
 
+        saved_indices.insert(saved_index, states.len());
         states.push(state);
     }
 
-    // States per iteration
-    let mut states_per_iteration = Vec::<[f64; 2]>::new();
-    let states_per_iteration_file =
-        fs::File::open(agent_path.join("states_per_iteration.csv")).unwrap();
-    let reader = BufReader::new(states_per_iteration_file);
-    for line in reader.lines() {
-        let line = line.unwrap();
-        let tokens: Vec<&str> = line.split(',').collect();
-        let iteration_number: f64 = tokens[0].trim().parse().unwrap();
-        let number_of_states: f64 = tokens[1].trim().parse().unwrap();
-        states_per_iteration.push([iteration_number, number_of_states]);
+    // Older agents have no transitions
+    let transitions_path = agent_path.join("transitions.csv");
+    let mut transitions = HashMap::new();
+    if transitions_path.exists() {
+        for (line_number, tokens) in read_csv(&transitions_path)? {
+            let location = format!("{}:{}", transitions_path.display(), line_number);
+            if tokens.len() < 3 {
+                return Err(format!(
+                    "{}: expected 3 fields, found {}",
+                    location,
+                    tokens.len()
+                ));
+            }
+            let from: usize = parse_field(&tokens[0], &location)?;
+            let to: usize = parse_field(&tokens[1], &location)?;
+            transitions.insert((from, to), parse_field(&tokens[2], &location)?);
+        }
     }
+    let transitions = remap_transitions(&transitions, |index| saved_indices.get(&index).copied());
 
-    // Max Q per iteration
-    let mut max_q_per_iteration = Vec::<[f64; 2]>::new();
-    let max_q_per_iteration_file =
-        fs::File::open(agent_path.join("max_q_per_iteration.csv")).unwrap();
-    let reader = BufReader::new(max_q_per_iteration_file);
-    for line in reader.lines() {
-        let line = line.unwrap();
-        let tokens: Vec<&str> = line.split(',').collect();
-        let iteration_number: f64 = tokens[0].trim().parse().unwrap();
-        let max_q: f64 = tokens[1].trim().parse().unwrap();
-        max_q_per_iteration.push([iteration_number, max_q]);
-    }
-
-    // Build agent
+    // Plots
+    let states_per_iteration = read_plot(&agent_path.join("states_per_iteration.csv"))?;
+    let max_q_per_iteration = read_plot(&agent_path.join("max_q_per_iteration.csv"))?;
+    let q_prior_path = agent_path.join("q_prior.csv");
+    let q_prior = if q_prior_path.exists() {
+        let mut q_prior = [0.0; 256];
+        for (i, (line_number, tokens)) in read_csv(&q_prior_path)?.iter().take(256).enumerate() {
+            let location = format!("{}:{}", q_prior_path.display(), line_number);
+            q_prior[i] = parse_field(&tokens[0], &location)?;
+        }
+        Some(q_prior)
+    } else {
+        None
+    };
+
+    // Only agents with a Q backend have it
+    let backend_path = agent_path.join("backend.bin");
+    let backend = match backend_path.exists() {
+        true => {
+            let error = |e: String| format!("{}: {}", backend_path.display(), e);
+            let bytes = fs::read(&backend_path).map_err(|e| error(e.to_string()))?;
+            let saved: SavedBackend =
+                bincode::deserialize(&bytes).map_err(|e| error(e.to_string()))?;
+            Some(saved.load().map_err(error)?)
+        }
+        false => None,
+    };
+
+    let mut agent = build_agent(
+        ser_des_agent,
+        states,
+        [states_per_iteration, max_q_per_iteration],
+        q_prior,
+        transitions,
+        report,
+    );
+    agent.backend = backend;
+    Ok(agent)
+}
+
+fn check_version(version: u32) -> Result<(), String> {
+    match version {
+        1..=AGENT_FORMAT_VERSION => Ok(()),
+        version => Err(format!(
+            "Unsupported agent format version {} (latest is {})",
+            version, AGENT_FORMAT_VERSION
+        )),
+    }
+}
+
+// Agent from what was loaded, validated, wherever it came from
+fn build_agent(
+    ser_des_agent: SerDesAgent,
+    states: Vec<State>,
+    [states_per_iteration, max_q_per_iteration]: [Vec<[f64; 2]>; 2],
+    q_prior: Option<[f32; 256]>,
+    transitions: HashMap<(usize, usize), u64>,
+    mut report: ValidationReport,
+) -> Agent {
     let mut agent = Agent::new();
     agent.number_of_states = ser_des_agent.number_of_states;
     agent.iteration_number = ser_des_agent.iteration_number;
     agent.training_time = ser_des_agent.training_time;
+    agent.characters = ser_des_agent.characters;
+    agent.learning_rate = ser_des_agent.learning_rate;
+    agent.discount_factor = ser_des_agent.discount_factor;
     agent.states = states;
     agent.states_per_iteration = states_per_iteration;
     agent.max_q_per_iteration = max_q_per_iteration;
+    agent.q_prior = q_prior;
+    agent.transitions = transitions;
+    agent.store_raw_frames = ser_des_agent.store_raw_frames;
+
+    report.merge(agent.validate());
+    if !report.is_clean() {
+        println!("Agent repaired on load:\n{}", report);
+    }
 
     agent
 }
+
+// The whole agent directory in one deflated file, quicker to copy around
+// and to load. load_agent takes either, see is_agent_file.
+const AGENT_FILE_TAG: &[u8; 8] = b"DLEAGENT";
+
+#[derive(Serialize, Deserialize)]
+struct SerDesState {
+    // PNG, as in the directory
+    frame: Vec<u8>,
+    char1_centroid: (f32, f32),
+    char2_centroid: (f32, f32),
+    roi: Option<((u32, u32), (u32, u32))>,
+    raw_frame: Option<Vec<u8>>,
+    q: Vec<f32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AgentFile {
+    metadata: SerDesAgent,
+    states: Vec<SerDesState>,
+    states_per_iteration: Vec<[f64; 2]>,
+    max_q_per_iteration: Vec<[f64; 2]>,
+    transitions: Vec<(usize, usize, u64)>,
+    q_prior: Option<Vec<f32>>,
+}
+
+pub fn is_agent_file(path: &Path) -> bool {
+    let mut tag = [0; 8];
+    fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut tag))
+        .is_ok_and(|_| &tag == AGENT_FILE_TAG)
+}
+
+// Same as save_agent_with_progress, into a single file. Only the dojo-agent
+// tool writes them for now.
+#[allow(dead_code)]
+pub fn save_agent_file(agent: &Agent, path: &str, progress: &Progress) -> Result<(), String> {
+    println!("Saving agent to {}...", path);
+
+    if Path::new(path).exists() {
+        return Err(format!("Path already exists: {}", path));
+    }
+
+    progress.start(agent.states.len());
+    let states = agent
+        .states
+        .par_iter()
+        .map(|state| {
+            if progress.is_cancelled() {
+                return Err("Cancelled".to_string());
+            }
+            let frame_abstraction = &state.frame_abstraction;
+            let mut frame = Vec::new();
+            PngEncoder::new(&mut frame)
+                .write_image(
+                    frame_abstraction.frame.as_raw(),
+                    frame_abstraction.frame.width(),
+                    frame_abstraction.frame.height(),
+                    ColorType::Rgb8,
+                )
+                .map_err(|e| e.to_string())?;
+            progress.advance();
+            Ok(SerDesState {
+                frame,
+                char1_centroid: frame_abstraction.char1_centroid,
+                char2_centroid: frame_abstraction.char2_centroid,
+                roi: frame_abstraction.roi,
+                raw_frame: state.raw_frame.clone(),
+                q: state.q.to_vec(),
+            })
+        })
+        .collect::<Result<Vec<SerDesState>, String>>()?;
+    let agent_file = AgentFile {
+        metadata: SerDesAgent::new(agent),
+        states,
+        states_per_iteration: agent.states_per_iteration.clone(),
+        max_q_per_iteration: agent.max_q_per_iteration.clone(),
+        transitions: agent.get_transitions(),
+        q_prior: agent.q_prior.map(|q_prior| q_prior.to_vec()),
+    };
+
+    let write = || -> Result<(), String> {
+        let mut file = fs::File::create(path).map_err(|e| e.to_string())?;
+        file.write_all(AGENT_FILE_TAG).map_err(|e| e.to_string())?;
+        let mut encoder = DeflateEncoder::new(BufWriter::new(file), Compression::default());
+        bincode::serialize_into(&mut encoder, &agent_file).map_err(|e| e.to_string())?;
+        // After the rest, so AgentFile keeps its layout and files from
+        // before backends just end earlier
+        let backend = agent.backend.as_ref();
+        let backend = backend.map(|backend| SavedBackend::new(backend.as_ref()));
+        let backend = backend.transpose()?;
+        bincode::serialize_into(&mut encoder, &backend).map_err(|e| e.to_string())?;
+        encoder.finish().map_err(|e| e.to_string())?;
+        Ok(())
+    };
+    if let Err(e) = write() {
+        let _ = fs::remove_file(path);
+        return Err(format!("{}: {}", path, e));
+    }
+    Ok(())
+}
+
+fn load_agent_file(path: &Path, progress: &Progress) -> Result<Agent, String> {
+    let error = |e: String| format!("{}: {}", path.display(), e);
+    let mut file = BufReader::new(fs::File::open(path).map_err(|e| error(e.to_string()))?);
+    let mut tag = [0; 8];
+    file.read_exact(&mut tag)
+        .map_err(|e| error(e.to_string()))?;
+    if &tag != AGENT_FILE_TAG {
+        return Err(error("not an agent file".to_string()));
+    }
+    let mut decoder = DeflateDecoder::new(file);
+    let agent_file: AgentFile =
+        bincode::deserialize_from(&mut decoder).map_err(|e| error(e.to_string()))?;
+    check_version(agent_file.metadata.version)?;
+    // Nothing left in files from before backends
+    let mut rest = Vec::new();
+    decoder
+        .read_to_end(&mut rest)
+        .map_err(|e| error(e.to_string()))?;
+    let backend: Option<SavedBackend> = match rest.is_empty() {
+        true => None,
+        false => bincode::deserialize(&rest).map_err(|e| error(e.to_string()))?,
+    };
+    let backend = backend
+        .map(|saved| saved.load())
+        .transpose()
+        .map_err(error)?;
+
+    // Broken states are dropped as in a directory, not failing
+    let mut report = ValidationReport::default();
+    let mut states = Vec::<State>::new();
+    let mut saved_indices = HashMap::new();
+    progress.start(agent_file.states.len());
+    for (saved_index, saved) in agent_file.states.into_iter().enumerate() {
+        if progress.is_cancelled() {
+            return Err("Cancelled".to_string());
+        }
+        progress.advance();
+        let frame = match image::load_from_memory(&saved.frame) {
+            Ok(frame) => frame.to_rgb8(),
+            Err(e) => {
+                report.dropped_states += 1;
+                report
+                    .messages
+                    .push(format!("State {}: dropped, {}", saved_index, e));
+                continue;
+            }
+        };
+        let mut frame_abstraction =
+            vision::FrameAbstraction::new(frame, saved.char1_centroid, saved.char2_centroid);
+        frame_abstraction.roi = saved.roi;
+        let mut state = State::new(frame_abstraction);
+        state.raw_frame = saved.raw_frame;
+        if saved.q.len() != state.q.len() {
+            report.resized_q_tables += 1;
+            report.messages.push(format!(
+                "State {}: expected {} Q values, found {}",
+                saved_index,
+                state.q.len(),
+                saved.q.len()
+            ));
+        }
+        for (i, q) in saved.q.iter().take(state.q.len()).enumerate() {
+            state.q[i] = *q;
+        }
+        for action in 0..=255 {
+            if state.q[action as usize] != 0.0 {
+                state.set_tried(action);
+            }
+        }
+        saved_indices.insert(saved_index, states.len());
+        states.push(state);
+    }
+
+    let transitions = agent_file
+        .transitions
+        .iter()
+        .map(|(from, to, count)| ((*from, *to), *count))
+        .collect();
+    let transitions = remap_transitions(&transitions, |index| saved_indices.get(&index).copied());
+    let q_prior = agent_file.q_prior.map(|saved| {
+        let mut q_prior = [0.0; 256];
+        for (q, saved) in q_prior.iter_mut().zip(saved) {
+            *q = saved;
+        }
+        q_prior
+    });
+
+    let mut agent = build_agent(
+        agent_file.metadata,
+        states,
+        [
+            agent_file.states_per_iteration,
+            agent_file.max_q_per_iteration,
+        ],
+        q_prior,
+        transitions,
+        report,
+    );
+    agent.backend = backend;
+    Ok(agent)
+}
+
+fn encode_raw_frame(raw_frame: &RgbImage) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut encoder = JpegEncoder::new_with_quality(&mut bytes, RAW_FRAME_QUALITY);
+    match encoder.encode_image(raw_frame) {
+        Ok(()) => Some(bytes),
+        Err(e) => {
+            error!("Error encoding raw frame: {}", e);
+            None
+        }
+    }
+}
+
+// Raw cropped frame of a state, next to its abstraction as <frame>_raw.*
+fn find_raw_frame(states_path: &Path, frame_name: &str) -> Option<PathBuf> {
+    let stem = Path::new(frame_name).file_stem()?.to_string_lossy();
+    RAW_FRAME_EXTENSIONS
+        .iter()
+        .map(|extension| states_path.join(format!("{}_raw.{}", stem, extension)))
+        .find(|path| path.is_file())
+}
+
+// Copies the agent at `path` to `new_path`, regenerating the abstraction,
+// centroids and region of interest of every state with a raw frame through
+// `abstract_frame`, e.g. after changing the vision settings. Q values are
+// kept as they are, so are states without a raw frame.
+pub fn reabstract_agent(
+    path: &str,
+    new_path: &str,
+    mut abstract_frame: impl FnMut(&RgbImage) -> vision::FrameAbstraction,
+) -> Result<String, String> {
+    println!("Re-abstracting agent {} into {}...", path, new_path);
+
+    let agent_path = Path::new(path);
+    let new_agent_path = Path::new(new_path);
+    let ser_des_agent = load_agent_metadata(agent_path)
+        .ok_or_else(|| format!("Not an agent: {}", agent_path.display()))?;
+    // Pixel centroids can't be mixed with normalized ones
+    if ser_des_agent.version < 3 {
+        return Err(format!(
+            "Agent format version {} is too old, load and save it first",
+            ser_des_agent.version
+        ));
+    }
+    if new_agent_path.exists() {
+        return Err(format!("Path already exists: {}", new_path));
+    }
+    copy_dir(agent_path, new_agent_path)?;
+
+    let states_path = new_agent_path.join("states");
+    let data_path = states_path.join("data.csv");
+    let mut data = String::new();
+    let mut reabstracted = 0;
+    let mut kept = 0;
+    for (line_number, tokens) in read_csv(&data_path)? {
+        let location = format!("{}:{}", data_path.display(), line_number);
+        if tokens.len() < 6 {
+            return Err(format!(
+                "{}: expected 6 fields, found {}",
+                location,
+                tokens.len()
+            ));
+        }
+        let Some(raw_path) = find_raw_frame(&states_path, &tokens[0]) else {
+            let _ = writeln!(data, "{}", tokens.join(","));
+            kept += 1;
+            continue;
+        };
+        let raw_frame = image::open(&raw_path)
+            .map_err(|e| format!("{}: {}", raw_path.display(), e))?
+            .to_rgb8();
+        let frame_abstraction = abstract_frame(&raw_frame);
+        let frame_path = states_path.join(&tokens[0]);
+        frame_abstraction
+            .frame
+            .save(&frame_path)
+            .map_err(|e| format!("{}: {}", frame_path.display(), e))?;
+        let roi = match frame_abstraction.roi {
+            Some((corner1, corner2)) => {
+                format!(",{},{},{},{}", corner1.0, corner1.1, corner2.0, corner2.1)
+            }
+            None => String::new(),
+        };
+        let _ = writeln!(
+            data,
+            "{},{},{},{},{},{}{}",
+            tokens[0],
+            frame_abstraction.char1_centroid.0,
+            frame_abstraction.char1_centroid.1,
+            frame_abstraction.char2_centroid.0,
+            frame_abstraction.char2_centroid.1,
+            tokens[5],
+            roi,
+        );
+        reabstracted += 1;
+    }
+    fs::write(&data_path, data).map_err(|e| format!("{}: {}", data_path.display(), e))?;
+
+    Ok(format!(
+        "{} states re-abstracted, {} without a raw frame kept as they were",
+        reabstracted, kept
+    ))
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<(), String> {
+    fs::create_dir_all(to).map_err(|e| format!("{}: {}", to.display(), e))?;
+    let entries = fs::read_dir(from).map_err(|e| format!("{}: {}", from.display(), e))?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let new_path = to.join(entry.file_name());
+        if path.is_dir() {
+            copy_dir(&path, &new_path)?;
+        } else {
+            fs::copy(&path, &new_path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+    }
+    Ok(())
+}
+
+// Transitions from or to states without a new index are dropped
+fn remap_transitions(
+    transitions: &HashMap<(usize, usize), u64>,
+    new_index: impl Fn(usize) -> Option<usize>,
+) -> HashMap<(usize, usize), u64> {
+    transitions
+        .iter()
+        .filter_map(|((from, to), count)| Some(((new_index(*from)?, new_index(*to)?), *count)))
+        .collect()
+}
+
+// The state-transition graph, edges weighted by how often they were taken
+// and nodes colored by their value (max Q), from blue (lowest) to red
+// (highest). GEXF for a .gexf path, GraphViz DOT otherwise.
+pub fn export_transition_graph(agent: &Agent, path: &str) -> Result<(), String> {
+    println!("Exporting transition graph to {}...", path);
+
+    let values: Vec<f32> = agent
+        .states
+        .iter()
+        .map(|state| state.q.iter().copied().fold(f32::NEG_INFINITY, f32::max))
+        .collect();
+    let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let color = |value: f32| {
+        let t = if max > min {
+            (value - min) / (max - min)
+        } else {
+            0.5
+        };
+        let red = (255.0 * t).round() as u8;
+        (red, 64, 255 - red)
+    };
+    let transitions = agent.get_transitions();
+
+    let mut graph = String::new();
+    let gexf = Path::new(path)
+        .extension()
+        .is_some_and(|extension| extension == "gexf");
+    if gexf {
+        let _ = writeln!(graph, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+        let _ = writeln!(
+            graph,
+            r#"<gexf xmlns="http://gexf.net/1.3" xmlns:viz="http://gexf.net/1.3/viz" version="1.3">"#
+        );
+        let _ = writeln!(graph, r#"  <graph defaultedgetype="directed">"#);
+        let _ = writeln!(graph, r#"    <attributes class="node">"#);
+        let _ = writeln!(
+            graph,
+            r#"      <attribute id="value" title="value" type="float"/>"#
+        );
+        let _ = writeln!(graph, r#"    </attributes>"#);
+        let _ = writeln!(graph, r#"    <nodes>"#);
+        for (i, value) in values.iter().enumerate() {
+            let (r, g, b) = color(*value);
+            let _ = writeln!(
+                graph,
+                r#"      <node id="{i}" label="{i}"><attvalues><attvalue for="value" value="{value}"/></attvalues><viz:color r="{r}" g="{g}" b="{b}"/></node>"#
+            );
+        }
+        let _ = writeln!(graph, r#"    </nodes>"#);
+        let _ = writeln!(graph, r#"    <edges>"#);
+        for (i, (from, to, count)) in transitions.iter().enumerate() {
+            let _ = writeln!(
+                graph,
+                r#"      <edge id="{i}" source="{from}" target="{to}" weight="{count}"/>"#
+            );
+        }
+        let _ = writeln!(graph, r#"    </edges>"#);
+        let _ = writeln!(graph, r#"  </graph>"#);
+        let _ = writeln!(graph, r#"</gexf>"#);
+    } else {
+        let _ = writeln!(graph, "digraph states {{");
+        let _ = writeln!(graph, "  node [shape=circle, style=filled];");
+        for (i, value) in values.iter().enumerate() {
+            let (r, g, b) = color(*value);
+            let _ = writeln!(
+                graph,
+                r##"  {i} [label="{i}\n{value:.2}", fillcolor="#{r:02x}{g:02x}{b:02x}"];"##
+            );
+        }
+        for (from, to, count) in transitions.iter() {
+            // Thicker the more it was taken, without drowning the rest
+            let penwidth = 1.0 + (*count as f64).ln();
+            let _ = writeln!(
+                graph,
+                r#"  {from} -> {to} [weight={count}, label="{count}", penwidth={penwidth:.2}];"#
+            );
+        }
+        let _ = writeln!(graph, "}}");
+    }
+
+    fs::write(path, graph).map_err(|e| format!("{}: {}", path, e))
+}
+
+// Comma separated fields per line, with 1-based line numbers for errors
+fn read_csv(path: &Path) -> Result<Vec<(usize, Vec<String>)>, String> {
+    let file = fs::File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut lines = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("{}:{}: {}", path.display(), i + 1, e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let tokens = line.split(',').map(|token| token.trim().to_string());
+        lines.push((i + 1, tokens.collect()));
+    }
+    Ok(lines)
+}
+
+fn read_plot(path: &Path) -> Result<Vec<[f64; 2]>, String> {
+    let mut points = Vec::new();
+    for (line_number, tokens) in read_csv(path)? {
+        let location = format!("{}:{}", path.display(), line_number);
+        if tokens.len() < 2 {
+            return Err(format!(
+                "{}: expected 2 fields, found {}",
+                location,
+                tokens.len()
+            ));
+        }
+        points.push([
+            parse_field(&tokens[0], &location)?,
+            parse_field(&tokens[1], &location)?,
+        ]);
+    }
+    Ok(points)
+}
+
+fn parse_field<T: std::str::FromStr>(token: &str, location: &str) -> Result<T, String> {
+    token
+        .parse()
+        .map_err(|_| format!("{}: invalid value '{}'", location, token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    use image::RgbImage;
+
+    // The worker thread holds the other reference to the agent until it
+    // finishes
+    #[test]
+    fn worker_threads_finish_when_dropped() {
+        let mut agents = Vec::new();
+        for _ in 0..32 {
+            let mut worker = AgentWorker::new(Agent::new());
+            let frame = RgbImage::new(368, 380);
+            let observation = vision::FrameAbstraction::new(frame, (0.25, 0.5), (0.75, 0.5));
+            worker.visit_state(observation, 0.0, 0.0, Duration::ZERO);
+            agents.push(Arc::downgrade(&worker.agent));
+        }
+
+        let start = Instant::now();
+        while agents.iter().any(|agent| agent.upgrade().is_some()) {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Running again after the budget stopped it gets the whole budget
+    #[test]
+    fn training_budget_counts_from_the_start_of_the_run() {
+        let mut agent = Agent::new();
+        let mut budget = TrainingBudget::new();
+        budget.max_minutes = 1;
+        budget.max_episodes = 2;
+        let mut episodes = Vec::new();
+
+        assert!(budget.check(&agent, &episodes).is_none());
+        episodes.extend([true, false]);
+        assert!(budget.check(&agent, &episodes).is_some());
+        assert_eq!(budget.episodes_left(&episodes), 0);
+
+        budget.stop();
+        assert_eq!(budget.episodes_left(&episodes), 2);
+        assert!(budget.check(&agent, &episodes).is_none());
+        agent.add_training_time(Duration::from_secs(59));
+        assert!(budget.check(&agent, &episodes).is_none());
+        agent.add_training_time(Duration::from_secs(1));
+        assert!(budget.check(&agent, &episodes).is_some());
+
+        budget.stop();
+        assert!(budget.check(&agent, &episodes).is_none());
+    }
+}
@@ -0,0 +1,134 @@
+// Dojo Learning Environment
+// Copyright (C) 2023-2024 Carlos Perez-Lopez
+//
+// This project is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>
+//
+// You can contact the author via carlospzlz@gmail.com
+
+use egui::RichText;
+
+use super::psx::{self, RamChange, RamSnapshot, System};
+
+const ROW_HEIGHT: f32 = 18.0;
+// Longer values are cut in the list, the printed report has them whole
+const MAX_HEX_BYTES: usize = 16;
+
+// Diff between the last two RAM snapshots, to find where a game keeps
+// things like the life of each player. Take one, make something happen
+// (e.g. get hit), take another one and look at what changed.
+pub struct RamDiff {
+    before: Option<RamSnapshot>,
+    after: Option<RamSnapshot>,
+    words: bool,
+    // Changed units closer than this are shown as one range
+    gap: usize,
+    changes: Vec<RamChange>,
+}
+
+impl RamDiff {
+    pub fn new() -> Self {
+        Self {
+            before: None,
+            after: None,
+            words: false,
+            gap: 0,
+            changes: Vec::new(),
+        }
+    }
+
+    fn snapshot(&mut self, system: &mut System) {
+        self.before = self.after.take();
+        self.after = Some(system.snapshot_ram());
+        self.update();
+    }
+
+    fn update(&mut self) {
+        let unit = if self.words { 4 } else { 1 };
+        self.changes = match (&self.before, &self.after) {
+            (Some(before), Some(after)) => before.diff(after, unit, self.gap),
+            _ => Vec::new(),
+        };
+    }
+
+    pub fn show(&mut self, ctx: &egui::Context, open: &mut bool, system: &mut System) {
+        egui::Window::new("RAM Diff").open(open).show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                if ui.button("Snapshot").clicked() {
+                    self.snapshot(system);
+                }
+                let mut changed = ui.checkbox(&mut self.words, "Words").changed();
+                ui.label("Gap:");
+                changed |= ui
+                    .add(egui::DragValue::new(&mut self.gap).clamp_range(0..=64))
+                    .changed();
+                if changed {
+                    self.update();
+                }
+                if ui.button("Print").clicked() {
+                    println!("{}", psx::diff_report(&self.changes));
+                }
+            });
+            match (&self.before, &self.after) {
+                (Some(before), Some(after)) => {
+                    let bytes: usize = self.changes.iter().map(|c| c.before.len()).sum();
+                    ui.label(format!(
+                        "Frame {} to {}: {} ranges, {} bytes",
+                        before.frame(),
+                        after.frame(),
+                        self.changes.len(),
+                        bytes
+                    ));
+                }
+                (None, Some(after)) => {
+                    ui.label(format!(
+                        "Snapshot at frame {}, take another one",
+                        after.frame()
+                    ));
+                }
+                _ => {
+                    ui.label("Take two snapshots to see what changed in between");
+                }
+            }
+            ui.separator();
+
+            egui::ScrollArea::vertical().max_height(300.0).show_rows(
+                ui,
+                ROW_HEIGHT,
+                self.changes.len(),
+                |ui, rows| {
+                    egui::Grid::new("ram_diff").striped(true).show(ui, |ui| {
+                        for change in &self.changes[rows] {
+                            ui.label(
+                                RichText::new(format!("0x{:08x}", change.address)).monospace(),
+                            );
+                            ui.label(format!("+{}", change.before.len()));
+                            ui.label(RichText::new(short_hex(&change.before)).monospace());
+                            ui.label("→");
+                            ui.label(RichText::new(short_hex(&change.after)).monospace());
+                            ui.end_row();
+                        }
+                    });
+                },
+            );
+        });
+    }
+}
+
+fn short_hex(bytes: &[u8]) -> String {
+    if bytes.len() > MAX_HEX_BYTES {
+        format!("{}…", psx::hex(&bytes[..MAX_HEX_BYTES]))
+    } else {
+        psx::hex(bytes)
+    }
+}
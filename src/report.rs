@@ -0,0 +1,205 @@
+// Dojo Learning Environment
+// Copyright (C) 2023-2024 Carlos Perez-Lopez
+//
+// This project is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>
+//
+// You can contact the author via carlospzlz@gmail.com
+
+use image::{Rgb, RgbImage};
+use imageproc::drawing::{draw_hollow_rect_mut, draw_line_segment_mut};
+use imageproc::rect::Rect;
+use log::error;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+const PLOT_WIDTH: u32 = 800;
+const PLOT_HEIGHT: u32 = 400;
+const PLOT_MARGIN: u32 = 20;
+const PLOT_COLORS: [Rgb<u8>; 3] = [Rgb([31, 119, 180]), Rgb([255, 127, 14]), Rgb([44, 160, 44])];
+
+pub struct PlotLine<'a> {
+    pub name: &'a str,
+    pub points: &'a [[f64; 2]],
+}
+
+pub struct Plot<'a> {
+    pub name: &'a str,
+    pub title: &'a str,
+    pub lines: Vec<PlotLine<'a>>,
+}
+
+pub struct Report<'a> {
+    // Name/value pairs, shown as given
+    pub config: Vec<(&'a str, String)>,
+    pub metrics: Vec<(&'a str, String)>,
+    pub plots: Vec<Plot<'a>>,
+    pub samples: Vec<RgbImage>,
+}
+
+// Writes report.md plus the plots (PNG and SVG) and sample images it links to
+pub fn export_report(report: &Report, path: &str) {
+    println!("Exporting report to {}...", path);
+
+    let report_path = Path::new(path);
+    if report_path.exists() {
+        println!("Path already exists: {}", path);
+        return;
+    }
+    if let Err(e) = fs::create_dir_all(report_path.join("samples")) {
+        error!("Error creating report directory: {}", e);
+        return;
+    }
+
+    let mut markdown = String::new();
+    let _ = writeln!(markdown, "# Training Report\n");
+
+    let _ = writeln!(markdown, "## Configuration\n");
+    write_table(&mut markdown, &report.config);
+
+    let _ = writeln!(markdown, "## Metrics\n");
+    write_table(&mut markdown, &report.metrics);
+
+    let _ = writeln!(markdown, "## Plots\n");
+    for plot in report.plots.iter() {
+        let png = format!("{}.png", plot.name);
+        let svg = format!("{}.svg", plot.name);
+        if let Err(e) = render_plot_png(plot).save(report_path.join(&png)) {
+            error!("Error saving plot {}: {}", png, e);
+        }
+        if let Err(e) = fs::write(report_path.join(&svg), render_plot_svg(plot)) {
+            error!("Error saving plot {}: {}", svg, e);
+        }
+        let _ = writeln!(
+            markdown,
+            "### {}\n\n![{}]({}) ([SVG]({}))\n",
+            plot.title, plot.title, png, svg
+        );
+    }
+
+    let _ = writeln!(markdown, "## Sample Abstractions\n");
+    for (i, sample) in report.samples.iter().enumerate() {
+        let sample_path = format!("samples/{:03}.png", i);
+        if let Err(e) = sample.save(report_path.join(&sample_path)) {
+            error!("Error saving sample {}: {}", sample_path, e);
+        }
+        let _ = writeln!(markdown, "![Sample {}]({})", i, sample_path);
+    }
+
+    match fs::File::create(report_path.join("report.md")) {
+        Ok(mut file) => {
+            if let Err(e) = file.write_all(markdown.as_bytes()) {
+                error!("Error writing report: {}", e);
+            }
+        }
+        Err(e) => error!("Error creating report: {}", e),
+    }
+}
+
+fn write_table(markdown: &mut String, rows: &[(&str, String)]) {
+    let _ = writeln!(markdown, "| Name | Value |\n| --- | --- |");
+    for (name, value) in rows.iter() {
+        let _ = writeln!(markdown, "| {} | {} |", name, value);
+    }
+    let _ = writeln!(markdown);
+}
+
+// Bounds over all lines, never empty so there is no division by zero
+fn plot_bounds(plot: &Plot) -> ([f64; 2], [f64; 2]) {
+    let points = plot.lines.iter().flat_map(|line| line.points.iter());
+    let (mut min, mut max) = ([f64::MAX; 2], [f64::MIN; 2]);
+    for point in points {
+        for axis in 0..2 {
+            min[axis] = min[axis].min(point[axis]);
+            max[axis] = max[axis].max(point[axis]);
+        }
+    }
+    for axis in 0..2 {
+        if min[axis] > max[axis] {
+            (min[axis], max[axis]) = (0.0, 1.0);
+        } else if min[axis] == max[axis] {
+            max[axis] = min[axis] + 1.0;
+        }
+    }
+    (min, max)
+}
+
+fn to_canvas(point: &[f64; 2], min: [f64; 2], max: [f64; 2]) -> (f32, f32) {
+    let width = (PLOT_WIDTH - 2 * PLOT_MARGIN) as f64;
+    let height = (PLOT_HEIGHT - 2 * PLOT_MARGIN) as f64;
+    let x = PLOT_MARGIN as f64 + (point[0] - min[0]) / (max[0] - min[0]) * width;
+    let y = PLOT_MARGIN as f64 + height - (point[1] - min[1]) / (max[1] - min[1]) * height;
+    (x as f32, y as f32)
+}
+
+fn render_plot_png(plot: &Plot) -> RgbImage {
+    let mut img = RgbImage::from_pixel(PLOT_WIDTH, PLOT_HEIGHT, Rgb([255, 255, 255]));
+    let frame = Rect::at(PLOT_MARGIN as i32, PLOT_MARGIN as i32)
+        .of_size(PLOT_WIDTH - 2 * PLOT_MARGIN, PLOT_HEIGHT - 2 * PLOT_MARGIN);
+    draw_hollow_rect_mut(&mut img, frame, Rgb([128, 128, 128]));
+
+    let (min, max) = plot_bounds(plot);
+    for (i, line) in plot.lines.iter().enumerate() {
+        let color = PLOT_COLORS[i % PLOT_COLORS.len()];
+        for segment in line.points.windows(2) {
+            let start = to_canvas(&segment[0], min, max);
+            let end = to_canvas(&segment[1], min, max);
+            draw_line_segment_mut(&mut img, start, end, color);
+        }
+    }
+    img
+}
+
+fn render_plot_svg(plot: &Plot) -> String {
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">",
+        PLOT_WIDTH, PLOT_HEIGHT
+    );
+    let _ = writeln!(svg, "<title>{}</title>", plot.title);
+    let _ = writeln!(
+        svg,
+        "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"white\" stroke=\"gray\"/>",
+        PLOT_MARGIN,
+        PLOT_MARGIN,
+        PLOT_WIDTH - 2 * PLOT_MARGIN,
+        PLOT_HEIGHT - 2 * PLOT_MARGIN
+    );
+
+    let (min, max) = plot_bounds(plot);
+    for (i, line) in plot.lines.iter().enumerate() {
+        let color = PLOT_COLORS[i % PLOT_COLORS.len()];
+        let points: Vec<String> = line
+            .points
+            .iter()
+            .map(|point| {
+                let (x, y) = to_canvas(point, min, max);
+                format!("{:.1},{:.1}", x, y)
+            })
+            .collect();
+        let _ = writeln!(
+            svg,
+            "<polyline fill=\"none\" stroke=\"rgb({},{},{})\" points=\"{}\"><title>{}</title></polyline>",
+            color[0],
+            color[1],
+            color[2],
+            points.join(" "),
+            line.name
+        );
+    }
+    let _ = writeln!(svg, "</svg>");
+    svg
+}
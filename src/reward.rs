@@ -0,0 +1,434 @@
+// Dojo Learning Environment
+// Copyright (C) 2023-2024 Carlos Perez-Lopez
+//
+// This project is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>
+//
+// You can contact the author via carlospzlz@gmail.com
+
+use serde::Serialize;
+use std::fs;
+use std::time::SystemTime;
+
+// What a reward function sees of one observation
+#[derive(Clone, Default, Serialize)]
+pub struct RewardInfo {
+    pub frame: u64,
+    pub agent_life: f32,
+    pub agent_damage: f32,
+    pub opponent_life: f32,
+    pub opponent_damage: f32,
+    // In [0, 1] within the crop
+    pub agent_centroid: (f32, f32),
+    pub opponent_centroid: (f32, f32),
+}
+
+impl RewardInfo {
+    fn variable(&self, name: &str) -> Option<f32> {
+        let value = match name {
+            "frame" => self.frame as f32,
+            "agent_life" => self.agent_life,
+            "agent_damage" => self.agent_damage,
+            "opponent_life" => self.opponent_life,
+            "opponent_damage" => self.opponent_damage,
+            "agent_x" => self.agent_centroid.0,
+            "agent_y" => self.agent_centroid.1,
+            "opponent_x" => self.opponent_centroid.0,
+            "opponent_y" => self.opponent_centroid.1,
+            "distance" => {
+                (self.agent_centroid.0 - self.opponent_centroid.0).abs()
+                    + (self.agent_centroid.1 - self.opponent_centroid.1).abs()
+            }
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+// Called on every observation with the previous and the current one
+pub type RewardFn = Box<dyn Fn(&RewardInfo, &RewardInfo) -> f32>;
+
+// The reward used so far: damage dealt minus damage taken, taking hits
+// weighted 4 times more
+pub fn damage_reward(_previous: &RewardInfo, info: &RewardInfo) -> f32 {
+    let reward = info.opponent_damage - info.agent_damage;
+    if reward < 0.0 {
+        reward * 4.0
+    } else {
+        reward
+    }
+}
+
+// Reward functions to pick from in the GUI: closures registered in code and
+// an expression read from a script file, reloaded whenever the file changes
+pub struct Rewards {
+    functions: Vec<(String, RewardFn)>,
+    // Index in functions, or functions.len() for the script
+    selected: usize,
+    script_path: String,
+    script: Option<Expr>,
+    script_error: Option<String>,
+    script_modified: Option<SystemTime>,
+    previous: Option<RewardInfo>,
+}
+
+impl Rewards {
+    pub fn new(script_path: &str) -> Self {
+        let mut rewards = Self {
+            functions: Vec::new(),
+            selected: 0,
+            script_path: script_path.to_string(),
+            script: None,
+            script_error: None,
+            script_modified: None,
+            previous: None,
+        };
+        rewards.register("Damage", Box::new(damage_reward));
+        rewards
+    }
+
+    pub fn register(&mut self, name: &str, function: RewardFn) {
+        self.functions.push((name.to_string(), function));
+    }
+
+    // The next observation has nothing before it, e.g. a new round
+    pub fn reset(&mut self) {
+        self.previous = None;
+    }
+
+    pub fn reward(&mut self, info: RewardInfo) -> f32 {
+        let previous = self.previous.take().unwrap_or_else(|| info.clone());
+        let reward = match self.functions.get(self.selected) {
+            Some((_, function)) => function(&previous, &info),
+            None => {
+                self.reload_if_modified();
+                match &self.script {
+                    Some(script) => script.eval(&previous, &info),
+                    None => 0.0,
+                }
+            }
+        };
+        self.previous = Some(info);
+        if reward.is_finite() {
+            reward
+        } else {
+            0.0
+        }
+    }
+
+    pub fn reload(&mut self) {
+        self.script_modified = modified(&self.script_path);
+        let result = fs::read_to_string(&self.script_path)
+            .map_err(|e| format!("{}: {}", self.script_path, e))
+            .and_then(|source| parse(&source));
+        match result {
+            Ok(script) => {
+                println!("Reward script loaded from {}", self.script_path);
+                self.script = Some(script);
+                self.script_error = None;
+            }
+            // The last good script stays in use
+            Err(e) => {
+                eprintln!("Error loading reward script: {}", e);
+                self.script_error = Some(e);
+            }
+        }
+    }
+
+    fn reload_if_modified(&mut self) {
+        if modified(&self.script_path) != self.script_modified {
+            self.reload();
+        }
+    }
+
+    pub fn combo_box(&mut self, ui: &mut egui::Ui) {
+        let script = self.functions.len();
+        let selected_text = match self.functions.get(self.selected) {
+            Some((name, _)) => name.clone(),
+            None => "Script".to_string(),
+        };
+        let mut selected = self.selected;
+        egui::ComboBox::from_id_source("reward")
+            .selected_text(selected_text)
+            .show_ui(ui, |ui| {
+                for (index, (name, _)) in self.functions.iter().enumerate() {
+                    ui.selectable_value(&mut selected, index, name);
+                }
+                ui.selectable_value(&mut selected, script, "Script");
+            });
+        if selected != self.selected {
+            self.selected = selected;
+            if selected == script {
+                self.reload();
+            }
+        }
+        if self.selected == script && ui.button("⟳").on_hover_text("Reload").clicked() {
+            self.reload();
+        }
+    }
+
+    pub fn script_error(&self) -> Option<&str> {
+        if self.selected == self.functions.len() {
+            self.script_error.as_deref()
+        } else {
+            None
+        }
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// A reward expression. Variables are the RewardInfo fields, with agent_x,
+// agent_y, opponent_x, opponent_y and distance for the centroids, and the
+// same names prefixed with prev_ for the previous observation. Comparisons
+// give 1 or 0, and min, max, abs and if(condition, then, else) are there.
+// Lines starting with # are comments.
+#[derive(Debug)]
+pub enum Expr {
+    Number(f32),
+    Variable { name: String, previous: bool },
+    Neg(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+    Compare(&'static str, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+impl Expr {
+    pub fn eval(&self, previous: &RewardInfo, info: &RewardInfo) -> f32 {
+        match self {
+            Expr::Number(value) => *value,
+            Expr::Variable {
+                name,
+                previous: true,
+            } => previous.variable(name).unwrap_or(0.0),
+            Expr::Variable { name, .. } => info.variable(name).unwrap_or(0.0),
+            Expr::Neg(expr) => -expr.eval(previous, info),
+            Expr::Binary(op, left, right) => {
+                let (left, right) = (left.eval(previous, info), right.eval(previous, info));
+                match op {
+                    '+' => left + right,
+                    '-' => left - right,
+                    '*' => left * right,
+                    _ => left / right,
+                }
+            }
+            Expr::Compare(op, left, right) => {
+                let (left, right) = (left.eval(previous, info), right.eval(previous, info));
+                let result = match *op {
+                    "<" => left < right,
+                    "<=" => left <= right,
+                    ">" => left > right,
+                    ">=" => left >= right,
+                    "==" => left == right,
+                    _ => left != right,
+                };
+                result as u8 as f32
+            }
+            Expr::Call(name, args) => {
+                let args: Vec<f32> = args.iter().map(|arg| arg.eval(previous, info)).collect();
+                match name.as_str() {
+                    "min" => args[0].min(args[1]),
+                    "max" => args[0].max(args[1]),
+                    "abs" => args[0].abs(),
+                    _ => {
+                        if args[0] != 0.0 {
+                            args[1]
+                        } else {
+                            args[2]
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f32),
+    Name(String),
+    Op(&'static str),
+}
+
+const OPERATORS: [&str; 13] = [
+    "<=", ">=", "==", "!=", "<", ">", "+", "-", "*", "/", "(", ")", ",",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    for line in source.lines() {
+        let mut rest = line.trim_start();
+        if rest.starts_with('#') {
+            continue;
+        }
+        while !rest.is_empty() {
+            let c = rest.chars().next().unwrap();
+            if c.is_whitespace() {
+                rest = rest.trim_start();
+            } else if c.is_ascii_digit() || c == '.' {
+                let end = rest
+                    .find(|c: char| !c.is_ascii_digit() && c != '.')
+                    .unwrap_or(rest.len());
+                let number = rest[..end]
+                    .parse()
+                    .map_err(|_| format!("Bad number '{}'", &rest[..end]))?;
+                tokens.push(Token::Number(number));
+                rest = &rest[end..];
+            } else if c.is_ascii_alphabetic() || c == '_' {
+                let end = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len());
+                tokens.push(Token::Name(rest[..end].to_string()));
+                rest = &rest[end..];
+            } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+                tokens.push(Token::Op(op));
+                rest = &rest[op.len()..];
+            } else {
+                return Err(format!("Unexpected '{}'", c));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+pub fn parse(source: &str) -> Result<Expr, String> {
+    let tokens = tokenize(source)?;
+    let mut parser = Parser { tokens, next: 0 };
+    let expr = parser.comparison()?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(token) => Err(format!("Unexpected {:?}", token)),
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next)
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Op(o)) if *o == op) {
+            self.next += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.eat(op) {
+            Ok(())
+        } else {
+            Err(format!("Expected '{}'", op))
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let left = self.sum()?;
+        for op in ["<=", ">=", "==", "!=", "<", ">"] {
+            if self.eat(op) {
+                let right = self.sum()?;
+                return Ok(Expr::Compare(op, Box::new(left), Box::new(right)));
+            }
+        }
+        Ok(left)
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut left = self.product()?;
+        loop {
+            let op = if self.eat("+") {
+                '+'
+            } else if self.eat("-") {
+                '-'
+            } else {
+                return Ok(left);
+            };
+            let right = self.product()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        loop {
+            let op = if self.eat("*") {
+                '*'
+            } else if self.eat("/") {
+                '/'
+            } else {
+                return Ok(left);
+            };
+            let right = self.unary()?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat("-") {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let token = self.peek().cloned().ok_or("Unexpected end")?;
+        self.next += 1;
+        match token {
+            Token::Number(value) => Ok(Expr::Number(value)),
+            Token::Op("(") => {
+                let expr = self.comparison()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Token::Name(name) if self.eat("(") => {
+                let arity = match name.as_str() {
+                    "abs" => 1,
+                    "min" | "max" => 2,
+                    "if" => 3,
+                    _ => return Err(format!("Unknown function '{}'", name)),
+                };
+                let mut args = vec![self.comparison()?];
+                while self.eat(",") {
+                    args.push(self.comparison()?);
+                }
+                self.expect(")")?;
+                if args.len() != arity {
+                    return Err(format!("{} takes {} arguments", name, arity));
+                }
+                Ok(Expr::Call(name, args))
+            }
+            Token::Name(name) => {
+                let (variable, previous) = match name.strip_prefix("prev_") {
+                    Some(variable) => (variable.to_string(), true),
+                    None => (name.clone(), false),
+                };
+                if RewardInfo::default().variable(&variable).is_none() {
+                    return Err(format!("Unknown variable '{}'", name));
+                }
+                Ok(Expr::Variable {
+                    name: variable,
+                    previous,
+                })
+            }
+            Token::Op(op) => Err(format!("Unexpected '{}'", op)),
+        }
+    }
+}
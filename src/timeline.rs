@@ -0,0 +1,215 @@
+// Dojo Learning Environment
+// Copyright (C) 2023-2024 Carlos Perez-Lopez
+//
+// This project is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>
+//
+// You can contact the author via carlospzlz@gmail.com
+
+use egui::plot::PlotBounds;
+use egui::{Color32, RichText, Sense};
+use std::time::{Duration, Instant};
+
+const STRIP_HEIGHT: f32 = 24.0;
+const MARKER_RADIUS: f32 = 4.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    Episode,
+    Ko,
+    StateLoad,
+    Checkpoint,
+}
+
+impl EventKind {
+    fn color(&self) -> Color32 {
+        match self {
+            EventKind::Episode => Color32::LIGHT_BLUE,
+            EventKind::Ko => Color32::LIGHT_RED,
+            EventKind::StateLoad => Color32::GRAY,
+            EventKind::Checkpoint => Color32::LIGHT_GREEN,
+        }
+    }
+}
+
+pub struct Event {
+    // Wall clock, since the session started
+    pub time: Duration,
+    pub kind: EventKind,
+    pub label: String,
+    // Where the plots were at: agent iteration and training episodes
+    pub iteration: usize,
+    pub episode: usize,
+}
+
+// Plot ranges a bookmark jumps to, from the event before it to the one after
+#[derive(Clone, Copy)]
+pub struct Bookmark {
+    pub iterations: (f64, f64),
+    pub episodes: (f64, f64),
+}
+
+// What happened during the session over wall-clock time. Every event is a
+// bookmark, clicking one returns the plot ranges around it.
+pub struct Timeline {
+    start: Instant,
+    events: Vec<Event>,
+    shown: [bool; 4],
+    selected: Option<usize>,
+}
+
+const KINDS: [(EventKind, &str); 4] = [
+    (EventKind::Episode, "Episodes"),
+    (EventKind::Ko, "KOs"),
+    (EventKind::StateLoad, "State Loads"),
+    (EventKind::Checkpoint, "Checkpoints"),
+];
+
+impl Timeline {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            events: Vec::new(),
+            shown: [true; 4],
+            selected: None,
+        }
+    }
+
+    pub fn push(&mut self, kind: EventKind, label: String, iteration: usize, episode: usize) {
+        self.events.push(Event {
+            time: self.start.elapsed(),
+            kind,
+            label,
+            iteration,
+            episode,
+        });
+    }
+
+    fn is_shown(&self, kind: EventKind) -> bool {
+        let index = KINDS.iter().position(|(k, _)| *k == kind).unwrap();
+        self.shown[index]
+    }
+
+    fn bookmark(&self, index: usize) -> Bookmark {
+        let event = &self.events[index];
+        let before = index.checked_sub(1).map(|i| &self.events[i]);
+        let after = self.events.get(index + 1);
+        let range = |value: fn(&Event) -> usize| {
+            let min = before.map_or(0, value).min(value(event));
+            let max = after.map_or(value(event), value).max(value(event));
+            // Never a single point, the plot couldn't show it
+            (min as f64, (max.max(min + 1)) as f64)
+        };
+        Bookmark {
+            iterations: range(|event| event.iteration),
+            episodes: range(|event| event.episode),
+        }
+    }
+
+    pub fn show(&mut self, ctx: &egui::Context, open: &mut bool) -> Option<Bookmark> {
+        let mut clicked = None;
+        egui::Window::new("Timeline").open(open).show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                for (i, (kind, name)) in KINDS.iter().enumerate() {
+                    let text = RichText::new(*name).color(kind.color());
+                    ui.checkbox(&mut self.shown[i], text);
+                }
+            });
+
+            // Strip, from the session start to now
+            let now = self.start.elapsed().as_secs_f32().max(1.0);
+            let width = ui.available_width();
+            let (rect, response) =
+                ui.allocate_exact_size(egui::vec2(width, STRIP_HEIGHT), Sense::click());
+            let painter = ui.painter_at(rect);
+            painter.rect_filled(rect, 2.0, ui.visuals().extreme_bg_color);
+            let x = |time: Duration| rect.left() + rect.width() * time.as_secs_f32() / now;
+            let mut hovered = None;
+            for (i, event) in self.events.iter().enumerate() {
+                if !self.is_shown(event.kind) {
+                    continue;
+                }
+                let center = egui::pos2(x(event.time), rect.center().y);
+                let radius = match self.selected == Some(i) {
+                    true => MARKER_RADIUS * 1.5,
+                    false => MARKER_RADIUS,
+                };
+                painter.circle_filled(center, radius, event.kind.color());
+                let near = response
+                    .hover_pos()
+                    .is_some_and(|pos| (pos.x - center.x).abs() <= MARKER_RADIUS);
+                if near {
+                    hovered = Some(i);
+                }
+            }
+            if let Some(i) = hovered {
+                let event = &self.events[i];
+                let text = format!("{} {}", format_time(event.time), event.label);
+                let response = response.clone().on_hover_text(text);
+                if response.clicked() {
+                    clicked = Some(i);
+                }
+            }
+            ui.label(format!("0:00 to {}", format_time(self.start.elapsed())));
+            ui.separator();
+
+            // Same events as a list, newest first
+            egui::ScrollArea::vertical()
+                .max_height(200.0)
+                .show(ui, |ui| {
+                    for (i, event) in self.events.iter().enumerate().rev() {
+                        if !self.is_shown(event.kind) {
+                            continue;
+                        }
+                        let text =
+                            RichText::new(format!("{} {}", format_time(event.time), event.label))
+                                .color(event.kind.color());
+                        let label = egui::SelectableLabel::new(self.selected == Some(i), text);
+                        if ui.add(label).clicked() {
+                            clicked = Some(i);
+                        }
+                    }
+                });
+        });
+        let index = clicked?;
+        self.selected = Some(index);
+        Some(self.bookmark(index))
+    }
+}
+
+// Bounds for PlotUi::set_plot_bounds, fitting the points within the range
+pub fn plot_bounds(points: &[[f64; 2]], (min_x, max_x): (f64, f64)) -> PlotBounds {
+    let (min_y, max_y) = points
+        .iter()
+        .filter(|[x, _]| *x >= min_x && *x <= max_x)
+        .fold(None, |bounds: Option<(f64, f64)>, [_, y]| match bounds {
+            Some((min, max)) => Some((min.min(*y), max.max(*y))),
+            None => Some((*y, *y)),
+        })
+        .unwrap_or((0.0, 1.0));
+    let margin = ((max_y - min_y) * 0.05).max(0.01);
+    PlotBounds::from_min_max([min_x, min_y - margin], [max_x, max_y + margin])
+}
+
+fn format_time(time: Duration) -> String {
+    let seconds = time.as_secs();
+    match seconds >= 3600 {
+        true => format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            (seconds / 60) % 60,
+            seconds % 60
+        ),
+        false => format!("{}:{:02}", seconds / 60, seconds % 60),
+    }
+}
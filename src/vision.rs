@@ -16,17 +16,88 @@
 //
 // You can contact the author via carlospzlz@gmail.com
 
-use image::{DynamicImage, GrayImage, Luma, Rgb, RgbImage};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GrayImage, ImageBuffer, Luma, Pixel, Rgb, RgbImage};
 use imageproc::distance_transform::Norm;
+use imageproc::drawing::{draw_filled_rect_mut, draw_hollow_rect_mut};
 use imageproc::morphology::dilate;
+use imageproc::rect::Rect;
+use imageproc::region_labelling::{connected_components, Connectivity};
+use serde::{Deserialize, Serialize};
 use std::cmp;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
 
 const LIFE_BAR_Y: u32 = 54;
 // Life bar seems to be 152 pixels wide
 const PLAYER_1_LIFE_BAR_X: [u32; 2] = [12, 164];
 const PLAYER_2_LIFE_BAR_X: [u32; 2] = [204, 356];
+// Life, hit and taken, plus some slack for the edges
+const MAX_LIFE_BAR_TRANSITIONS: u32 = 6;
 const VISUALIZATION_BAR_HEIGHT: u32 = 7;
+const RAW_OBSERVATION_DOWNSAMPLE: u32 = 8;
+// Scale of the thumbnails used for a first, coarse MSE
+const THUMBNAIL_DOWNSAMPLE: u32 = 8;
+pub const CROP_Y: u32 = 100;
+// Also the width of each tile in a side by side observation
+const CROP_WIDTH: u32 = 368;
+const ABSTRACTION_CACHE_SIZE: usize = 8;
+// Cells of the coarse grid occupancy keys are taken on, 16x12 fits in 3 u64
+const OCCUPANCY_GRID_WIDTH: u32 = 16;
+const OCCUPANCY_GRID_HEIGHT: u32 = 12;
+// Traces fade older positions out, only the recent ones count
+const OCCUPANCY_THRESHOLD: u8 = 128;
+// Fraction of the crop characters are expected to cover, less is probably
+// nothing detected and more the background getting through
+const MIN_CHARACTERS_AREA: f32 = 0.005;
+const MAX_CHARACTERS_AREA: f32 = 0.4;
+// Smaller blobs are leftovers of the segmentation, not characters
+const MIN_COMPONENT_AREA: f32 = 0.001;
+// Centroids moving this much between observations (both distances added)
+// is a wrong abstraction, not the characters moving
+const MAX_CENTROID_JUMP: f32 = 0.4;
+// Display area the life bars and the crop were measured on
+pub const DISPLAY_WIDTH: u32 = 368;
+pub const DISPLAY_HEIGHT: u32 = 480;
+
+// Where and how the GPU shows the current frame. The framebuffer is already
+// read from the origin, so only size and depth change what vision sees
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DisplayInfo {
+    pub origin: (u32, u32),
+    pub size: (u32, u32),
+    pub is_24bit: bool,
+}
+
+impl DisplayInfo {
+    pub fn is_native(&self) -> bool {
+        self.size == (DISPLAY_WIDTH, DISPLAY_HEIGHT)
+    }
+
+    // 24-bit is only used by the movies, never by the combat screen
+    pub fn can_show_hud(&self) -> bool {
+        !self.is_24bit
+    }
+}
+
+// Rectangle (x, y, width, height) in cropped frame coordinates, left out
+// of the abstraction
+pub type Exclusion = [u32; 4];
+
+// What the agent gets to see. Tuples are laid out side by side
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Observation {
+    Raw,
+    Abstraction,
+    Trace,
+    AbstractionAndRaw,
+    TraceAndRaw,
+    // Positions, health and moves read from RAM, see game_data
+    Memory,
+}
 
 pub struct LifeInfo {
     pub life: f32,
@@ -45,20 +116,147 @@ impl Default for LifeInfo {
 #[derive(Clone)]
 pub struct FrameAbstraction {
     pub frame: RgbImage,
-    pub char1_centroid: (u32, u32),
-    pub char2_centroid: (u32, u32),
+    // In [0, 1] within the crop, so they don't depend on the resolution
+    pub char1_centroid: (f32, f32),
+    pub char2_centroid: (f32, f32),
+    // Corners of the box around both characters, None if nothing was found
+    pub roi: Option<((u32, u32), (u32, u32))>,
+    // Cropped frame it was taken from, only attached for agents that keep it
+    pub raw_frame: Option<RgbImage>,
 }
 
 impl FrameAbstraction {
-    pub fn new(frame: RgbImage, char1_centroid: (u32, u32), char2_centroid: (u32, u32)) -> Self {
+    // The region of interest is taken from the (abstraction) frame, so it
+    // has to be built before composing the observation
+    pub fn new(frame: RgbImage, char1_centroid: (f32, f32), char2_centroid: (f32, f32)) -> Self {
+        let roi = find_roi(&frame);
         Self {
             frame,
             char1_centroid,
             char2_centroid,
+            roi,
+            raw_frame: None,
+        }
+    }
+}
+
+// How far an abstraction can be trusted, from what it detected and how
+// much the centroids moved since the previous observation
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AbstractionQuality {
+    // Fraction of the crop covered by characters
+    pub area: f32,
+    // Separate blobs of characters, two unless they overlap
+    pub components: usize,
+    // Manhattan distance both centroids moved, added
+    pub centroid_jump: f32,
+    // In [0, 1], the product of how good each of the above is
+    pub score: f32,
+}
+
+impl AbstractionQuality {
+    pub fn new(
+        segmented_frame: &RgbImage,
+        centroids: [(f32, f32); 2],
+        previous_centroids: Option<[(f32, f32); 2]>,
+    ) -> Self {
+        let (width, height) = segmented_frame.dimensions();
+        let pixels = (width * height).max(1) as f32;
+        let mask = GrayImage::from_fn(width, height, |x, y| {
+            let Rgb([red, green, blue]) = *segmented_frame.get_pixel(x, y);
+            Luma([(red > 0 || green > 0 || blue > 0) as u8 * 255])
+        });
+        let labels = connected_components(&mask, Connectivity::Eight, Luma([0]));
+        let mut sizes = HashMap::<u32, u32>::new();
+        for label in labels.pixels().filter(|label| label[0] != 0) {
+            *sizes.entry(label[0]).or_insert(0) += 1;
+        }
+        let area = sizes.values().sum::<u32>() as f32 / pixels;
+        let components = sizes
+            .values()
+            .filter(|size| **size as f32 / pixels >= MIN_COMPONENT_AREA)
+            .count();
+        let centroid_jump = previous_centroids.map_or(0.0, |previous| {
+            centroids
+                .iter()
+                .zip(previous)
+                .map(|(now, before)| (now.0 - before.0).abs() + (now.1 - before.1).abs())
+                .sum()
+        });
+
+        let area_score = if area < MIN_CHARACTERS_AREA {
+            area / MIN_CHARACTERS_AREA
+        } else if area > MAX_CHARACTERS_AREA {
+            1.0 - (area - MAX_CHARACTERS_AREA) / (1.0 - MAX_CHARACTERS_AREA)
+        } else {
+            1.0
+        };
+        // Overlapping characters are one blob, which happens but says less
+        let components_score = match components {
+            0 => 0.0,
+            1 => 0.5,
+            components => 2.0 / components as f32,
+        };
+        let stability_score = 1.0 - (centroid_jump / MAX_CENTROID_JUMP).min(1.0);
+
+        Self {
+            area,
+            components,
+            centroid_jump,
+            score: area_score * components_score * stability_score,
         }
     }
 }
 
+// Cells of a coarse grid each character covers, red for character 1 and blue
+// for character 2 as in the abstraction, plus the cell of each centroid.
+// Observations with the same key are the same state, found by hashing
+// instead of MSE.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct OccupancyKey {
+    cells: [[u64; 3]; 2],
+    centroid_cells: [(u8, u8); 2],
+}
+
+impl OccupancyKey {
+    pub fn new(frame_abstraction: &FrameAbstraction) -> Self {
+        let img = &frame_abstraction.frame;
+        // Only the first tile of a side by side observation
+        let width = cmp::min(img.width(), CROP_WIDTH);
+        let height = img.height();
+        let mut key = OccupancyKey::default();
+        if width == 0 || height == 0 {
+            return key;
+        }
+        for y in 0..height {
+            let cell_y = y * OCCUPANCY_GRID_HEIGHT / height;
+            for x in 0..width {
+                let cell = cell_y * OCCUPANCY_GRID_WIDTH + x * OCCUPANCY_GRID_WIDTH / width;
+                let Rgb([red, _, blue]) = *img.get_pixel(x, y);
+                for (cells, value) in key.cells.iter_mut().zip([red, blue]) {
+                    if value >= OCCUPANCY_THRESHOLD {
+                        cells[cell as usize / 64] |= 1 << (cell % 64);
+                    }
+                }
+            }
+        }
+        let centroid_cell = |(x, y): (f32, f32)| {
+            let cell_x = (x * OCCUPANCY_GRID_WIDTH as f32) as u8;
+            let cell_y = (y * OCCUPANCY_GRID_HEIGHT as f32) as u8;
+            (
+                cell_x.min(OCCUPANCY_GRID_WIDTH as u8 - 1),
+                cell_y.min(OCCUPANCY_GRID_HEIGHT as u8 - 1),
+            )
+        };
+        key.centroid_cells = [
+            centroid_cell(frame_abstraction.char1_centroid),
+            centroid_cell(frame_abstraction.char2_centroid),
+        ];
+        key
+    }
+}
+
+#[derive(Clone)]
 pub struct VisionStages {
     pub cropped_frame: RgbImage,
     pub contrast_frame: RgbImage,
@@ -105,6 +303,66 @@ impl Default for VisionStages {
     }
 }
 
+// Last few abstractions, keyed by a hash of the cropped frame and the
+// pipeline parameters. Pixel probabilities are not part of the key
+pub struct AbstractionCache {
+    entries: VecDeque<(u64, FrameAbstraction, VisionStages)>,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl AbstractionCache {
+    pub fn new() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: u64) -> Option<(FrameAbstraction, VisionStages)> {
+        match self.entries.iter().find(|entry| entry.0 == key) {
+            Some((_, frame_abstraction, vision_stages)) => {
+                self.hits += 1;
+                Some((frame_abstraction.clone(), vision_stages.clone()))
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        key: u64,
+        frame_abstraction: FrameAbstraction,
+        vision_stages: VisionStages,
+    ) {
+        if self.entries.len() == ABSTRACTION_CACHE_SIZE {
+            self.entries.pop_front();
+        }
+        self.entries
+            .push_back((key, frame_abstraction, vision_stages));
+    }
+
+    // When the pixel probabilities change under it
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+pub fn hash_cropped_frame<T: Hash>(frame: &RgbImage, parameters: T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    // Skip the life bars, same as the crop in get_frame_abstraction
+    let row_size = frame.width() as usize * 3;
+    let start = cmp::min(CROP_Y as usize * row_size, frame.as_raw().len());
+    frame.as_raw()[start..].hash(&mut hasher);
+    frame.dimensions().hash(&mut hasher);
+    parameters.hash(&mut hasher);
+    hasher.finish()
+}
+
 #[derive(Debug, PartialEq)]
 struct Character {
     mask: GrayImage,
@@ -149,13 +407,46 @@ fn draw_visualized_life_bar(
     }
 }
 
+#[allow(dead_code)]
 pub fn get_life_info(img: RgbImage) -> (LifeInfo, LifeInfo) {
     let img = DynamicImage::ImageRgb8(img).to_luma8();
-    let player_1_life_info = get_life_info_for_player(&img, PLAYER_1_LIFE_BAR_X);
-    let player_2_life_info = get_life_info_for_player(&img, PLAYER_2_LIFE_BAR_X);
+    get_life_info_gray(&img)
+}
+
+// Straight from a grayscale frame, e.g. System::get_framebuffer_gray
+pub fn get_life_info_gray(img: &GrayImage) -> (LifeInfo, LifeInfo) {
+    let player_1_life_info = get_life_info_for_player(img, PLAYER_1_LIFE_BAR_X);
+    let player_2_life_info = get_life_info_for_player(img, PLAYER_2_LIFE_BAR_X);
     (player_1_life_info, player_2_life_info)
 }
 
+// In combat each bar is a few runs of remaining, hit and taken life. Any
+// other screen (continue, demo, menus) shows something else on that row, or
+// nothing at all, so it can't be mistaken for a KO.
+pub fn is_hud_visible_gray(img: &GrayImage) -> bool {
+    let mut any_life = false;
+    for x_limits in [PLAYER_1_LIFE_BAR_X, PLAYER_2_LIFE_BAR_X] {
+        let mut transitions = 0;
+        let mut previous = None;
+        for x in x_limits[0]..x_limits[1] {
+            let class = match img.get_pixel(x, LIFE_BAR_Y)[0] {
+                0..=100 => 0,
+                101..=200 => 1,
+                201..=255 => 2,
+            };
+            any_life |= class != 0;
+            if previous.is_some_and(|previous| previous != class) {
+                transitions += 1;
+            }
+            previous = Some(class);
+        }
+        if transitions > MAX_LIFE_BAR_TRANSITIONS {
+            return false;
+        }
+    }
+    any_life
+}
+
 fn get_life_info_for_player(img: &GrayImage, x_limits: [u32; 2]) -> LifeInfo {
     let mut life_count = 0;
     let mut damage_count = 0;
@@ -185,10 +476,17 @@ pub fn get_frame_abstraction(
     char2_probability_threshold: f64,
     char1_dilate_k: u8,
     char2_dilate_k: u8,
+    exclusions: &[Exclusion],
 ) -> (FrameAbstraction, VisionStages) {
     // Remove life bars
-    let cropped_frame = DynamicImage::ImageRgb8(frame.clone()).crop(0, 100, 368, 480);
-    let cropped_frame = cropped_frame.clone().to_rgb8();
+    let mut cropped_frame = crop_frame(frame);
+
+    // Remove what is left of the HUD, e.g. timer and names
+    for exclusion in exclusions {
+        if let Some(rect) = exclusion_rect(exclusion, 0) {
+            draw_filled_rect_mut(&mut cropped_frame, rect, Rgb([0, 0, 0]));
+        }
+    }
 
     // Apply contrast thresholds
     let contrast_frame = apply_thresholds(
@@ -307,8 +605,11 @@ pub fn get_frame_abstraction(
 
     let segmented_frame = merge_segmented_chars(segmented_char1, segmented_char2, char1, char2);
 
-    let frame_abstraction =
-        FrameAbstraction::new(segmented_frame.clone(), char1_centroid, char2_centroid);
+    let frame_abstraction = FrameAbstraction::new(
+        segmented_frame.clone(),
+        normalize_centroid(char1_centroid, &segmented_frame),
+        normalize_centroid(char2_centroid, &segmented_frame),
+    );
 
     // Vision stages
     let mask = DynamicImage::ImageLuma8(mask).to_rgb8();
@@ -325,6 +626,84 @@ pub fn get_frame_abstraction(
     (frame_abstraction, vision_stages)
 }
 
+pub fn compose_observation(
+    observation: Observation,
+    cropped_frame: &RgbImage,
+    abstraction: &RgbImage,
+    trace: &RgbImage,
+) -> RgbImage {
+    match observation {
+        Observation::Raw => downsample_raw(cropped_frame),
+        Observation::Abstraction => abstraction.clone(),
+        Observation::Trace => trace.clone(),
+        Observation::AbstractionAndRaw => {
+            concat_images(abstraction, &downsample_raw(cropped_frame))
+        }
+        Observation::TraceAndRaw => concat_images(trace, &downsample_raw(cropped_frame)),
+        // Not a picture, built by game_data instead
+        Observation::Memory => abstraction.clone(),
+    }
+}
+
+// Downsample but keep the size, so centroids still match
+fn downsample_raw(img: &RgbImage) -> RgbImage {
+    let (width, height) = img.dimensions();
+    let small = imageops::resize(
+        img,
+        cmp::max(width / RAW_OBSERVATION_DOWNSAMPLE, 1),
+        cmp::max(height / RAW_OBSERVATION_DOWNSAMPLE, 1),
+        FilterType::Triangle,
+    );
+    imageops::resize(&small, width, height, FilterType::Nearest)
+}
+
+fn concat_images(left: &RgbImage, right: &RgbImage) -> RgbImage {
+    let height = cmp::max(left.height(), right.height());
+    let mut img = RgbImage::new(left.width() + right.width(), height);
+    imageops::replace(&mut img, left, 0, 0);
+    imageops::replace(&mut img, right, left.width() as i64, 0);
+    img
+}
+
+// Most likely colours of a character first, skipping colours seen fewer than
+// `min_samples` times since their probability means little
+pub fn top_pixel_probabilities(
+    pixel_probability: &HashMap<Rgb<u8>, (u64, u64)>,
+    n: usize,
+    min_samples: u64,
+) -> Vec<(Rgb<u8>, f64, u64)> {
+    let mut colors: Vec<(Rgb<u8>, f64, u64)> = pixel_probability
+        .iter()
+        .filter(|(_, (_, total))| *total >= min_samples)
+        .map(|(pixel, (count, total))| (*pixel, *count as f64 / *total as f64, *total))
+        .collect();
+    colors.sort_by(|a, b| b.1.total_cmp(&a.1).then(b.2.cmp(&a.2)));
+    colors.truncate(n);
+    colors
+}
+
+pub fn save_pixel_probability(
+    pixel_probability: &HashMap<Rgb<u8>, (u64, u64)>,
+    path: &Path,
+) -> Result<(), String> {
+    let entries: Vec<([u8; 3], u64, u64)> = pixel_probability
+        .iter()
+        .map(|(pixel, (count, total))| (pixel.0, *count, *total))
+        .collect();
+    let bytes = bincode::serialize(&entries).map_err(|e| e.to_string())?;
+    fs::write(path, bytes).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+pub fn load_pixel_probability(path: &Path) -> Result<HashMap<Rgb<u8>, (u64, u64)>, String> {
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let entries: Vec<([u8; 3], u64, u64)> =
+        bincode::deserialize(&bytes).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(entries
+        .into_iter()
+        .map(|(pixel, count, total)| (Rgb(pixel), (count, total)))
+        .collect())
+}
+
 #[allow(dead_code)]
 pub fn enclose_with_q(img: &mut RgbImage, q: f32) {
     if q == 0.0 {
@@ -540,6 +919,23 @@ pub fn draw_x_limits(img: &mut RgbImage, x_limits: (u32, u32)) {
     }
 }
 
+fn exclusion_rect(exclusion: &Exclusion, y_offset: u32) -> Option<Rect> {
+    let [x, y, width, height] = *exclusion;
+    if width == 0 || height == 0 {
+        return None;
+    }
+    Some(Rect::at(x as i32, (y + y_offset) as i32).of_size(width, height))
+}
+
+// Pass CROP_Y as offset when drawing over a full frame
+pub fn draw_exclusions(img: &mut RgbImage, exclusions: &[Exclusion], y_offset: u32) {
+    for exclusion in exclusions {
+        if let Some(rect) = exclusion_rect(exclusion, y_offset) {
+            draw_hollow_rect_mut(img, rect, Rgb([255, 0, 255]));
+        }
+    }
+}
+
 pub fn draw_border(img: &mut RgbImage, color: Rgb<u8>) {
     for x in 0..img.width() {
         img.put_pixel(x, 0, color);
@@ -844,12 +1240,42 @@ pub fn add_to_trace(img: &RgbImage, trace: &RgbImage, amount: u8) -> RgbImage {
     traced_img
 }
 
-pub fn draw_centroid(img: &mut RgbImage, centroid: (u32, u32), radius: u32) {
+// Size of the crop the centroids are normalized to, the first tile in side
+// by side observations
+fn tile_size(img: &RgbImage) -> (u32, u32) {
+    (cmp::min(img.width(), CROP_WIDTH), img.height())
+}
+
+pub fn normalize_centroid(centroid: (u32, u32), img: &RgbImage) -> (f32, f32) {
+    let (width, height) = tile_size(img);
+    (
+        centroid.0 as f32 / cmp::max(width, 1) as f32,
+        centroid.1 as f32 / cmp::max(height, 1) as f32,
+    )
+}
+
+pub fn denormalize_centroid(centroid: (f32, f32), img: &RgbImage) -> (u32, u32) {
+    let (width, height) = tile_size(img);
+    (
+        (centroid.0 * width as f32).round() as u32,
+        (centroid.1 * height as f32).round() as u32,
+    )
+}
+
+// Radius is a fraction of the crop as well
+pub fn draw_centroid(img: &mut RgbImage, centroid: (f32, f32), radius: f32) {
+    let (width, height) = tile_size(img);
+    let centroid = denormalize_centroid(centroid, img);
+    let radius = (
+        (radius * width as f32).round() as u32,
+        (radius * height as f32).round() as u32,
+    );
+
     // Draw point
     draw_filled_square(img, centroid, 2);
 
     // Draw radius
-    draw_square(img, centroid, radius);
+    draw_rectangle(img, centroid, radius);
 }
 
 fn draw_filled_square(img: &mut RgbImage, centroid: (u32, u32), radius: u32) {
@@ -869,14 +1295,14 @@ fn draw_filled_square(img: &mut RgbImage, centroid: (u32, u32), radius: u32) {
     }
 }
 
-fn draw_square(img: &mut RgbImage, centroid: (u32, u32), radius: u32) {
+fn draw_rectangle(img: &mut RgbImage, centroid: (u32, u32), radius: (u32, u32)) {
     let corner1 = (
-        cmp::max(centroid.0 as i32 - radius as i32, 0) as u32,
-        cmp::max(centroid.1 as i32 - radius as i32, 0) as u32,
+        cmp::max(centroid.0 as i32 - radius.0 as i32, 0) as u32,
+        cmp::max(centroid.1 as i32 - radius.1 as i32, 0) as u32,
     );
     let corner2 = (
-        cmp::min(centroid.0 + radius, img.width() - 1),
-        cmp::min(centroid.1 + radius, img.height() - 1),
+        cmp::min(centroid.0 + radius.0, img.width() - 1),
+        cmp::min(centroid.1 + radius.1, img.height() - 1),
     );
     for x in corner1.0..corner2.0 {
         img.put_pixel(x, corner1.1, Rgb([0, 255, 0]));
@@ -888,6 +1314,164 @@ fn draw_square(img: &mut RgbImage, centroid: (u32, u32), radius: u32) {
     }
 }
 
+// Box around everything that is not background in the first tile
+fn find_roi(img: &RgbImage) -> Option<((u32, u32), (u32, u32))> {
+    let width = cmp::min(img.width(), CROP_WIDTH);
+    let mut roi: Option<((u32, u32), (u32, u32))> = None;
+    for y in 0..img.height() {
+        for x in 0..width {
+            if img.get_pixel(x, y).0 == [0, 0, 0] {
+                continue;
+            }
+            roi = Some(match roi {
+                Some((corner1, corner2)) => (
+                    (corner1.0.min(x), corner1.1.min(y)),
+                    (corner2.0.max(x), corner2.1.max(y)),
+                ),
+                None => ((x, y), (x, y)),
+            });
+        }
+    }
+    roi
+}
+
+// MSE only within the union of both regions of interest, repeated on every
+// tile of side by side observations. Falls back to the whole frame when no
+// characters were found in either.
+pub fn compute_roi_mse(abstraction1: &FrameAbstraction, abstraction2: &FrameAbstraction) -> f64 {
+    roi_mse(
+        &abstraction1.frame,
+        &abstraction2.frame,
+        abstraction1.roi,
+        abstraction2.roi,
+        CROP_WIDTH,
+    )
+}
+
+// The part of the frame the abstraction is taken from, without life bars
+pub fn crop_frame(frame: &RgbImage) -> RgbImage {
+    DynamicImage::ImageRgb8(frame.clone())
+        .crop(0, CROP_Y, CROP_WIDTH, 480)
+        .to_rgb8()
+}
+
+// Brings a frame of any display size to the DISPLAY_WIDTH x DISPLAY_HEIGHT
+// layout the fixed positions assume. Non-interlaced frames get their lines
+// doubled and the rest is letterboxed, centred horizontally, so the HUD stays
+// in place and nothing is read out of bounds
+pub fn fit_frame<P>(frame: ImageBuffer<P, Vec<P::Subpixel>>) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel + 'static,
+{
+    let (width, height) = frame.dimensions();
+    if (width, height) == (DISPLAY_WIDTH, DISPLAY_HEIGHT) || width == 0 || height == 0 {
+        return frame;
+    }
+    let frame = if height * 2 <= DISPLAY_HEIGHT {
+        imageops::resize(&frame, width, height * 2, FilterType::Nearest)
+    } else {
+        frame
+    };
+    let mut fitted = ImageBuffer::new(DISPLAY_WIDTH, DISPLAY_HEIGHT);
+    let x = (DISPLAY_WIDTH as i64 - width as i64) / 2;
+    imageops::replace(&mut fitted, &frame, x, 0);
+    fitted
+}
+
+// Puts a cropped frame back where get_frame_abstraction crops it from, the
+// life bars area left black, so a stored crop can be abstracted again
+pub fn uncrop_frame(cropped_frame: &RgbImage) -> RgbImage {
+    let (width, height) = cropped_frame.dimensions();
+    let mut frame = RgbImage::new(width, CROP_Y + height);
+    imageops::replace(&mut frame, cropped_frame, 0, CROP_Y as i64);
+    frame
+}
+
+// Downsampled abstraction frame for compute_coarse_mse
+pub fn thumbnail(img: &RgbImage) -> RgbImage {
+    let (width, height) = img.dimensions();
+    imageops::resize(
+        img,
+        cmp::max(width / THUMBNAIL_DOWNSAMPLE, 1),
+        cmp::max(height / THUMBNAIL_DOWNSAMPLE, 1),
+        FilterType::Triangle,
+    )
+}
+
+// Same as compute_roi_mse on thumbnails of both frames, much cheaper but only
+// good to rank candidates
+pub fn compute_coarse_mse(
+    thumbnail1: &RgbImage,
+    abstraction1: &FrameAbstraction,
+    thumbnail2: &RgbImage,
+    abstraction2: &FrameAbstraction,
+) -> f64 {
+    let scale = |roi: Option<((u32, u32), (u32, u32))>| {
+        roi.map(|(corner1, corner2)| {
+            (
+                (
+                    corner1.0 / THUMBNAIL_DOWNSAMPLE,
+                    corner1.1 / THUMBNAIL_DOWNSAMPLE,
+                ),
+                (
+                    corner2.0 / THUMBNAIL_DOWNSAMPLE,
+                    corner2.1 / THUMBNAIL_DOWNSAMPLE,
+                ),
+            )
+        })
+    };
+    roi_mse(
+        thumbnail1,
+        thumbnail2,
+        scale(abstraction1.roi),
+        scale(abstraction2.roi),
+        CROP_WIDTH / THUMBNAIL_DOWNSAMPLE,
+    )
+}
+
+fn roi_mse(
+    img1: &RgbImage,
+    img2: &RgbImage,
+    roi1: Option<((u32, u32), (u32, u32))>,
+    roi2: Option<((u32, u32), (u32, u32))>,
+    tile_width: u32,
+) -> f64 {
+    if img1.dimensions() != img2.dimensions() {
+        panic!("Images must have the same dimensions for MSE calculation");
+    }
+
+    let (corner1, corner2) = match (roi1, roi2) {
+        (Some(roi1), Some(roi2)) => (
+            (roi1.0 .0.min(roi2.0 .0), roi1.0 .1.min(roi2.0 .1)),
+            (roi1.1 .0.max(roi2.1 .0), roi1.1 .1.max(roi2.1 .1)),
+        ),
+        (Some(roi), None) | (None, Some(roi)) => roi,
+        (None, None) => return compute_mse(img1, img2),
+    };
+
+    let (width, height) = img1.dimensions();
+    let mut error_sum = 0u64;
+    let mut total_pixels = 0u64;
+    for tile_x in (0..width).step_by(tile_width as usize) {
+        for y in corner1.1..=cmp::min(corner2.1, height - 1) {
+            for x in tile_x + corner1.0..=cmp::min(tile_x + corner2.0, width - 1) {
+                let p1 = img1.get_pixel(x, y);
+                let p2 = img2.get_pixel(x, y);
+                for channel in 0..3 {
+                    let diff = p1[channel] as i32 - p2[channel] as i32;
+                    error_sum += (diff * diff) as u64;
+                }
+                total_pixels += 1;
+            }
+        }
+    }
+
+    if total_pixels == 0 {
+        return 0.0;
+    }
+    error_sum as f64 / (total_pixels * 3) as f64
+}
+
 pub fn compute_mse(img1: &RgbImage, img2: &RgbImage) -> f64 {
     // Ensure images have the same dimensions
     if img1.dimensions() != img2.dimensions() {
@@ -918,3 +1502,55 @@ pub fn compute_mse(img1: &RgbImage, img2: &RgbImage) -> f64 {
     let total_pixels = (width * height * 3) as f64; // 3 channels per pixel
     error_sum as f64 / total_pixels
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Life bars drawn where a 320x240 non-interlaced display has them, one
+    // line at half LIFE_BAR_Y, the HUD centred like in 368x480
+    fn small_display_frame() -> GrayImage {
+        let mut frame = GrayImage::from_pixel(320, 240, Luma([30]));
+        for x in 0..320 {
+            frame.put_pixel(x, LIFE_BAR_Y / 2, Luma([150]));
+        }
+        frame
+    }
+
+    #[test]
+    fn fitted_frame_keeps_the_life_bars_in_place() {
+        let frame = fit_frame(small_display_frame());
+        assert_eq!(frame.dimensions(), (DISPLAY_WIDTH, DISPLAY_HEIGHT));
+        assert!(is_hud_visible_gray(&frame));
+
+        let (player_1, player_2) = get_life_info_gray(&frame);
+        assert!(player_1.life > 0.9);
+        assert!(player_2.life > 0.9);
+        assert_eq!(player_1.damage, 0.0);
+    }
+
+    #[test]
+    fn native_frame_is_left_alone() {
+        let frame = GrayImage::from_pixel(DISPLAY_WIDTH, DISPLAY_HEIGHT, Luma([30]));
+        assert_eq!(fit_frame(frame.clone()), frame);
+    }
+
+    #[test]
+    fn scrolled_or_movie_displays() {
+        let scrolled = DisplayInfo {
+            origin: (0, 256),
+            size: (DISPLAY_WIDTH, DISPLAY_HEIGHT),
+            is_24bit: false,
+        };
+        assert!(scrolled.is_native());
+        assert!(scrolled.can_show_hud());
+
+        let movie = DisplayInfo {
+            origin: (0, 0),
+            size: (320, 240),
+            is_24bit: true,
+        };
+        assert!(!movie.is_native());
+        assert!(!movie.can_show_hud());
+    }
+}